@@ -0,0 +1,83 @@
+import { test, expect } from '@playwright/test';
+import { callWasm } from '../helpers/wasm';
+import { openApp } from '../helpers/app';
+
+/**
+ * Backup Tests
+ *
+ * An account exported with export_backup is restored in a fresh browser with import_backup.
+ */
+
+test.describe('Backup', () => {
+  test('restores identity, wallet seed and relays in a fresh browser', async ({ browser }) => {
+    const original = await openApp(browser);
+    const fresh = await openApp(browser);
+
+    try {
+      await callWasm(original, 'add_relay', 'ws://127.0.0.1:8080');
+      const npub = await callWasm(original, 'get_npub');
+      const mnemonic = await callWasm(original, 'export_wallet_mnemonic');
+      const relays = JSON.parse(await callWasm(original, 'get_relays'));
+
+      const blob = await callWasm(original, 'export_backup', 'correct horse');
+      expect(blob).not.toContain(mnemonic);
+
+      // Wrong passphrase leaves the fresh browser untouched
+      const freshNpub = await callWasm(fresh, 'get_npub');
+      const wrong = await callWasm(fresh, 'import_backup', blob, 'wrong passphrase', false)
+        .then(() => null, (err: Error) => err.message);
+      expect(wrong).toContain('Wrong passphrase');
+      expect(await callWasm(fresh, 'get_npub')).toBe(freshNpub);
+
+      // The fresh browser has a mnemonic of its own, which is only discarded on request
+      expect(await callWasm(fresh, 'get_wallet_seed_type')).toBe('mnemonic');
+      const freshMnemonic = await callWasm(fresh, 'export_wallet_mnemonic');
+      const kept = await callWasm(fresh, 'import_backup', blob, 'correct horse', false)
+        .then(() => null, (err: Error) => err.message);
+      expect(kept).toContain('already has a different mnemonic');
+      expect(await callWasm(fresh, 'get_npub')).toBe(freshNpub);
+      expect(await callWasm(fresh, 'export_wallet_mnemonic')).toBe(freshMnemonic);
+
+      const report = JSON.parse(await callWasm(fresh, 'import_backup', blob, 'correct horse', true));
+      expect(report.npub).toBe(npub);
+      expect(report.signer_type).toBe('local');
+      expect(report.wallet_seed).toBe('mnemonic');
+      expect(report.relays).toBe(relays.length);
+
+      expect(await callWasm(fresh, 'get_npub')).toBe(npub);
+      expect(await callWasm(fresh, 'export_wallet_mnemonic')).toBe(mnemonic);
+      expect(JSON.parse(await callWasm(fresh, 'get_relays'))).toEqual(relays);
+    } finally {
+      await original.context().close();
+      await fresh.context().close();
+    }
+  });
+
+  test('a backup with a corrupt section changes nothing', async ({ browser }) => {
+    const original = await openApp(browser);
+    const fresh = await openApp(browser);
+
+    try {
+      const mnemonic = await callWasm(original, 'export_wallet_mnemonic');
+      const blob = await callWasm(original, 'export_backup', 'correct horse');
+
+      await callWasm(fresh, 'get_wallet_seed_type');
+      const freshNpub = await callWasm(fresh, 'get_npub');
+      const freshMnemonic = await callWasm(fresh, 'export_wallet_mnemonic');
+
+      // Re-seal the same payload with one storage section replaced by garbage
+      for (const [section, message] of [['mdk', 'Invalid MDK backup'], ['wallet', 'Invalid wallet backup']]) {
+        const corrupt = await callWasm(original, 'debug_corrupt_backup_section', blob, 'correct horse', section);
+        const error = await callWasm(fresh, 'import_backup', corrupt, 'correct horse', true)
+          .then(() => null, (err: Error) => err.message);
+        expect(error).toContain(message);
+        expect(await callWasm(fresh, 'get_npub')).toBe(freshNpub);
+        expect(await callWasm(fresh, 'export_wallet_mnemonic')).toBe(freshMnemonic);
+      }
+      expect(freshMnemonic).not.toBe(mnemonic);
+    } finally {
+      await original.context().close();
+      await fresh.context().close();
+    }
+  });
+});
@@ -0,0 +1,124 @@
+import { test, expect, Page } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { callWasm, recordRecoveries, recoveries } from '../helpers/wasm';
+
+/**
+ * Commit Race Tests
+ *
+ * Two admins changing the group at the same time both create a commit for the
+ * same epoch. Commits are held until a relay has them, so the commit that sorts
+ * first under MIP-03 wins and the other change is re-applied on top of it,
+ * without either side forking and rolling back.
+ */
+
+async function group(page: Page, name: string): Promise<any> {
+  return JSON.parse(await callWasm(page, 'get_groups')).find((g: any) => g.name === name);
+}
+
+test.describe('Commit races', () => {
+  test('concurrent commits do not fork the group', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Fork Group', await bob.getNpub(), true);
+      await bob.waitForGroup('Fork Group', 20000);
+
+      await alice.openChat('Fork Group');
+      await bob.openChat('Fork Group');
+      await recordRecoveries(alice.page);
+      await recordRecoveries(bob.page);
+
+      const groupId = (await group(alice.page, 'Fork Group')).id;
+      const aliceNpub = await alice.getNpub();
+      const bobNpub = await bob.getNpub();
+
+      // Both admins commit for the same epoch
+      const results = await Promise.all([
+        callWasm(alice.page, 'promote_to_admin_and_publish', groupId, bobNpub),
+        callWasm(bob.page, 'promote_to_admin_and_publish', groupId, aliceNpub),
+      ]);
+      expect(results.map((r: string) => JSON.parse(r).confirmed)).toEqual([true, true]);
+
+      // Neither side had to switch branches
+      expect(await recoveries(alice.page)).toEqual([]);
+      expect(await recoveries(bob.page)).toEqual([]);
+
+      // Both are on the same branch
+      await alice.sendMessage('after the race');
+      await bob.waitForMessage('after the race', 20000);
+      await bob.sendMessage('reply after the race');
+      await alice.waitForMessage('reply after the race', 20000);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+
+  test('concurrent invites both land', async ({ browser }) => {
+    test.setTimeout(180000);
+
+    const contexts = await Promise.all([1, 2, 3, 4].map(() => browser.newContext()));
+    for (const context of contexts) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const [alice, bob, carol, dave] = await Promise.all(
+      ['Alice', 'Bob', 'Carol', 'Dave'].map(async (name, i) => new TestUser(await contexts[i].newPage(), name)));
+
+    try {
+      for (const user of [alice, bob, carol, dave]) {
+        await user.init();
+      }
+      await bob.createKeyPackage();
+      await carol.createKeyPackage();
+      await dave.createKeyPackage();
+
+      await alice.createGroup('Race Group', await bob.getNpub(), true);
+      await bob.waitForGroup('Race Group', 20000);
+
+      const groupId = (await group(alice.page, 'Race Group')).id;
+      const carolNpub = await carol.getNpub();
+      const daveNpub = await dave.getNpub();
+
+      // Both admins commit for the same epoch
+      await Promise.all([
+        callWasm(alice.page, 'invite_member_to_group', groupId, carolNpub, false),
+        callWasm(bob.page, 'invite_member_to_group', groupId, daveNpub, false),
+      ]);
+
+      // Both invites made it, and the admins agree on the members
+      await expect.poll(async () => (await group(alice.page, 'Race Group')).member_npubs.sort(), { timeout: 30000 })
+        .toEqual((await group(bob.page, 'Race Group')).member_npubs.sort());
+      const members = (await group(alice.page, 'Race Group')).member_npubs;
+      expect(members).toContain(carolNpub);
+      expect(members).toContain(daveNpub);
+
+      // Both new members got a Welcome for the commit that was kept
+      await carol.waitForGroup('Race Group', 30000);
+      await dave.waitForGroup('Race Group', 30000);
+
+      await alice.openChat('Race Group');
+      await dave.openChat('Race Group');
+      await alice.sendMessage('after the race');
+      await dave.waitForMessage('after the race', 20000);
+    } finally {
+      await Promise.all(contexts.map((context) => context.close()));
+    }
+  });
+});
@@ -0,0 +1,139 @@
+import { test, expect, Page } from '@playwright/test';
+import { ensureMintRunning } from '../helpers/mint';
+import { callWasm } from '../helpers/wasm';
+import { openApp } from '../helpers/app';
+
+/**
+ * Encryption at Rest Tests
+ *
+ * With a passphrase set, the Nostr key, MDK state and wallet proofs are only
+ * stored as enc1: envelopes and stay unreadable until the storage is unlocked.
+ *
+ * Requires a local mint (cdk-mintd with the fake Lightning backend, see tests/helpers/mint.ts)
+ */
+
+/**
+ * The stored values of an IndexedDB object store, as written by the app
+ * (wallet_db records keep theirs in the `data` field)
+ */
+async function storedValues(page: Page, database: string, store: string): Promise<string[]> {
+  return page.evaluate(({ database, store }) => new Promise<string[]>((resolve, reject) => {
+    const open = indexedDB.open(database);
+    open.onerror = () => reject(open.error);
+    open.onsuccess = () => {
+      const request = open.result.transaction(store, 'readonly').objectStore(store).getAll();
+      request.onerror = () => reject(request.error);
+      request.onsuccess = () => {
+        open.result.close();
+        resolve(request.result.map((v: any) => typeof v === 'string' ? v : v.data));
+      };
+    };
+  }), { database, store });
+}
+
+/** Every value holding the key, chats or ecash */
+async function secretValues(page: Page): Promise<string[]> {
+  const key = await page.evaluate(() => localStorage.getItem('nostr_secret_key'));
+  return [
+    key!,
+    ...await storedValues(page, 'mdk_storage', 'groups'),
+    ...await storedValues(page, 'mdk_storage', 'openmls'),
+    ...await storedValues(page, 'wallet_db', 'proofs'),
+  ];
+}
+
+async function failure(promise: Promise<unknown>): Promise<string | null> {
+  return promise.then(() => null, (err: Error) => err.message);
+}
+
+test.describe('Encryption at Rest', () => {
+  let mintUrl: string;
+
+  test.beforeAll(async () => {
+    mintUrl = await ensureMintRunning(3338);
+  });
+
+  test('seals stored data and only opens it with the passphrase', async ({ browser }) => {
+    test.setTimeout(90000);
+
+    const page = await openApp(browser);
+
+    try {
+      const npub = await callWasm(page, 'get_npub');
+      const groupId = await callWasm(page, 'create_group_with_members', 'Vault Group', 'sealed', '[]');
+
+      const invoice = JSON.parse(await callWasm(page, 'create_lightning_invoice', mintUrl, BigInt(100), 'vault test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      const balance = async () => JSON.parse(await callWasm(page, 'get_all_mint_balances'))
+        .find((b: any) => b.mint === mintUrl)?.balance;
+      const groupNames = async () => JSON.parse(await callWasm(page, 'get_groups')).map((g: any) => g.name);
+      expect(await balance()).toBe(100);
+
+      // Plaintext until a passphrase is set
+      const plaintext = await secretValues(page);
+      expect(plaintext.length).toBeGreaterThan(3);
+      expect(plaintext.some(v => v.startsWith('enc1:'))).toBe(false);
+
+      await callWasm(page, 'change_passphrase', '', 'correct horse');
+      expect(await callWasm(page, 'is_encryption_enabled')).toBe(true);
+      const sealed = await secretValues(page);
+      expect(sealed.length).toBe(plaintext.length);
+      for (const value of sealed) {
+        expect(value.startsWith('enc1:')).toBe(true);
+      }
+      expect(sealed.join()).not.toContain(groupId);
+
+      // Locked: nothing can be read, and a wrong passphrase doesn't open it
+      await callWasm(page, 'lock');
+      expect(await callWasm(page, 'is_storage_locked')).toBe(true);
+      expect(await failure(callWasm(page, 'get_groups'))).toContain('locked');
+      expect(await failure(callWasm(page, 'get_all_mint_balances'))).toContain('locked');
+      expect(await failure(callWasm(page, 'unlock', 'wrong passphrase'))).toContain('Wrong passphrase');
+      expect(await callWasm(page, 'is_storage_locked')).toBe(true);
+
+      await callWasm(page, 'unlock', 'correct horse');
+      expect(await callWasm(page, 'get_npub')).toBe(npub);
+      expect(await groupNames()).toContain('Vault Group');
+      expect(await balance()).toBe(100);
+
+      // Changing the passphrase needs the old one, and the old one stops working
+      expect(await failure(callWasm(page, 'change_passphrase', 'wrong passphrase', 'battery staple')))
+        .toContain('Wrong passphrase');
+      await callWasm(page, 'change_passphrase', 'correct horse', 'battery staple');
+      await callWasm(page, 'lock');
+      expect(await failure(callWasm(page, 'unlock', 'correct horse'))).toContain('Wrong passphrase');
+      await callWasm(page, 'unlock', 'battery staple');
+      for (const value of await secretValues(page)) {
+        expect(value.startsWith('enc1:')).toBe(true);
+      }
+
+      // A reload forgets the key; the app asks for the passphrase before loading anything
+      page.once('dialog', dialog => dialog.accept('battery staple'));
+      await page.reload();
+      await page.waitForSelector('#status', { timeout: 10000 });
+      expect(await callWasm(page, 'is_storage_locked')).toBe(false);
+      expect(await callWasm(page, 'get_npub')).toBe(npub);
+
+      // Removing the passphrase brings back readable plaintext
+      await callWasm(page, 'change_passphrase', 'battery staple', '');
+      expect(await callWasm(page, 'is_encryption_enabled')).toBe(false);
+      const restored = await secretValues(page);
+      expect(restored.length).toBe(plaintext.length);
+      expect(restored.some(v => v.startsWith('enc1:'))).toBe(false);
+      expect(restored[0]).toMatch(/^[0-9a-f]{64}$/);
+
+      await page.reload();
+      await page.waitForSelector('#status', { timeout: 10000 });
+      expect(await callWasm(page, 'is_storage_locked')).toBe(false);
+      expect(await callWasm(page, 'get_npub')).toBe(npub);
+      expect(await groupNames()).toContain('Vault Group');
+      expect(await balance()).toBe(100);
+    } finally {
+      await page.context().close();
+    }
+  });
+});
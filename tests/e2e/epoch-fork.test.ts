@@ -0,0 +1,66 @@
+import { test, expect } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { callWasm, recordRecoveries, recoveries } from '../helpers/wasm';
+
+/**
+ * Epoch Fork Tests
+ *
+ * A commit for an epoch we already moved past, that sorts first under MIP-03
+ * ordering, forks the group: we roll back to the checkpoint of that epoch,
+ * apply it and replay what followed. Commits are held until a relay has them
+ * (see commit-race.test.ts), so the fork is forced here by publishing a
+ * competing commit only after ours was merged.
+ */
+
+test.describe('Epoch forks', () => {
+  test('rolls back to a competing commit that sorts first', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Fork Group', await bob.getNpub(), true);
+      await bob.waitForGroup('Fork Group', 20000);
+
+      await alice.openChat('Fork Group');
+      await recordRecoveries(alice.page);
+
+      const groupId = JSON.parse(await callWasm(alice.page, 'get_groups'))
+        .find((g: any) => g.name === 'Fork Group').id;
+      const aliceNpub = await alice.getNpub();
+      const bobNpub = await bob.getNpub();
+
+      // Bob commits for this epoch first but his commit reaches the relay late
+      const detached = await callWasm(bob.page, 'debug_create_detached_commit', groupId, aliceNpub, false);
+      await alice.page.waitForTimeout(1100);
+
+      // Alice commits for the same epoch and merges it, as no relay has a competing commit yet
+      await callWasm(alice.page, 'promote_to_admin_and_publish', groupId, bobNpub);
+      expect(await recoveries(alice.page)).toEqual([]);
+
+      // Bob's earlier commit is canonical: Alice rolls back and switches to it
+      await callWasm(bob.page, 'debug_publish_event', detached);
+      await expect.poll(async () => (await recoveries(alice.page)).length, { timeout: 30000 }).toBe(1);
+      const [report] = await recoveries(alice.page);
+      expect(report.outcome).toBe('rolled_back');
+      expect(report.own_commit_discarded).toBe(true);
+      expect(report.canonical_commit).toBe(JSON.parse(detached).id);
+      expect(report.discarded_commit).not.toBe(report.canonical_commit);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
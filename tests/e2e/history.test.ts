@@ -0,0 +1,74 @@
+import { test, expect } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * History Paging Tests
+ *
+ * load_older_messages pages back through a group's messages, oldest first,
+ * backfilling periods that were never fetched from the relays.
+ */
+
+test.describe('History paging', () => {
+  test('pages through older messages until the start', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('History Group', await bob.getNpub());
+      await bob.waitForGroup('History Group', 20000);
+
+      await alice.openChat('History Group');
+      await bob.openChat('History Group');
+      const sent = ['first', 'second', 'third', 'fourth', 'fifth'];
+      for (const text of sent) {
+        await alice.sendMessage(text);
+        await bob.waitForMessage(text, 20000);
+        // Distinct seconds, so pages split cleanly on created_at
+        await alice.page.waitForTimeout(1100);
+      }
+
+      const groupId = JSON.parse(await callWasm(bob.page, 'get_groups'))
+        .find((g: any) => g.name === 'History Group').id;
+
+      // Pages of two, newest page first, each oldest first
+      const pages: string[][] = [];
+      let before: number | undefined = undefined;
+      for (let i = 0; i < 10; i++) {
+        const page = JSON.parse(await callWasm(bob.page, 'load_older_messages', groupId, before, 2));
+        pages.push(page.messages.map((m: any) => m.content));
+        if (!page.has_more) {
+          break;
+        }
+        // A page can come back empty while a long gap is still being backfilled
+        before = page.messages.length > 0 ? page.messages[0].created_at : before;
+      }
+
+      expect(pages[0]).toEqual(['fourth', 'fifth']);
+      expect(pages[1]).toEqual(['second', 'third']);
+      expect([...pages].reverse().flat()).toEqual(sent);
+
+      // Nothing is fetched again once the history is covered
+      const again = JSON.parse(await callWasm(bob.page, 'load_older_messages', groupId, undefined, 10));
+      expect(again.messages.map((m: any) => m.content)).toEqual(sent);
+      expect(again.fetched).toBe(0);
+      expect(again.has_more).toBe(false);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
@@ -0,0 +1,37 @@
+import { test, expect } from '@playwright/test';
+import { callWasm } from '../helpers/wasm';
+import { openApp } from '../helpers/app';
+
+/**
+ * NIP-49 Tests
+ *
+ * An identity exported with export_ncryptsec is imported in another browser with import_ncryptsec.
+ */
+
+test.describe('ncryptsec', () => {
+  test('round-trips an identity and rejects a wrong password', async ({ browser }) => {
+    const original = await openApp(browser);
+    const other = await openApp(browser);
+
+    try {
+      const npub = await callWasm(original, 'get_npub');
+
+      // A low scrypt cost keeps the test fast; costs past the cap are refused
+      await expect(callWasm(original, 'export_ncryptsec', 'hunter2', 23)).rejects.toThrow(/scrypt cost/);
+      const ncryptsec = await callWasm(original, 'export_ncryptsec', 'hunter2', 12);
+      expect(ncryptsec).toMatch(/^ncryptsec1/);
+
+      // A wrong password leaves the other browser's identity alone
+      const otherNpub = await callWasm(other, 'get_npub');
+      await expect(callWasm(other, 'import_ncryptsec', ncryptsec, 'hunter3')).rejects.toThrow(/Wrong password/);
+      expect(await callWasm(other, 'get_npub')).toBe(otherNpub);
+
+      await callWasm(other, 'import_ncryptsec', ncryptsec, 'hunter2');
+      expect(await callWasm(other, 'get_npub')).toBe(npub);
+      expect(await callWasm(other, 'get_nsec')).toBe(await callWasm(original, 'get_nsec'));
+    } finally {
+      await original.context().close();
+      await other.context().close();
+    }
+  });
+});
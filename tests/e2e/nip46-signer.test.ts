@@ -0,0 +1,65 @@
+import { test, expect } from '@playwright/test';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * NIP-46 Remote Signer Tests
+ *
+ * One browser context runs a stand-in bunker (debug_start_test_bunker), the other
+ * signs through it over the local relay.
+ *
+ * Note: Relay is managed globally (see tests/global-setup.ts)
+ * It runs on ws://localhost:8080 and stays running across test runs
+ */
+const RELAY = 'ws://localhost:8080';
+
+test.describe('NIP-46 Remote Signer', () => {
+  test('signs through a bunker and keeps the session across reloads', async ({ browser }) => {
+    test.setTimeout(60000);
+
+    const bunkerContext = await browser.newContext();
+    const appContext = await browser.newContext();
+    for (const context of [bunkerContext, appContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const bunkerPage = await bunkerContext.newPage();
+    const appPage = await appContext.newPage();
+
+    try {
+      await bunkerPage.goto('/');
+      await bunkerPage.waitForSelector('#status', { timeout: 10000 });
+      const bunker = JSON.parse(await callWasm(bunkerPage, 'debug_start_test_bunker', RELAY));
+      console.log(`Test bunker: ${bunker.bunker_uri}`);
+
+      await appPage.goto('/');
+      await appPage.waitForSelector('#status', { timeout: 10000 });
+
+      // Connecting switches identity to the bunker's user key
+      const npub = await callWasm(appPage, 'connect_remote_signer', bunker.bunker_uri);
+      expect(npub).toBe(bunker.npub);
+      expect(await callWasm(appPage, 'get_signer_type')).toBe('nip46');
+
+      // The local key left from before belongs to another identity: it isn't exported as this one's
+      await expect(callWasm(appPage, 'get_nsec')).rejects.toThrow(/held by the nip46 signer/);
+      await expect(callWasm(appPage, 'export_ncryptsec', 'password', 12)).rejects.toThrow(/held by the nip46 signer/);
+
+      // Signing goes through the bunker
+      const published = await callWasm(appPage, 'publish_profile_metadata', JSON.stringify({ name: 'Bunker User' }));
+      expect(published).toBe('success');
+
+      // The session is persisted: after a reload we still sign as the bunker user
+      await appPage.reload();
+      await appPage.waitForSelector('#status', { timeout: 10000 });
+      await appPage.click('.nav-item:has-text("Identity")');
+      await expect(appPage.locator('#npub')).toHaveText(bunker.npub, { timeout: 10000 });
+
+      const republished = await callWasm(appPage, 'publish_profile_metadata', JSON.stringify({ name: 'Bunker User 2' }));
+      expect(republished).toBe('success');
+    } finally {
+      await bunkerContext.close();
+      await appContext.close();
+    }
+  });
+});
@@ -0,0 +1,88 @@
+import { test, expect } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Outbox Tests
+ *
+ * Messages are recorded before they are published, with their delivery state
+ * on each relay, and relays that missed them stay queued for retry. The
+ * delivery receipt outlives the outbox entry.
+ */
+
+test.describe('Outbox', () => {
+  test('reports per-relay delivery and keeps unreachable relays pending', async ({ browser }) => {
+    test.setTimeout(90000);
+
+    // Nothing listens on port 8099
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    await aliceContext.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080', 'ws://localhost:8099'];
+    });
+    await bobContext.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Outbox Group', await bob.getNpub());
+      await bob.waitForGroup('Outbox Group', 20000);
+
+      await alice.openChat('Outbox Group');
+      await alice.sendMessage('queued somewhere');
+
+      const outbox = JSON.parse(await callWasm(alice.page, 'get_outbox'));
+      const message = outbox.find((entry: any) => entry.purpose === 'message');
+      expect(message).toBeDefined();
+      expect(message.status).toBe('sent');
+      expect(message.relays['ws://localhost:8080'].status).toBe('sent');
+      expect(message.relays['ws://localhost:8099'].status).toBe('pending');
+      expect(message.next_retry_at).not.toBeNull();
+
+      // The Welcome for Bob went through the outbox too
+      expect(outbox.some((entry: any) => entry.purpose === 'welcome')).toBe(true);
+
+      // The unreachable relay isn't connected, so nothing is retried
+      expect(await callWasm(alice.page, 'retry_outbox')).toBe(0);
+
+      // The message shows the same receipt; Bob's copy carries none
+      const groupId = JSON.parse(await callWasm(alice.page, 'get_groups'))
+        .find((g: any) => g.name === 'Outbox Group').id;
+      const ownMessages = JSON.parse(await callWasm(alice.page, 'get_messages_for_group', groupId));
+      const sent = ownMessages.find((m: any) => m.content === 'queued somewhere');
+      expect(sent.delivery.status).toBe('sent');
+      expect(sent.delivery.relays['ws://localhost:8099'].error).toBeTruthy();
+
+      // The receipt is kept with the message after its outbox entry expires
+      await alice.page.evaluate(() => new Promise<void>((resolve, reject) => {
+        const open = indexedDB.open('mdk_storage');
+        open.onerror = () => reject(open.error);
+        open.onsuccess = () => {
+          const tx = open.result.transaction(['outbox', 'outbox_pending'], 'readwrite');
+          tx.objectStore('outbox').clear();
+          tx.objectStore('outbox_pending').clear();
+          tx.oncomplete = () => { open.result.close(); resolve(); };
+          tx.onerror = () => reject(tx.error);
+        };
+      }));
+      expect(JSON.parse(await callWasm(alice.page, 'get_outbox'))).toEqual([]);
+      const kept = JSON.parse(await callWasm(alice.page, 'get_messages_for_group', groupId))
+        .find((m: any) => m.content === 'queued somewhere');
+      expect(kept.delivery).toEqual(sent.delivery);
+
+      await bob.openChat('Outbox Group');
+      await bob.waitForMessage('queued somewhere');
+      const bobMessages = JSON.parse(await callWasm(bob.page, 'get_messages_for_group', groupId));
+      expect(bobMessages.find((m: any) => m.content === 'queued somewhere').delivery).toBeNull();
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
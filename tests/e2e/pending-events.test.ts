@@ -0,0 +1,57 @@
+import { test, expect } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Pending Event Tests
+ *
+ * A message from an epoch we haven't reached yet (it arrived before the commit
+ * that starts that epoch) is queued and processed once the commit arrives.
+ */
+
+test.describe('Pending events', () => {
+  test('a message that beats its commit is shown once the commit arrives', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Pending Group', await bob.getNpub(), true);
+      await bob.waitForGroup('Pending Group', 20000);
+
+      await alice.openChat('Pending Group');
+      await bob.openChat('Pending Group');
+
+      const groupId = JSON.parse(await callWasm(alice.page, 'get_groups'))
+        .find((g: any) => g.name === 'Pending Group').id;
+
+      // Alice moves to the next epoch without publishing the commit, then sends from it
+      const commit = await callWasm(alice.page, 'debug_create_detached_commit', groupId, await bob.getNpub(), true);
+      await alice.sendMessage('from the next epoch');
+      await alice.waitForMessage('from the next epoch', 20000);
+
+      // Bob can't read it yet
+      await bob.page.waitForTimeout(3000);
+      await expect(bob.page.locator('#chat-messages').getByText('from the next epoch', { exact: true })).toHaveCount(0);
+
+      // Once the commit arrives the queued message goes through
+      await callWasm(alice.page, 'debug_publish_event', commit);
+      await bob.waitForMessage('from the next epoch', 20000);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
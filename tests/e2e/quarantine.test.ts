@@ -0,0 +1,59 @@
+import { test, expect } from '@playwright/test';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Quarantine Tests
+ *
+ * Stored records that can't be read are set aside (and reported by get_quarantine)
+ * instead of failing the load or being dropped.
+ */
+
+test.describe('Quarantine', () => {
+  test.beforeEach(async ({ page }) => {
+    await page.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    await page.goto('/');
+    await page.waitForSelector('#status', { timeout: 10000 });
+  });
+
+  test('sets aside unreadable records and keeps loading', async ({ page }) => {
+    // Make sure the databases exist, then nothing is quarantined yet
+    expect(JSON.parse(await callWasm(page, 'get_quarantine'))).toEqual([]);
+
+    // A group record from a newer app version and an unparseable legacy snapshot
+    await page.evaluate(async () => {
+      await new Promise<void>((resolve, reject) => {
+        const open = indexedDB.open('mdk_storage');
+        open.onerror = () => reject(open.error);
+        open.onsuccess = () => {
+          const db = open.result;
+          const tx = db.transaction('groups', 'readwrite');
+          tx.objectStore('groups').put('{"schema_version":99,"data":{}}', 'aa');
+          tx.oncomplete = () => { db.close(); resolve(); };
+          tx.onerror = () => reject(tx.error);
+        };
+      });
+      localStorage.setItem('mdk_state', '{not json');
+    });
+
+    await callWasm(page, 'clear_storage_cache');
+    const entries = JSON.parse(await callWasm(page, 'get_quarantine'));
+
+    const group = entries.find((e: any) => e.database === 'mdk_storage' && e.store === 'groups');
+    expect(group?.key).toBe('aa');
+    expect(group?.error).toContain('newer version');
+
+    const legacy = entries.find((e: any) => e.database === 'localStorage' && e.store === 'mdk_state');
+    expect(legacy).toBeTruthy();
+
+    // The originals are moved, not deleted
+    const kept = await page.evaluate(() =>
+      Object.keys(localStorage).filter(k => k.startsWith('mdk_state.quarantine/')).length);
+    expect(kept).toBe(1);
+    expect(await page.evaluate(() => localStorage.getItem('mdk_state'))).toBeNull();
+
+    // Everything else still works
+    expect(JSON.parse(await callWasm(page, 'get_groups'))).toEqual([]);
+  });
+});
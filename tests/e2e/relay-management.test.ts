@@ -1,4 +1,5 @@
 import { test, expect } from '@playwright/test';
+import { callWasm } from '../helpers/wasm';
 
 /**
  * Relay Management Tests
@@ -86,4 +87,36 @@ test.describe('Relay Management', () => {
 
     await context.close();
   });
+
+  test('relay edits apply to the live connection pool', async ({ browser }) => {
+    const context = await browser.newContext();
+    await context.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    const page = await context.newPage();
+    await page.goto('/');
+    await page.waitForSelector('#status', { timeout: 10000 });
+
+    const pooled = async () => {
+      const health = JSON.parse(await callWasm(page, 'get_relay_health'));
+      return health.map((h: any) => ({ url: h.url.replace(/\/$/, ''), connected: h.connected }));
+    };
+
+    await callWasm(page, 'add_relay', 'ws://127.0.0.1:8080');
+    await expect.poll(pooled, { timeout: 10000 }).toEqual([
+      { url: 'ws://127.0.0.1:8080', connected: true },
+      { url: 'ws://localhost:8080', connected: true },
+    ]);
+
+    await callWasm(page, 'remove_relay', 'ws://127.0.0.1:8080');
+    expect(await pooled()).toEqual([{ url: 'ws://localhost:8080', connected: true }]);
+
+    // An unreachable relay is rejected and doesn't stay in the pool
+    const error = await callWasm(page, 'add_relay', 'ws://localhost:1')
+      .then(() => null, (err: Error) => err.message);
+    expect(error).toContain('Failed to connect');
+    expect(await pooled()).toEqual([{ url: 'ws://localhost:8080', connected: true }]);
+
+    await context.close();
+  });
 });
@@ -0,0 +1,169 @@
+import { test, expect, Page } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { ensureMintRunning } from '../helpers/mint';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Sent Token Tests
+ *
+ * Tokens we send are kept until someone claims them, so the sender can take
+ * back the ones nobody redeemed (P2PK-locked ones once their locktime passed).
+ *
+ * Requires a local mint (cdk-mintd with the fake Lightning backend, see tests/helpers/mint.ts)
+ */
+
+async function balance(page: Page, mintUrl: string): Promise<number | undefined> {
+  const balances = JSON.parse(await callWasm(page, 'get_all_mint_balances'));
+  return balances.find((b: any) => b.mint === mintUrl)?.balance;
+}
+
+test.describe('Sent tokens', () => {
+  let mintUrl: string;
+
+  test.beforeAll(async () => {
+    mintUrl = await ensureMintRunning(3338);
+  });
+
+  test('the sender reclaims a token nobody claimed', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+
+      await callWasm(alice.page, 'add_trusted_mint', mintUrl);
+      await callWasm(alice.page, 'set_current_mint', mintUrl);
+      const invoice = JSON.parse(await callWasm(alice.page, 'create_lightning_invoice', mintUrl, BigInt(100), 'reclaim test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(alice.page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      const forgotten = await callWasm(alice.page, 'send_ecash', BigInt(30));
+      const redeemed = await callWasm(alice.page, 'send_ecash', BigInt(20));
+      expect(await balance(alice.page, mintUrl)).toBe(50);
+
+      const pending = JSON.parse(await callWasm(alice.page, 'list_pending_sent_tokens'));
+      expect(pending.map((sent: any) => [sent.amount, sent.token])).toEqual([[30, forgotten], [20, redeemed]]);
+
+      // Bob redeems one; checking with the mint drops it from the list
+      await callWasm(bob.page, 'receive_token', redeemed);
+      const checked = JSON.parse(await callWasm(alice.page, 'check_sent_tokens'));
+      expect(checked.claimed).toEqual([pending[1].id]);
+      await expect(callWasm(alice.page, 'reclaim_token', pending[1].id)).rejects.toThrow(/already claimed/);
+
+      // Alice takes the other one back
+      const reclaimed = JSON.parse(await callWasm(alice.page, 'reclaim_token', pending[0].id));
+      expect(reclaimed.amount).toBe(30);
+      expect(await balance(alice.page, mintUrl)).toBe(80);
+      expect(JSON.parse(await callWasm(alice.page, 'list_pending_sent_tokens'))).toEqual([]);
+
+      await expect(callWasm(alice.page, 'reclaim_token', pending[0].id)).rejects.toThrow(/already reclaimed/);
+      await expect(callWasm(bob.page, 'receive_token', forgotten)).rejects.toThrow();
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+
+  test('a locked token goes back to the sender after its locktime', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      const aliceNpub = await alice.getNpub();
+      const bobNpub = await bob.getNpub();
+
+      await callWasm(alice.page, 'add_trusted_mint', mintUrl);
+      await callWasm(alice.page, 'set_current_mint', mintUrl);
+      const invoice = JSON.parse(await callWasm(alice.page, 'create_lightning_invoice', mintUrl, BigInt(100), 'refund test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(alice.page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      // Two tokens for Bob that Alice can take back after a few seconds
+      const locktime = Math.floor(Date.now() / 1000) + 5;
+      const reclaimed = await callWasm(alice.page, 'send_ecash_p2pk', BigInt(30), bobNpub, locktime, null);
+      const received = await callWasm(alice.page, 'send_ecash_p2pk', BigInt(20), bobNpub, locktime, null);
+
+      const info = JSON.parse(await callWasm(bob.page, 'parse_token_info', reclaimed));
+      expect(info).toMatchObject({ secret_kind: 'P2PK', secret_npub: bobNpub, locktime, lock_expired: false, refund_npubs: [aliceNpub] });
+
+      const pending = JSON.parse(await callWasm(alice.page, 'list_pending_sent_tokens'));
+      const id = pending.find((sent: any) => sent.token === reclaimed).id;
+      await expect(callWasm(alice.page, 'reclaim_token', id)).rejects.toThrow(/locked to its recipient/);
+
+      await expect.poll(async () => JSON.parse(await callWasm(bob.page, 'parse_token_info', reclaimed)).lock_expired,
+        { timeout: 15000, intervals: [1000] }).toBe(true);
+
+      // Alice signs as the refund key, either way
+      expect(JSON.parse(await callWasm(alice.page, 'reclaim_token', id)).amount).toBe(30);
+      expect(await callWasm(alice.page, 'receive_token', received)).toBe(20);
+      expect(await balance(alice.page, mintUrl)).toBe(100);
+
+      await expect(callWasm(bob.page, 'receive_token', reclaimed)).rejects.toThrow();
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+
+  test('tokens are reclaimed automatically after the timeout', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const context = await browser.newContext();
+    await context.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    const alice = new TestUser(await context.newPage(), 'Alice');
+
+    try {
+      await alice.init();
+
+      await callWasm(alice.page, 'add_trusted_mint', mintUrl);
+      await callWasm(alice.page, 'set_current_mint', mintUrl);
+      const invoice = JSON.parse(await callWasm(alice.page, 'create_lightning_invoice', mintUrl, BigInt(50), 'auto reclaim test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(alice.page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      await callWasm(alice.page, 'send_ecash', BigInt(10));
+      expect(await callWasm(alice.page, 'get_auto_reclaim_timeout')).toBeUndefined();
+      await callWasm(alice.page, 'set_auto_reclaim_timeout', 1);
+      expect(await callWasm(alice.page, 'get_auto_reclaim_timeout')).toBe(1);
+
+      await expect.poll(async () => {
+        const checked = JSON.parse(await callWasm(alice.page, 'check_sent_tokens'));
+        return Object.values(checked.reclaimed);
+      }, { timeout: 10000, intervals: [1500] }).toEqual([10]);
+      expect(await balance(alice.page, mintUrl)).toBe(50);
+    } finally {
+      await context.close();
+    }
+  });
+});
@@ -0,0 +1,202 @@
+import { test, expect, Page } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+
+/**
+ * Subscription Tests
+ *
+ * All groups and Welcomes share one REQ; callbacks are registered per group
+ * and removed again with the handle subscribe_to_group_messages returns.
+ */
+
+// Register a callback for a group that records messages under window.received[name]
+async function subscribeRecorder(page: Page, groupId: string, name: string): Promise<number> {
+  return page.evaluate(async ({ groupId, name }) => {
+    const wasm: any = await import('/pkg/mdk_ecash_web.js');
+    const received = ((window as any).received ??= {});
+    received[name] = [];
+    return await wasm.subscribe_to_group_messages(groupId, (message: any) => {
+      received[name].push(message.content);
+    });
+  }, { groupId, name });
+}
+
+async function received(page: Page, name: string): Promise<string[]> {
+  return page.evaluate((name) => (window as any).received[name], name);
+}
+
+test.describe('Subscriptions', () => {
+  test('routes messages to each callback until it unsubscribes', async ({ browser }) => {
+    test.setTimeout(90000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Subscription Group', await bob.getNpub());
+      await bob.waitForGroup('Subscription Group', 20000);
+
+      const groupId = await bob.page.evaluate(async () => {
+        const wasm: any = await import('/pkg/mdk_ecash_web.js');
+        const groups = JSON.parse(await wasm.get_groups());
+        return groups.find((g: any) => g.name === 'Subscription Group').id;
+      });
+
+      // Subscribing the same group twice shares the REQ and gives distinct handles
+      const first = await subscribeRecorder(bob.page, groupId, 'first');
+      const second = await subscribeRecorder(bob.page, groupId, 'second');
+      expect(first).not.toBe(second);
+
+      await alice.openChat('Subscription Group');
+      await alice.sendMessage('to both');
+      await expect.poll(() => received(bob.page, 'first'), { timeout: 15000 }).toContain('to both');
+      await expect.poll(() => received(bob.page, 'second'), { timeout: 15000 }).toContain('to both');
+
+      const removed = await bob.page.evaluate(async (handle) => {
+        const wasm: any = await import('/pkg/mdk_ecash_web.js');
+        return await wasm.unsubscribe(handle);
+      }, first);
+      expect(removed).toBe(true);
+
+      await alice.sendMessage('to second only');
+      await expect.poll(() => received(bob.page, 'second'), { timeout: 15000 }).toContain('to second only');
+      expect(await received(bob.page, 'first')).not.toContain('to second only');
+
+      // Group messages and Welcomes share one REQ with a filter each
+      const requests = JSON.parse(await bob.page.evaluate(async () => {
+        const wasm: any = await import('/pkg/mdk_ecash_web.js');
+        return wasm.debug_open_requests();
+      }));
+      expect(Object.keys(requests)).toHaveLength(1);
+      const filters: any[] = Object.values(requests)[0] as any[];
+      expect(filters).toHaveLength(2);
+      expect(filters.find(f => f.kinds?.includes(445))?.['#h']).toHaveLength(1);
+      expect(filters.find(f => f.kinds?.includes(444))?.['#p']).toHaveLength(1);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+
+  test('delivers an event seen on several relays once', async ({ browser }) => {
+    test.setTimeout(90000);
+
+    // The same relay under two URLs: every event arrives twice
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    await aliceContext.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    await bobContext.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080', 'ws://127.0.0.1:8080'];
+    });
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+
+      // Quorum of 0 relays is rejected
+      const error = await bob.page.evaluate(async () => {
+        const wasm: any = await import('/pkg/mdk_ecash_web.js');
+        try { wasm.set_subscription_history_options(0, 1000); return null; } catch (e: any) { return String(e); }
+      });
+      expect(error).toContain('Quorum');
+
+      await bob.createKeyPackage();
+      await alice.createGroup('Dedup Group', await bob.getNpub());
+      await bob.waitForGroup('Dedup Group', 20000);
+
+      const groupId = await bob.page.evaluate(async () => {
+        const wasm: any = await import('/pkg/mdk_ecash_web.js');
+        const groups = JSON.parse(await wasm.get_groups());
+        return groups.find((g: any) => g.name === 'Dedup Group').id;
+      });
+      await subscribeRecorder(bob.page, groupId, 'dedup');
+
+      await alice.openChat('Dedup Group');
+      await alice.sendMessage('only once');
+      await expect.poll(() => received(bob.page, 'dedup'), { timeout: 15000 }).toContain('only once');
+
+      // Give the second copy time to arrive
+      await bob.page.waitForTimeout(2000);
+      expect((await received(bob.page, 'dedup')).filter(m => m === 'only once')).toHaveLength(1);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+
+  test('processes history from several relays once each, oldest first', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    // The same relay under two URLs: all of Bob's history arrives twice
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    await aliceContext.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    await bobContext.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080', 'ws://127.0.0.1:8080'];
+    });
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Catch-up Group', await bob.getNpub());
+      await bob.waitForGroup('Catch-up Group', 20000);
+
+      // Bob goes offline while Alice writes
+      await bob.page.close();
+      await alice.openChat('Catch-up Group');
+      const sent = ['h1', 'h2', 'h3', 'h4'];
+      for (const text of sent) {
+        await alice.sendMessage(text);
+        // Distinct seconds, so created_at alone gives the order
+        await alice.page.waitForTimeout(1100);
+      }
+
+      // Back online: the history comes from both relays
+      const page = await bobContext.newPage();
+      const processed: string[] = [];
+      const failures: string[] = [];
+      page.on('console', message => {
+        const text = message.text();
+        const application = text.match(/Application message: '(h\d)'/);
+        if (application) {
+          processed.push(application[1]);
+        }
+        if (text.includes('Failed to handle event')) {
+          failures.push(text);
+        }
+      });
+      await page.goto('/');
+      await page.waitForSelector('#status', { timeout: 10000 });
+
+      await expect.poll(() => processed.length, { timeout: 20000 }).toBe(sent.length);
+      // Give the second relay's copies time to arrive
+      await page.waitForTimeout(2000);
+      expect(processed).toEqual(sent);
+      expect(failures).toEqual([]);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
@@ -0,0 +1,184 @@
+import { test, expect, Page } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { ensureMintRunning } from '../helpers/mint';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Token Message Tests
+ *
+ * Ecash sent to a group shows up as a payment message that members can claim once,
+ * and every member can see who claimed it.
+ *
+ * Requires a local mint (cdk-mintd with the fake Lightning backend, see tests/helpers/mint.ts)
+ */
+
+// Ids of the payments shown in a group, by amount
+async function paymentIds(page: Page, groupId: string): Promise<Record<number, string>> {
+  const messages = JSON.parse(await callWasm(page, 'get_messages_for_group', groupId));
+  return Object.fromEntries(messages.filter((m: any) => m.payment).map((m: any) => [m.payment.amount, m.id]));
+}
+
+async function paymentStatus(page: Page, groupId: string, messageId: string): Promise<any> {
+  const messages = JSON.parse(await callWasm(page, 'get_messages_for_group', groupId));
+  const payment = messages.find((m: any) => m.id === messageId)?.payment;
+  return payment && { status: payment.status, claimed_by: payment.claimed_by };
+}
+
+test.describe('Token messages', () => {
+  let mintUrl: string;
+
+  test.beforeAll(async () => {
+    mintUrl = await ensureMintRunning(3338);
+  });
+
+  test('a member claims ecash sent to the group', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Payment Group', await bob.getNpub());
+      await bob.waitForGroup('Payment Group', 20000);
+
+      // Mint 100 sats for Alice (the fake wallet pays the invoice by itself)
+      await callWasm(alice.page, 'add_trusted_mint', mintUrl);
+      await callWasm(alice.page, 'set_current_mint', mintUrl);
+      const invoice = JSON.parse(await callWasm(alice.page, 'create_lightning_invoice', mintUrl, BigInt(100), 'payment test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(alice.page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      const groupId = JSON.parse(await callWasm(alice.page, 'get_groups'))
+        .find((g: any) => g.name === 'Payment Group').id;
+      await callWasm(alice.page, 'send_ecash_to_group', groupId, BigInt(21), 'for the pizza');
+
+      // Bob sees a payment, not a token string
+      let messageId = '';
+      await expect.poll(async () => {
+        const messages = JSON.parse(await callWasm(bob.page, 'get_messages_for_group', groupId));
+        const payment = messages.find((m: any) => m.payment);
+        messageId = payment?.id ?? '';
+        return payment?.payment && {
+          amount: payment.payment.amount,
+          mint: payment.payment.mint,
+          memo: payment.payment.memo,
+          claimed_at: payment.payment.claimed_at,
+        };
+      }, { timeout: 20000, intervals: [1000] }).toEqual({
+        amount: 21,
+        mint: mintUrl,
+        memo: 'for the pizza',
+        claimed_at: null,
+      });
+
+      const claim = JSON.parse(await callWasm(bob.page, 'claim_token_message', messageId));
+      expect(claim.amount).toBe(21);
+
+      const balances = JSON.parse(await callWasm(bob.page, 'get_all_mint_balances'));
+      expect(balances.find((b: any) => b.mint === mintUrl)?.balance).toBe(21);
+
+      // Shown as claimed from now on, and can't be claimed twice
+      const messages = JSON.parse(await callWasm(bob.page, 'get_messages_for_group', groupId));
+      expect(messages.find((m: any) => m.id === messageId).payment.claimed_at).toBe(claim.claimed_at);
+      await expect(callWasm(bob.page, 'claim_token_message', messageId)).rejects.toThrow(/Already claimed/);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+
+  test('members see who claimed a token', async ({ browser }) => {
+    test.setTimeout(120000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      await alice.createGroup('Claims Group', await bob.getNpub());
+      await bob.waitForGroup('Claims Group', 20000);
+
+      await callWasm(alice.page, 'add_trusted_mint', mintUrl);
+      await callWasm(alice.page, 'set_current_mint', mintUrl);
+      const invoice = JSON.parse(await callWasm(alice.page, 'create_lightning_invoice', mintUrl, BigInt(100), 'claims test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(alice.page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      const groupId = JSON.parse(await callWasm(alice.page, 'get_groups'))
+        .find((g: any) => g.name === 'Claims Group').id;
+      const aliceNpub = await alice.getNpub();
+      const bobNpub = await bob.getNpub();
+
+      // Three payments: one for Bob, one Alice takes back, one redeemed outside the chat
+      await callWasm(alice.page, 'send_ecash_to_group', groupId, BigInt(5), 'for bob');
+      await callWasm(alice.page, 'send_ecash_to_group', groupId, BigInt(6), 'never mind');
+      const outside = await callWasm(alice.page, 'send_ecash', BigInt(7));
+      await callWasm(alice.page, 'send_token_to_group', groupId, outside, null);
+
+      await expect.poll(async () => Object.keys(await paymentIds(bob.page, groupId)).length, { timeout: 20000 }).toBe(3);
+      const { 5: forBob, 6: reclaimed, 7: redeemedElsewhere } = await paymentIds(bob.page, groupId);
+
+      await callWasm(bob.page, 'check_token_claims', groupId);
+      expect(await paymentStatus(bob.page, groupId, forBob)).toEqual({ status: 'unclaimed', claimed_by: null });
+
+      await callWasm(bob.page, 'claim_token_message', forBob);
+      await callWasm(alice.page, 'claim_token_message', reclaimed);
+      await callWasm(bob.page, 'receive_token', outside);
+
+      // Claim notices tell who took a token; the mint tells that the last one is gone
+      await expect.poll(async () => {
+        await callWasm(alice.page, 'check_token_claims', groupId);
+        return [
+          await paymentStatus(alice.page, groupId, forBob),
+          await paymentStatus(alice.page, groupId, reclaimed),
+          await paymentStatus(alice.page, groupId, redeemedElsewhere),
+        ];
+      }, { timeout: 20000, intervals: [1000] }).toEqual([
+        { status: 'claimed_by_other', claimed_by: bobNpub },
+        { status: 'reclaimed_by_sender', claimed_by: aliceNpub },
+        { status: 'claimed_by_other', claimed_by: null },
+      ]);
+
+      await expect.poll(async () => {
+        await callWasm(bob.page, 'check_token_claims', groupId);
+        return [
+          await paymentStatus(bob.page, groupId, forBob),
+          await paymentStatus(bob.page, groupId, reclaimed),
+          await paymentStatus(bob.page, groupId, redeemedElsewhere),
+        ];
+      }, { timeout: 20000, intervals: [1000] }).toEqual([
+        { status: 'claimed_by_me', claimed_by: bobNpub },
+        { status: 'reclaimed_by_sender', claimed_by: aliceNpub },
+        { status: 'claimed_by_me', claimed_by: bobNpub },
+      ]);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
@@ -0,0 +1,105 @@
+import { test, expect, Page } from '@playwright/test';
+import { TestUser } from '../helpers/user';
+import { ensureMintRunning } from '../helpers/mint';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Group Treasury Tests
+ *
+ * Ecash deposited to a group is locked to its admins; spending it takes the
+ * signatures of as many of them as the depositor chose, collected in the group.
+ *
+ * Requires a local mint (cdk-mintd with the fake Lightning backend, see tests/helpers/mint.ts)
+ */
+
+async function treasury(page: Page, groupId: string): Promise<any[]> {
+  return JSON.parse(await callWasm(page, 'get_treasury', groupId));
+}
+
+test.describe('Group treasury', () => {
+  let mintUrl: string;
+
+  test.beforeAll(async () => {
+    mintUrl = await ensureMintRunning(3338);
+  });
+
+  test('admins co-sign a spend from the treasury', async ({ browser }) => {
+    test.setTimeout(180000);
+
+    const aliceContext = await browser.newContext();
+    const bobContext = await browser.newContext();
+    for (const context of [aliceContext, bobContext]) {
+      await context.addInitScript(() => {
+        (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+      });
+    }
+
+    const alice = new TestUser(await aliceContext.newPage(), 'Alice');
+    const bob = new TestUser(await bobContext.newPage(), 'Bob');
+
+    try {
+      await alice.init();
+      await bob.init();
+      await bob.createKeyPackage();
+      const aliceNpub = await alice.getNpub();
+      const bobNpub = await bob.getNpub();
+
+      // Both are admins
+      await alice.createGroup('Treasury Group', bobNpub, true);
+      await bob.waitForGroup('Treasury Group', 20000);
+      const groupId = JSON.parse(await callWasm(alice.page, 'get_groups'))
+        .find((g: any) => g.name === 'Treasury Group').id;
+
+      await callWasm(alice.page, 'add_trusted_mint', mintUrl);
+      await callWasm(alice.page, 'set_current_mint', mintUrl);
+      const invoice = JSON.parse(await callWasm(alice.page, 'create_lightning_invoice', mintUrl, BigInt(100), 'treasury test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(alice.page, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      await expect(callWasm(alice.page, 'send_ecash_to_group_multisig', groupId, BigInt(40), 3)).rejects.toThrow(/threshold/);
+      await callWasm(alice.page, 'send_ecash_to_group_multisig', groupId, BigInt(40), 2);
+
+      // Bob sees a 2-of-2 deposit, and no chat message for it
+      await expect.poll(async () => (await treasury(bob.page, groupId)).length, { timeout: 20000 }).toBe(1);
+      const [deposit] = await treasury(bob.page, groupId);
+      expect(deposit).toMatchObject({ amount: 40, mint: mintUrl, sender: aliceNpub, threshold: 2, spent: false, spent_by: null });
+      expect([...deposit.signers].sort()).toEqual([aliceNpub, bobNpub].sort());
+      const messages = JSON.parse(await callWasm(bob.page, 'get_messages_for_group', groupId));
+      expect(messages.some((m: any) => m.payment)).toBe(false);
+
+      // Bob asks for it; his own signature isn't enough
+      await callWasm(bob.page, 'request_treasury_spend', deposit.id, 'server costs');
+      const [asked] = (await treasury(bob.page, groupId))[0].requests;
+      expect(asked).toMatchObject({ requested_by: bobNpub, memo: 'server costs', signed_by: [], ready: false });
+      await expect(callWasm(bob.page, 'spend_treasury_deposit', asked.id)).rejects.toThrow(/1 of 2 signatures/);
+
+      // Alice co-signs
+      await expect.poll(async () => (await treasury(alice.page, groupId))[0].requests.length, { timeout: 20000 }).toBe(1);
+      await expect(callWasm(alice.page, 'spend_treasury_deposit', asked.id)).rejects.toThrow(/Only the member who asked/);
+      await callWasm(alice.page, 'sign_treasury_spend', asked.id);
+
+      await expect.poll(async () => (await treasury(bob.page, groupId))[0].requests[0].ready, { timeout: 20000 }).toBe(true);
+
+      // Alice's signature is only good for Bob's outputs: her own request still needs Bob's
+      await callWasm(alice.page, 'request_treasury_spend', deposit.id, null);
+      const mine = (await treasury(alice.page, groupId))[0].requests.find((r: any) => r.requested_by === aliceNpub);
+      expect(mine).toMatchObject({ signed_by: [], ready: false });
+      await expect(callWasm(alice.page, 'spend_treasury_deposit', mine.id)).rejects.toThrow(/1 of 2 signatures/);
+
+      const spent = JSON.parse(await callWasm(bob.page, 'spend_treasury_deposit', asked.id));
+      expect(spent.amount).toBe(40);
+      const balances = JSON.parse(await callWasm(bob.page, 'get_all_mint_balances'));
+      expect(balances.find((b: any) => b.mint === mintUrl)?.balance).toBe(40);
+
+      // Everyone sees it spent, and it can't be asked for again
+      await expect.poll(async () => (await treasury(alice.page, groupId))[0].spent_by, { timeout: 20000 }).toBe(bobNpub);
+      expect((await treasury(alice.page, groupId))[0].spent).toBe(true);
+      await expect(callWasm(alice.page, 'request_treasury_spend', deposit.id, null)).rejects.toThrow(/already spent/);
+    } finally {
+      await aliceContext.close();
+      await bobContext.close();
+    }
+  });
+});
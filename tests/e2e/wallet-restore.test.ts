@@ -0,0 +1,87 @@
+import { test, expect } from '@playwright/test';
+import { ensureMintRunning } from '../helpers/mint';
+import { callWasm } from '../helpers/wasm';
+import { openApp } from '../helpers/app';
+
+/**
+ * Wallet Restore Tests (NUT-13)
+ *
+ * Funds minted in one browser are recovered in a fresh one from the wallet mnemonic alone.
+ *
+ * Requires a local mint (cdk-mintd with the fake Lightning backend, see tests/helpers/mint.ts)
+ */
+
+test.describe('Wallet Restore', () => {
+  let mintUrl: string;
+
+  test.beforeAll(async () => {
+    mintUrl = await ensureMintRunning(3338);
+  });
+
+  test('recovers minted funds from the mnemonic in a fresh browser', async ({ browser }) => {
+    test.setTimeout(60000);
+
+    const original = await openApp(browser);
+    const fresh = await openApp(browser);
+
+    try {
+      const mnemonic = await callWasm(original, 'export_wallet_mnemonic');
+
+      // Mint 100 sats (the fake wallet pays the invoice by itself)
+      const invoice = JSON.parse(await callWasm(original, 'create_lightning_invoice', mintUrl, BigInt(100), 'restore test'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(original, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      // A fresh browser knows nothing about these proofs until it restores
+      // The fresh browser's own mnemonic holds nothing, so replacing it is fine
+      await callWasm(fresh, 'import_wallet_mnemonic', mnemonic, true);
+      const result = JSON.parse(await callWasm(fresh, 'restore_wallet', JSON.stringify([mintUrl])));
+      expect(result.failed).toEqual([]);
+      expect(result.restored).toEqual([{ mint: mintUrl, amount: 100 }]);
+
+      const balances = JSON.parse(await callWasm(fresh, 'get_all_mint_balances'));
+      expect(balances.find((b: any) => b.mint === mintUrl)?.balance).toBe(100);
+    } finally {
+      await original.context().close();
+      await fresh.context().close();
+    }
+  });
+
+  test('importing a used mnemonic restores from known mints so new outputs are not reused', async ({ browser }) => {
+    test.setTimeout(90000);
+
+    const original = await openApp(browser);
+    const fresh = await openApp(browser);
+
+    try {
+      const mnemonic = await callWasm(original, 'export_wallet_mnemonic');
+
+      const invoice = JSON.parse(await callWasm(original, 'create_lightning_invoice', mintUrl, BigInt(100), 'used seed'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(original, 'check_mint_quote', mintUrl, invoice.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      // The fresh browser already trusts the mint, so the import itself restores from it
+      await callWasm(fresh, 'add_trusted_mint', mintUrl);
+      const imported = JSON.parse(await callWasm(fresh, 'import_wallet_mnemonic', mnemonic, true));
+      expect(imported.failed).toEqual([]);
+      expect(imported.restored).toEqual([{ mint: mintUrl, amount: 100 }]);
+
+      // Minting again derives outputs past the restored ones, which the mint accepts
+      const next = JSON.parse(await callWasm(fresh, 'create_lightning_invoice', mintUrl, BigInt(50), 'after import'));
+      await expect.poll(async () => {
+        const status = JSON.parse(await callWasm(fresh, 'check_mint_quote', mintUrl, next.quote_id));
+        return status.paid;
+      }, { timeout: 20000, intervals: [1000] }).toBe(true);
+
+      const balances = JSON.parse(await callWasm(fresh, 'get_all_mint_balances'));
+      expect(balances.find((b: any) => b.mint === mintUrl)?.balance).toBe(150);
+    } finally {
+      await original.context().close();
+      await fresh.context().close();
+    }
+  });
+});
@@ -0,0 +1,76 @@
+import { test, expect } from '@playwright/test';
+import { callWasm } from '../helpers/wasm';
+
+/**
+ * Wallet Seed Tests
+ *
+ * The ecash wallet has its own BIP-39 mnemonic, independent of the Nostr identity.
+ */
+
+const TEST_MNEMONIC = 'abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about';
+const OTHER_MNEMONIC = 'legal winner thank year wave sausage worth useful legal winner thank yellow';
+
+test.describe('Wallet Seed', () => {
+  test('new wallets get a mnemonic that survives an identity reset', async ({ browser }) => {
+    const context = await browser.newContext();
+    await context.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    const page = await context.newPage();
+
+    try {
+      await page.goto('/');
+      await page.waitForSelector('#status', { timeout: 10000 });
+
+      expect(await callWasm(page, 'get_wallet_seed_type')).toBe('mnemonic');
+      const mnemonic = await callWasm(page, 'export_wallet_mnemonic');
+      expect(mnemonic.split(' ')).toHaveLength(12);
+
+      // A new Nostr identity keeps the wallet seed
+      const npub = await callWasm(page, 'get_npub');
+      await callWasm(page, 'generate_keys');
+      expect(await callWasm(page, 'get_npub')).not.toBe(npub);
+      expect(await callWasm(page, 'export_wallet_mnemonic')).toBe(mnemonic);
+    } finally {
+      await context.close();
+    }
+  });
+
+  test('imports a mnemonic and rejects invalid ones', async ({ browser }) => {
+    const context = await browser.newContext();
+    await context.addInitScript(() => {
+      (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+    });
+    const page = await context.newPage();
+
+    try {
+      await page.goto('/');
+      await page.waitForSelector('#status', { timeout: 10000 });
+
+      const invalid = await callWasm(page, 'import_wallet_mnemonic', 'not a valid mnemonic')
+        .then(() => null, (err: Error) => err.message);
+      expect(invalid).toContain('Invalid mnemonic');
+
+      await callWasm(page, 'import_wallet_mnemonic', `  ${TEST_MNEMONIC} `, true);
+      expect(await callWasm(page, 'export_wallet_mnemonic')).toBe(TEST_MNEMONIC);
+      expect(await callWasm(page, 'get_wallet_seed_type')).toBe('mnemonic');
+
+      // Re-importing the same words is fine, replacing them needs confirmation
+      await callWasm(page, 'import_wallet_mnemonic', TEST_MNEMONIC);
+      const replaced = await callWasm(page, 'import_wallet_mnemonic', OTHER_MNEMONIC)
+        .then(() => null, (err: Error) => err.message);
+      expect(replaced).toContain('already has a different mnemonic');
+      expect(await callWasm(page, 'export_wallet_mnemonic')).toBe(TEST_MNEMONIC);
+
+      await callWasm(page, 'import_wallet_mnemonic', OTHER_MNEMONIC, true);
+      expect(await callWasm(page, 'export_wallet_mnemonic')).toBe(OTHER_MNEMONIC);
+
+      // Generating over an existing mnemonic is refused
+      const generated = await callWasm(page, 'generate_wallet_mnemonic')
+        .then(() => null, (err: Error) => err.message);
+      expect(generated).toContain('already has a mnemonic');
+    } finally {
+      await context.close();
+    }
+  });
+});
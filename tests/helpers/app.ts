@@ -0,0 +1,15 @@
+import { Browser, Page } from '@playwright/test';
+
+/**
+ * Open the app in a fresh browser context on the local relay and wait for WASM to load
+ */
+export async function openApp(browser: Browser): Promise<Page> {
+  const context = await browser.newContext();
+  await context.addInitScript(() => {
+    (window as any).OVERRIDE_DEFAULT_RELAYS = ['ws://localhost:8080'];
+  });
+  const page = await context.newPage();
+  await page.goto('/');
+  await page.waitForSelector('#status', { timeout: 10000 });
+  return page;
+}
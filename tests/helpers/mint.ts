@@ -0,0 +1,94 @@
+import { spawn, ChildProcess } from 'child_process';
+import { promisify } from 'util';
+import { exec } from 'child_process';
+import * as fs from 'fs/promises';
+import * as path from 'path';
+import * as os from 'os';
+import * as net from 'net';
+
+const execAsync = promisify(exec);
+
+/**
+ * Check if a port is in use
+ */
+async function isPortInUse(port: number): Promise<boolean> {
+  return new Promise((resolve) => {
+    const server = net.createServer();
+
+    server.once('error', (err: NodeJS.ErrnoException) => {
+      resolve(err.code === 'EADDRINUSE');
+    });
+
+    server.once('listening', () => {
+      server.close();
+      resolve(false);
+    });
+
+    server.listen(port, '127.0.0.1');
+  });
+}
+
+/**
+ * Ensure a local Cashu mint is running on the specified port
+ * Uses cdk-mintd with the fake Lightning backend, which pays every mint quote
+ * automatically. If already running, does nothing.
+ *
+ * Prerequisites:
+ * - cdk-mintd installed: cargo install cdk-mintd
+ *
+ * The mint is NOT cleaned up - it stays running for future test runs
+ *
+ * @returns The mint URL
+ */
+export async function ensureMintRunning(port: number = 3338): Promise<string> {
+  const mintUrl = `http://localhost:${port}`;
+
+  if (await isPortInUse(port)) {
+    console.log(`✅ Mint already running on port ${port}`);
+    return mintUrl;
+  }
+
+  console.log(`🚀 Starting Cashu mint on port ${port}...`);
+
+  try {
+    await execAsync('which cdk-mintd');
+  } catch (err) {
+    throw new Error('cdk-mintd not found. Install it with: cargo install cdk-mintd');
+  }
+
+  // Persistent work directory (not in /tmp), like the test relay
+  const workDir = path.join(os.homedir(), '.cashu-mint-test');
+  await fs.mkdir(workDir, { recursive: true });
+
+  const mintProcess: ChildProcess = spawn(
+    'cdk-mintd',
+    ['--work-dir', workDir],
+    {
+      detached: true,
+      stdio: 'ignore',
+      env: {
+        ...process.env,
+        CDK_MINTD_URL: mintUrl,
+        CDK_MINTD_LISTEN_HOST: '127.0.0.1',
+        CDK_MINTD_LISTEN_PORT: `${port}`,
+        CDK_MINTD_LN_BACKEND: 'fakewallet',
+        CDK_MINTD_DATABASE: 'sqlite',
+        // Fixed test mnemonic so keysets survive restarts
+        CDK_MINTD_MNEMONIC: 'abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about',
+      },
+    }
+  );
+
+  // Unref so it doesn't keep the test process alive
+  mintProcess.unref();
+
+  // Wait a moment for the mint to start
+  await new Promise((resolve) => setTimeout(resolve, 3000));
+
+  if (!(await isPortInUse(port))) {
+    throw new Error(`Failed to start mint on port ${port}`);
+  }
+
+  console.log(`✅ Mint started on ${mintUrl} (will stay running)`);
+  return mintUrl;
+}
@@ -0,0 +1,29 @@
+import { Page } from '@playwright/test';
+
+/**
+ * Call an exported WASM function from the page's already-initialized module
+ */
+export async function callWasm(page: Page, name: string, ...args: unknown[]): Promise<any> {
+  return page.evaluate(async ({ name, args }) => {
+    const wasm: any = await import('/pkg/mdk_ecash_web.js');
+    return await wasm[name](...args);
+  }, { name, args });
+}
+
+/**
+ * Collect the page's group recovery reports (see set_group_recovery_callback)
+ */
+export async function recordRecoveries(page: Page) {
+  await page.evaluate(async () => {
+    const wasm: any = await import('/pkg/mdk_ecash_web.js');
+    (window as any).recoveries = [];
+    wasm.set_group_recovery_callback((json: string) => (window as any).recoveries.push(JSON.parse(json)));
+  });
+}
+
+/**
+ * The recovery reports collected since recordRecoveries
+ */
+export async function recoveries(page: Page): Promise<any[]> {
+  return page.evaluate(() => (window as any).recoveries);
+}
@@ -0,0 +1,192 @@
+//! Encrypted account backups: identity, MDK groups, wallet, trusted mints and
+//! relays in one passphrase-protected file
+//!
+//! The file is JSON {format, version, encryption}, where `encryption` holds the
+//! payload sealed with the passphrase (scrypt + XChaCha20-Poly1305, see vault).
+//! The payload and each storage section carry their own version. `open` checks
+//! all of them and parses every section, so nothing is written for a backup
+//! that can't be restored in full.
+
+use nostr::{Keys, PublicKey};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::signer::{self, Nip46Session};
+use crate::vault::{self, PassphraseSealed};
+use crate::wallet_seed::StoredSeeds;
+use crate::mdk_storage::{self, MdkHybridStorage, MdkRestore};
+use crate::wallet_db::{self, HybridWalletDatabase, WalletRestore};
+
+const BACKUP_FORMAT: &str = "mdk-ecash-backup";
+const FILE_VERSION: u32 = 1;
+const PAYLOAD_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    format: String,
+    version: u32,
+    encryption: PassphraseSealed,
+}
+
+/// Who we are and how we sign
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Identity {
+    pub signer_type: String,
+    /// Local secret key (hex); also kept for external signers if one was stored
+    pub secret_key: Option<String>,
+    /// Cached public key of an external signer (hex)
+    pub signer_pubkey: Option<String>,
+    pub nip46_session: Option<Nip46Session>,
+}
+
+/// A storage dump together with the version of its layout
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Section {
+    pub version: u32,
+    pub data: serde_json::Value,
+}
+
+impl Section {
+    fn check(&self, name: &str, supported: u32) -> Result<(), JsValue> {
+        if self.version > supported {
+            return Err(JsValue::from_str(&format!(
+                "Backup {} data is version {}, this app reads up to {} - update the app first",
+                name, self.version, supported
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BackupPayload {
+    pub version: u32,
+    /// Unix seconds
+    pub created_at: u64,
+    pub identity: Identity,
+    pub wallet_seed: StoredSeeds,
+    pub mdk: Section,
+    pub wallet: Section,
+    pub trusted_mints: Vec<String>,
+    pub relays: Vec<String>,
+}
+
+impl BackupPayload {
+    pub(crate) fn new(
+        identity: Identity,
+        wallet_seed: StoredSeeds,
+        mdk: serde_json::Value,
+        wallet: serde_json::Value,
+        trusted_mints: Vec<String>,
+        relays: Vec<String>,
+    ) -> Self {
+        Self {
+            version: PAYLOAD_VERSION,
+            created_at: (js_sys::Date::now() / 1000.0) as u64,
+            identity,
+            wallet_seed,
+            mdk: Section { version: mdk_storage::BACKUP_VERSION, data: mdk },
+            wallet: Section { version: wallet_db::BACKUP_VERSION, data: wallet },
+            trusted_mints,
+            relays,
+        }
+    }
+
+    /// Public key of the backed-up identity
+    pub(crate) fn public_key(&self) -> Result<PublicKey, JsValue> {
+        let identity = &self.identity;
+        match identity.signer_type.as_str() {
+            signer::SIGNER_TYPE_LOCAL => {
+                let secret_key = identity.secret_key.as_deref()
+                    .ok_or_else(|| JsValue::from_str("Backup has no secret key"))?;
+                Keys::parse(secret_key)
+                    .map(|keys| keys.public_key())
+                    .map_err(|e| JsValue::from_str(&format!("Invalid secret key in backup: {}", e)))
+            }
+            signer::SIGNER_TYPE_NIP07 | signer::SIGNER_TYPE_NIP46 => {
+                let pubkey = identity.signer_pubkey.as_deref()
+                    .ok_or_else(|| JsValue::from_str("Backup has no signer public key"))?;
+                PublicKey::from_hex(pubkey)
+                    .map_err(|e| JsValue::from_str(&format!("Invalid public key in backup: {}", e)))
+            }
+            other => Err(JsValue::from_str(&format!("Unknown signer type in backup: {}", other))),
+        }
+    }
+
+    /// Everything that can be checked without writing
+    fn validate(&self) -> Result<(), JsValue> {
+        self.mdk.check("group", mdk_storage::BACKUP_VERSION)?;
+        self.wallet.check("wallet", wallet_db::BACKUP_VERSION)?;
+        self.public_key()?;
+        if self.identity.signer_type == signer::SIGNER_TYPE_NIP46 && self.identity.nip46_session.is_none() {
+            return Err(JsValue::from_str("Backup has no remote signer session"));
+        }
+        self.wallet_seed.validate()
+    }
+}
+
+/// A decrypted backup whose storage sections have been parsed
+pub(crate) struct OpenedBackup {
+    pub payload: BackupPayload,
+    pub mdk: MdkRestore,
+    pub wallet: WalletRestore,
+}
+
+/// Encrypt a payload into a backup file
+pub(crate) fn seal(payload: &BackupPayload, passphrase: &str) -> Result<String, JsValue> {
+    if passphrase.is_empty() {
+        return Err(JsValue::from_str("Passphrase must not be empty"));
+    }
+
+    let json = serde_json::to_vec(payload)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize backup: {}", e)))?;
+    let file = BackupFile {
+        format: BACKUP_FORMAT.to_string(),
+        version: FILE_VERSION,
+        encryption: vault::seal_with_passphrase(passphrase, &json)?,
+    };
+
+    serde_json::to_string(&file)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize backup: {}", e)))
+}
+
+/// Decrypt, validate and parse a backup file
+pub(crate) fn open(blob: &str, passphrase: &str) -> Result<OpenedBackup, JsValue> {
+    let mut payload = decrypt(blob, passphrase)?;
+    let mdk = MdkHybridStorage::parse_backup(payload.mdk.data.take())?;
+    let wallet = HybridWalletDatabase::parse_backup(payload.wallet.data.take())?;
+    Ok(OpenedBackup { payload, mdk, wallet })
+}
+
+/// Decrypt a backup file and check its versions, leaving the storage sections unparsed
+pub(crate) fn decrypt(blob: &str, passphrase: &str) -> Result<BackupPayload, JsValue> {
+    let file: BackupFile = serde_json::from_str(blob.trim())
+        .map_err(|e| JsValue::from_str(&format!("Not a backup file: {}", e)))?;
+    if file.format != BACKUP_FORMAT {
+        return Err(JsValue::from_str("Not a backup file"));
+    }
+    if file.version > FILE_VERSION {
+        return Err(JsValue::from_str(&format!(
+            "Backup file is version {}, this app reads up to {} - update the app first",
+            file.version, FILE_VERSION
+        )));
+    }
+
+    let json = vault::open_with_passphrase(passphrase, &file.encryption)?;
+
+    // Check the version before the full parse so a newer layout gets a clear error
+    let value: serde_json::Value = serde_json::from_slice(&json)
+        .map_err(|e| JsValue::from_str(&format!("Backup is corrupt: {}", e)))?;
+    let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+    if version > PAYLOAD_VERSION as u64 {
+        return Err(JsValue::from_str(&format!(
+            "Backup contents are version {}, this app reads up to {} - update the app first",
+            version, PAYLOAD_VERSION
+        )));
+    }
+
+    let payload: BackupPayload = serde_json::from_value(value)
+        .map_err(|e| JsValue::from_str(&format!("Backup is corrupt: {}", e)))?;
+    payload.validate()?;
+    Ok(payload)
+}
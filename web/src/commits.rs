@@ -0,0 +1,249 @@
+//! Commits that wait for the relays
+//!
+//! A commit we create stays pending (not merged) until it has been seen on a
+//! relay. Meanwhile the group's events around it are fetched: a competing
+//! commit for the same epoch that sorts first under MIP-03 (earlier
+//! `created_at`, then lower event ID) is applied instead, and our change is
+//! created again on top of the new epoch. A competing commit that sorts after
+//! ours is left alone (and held back locally until ours is merged), since its
+//! author follows the same rule and switches to ours, so concurrent admins
+//! don't fork the group. A commit no relay accepted is dropped, since nobody
+//! else can apply it. Once a relay accepted it, other members may already apply
+//! it, so it is never dropped: if it doesn't show up within `MAX_CONFIRM_WAIT`
+//! the caller is told it is unconfirmed, its outbox entry stays to be retried,
+//! and it is merged when it arrives through the subscription (`confirm_late`).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use mdk_core::groups::UpdateGroupResult;
+use mdk_core::MDK;
+use mdk_storage_traits::messages::MessageStorage;
+use mdk_storage_traits::GroupId;
+use nostr::{Alphabet, Event, EventId, Filter, Kind, SingleLetterTag, Timestamp};
+use nostr_sdk::{Client, Output};
+use wasm_bindgen::JsValue;
+
+use crate::mdk_storage::{MdkHybridStorage, SharedMdkStorage};
+use crate::{outbox, recovery};
+
+/// Times our change is created again after losing to a competing commit
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Fetches of the group's events while waiting for our commit to show up
+const CONFIRM_POLLS: u32 = 5;
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(1);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest wait between rounds of polls for a commit a relay accepted but doesn't serve yet
+const MAX_CONFIRM_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Longest total wait for a commit a relay accepted before it is returned unconfirmed
+const MAX_CONFIRM_WAIT: Duration = Duration::from_secs(120);
+
+/// A published commit, merged once a relay had it
+pub(crate) struct ConfirmedCommit {
+    pub result: UpdateGroupResult,
+    pub send_result: Output<EventId>,
+    /// How many times it was created again on top of a competing commit
+    pub retries: u32,
+    /// False if a relay accepted it but none served it in time: it stays
+    /// pending and is merged once it shows up
+    pub confirmed: bool,
+}
+
+/// Wait for `commit` to show up on a relay. Returns the group events we haven't
+/// processed that sort before it, or None if no relay had it in time.
+async fn confirm(
+    client: &Client,
+    storage: &MdkHybridStorage,
+    nostr_group_id: &[u8; 32],
+    commit: &Event,
+) -> Result<Option<Vec<Event>>, JsValue> {
+    let since = commit.created_at.as_u64().saturating_sub(recovery::CLOCK_SKEW_SECS);
+    let filter = Filter::new()
+        .kind(Kind::MlsGroupMessage)
+        .custom_tag(SingleLetterTag::lowercase(Alphabet::H), hex::encode(nostr_group_id))
+        .since(Timestamp::from(since));
+
+    for poll in 0..CONFIRM_POLLS {
+        if poll > 0 {
+            crate::sleep(CONFIRM_INTERVAL).await;
+        }
+
+        let events = client.fetch_events(filter.clone(), FETCH_TIMEOUT).await
+            .map_err(|e| JsValue::from_str(&format!("Failed to fetch group events: {}", e)))?;
+        if !events.iter().any(|event| event.id == commit.id) {
+            continue;
+        }
+
+        let ours = recovery::mip03_order(commit);
+        let mut earlier: Vec<Event> = events.into_iter()
+            .filter(|event| recovery::mip03_order(event) < ours)
+            .filter(|event| storage.find_processed_message_by_event_id(&event.id).ok().flatten().is_none())
+            .collect();
+        earlier.sort_by_key(recovery::mip03_order);
+        return Ok(Some(earlier));
+    }
+
+    Ok(None)
+}
+
+thread_local! {
+    // MIP-03 position of our commit waiting for a relay, by group
+    static PENDING: RefCell<HashMap<GroupId, (Timestamp, String)>> = RefCell::new(HashMap::new());
+
+    // Our commit returned unconfirmed and the epoch it moves past, by group
+    static UNCONFIRMED: RefCell<HashMap<GroupId, (EventId, u64)>> = RefCell::new(HashMap::new());
+}
+
+/// Marks a group's commit as pending for as long as it lives
+struct PendingCommit(GroupId);
+
+impl PendingCommit {
+    fn new(group_id: &GroupId, commit: &Event) -> Self {
+        PENDING.with(|p| p.borrow_mut().insert(group_id.clone(), recovery::mip03_order(commit)));
+        Self(group_id.clone())
+    }
+}
+
+impl Drop for PendingCommit {
+    fn drop(&mut self) {
+        PENDING.with(|p| p.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Whether an event has to wait for our pending commit: it sorts after it, so
+/// applying it first could make the group's canonical commit lose locally
+pub(crate) fn holds_back(group_id: &GroupId, event: &Event) -> bool {
+    PENDING.with(|p| p.borrow().get(group_id).is_some_and(|ours| recovery::mip03_order(event) > *ours))
+}
+
+/// Merge our commit returned unconfirmed, if `event` is it. Returns whether it was merged.
+pub(crate) async fn confirm_late(
+    mdk: &MDK<SharedMdkStorage>,
+    storage: &MdkHybridStorage,
+    group_id: &GroupId,
+    event: &Event,
+) -> Result<bool, JsValue> {
+    let Some((_, epoch)) = UNCONFIRMED.with(|u| u.borrow().get(group_id).copied())
+        .filter(|(commit_id, _)| *commit_id == event.id) else {
+        return Ok(false);
+    };
+    UNCONFIRMED.with(|u| u.borrow_mut().remove(group_id));
+
+    // A competing commit was applied meanwhile: ours goes through fork recovery instead
+    if recovery::epoch_of(storage, group_id) != Some(epoch) {
+        return Ok(false);
+    }
+
+    mdk.merge_pending_commit(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
+    recovery::record_commit(group_id, epoch, event, true).await?;
+    storage.save_snapshot().await?;
+    crate::log(&format!("  ✓ Commit {} showed up late and was merged", event.id.to_hex()));
+    Ok(true)
+}
+
+/// Create a commit with `create`, publish it and merge it once a relay has it.
+/// If a competing commit for the same epoch wins, `create` runs again on the new epoch.
+pub(crate) async fn commit(
+    mdk: &MDK<SharedMdkStorage>,
+    storage: &MdkHybridStorage,
+    client: &Client,
+    group_id: &GroupId,
+    create: impl Fn(&MDK<SharedMdkStorage>) -> Result<UpdateGroupResult, JsValue>,
+) -> Result<ConfirmedCommit, JsValue> {
+    for retries in 0..MAX_ATTEMPTS {
+        let attempt = attempt(mdk, storage, client, group_id, &create, retries).await;
+
+        // Events held back while our commit was pending can go through now
+        recovery::retry_pending(storage, group_id).await?;
+
+        if let Some(confirmed) = attempt? {
+            return Ok(confirmed);
+        }
+    }
+
+    Err(JsValue::from_str(&format!(
+        "The group kept changing while applying your change; gave up after {} attempts",
+        MAX_ATTEMPTS
+    )))
+}
+
+/// One round of `commit`: None if a competing commit won
+async fn attempt(
+    mdk: &MDK<SharedMdkStorage>,
+    storage: &MdkHybridStorage,
+    client: &Client,
+    group_id: &GroupId,
+    create: &impl Fn(&MDK<SharedMdkStorage>) -> Result<UpdateGroupResult, JsValue>,
+    retries: u32,
+) -> Result<Option<ConfirmedCommit>, JsValue> {
+    let epoch = recovery::checkpoint(storage, group_id).await?;
+    let before = storage.snapshot_group(group_id)
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+
+    let result = create(mdk).map_err(|e| {
+        if retries == 0 {
+            return e;
+        }
+        JsValue::from_str(&format!(
+            "Another member changed the group first and your change no longer applies: {}",
+            e.as_string().unwrap_or_default()
+        ))
+    })?;
+    let commit = &result.evolution_event;
+    let _pending = PendingCommit::new(group_id, commit);
+
+    crate::log(&format!("  ⏳ Commit {} pending until a relay has it", commit.id.to_hex()));
+    let send_result = outbox::publish(client, commit, outbox::EventPurpose::Commit, Some(group_id)).await?;
+
+    let accepted = !send_result.success.is_empty();
+    let started = js_sys::Date::now();
+    let mut rounds: u32 = 0;
+    let earlier = loop {
+        match confirm(client, storage, &before.nostr_group_id(), commit).await {
+            Ok(Some(earlier)) => break earlier,
+            Ok(None) if !accepted => {
+                // Nobody else can apply a commit no relay took: drop it rather than leave the group waiting on it
+                storage.restore_group(&before)?;
+                storage.save_snapshot().await?;
+                outbox::cancel(&commit.id).await?;
+                return Err(JsValue::from_str("No relay confirmed the change, so it was not applied. Please try again."));
+            }
+            Err(e) if !accepted => return Err(e),
+            // Other members may apply it already, so rolling back now would fork the group
+            Ok(None) => crate::log(&format!("  ⏳ Commit {} was accepted but isn't served yet, still waiting", commit.id.to_hex())),
+            Err(e) => crate::log(&format!("  ⚠️ Failed to look for commit {}, still waiting: {:?}", commit.id.to_hex(), e)),
+        }
+        if js_sys::Date::now() - started >= MAX_CONFIRM_WAIT.as_millis() as f64 {
+            // Stop holding back the group's events; the outbox keeps retrying the relays that didn't take it
+            crate::log(&format!("  ⏳ Commit {} still unconfirmed, merging it once it shows up", commit.id.to_hex()));
+            UNCONFIRMED.with(|u| u.borrow_mut().insert(group_id.clone(), (commit.id, epoch)));
+            return Ok(Some(ConfirmedCommit { result, send_result, retries, confirmed: false }));
+        }
+        rounds += 1;
+        crate::sleep((CONFIRM_INTERVAL * rounds).min(MAX_CONFIRM_BACKOFF)).await;
+    };
+
+    for event in earlier {
+        Box::pin(crate::handle_group_event(Box::new(event))).await?;
+    }
+
+    // Also covers a competing commit that arrived through the subscription meanwhile
+    if recovery::epoch_of(storage, group_id).is_some_and(|current| current > epoch) {
+        crate::log(&format!("  🔀 A competing commit for epoch {} came first, re-applying our change", epoch));
+        outbox::cancel(&commit.id).await?;
+        return Ok(None);
+    }
+
+    mdk.merge_pending_commit(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
+    recovery::record_commit(group_id, epoch, commit, true).await?;
+    storage.save_snapshot().await?;
+    crate::log(&format!("  ✓ Commit {} confirmed and merged", commit.id.to_hex()));
+
+    Ok(Some(ConfirmedCommit { result, send_result, retries, confirmed: true }))
+}
@@ -0,0 +1,232 @@
+//! Paging through a group's history, backfilled from the relays
+//!
+//! The shared subscription only asks for events since shortly before the last
+//! message we have, and relays may cap how many stored events they send, so
+//! a device that was offline can miss a period. Each group keeps the periods
+//! its relay history is known to be complete for: what `load_older_messages`
+//! fetched with `until`/`limit` filters, plus the time the shared subscription
+//! was open. A page of older messages comes from local storage first; the
+//! parts of its period outside those (the gaps) are fetched from the relays.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use mdk_core::MDK;
+use mdk_storage_traits::groups::GroupStorage;
+use mdk_storage_traits::messages::types::Message;
+use mdk_storage_traits::messages::MessageStorage;
+use mdk_storage_traits::GroupId;
+use nostr::{Alphabet, Event, Filter, Kind, SingleLetterTag, Timestamp};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::mdk_storage::{self, MdkHybridStorage, SharedMdkStorage, HISTORY_STORE};
+use crate::{idb, recovery, relay_pool, schema, vault};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Periods (unix seconds, both ends included) a group's history was fetched for
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Coverage {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl Coverage {
+    fn add(&mut self, since: u64, until: u64) {
+        if since > until {
+            return;
+        }
+        self.ranges.push((since, until));
+        self.ranges.sort();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for (since, until) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if since <= last.1.saturating_add(1) => last.1 = last.1.max(until),
+                _ => merged.push((since, until)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// Parts of `since..=until` not covered, oldest first
+    fn gaps(&self, since: u64, until: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut from = since;
+        for &(start, end) in &self.ranges {
+            if end < from {
+                continue;
+            }
+            if start > until {
+                break;
+            }
+            if start > from {
+                gaps.push((from, start - 1));
+            }
+            from = end.saturating_add(1);
+            if from > until {
+                return gaps;
+            }
+        }
+        if from <= until {
+            gaps.push((from, until));
+        }
+        gaps
+    }
+}
+
+fn coverage_key(group_id: &GroupId) -> JsValue {
+    JsValue::from_str(&hex::encode(group_id.as_slice()))
+}
+
+async fn load(group_id: &GroupId) -> Result<Coverage, JsValue> {
+    let db = mdk_storage::database().await?;
+    let Some(stored) = idb::get(&db, HISTORY_STORE, &coverage_key(group_id)).await? else {
+        return Ok(Coverage::default());
+    };
+    let stored = stored.as_string()
+        .ok_or_else(|| JsValue::from_str("Non-string history coverage"))?;
+    schema::decode(HISTORY_STORE, &vault::open(&stored)?)
+        .map_err(|e| JsValue::from_str(&format!("Invalid history coverage: {}", e)))
+}
+
+async fn save(group_id: &GroupId, coverage: &Coverage) -> Result<(), JsValue> {
+    let json = schema::encode(HISTORY_STORE, coverage)
+        .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+    let value = JsValue::from_str(&vault::seal(&json)?);
+
+    let db = mdk_storage::database().await?;
+    let tx = idb::write_transaction(&db, &[HISTORY_STORE])?;
+    tx.object_store(HISTORY_STORE)?.put_with_key(&value, &coverage_key(group_id))?;
+    idb::await_transaction(&tx).await
+}
+
+thread_local! {
+    // Since when the shared subscription has been delivering each group's events live
+    static LIVE: RefCell<HashMap<GroupId, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Note which groups the shared subscription covers from now on (empty when it is closed)
+pub(crate) fn set_live(groups: &[GroupId]) {
+    let now = Timestamp::now().as_u64();
+    LIVE.with(|l| {
+        let mut live = l.borrow_mut();
+        live.retain(|group_id, _| groups.contains(group_id));
+        for group_id in groups {
+            live.entry(group_id.clone()).or_insert(now);
+        }
+    });
+}
+
+pub(crate) fn reset() {
+    LIVE.with(|l| l.borrow_mut().clear());
+}
+
+/// One page of `load_older_messages`
+pub(crate) struct Page {
+    /// Oldest first
+    pub messages: Vec<Message>,
+    /// Whether there are older messages here or on the relays
+    pub has_more: bool,
+    /// Events fetched from the relays for this page, and how many of them couldn't be read
+    pub fetched: usize,
+    pub unreadable: usize,
+}
+
+/// Up to `limit` stored messages before `before`, oldest first
+fn local_page(storage: &MdkHybridStorage, group_id: &GroupId, before: u64, limit: usize) -> Result<(Vec<Message>, bool), JsValue> {
+    let mut messages: Vec<Message> = storage.messages(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get messages: {}", e)))?
+        .into_iter()
+        .filter(|msg| msg.created_at.as_u64() < before)
+        .collect();
+    messages.sort_by_key(|msg| msg.created_at);
+    let older = messages.len() > limit;
+    let page = messages.split_off(messages.len().saturating_sub(limit));
+    Ok((page, older))
+}
+
+/// Process backfilled events we haven't seen, in MIP-03 order. Returns how many failed.
+/// These are old events, so failures are not queued for a newer epoch or treated as forks.
+fn process(mdk: &MDK<SharedMdkStorage>, storage: &MdkHybridStorage, mut events: Vec<Event>) -> usize {
+    events.sort_by_key(recovery::mip03_order);
+    events.into_iter()
+        .filter(|event| storage.find_processed_message_by_event_id(&event.id).ok().flatten().is_none())
+        .filter(|event| match mdk.process_message(event) {
+            Ok(_) => false,
+            Err(e) => {
+                crate::log(&format!("  ⚠️  Couldn't read backfilled event {}: {}", event.id.to_hex(), e));
+                true
+            }
+        })
+        .count()
+}
+
+/// A page of messages older than `before`: local ones first, with the gaps in
+/// that period fetched from the relays
+pub(crate) async fn older_messages(
+    mdk: &MDK<SharedMdkStorage>,
+    storage: &MdkHybridStorage,
+    group_id: &GroupId,
+    before: u64,
+    limit: usize,
+) -> Result<Page, JsValue> {
+    let group = storage.find_group_by_mls_group_id(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+
+    // Keep what the subscription covered so far, in case the page goes away
+    let mut coverage = load(group_id).await?;
+    let now = Timestamp::now().as_u64();
+    if let Some(since) = LIVE.with(|l| l.borrow().get(group_id).copied()) {
+        coverage.add(since, now);
+    }
+
+    // The page reaches back to its oldest local message, or all the way if it isn't full
+    let (page, _) = local_page(storage, group_id, before, limit)?;
+    let oldest = if page.len() == limit { page[0].created_at.as_u64() } else { 0 };
+    let gaps = coverage.gaps(oldest, before.saturating_sub(1));
+
+    let mut fetched = 0;
+    let mut unreadable = 0;
+    if !gaps.is_empty() {
+        let client = relay_pool::client().await?;
+        // Newest first, as the page is read from the end
+        for (since, until) in gaps.into_iter().rev() {
+            crate::log(&format!("  📜 Backfilling {}..{} from the relays", since, until));
+            let filter = Filter::new()
+                .kind(Kind::MlsGroupMessage)
+                .custom_tag(SingleLetterTag::lowercase(Alphabet::H), hex::encode(group.nostr_group_id))
+                .since(Timestamp::from(since))
+                .until(Timestamp::from(until))
+                .limit(limit);
+            let events: Vec<Event> = client.fetch_events(filter, FETCH_TIMEOUT).await
+                .map_err(|e| JsValue::from_str(&format!("Failed to fetch history: {}", e)))?
+                .into_iter()
+                .collect();
+
+            // A full batch may have been cut off: only its own span is complete
+            let full = events.len() >= limit;
+            let covered_since = match events.iter().map(|event| event.created_at.as_u64()).min() {
+                Some(oldest_fetched) if full => oldest_fetched.saturating_add(1),
+                _ => since,
+            };
+            fetched += events.len();
+            unreadable += process(mdk, storage, events);
+            coverage.add(covered_since, until);
+
+            if full {
+                break;
+            }
+        }
+        storage.save_snapshot().await?;
+    }
+    save(group_id, &coverage).await?;
+
+    let (messages, older) = local_page(storage, group_id, before, limit)?;
+    let reached = messages.first().map(|msg| msg.created_at.as_u64()).unwrap_or(before);
+    let has_more = older || !coverage.gaps(0, reached.saturating_sub(1)).is_empty();
+
+    Ok(Page { messages, has_more, fetched, unreadable })
+}
@@ -0,0 +1,201 @@
+//! Small async helpers around the raw IndexedDB bindings in web-sys
+//!
+//! IndexedDB is callback based, so every request/transaction is bridged to a
+//! JS Promise and awaited with JsFuture. Connections are cached per database
+//! name for the lifetime of the page (IdbDatabase is !Send, so the cache is
+//! thread-local rather than living inside the storage structs).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    window, IdbDatabase, IdbFactory, IdbObjectStore, IdbRequest, IdbTransaction,
+    IdbTransactionMode, IdbVersionChangeEvent,
+};
+
+/// Name, version and upgrade routine of an IndexedDB database
+pub(crate) struct DbSchema {
+    pub name: &'static str,
+    pub version: u32,
+    /// Called from `onupgradeneeded` with the previous version (0 for a new database)
+    pub upgrade: fn(&IdbDatabase, &IdbTransaction, u32) -> Result<(), JsValue>,
+}
+
+thread_local! {
+    static CONNECTIONS: RefCell<HashMap<&'static str, IdbDatabase>> = RefCell::new(HashMap::new());
+}
+
+fn factory() -> Result<IdbFactory, JsValue> {
+    window()
+        .ok_or_else(|| JsValue::from_str("No window"))?
+        .indexed_db()?
+        .ok_or_else(|| JsValue::from_str("No IndexedDB available"))
+}
+
+/// Get the cached connection for a database, opening (and upgrading) it on first use
+pub(crate) async fn connection(schema: &DbSchema) -> Result<IdbDatabase, JsValue> {
+    if let Some(db) = CONNECTIONS.with(|c| c.borrow().get(schema.name).cloned()) {
+        return Ok(db);
+    }
+
+    let open_request = factory()?.open_with_u32(schema.name, schema.version)?;
+
+    let upgrade = schema.upgrade;
+    let upgrade_request = open_request.clone();
+    let on_upgrade = Closure::<dyn FnMut(IdbVersionChangeEvent)>::new(move |event: IdbVersionChangeEvent| {
+        let result = upgrade_request.result().and_then(|db| {
+            let tx = upgrade_request
+                .transaction()
+                .ok_or_else(|| JsValue::from_str("No upgrade transaction"))?;
+            upgrade(&db.unchecked_into(), &tx, event.old_version() as u32)
+        });
+
+        if let Err(e) = result {
+            web_sys::console::error_1(&JsValue::from_str(&format!("IndexedDB upgrade failed: {:?}", e)));
+            if let Some(tx) = upgrade_request.transaction() {
+                let _ = tx.abort();
+            }
+        }
+    });
+    open_request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+
+    let result = await_request(&open_request).await;
+    open_request.set_onupgradeneeded(None);
+    drop(on_upgrade);
+
+    let db: IdbDatabase = result?.unchecked_into();
+    CONNECTIONS.with(|c| c.borrow_mut().insert(schema.name, db.clone()));
+    Ok(db)
+}
+
+/// Close and forget the cached connection for a database (if any)
+pub(crate) fn close(name: &str) {
+    if let Some(db) = CONNECTIONS.with(|c| c.borrow_mut().remove(name)) {
+        db.close();
+    }
+}
+
+/// Delete a whole database. Closes our own connection first so the
+/// deletion isn't blocked; the browser completes it in the background.
+pub(crate) fn delete_database(name: &str) -> Result<(), JsValue> {
+    close(name);
+    factory()?.delete_database(name)?;
+    Ok(())
+}
+
+/// Create any object stores in `names` that don't exist yet (for use in upgrades)
+pub(crate) fn create_missing_stores(db: &IdbDatabase, names: &[&str]) -> Result<(), JsValue> {
+    let existing = db.object_store_names();
+    for name in names {
+        if !existing.contains(name) {
+            db.create_object_store(name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Wait for a request to succeed and return its result
+pub(crate) async fn await_request(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+
+    let outcome = JsFuture::from(promise).await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+
+    match outcome {
+        Ok(_) => request.result(),
+        Err(_) => Err(request
+            .error()
+            .ok()
+            .flatten()
+            .map(JsValue::from)
+            .unwrap_or_else(|| JsValue::from_str("IndexedDB request failed"))),
+    }
+}
+
+/// Wait for a transaction to commit. Resolves with an error if it errors or aborts.
+pub(crate) async fn await_transaction(tx: &IdbTransaction) -> Result<(), JsValue> {
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        tx.set_oncomplete(Some(&resolve));
+        tx.set_onerror(Some(&reject));
+        tx.set_onabort(Some(&reject));
+    });
+
+    let outcome = JsFuture::from(promise).await;
+    tx.set_oncomplete(None);
+    tx.set_onerror(None);
+    tx.set_onabort(None);
+
+    outcome.map(|_| ()).map_err(|_| {
+        tx.error()
+            .map(JsValue::from)
+            .unwrap_or_else(|| JsValue::from_str("IndexedDB transaction aborted"))
+    })
+}
+
+/// Open a readwrite transaction over the given stores
+pub(crate) fn write_transaction(db: &IdbDatabase, stores: &[&str]) -> Result<IdbTransaction, JsValue> {
+    let names: js_sys::Array = stores.iter().map(|s| JsValue::from_str(s)).collect();
+    db.transaction_with_str_sequence_and_mode(&names, IdbTransactionMode::Readwrite)
+}
+
+/// Read every (key, value) pair in a store, in key order
+pub(crate) async fn read_all(db: &IdbDatabase, store_name: &str) -> Result<Vec<(JsValue, JsValue)>, JsValue> {
+    let tx = db.transaction_with_str(store_name)?;
+    let store: IdbObjectStore = tx.object_store(store_name)?;
+
+    // getAll and getAllKeys both return records in key order, so they line up
+    let keys: js_sys::Array = await_request(&store.get_all_keys()?).await?.unchecked_into();
+    let values: js_sys::Array = await_request(&store.get_all()?).await?.unchecked_into();
+
+    Ok(keys.iter().zip(values.iter()).collect())
+}
+
+/// Read a single value by key (None if absent)
+pub(crate) async fn get(db: &IdbDatabase, store_name: &str, key: &JsValue) -> Result<Option<JsValue>, JsValue> {
+    let tx = db.transaction_with_str(store_name)?;
+    let value = await_request(&tx.object_store(store_name)?.get(key)?).await?;
+    Ok(if value.is_undefined() { None } else { Some(value) })
+}
+
+/// Read every value in a store
+pub(crate) async fn get_all_values(db: &IdbDatabase, store_name: &str) -> Result<Vec<JsValue>, JsValue> {
+    let tx = db.transaction_with_str(store_name)?;
+    let values: js_sys::Array = await_request(&tx.object_store(store_name)?.get_all()?).await?.unchecked_into();
+    Ok(values.iter().collect())
+}
+
+/// Read every value whose index key equals `key`
+pub(crate) async fn get_all_by_index(
+    db: &IdbDatabase,
+    store_name: &str,
+    index_name: &str,
+    key: &JsValue,
+) -> Result<Vec<JsValue>, JsValue> {
+    let tx = db.transaction_with_str(store_name)?;
+    let index = tx.object_store(store_name)?.index(index_name)?;
+    let values: js_sys::Array = await_request(&index.get_all_with_key(key)?).await?.unchecked_into();
+    Ok(values.iter().collect())
+}
+
+/// Build a plain JS object from (field, value) pairs - used for records with indexed fields
+pub(crate) fn object(fields: &[(&str, JsValue)]) -> Result<JsValue, JsValue> {
+    let obj = js_sys::Object::new();
+    for (name, value) in fields {
+        js_sys::Reflect::set(&obj, &JsValue::from_str(name), value)?;
+    }
+    Ok(obj.into())
+}
+
+/// Read a string field from a record object
+pub(crate) fn string_field(record: &JsValue, name: &str) -> Option<String> {
+    js_sys::Reflect::get(record, &JsValue::from_str(name))
+        .ok()
+        .and_then(|v| v.as_string())
+}
@@ -11,12 +11,39 @@ use serde::{Serialize, Deserialize};
 use once_cell::sync::Lazy;
 use tokio::sync::Mutex as TokioMutex;
 
+mod idb;
+mod schema;
+mod vault;
+
+mod relay_pool;
+mod outbox;
+mod recovery;
+mod commits;
+mod history;
+mod payments;
+mod sent_tokens;
+mod p2pk;
+mod treasury;
+
+mod subscriptions;
+
+mod signer;
+use signer::{get_public_key, get_signer};
+
 mod wallet_db;
 use wallet_db::HybridWalletDatabase;
 
+mod wallet_seed;
+
+mod backup;
+
 mod mdk_storage;
 use mdk_storage::{MdkHybridStorage, SharedMdkStorage};
 
+// The storage tests need IndexedDB, so they run in a headless browser (wasm-pack test --headless --chrome)
+#[cfg(all(test, target_arch = "wasm32"))]
+wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
 use cdk::wallet::{Wallet, WalletBuilder, ReceiveOptions};
 use cdk::nuts::{CurrencyUnit, Token};
 use cdk::mint_url::MintUrl;
@@ -44,8 +71,8 @@ async fn get_or_create_wallet_db() -> Result<HybridWalletDatabase, JsValue> {
         return Ok(db.clone());
     }
 
-    // First access this session - load from localStorage
-    log("📦 Loading wallet database from localStorage (first access this session)");
+    // First access this session - open IndexedDB (migrating any localStorage snapshot)
+    log("📦 Loading wallet database from IndexedDB (first access this session)");
     let db = HybridWalletDatabase::new().await?;
     *cache = Some(db.clone());
     log("✅ Wallet database cached for session");
@@ -61,8 +88,8 @@ async fn get_or_create_storage() -> Result<SharedMdkStorage, JsValue> {
         return Ok(SharedMdkStorage::new(Arc::clone(storage)));
     }
 
-    // First access this session - load from localStorage and wrap in Arc
-    log("📦 Loading storage from localStorage (first access this session)");
+    // First access this session - load from IndexedDB and wrap in Arc
+    log("📦 Loading storage from IndexedDB (first access this session)");
     let storage = Arc::new(MdkHybridStorage::new().await?);
     *cache = Some(Arc::clone(&storage));
     log("✅ Storage cached for session");
@@ -80,6 +107,8 @@ async fn create_mdk() -> Result<MDK<SharedMdkStorage>, JsValue> {
 pub async fn clear_storage_cache() {
     let mut cache = STORAGE_CACHE.lock().await;
     *cache = None;
+    recovery::reset();
+    history::reset();
     log("🗑️  Cleared in-memory storage cache");
 }
 
@@ -90,7 +119,7 @@ pub fn save_storage() -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
             let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save storage: {:?}", e)))?;
             Ok::<(), JsValue>(())
         }
@@ -103,25 +132,22 @@ pub fn save_storage() -> js_sys::Promise {
 /// Helper function to get Nostr keys
 fn get_keys() -> Result<Keys, JsValue> {
     let storage = get_local_storage()?;
-    let secret_hex = storage
-        .get_item("nostr_secret_key")?
+    let secret_hex = read_secret_hex(&storage)?
         .ok_or_else(|| JsValue::from_str("No keys found in localStorage"))?;
 
     Keys::parse(&secret_hex)
         .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))
 }
 
-/// Helper function to create a Nostr client connected to configured relays
-async fn create_connected_client() -> Result<Client, JsValue> {
-    let client = Client::default();
-    let relays = get_relays_internal()?;
-    for relay in &relays {
-        if let Ok(url) = RelayUrl::parse(relay) {
-            let _ = client.add_relay(url).await;
-        }
-    }
-    client.connect().await;
-    Ok(client)
+/// Wait without blocking the browser's event loop
+async fn sleep(duration: Duration) {
+    let millis = duration.as_millis() as i32;
+    wasm_bindgen_futures::JsFuture::from(js_sys::Promise::new(&mut |resolve, _| {
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, millis)
+            .unwrap();
+    })).await.ok();
 }
 
 /// Get current mint URL from localStorage, or use default
@@ -144,19 +170,6 @@ fn get_current_mint_url() -> Result<String, JsValue> {
 
 /// Helper function to create a wallet for a specific mint URL
 async fn create_wallet_for_mint(mint_url_str: String) -> Result<Wallet, JsValue> {
-    // Get Nostr keys from localStorage
-    let storage = get_local_storage()?;
-    let secret_hex = storage
-        .get_item("nostr_secret_key")?
-        .ok_or_else(|| JsValue::from_str("No keys found in localStorage"))?;
-
-    let keys = Keys::parse(&secret_hex)
-        .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))?;
-
-    // Create seed from Nostr secret key
-    let mut seed = [0u8; 64];
-    seed[..32].copy_from_slice(keys.secret_key().as_secret_bytes());
-
     // Parse the provided mint URL
     let mint_url = MintUrl::from_str(&mint_url_str)
         .map_err(|e| JsValue::from_str(&format!("Invalid mint URL: {}", e)))?;
@@ -164,6 +177,15 @@ async fn create_wallet_for_mint(mint_url_str: String) -> Result<Wallet, JsValue>
     // Get singleton database (shared across all mints and all wallet instances)
     let db = get_or_create_wallet_db().await?;
 
+    wallet_on(db, mint_url).await
+}
+
+/// Build a wallet for `mint_url` on `db`: the singleton, or the handle an
+/// `atomic` scope passes to its operation (so the wallet's writes are staged there)
+async fn wallet_on(db: HybridWalletDatabase, mint_url: MintUrl) -> Result<Wallet, JsValue> {
+    // Wallet seed (mnemonic, or the pinned nsec-derived seed for older wallets)
+    let seed = wallet_seed::wallet_seed(&db).await?;
+
     // Build wallet
     let wallet = WalletBuilder::new()
         .mint_url(mint_url)
@@ -179,37 +201,16 @@ async fn create_wallet_for_mint(mint_url_str: String) -> Result<Wallet, JsValue>
 /// Helper function to create a wallet from stored keys and database
 /// Uses the current mint URL from localStorage
 async fn create_wallet() -> Result<Wallet, JsValue> {
-    // Get Nostr keys from localStorage
-    let storage = get_local_storage()?;
-    let secret_hex = storage
-        .get_item("nostr_secret_key")?
-        .ok_or_else(|| JsValue::from_str("No keys found in localStorage"))?;
-
-    let keys = Keys::parse(&secret_hex)
-        .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))?;
-
-    // Create seed from Nostr secret key
-    let mut seed = [0u8; 64];
-    seed[..32].copy_from_slice(keys.secret_key().as_secret_bytes());
-
     // Get current mint URL from localStorage
-    let mint_url_str = get_current_mint_url()?;
-    let mint_url = MintUrl::from_str(&mint_url_str)
-        .map_err(|e| JsValue::from_str(&format!("Invalid mint URL: {}", e)))?;
-
-    // Get singleton database (shared across all mints and all wallet instances)
-    let db = get_or_create_wallet_db().await?;
-
-    // Build wallet
-    let wallet = WalletBuilder::new()
-        .mint_url(mint_url)
-        .unit(CurrencyUnit::Sat)
-        .localstore(Arc::new(db))
-        .seed(seed)
-        .build()
-        .map_err(|e| JsValue::from_str(&format!("Failed to build wallet: {}", e)))?;
+    create_wallet_for_mint(get_current_mint_url()?).await
+}
 
-    Ok(wallet)
+/// Read the Nostr secret key (hex), decrypting it if a passphrase is set
+fn read_secret_hex(storage: &Storage) -> Result<Option<String>, JsValue> {
+    match storage.get_item("nostr_secret_key")? {
+        Some(stored) => Ok(Some(vault::open(&stored)?)),
+        None => Ok(None),
+    }
 }
 
 // Helper to get localStorage
@@ -220,60 +221,186 @@ fn get_local_storage() -> Result<Storage, JsValue> {
         .ok_or_else(|| JsValue::from_str("No localStorage available"))
 }
 
+/// localStorage key holding the HistoryOptions of the shared subscriptions
+const HISTORY_OPTIONS_KEY: &str = "subscription_history_options";
+
+/// When an ordered subscription hands its buffered history to the handler
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct HistoryOptions {
+    /// Relays that must send EOSE first (None = every relay in the pool)
+    quorum: Option<usize>,
+    /// Longest wait for that quorum; slow relays' history is processed as it arrives afterwards
+    timeout_ms: u64,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> Self {
+        Self {
+            quorum: None,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+impl HistoryOptions {
+    /// Options set with set_subscription_history_options, or the defaults
+    fn load() -> Self {
+        get_local_storage()
+            .ok()
+            .and_then(|storage| storage.get_item(HISTORY_OPTIONS_KEY).ok().flatten())
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Configure how long subscriptions buffer history before processing it in order:
+/// until `quorum` relays sent EOSE (omit for all relays) or `timeout_ms` passed.
+/// Applies to subscriptions opened afterwards.
+#[wasm_bindgen]
+pub fn set_subscription_history_options(quorum: Option<u32>, timeout_ms: u32) -> Result<(), JsValue> {
+    if quorum == Some(0) {
+        return Err(JsValue::from_str("Quorum must be at least 1 relay"));
+    }
+
+    let options = HistoryOptions {
+        quorum: quorum.map(|q| q as usize),
+        timeout_ms: timeout_ms as u64,
+    };
+    let json = serde_json::to_string(&options)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))?;
+    get_local_storage()?.set_item(HISTORY_OPTIONS_KEY, &json)
+}
+
+/// Next thing an ordered subscription has to handle
+enum HistoryStep {
+    Notification(Option<RelayPoolNotification>),
+    Deadline,
+}
+
 /// Helper for ordered event subscriptions
-/// Collects historical events until EOSE, sorts by created_at (oldest first),
-/// processes them in order, then continues with real-time events
-async fn subscribe_with_ordered_history<F, Fut>(
+/// Sends one REQ with all `filters`, collects historical events until enough
+/// relays sent EOSE for it (or the timeout passes), sorts them by created_at
+/// (oldest first), processes them in order, then continues with real-time events. History of relays that
+/// hadn't sent EOSE yet keeps being buffered until they do (or the timeout passes).
+/// Events seen on several relays are only processed once.
+async fn subscribe_with_ordered_history<C, F, Fut>(
     client: &Client,
-    filter: Filter,
+    subscription_id: nostr::SubscriptionId,
+    filters: Vec<Filter>,
+    options: HistoryOptions,
+    is_current: C,
     event_handler: F,
 ) -> Result<(), JsValue>
 where
+    C: Fn() -> bool,
     F: Fn(Box<nostr::Event>) -> Fut + Clone + 'static,
     Fut: std::future::Future<Output = Result<(), JsValue>>,
 {
-    // Subscribe to filter
-    client.subscribe(filter.clone(), None).await
-        .map_err(|e| JsValue::from_str(&format!("Failed to subscribe: {}", e)))?;
+    use std::future::Future;
+    use std::task::Poll;
+
+    // A REQ with an existing ID replaces it on the relays
+    relay_pool::subscribe(client, subscription_id.clone(), filters).await?;
 
     let mut notifications = client.notifications();
+    let quorum = options.quorum.unwrap_or(client.relays().await.len()).max(1);
+
+    // Relays that sent EOSE for this subscription
+    let mut eose_relays: HashSet<RelayUrl> = HashSet::new();
+    let mut buffered: Vec<Box<nostr::Event>> = Vec::new();
+    let mut seen: HashSet<nostr::EventId> = HashSet::new();
+    // Quorum reached: events from relays past their EOSE are real-time
+    let mut history_flushed = false;
+    // Timeout passed: nothing is buffered anymore
+    let mut deadline_passed = false;
+    let mut deadline = Box::pin(sleep(Duration::from_millis(options.timeout_ms)));
+
+    // One REQ serves every group, so a bad event is logged and skipped rather than ending the loop
+    async fn handle<F, Fut>(event: Box<nostr::Event>, event_handler: &F)
+    where
+        F: Fn(Box<nostr::Event>) -> Fut,
+        Fut: std::future::Future<Output = Result<(), JsValue>>,
+    {
+        let event_id = event.id;
+        if let Err(e) = event_handler(event).await {
+            log(&format!("  ⚠️ Failed to handle event {}: {:?}", event_id.to_hex(), e));
+        }
+    }
 
-    // Track which relays have sent EOSE
-    let mut eose_relays: HashSet<String> = HashSet::new();
-    let mut historical_events: Vec<Box<nostr::Event>> = Vec::new();
-    let mut processed_historical = false;
+    // Oldest first; ties broken by ID so every relay mix yields the same order
+    async fn flush<F, Fut>(buffered: &mut Vec<Box<nostr::Event>>, event_handler: &F)
+    where
+        F: Fn(Box<nostr::Event>) -> Fut,
+        Fut: std::future::Future<Output = Result<(), JsValue>>,
+    {
+        if buffered.is_empty() {
+            return;
+        }
+        log(&format!("  Sorting {} historical events by created_at...", buffered.len()));
+        buffered.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        for event in buffered.drain(..) {
+            handle(event, event_handler).await;
+        }
+    }
 
-    while let Ok(notification) = notifications.recv().await {
-        match notification {
-            RelayPoolNotification::Event { event, .. } => {
-                if !processed_historical {
-                    // Still collecting historical events
-                    historical_events.push(event);
-                } else {
-                    // Real-time event - process immediately
-                    event_handler(event).await?;
+    loop {
+        let step = {
+            let mut recv = std::pin::pin!(notifications.recv());
+            std::future::poll_fn(|cx| {
+                if let Poll::Ready(notification) = recv.as_mut().poll(cx) {
+                    return Poll::Ready(HistoryStep::Notification(notification.ok()));
                 }
-            }
-            RelayPoolNotification::Message { relay_url, message } => {
-                // Check for EOSE message using Debug format (EOSE is RelayMessage::EndOfStoredEvents)
-                let msg_str = format!("{:?}", message);
-                if msg_str.contains("EndOfStoredEvents") {
-                    eose_relays.insert(relay_url.to_string());
-                    log(&format!("  EOSE from {} ({} total)", relay_url, eose_relays.len()));
-
-                    // Once we have EOSE from first relay, process historical events
-                    if !processed_historical && eose_relays.len() >= 1 {
-                        log(&format!("  Sorting {} historical events by created_at...", historical_events.len()));
-
-                        // Sort by created_at (oldest first)
-                        historical_events.sort_by_key(|e| e.created_at);
+                if !deadline_passed && deadline.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(HistoryStep::Deadline);
+                }
+                Poll::Pending
+            })
+            .await
+        };
+
+        // Superseded by a newer REQ under the same ID
+        if !is_current() {
+            break;
+        }
 
-                        // Process sorted historical events
-                        for event in historical_events.drain(..) {
-                            event_handler(event).await?;
-                        }
+        match step {
+            HistoryStep::Notification(None) => break,
+            HistoryStep::Deadline => {
+                deadline_passed = true;
+                if !history_flushed {
+                    log(&format!("  ⏱️ EOSE from {}/{} relay(s) before the timeout", eose_relays.len(), quorum));
+                    history_flushed = true;
+                }
+                flush(&mut buffered, &event_handler).await;
+            }
+            // The pool is shared, so skip events of other subscriptions
+            HistoryStep::Notification(Some(RelayPoolNotification::Event { relay_url, subscription_id: id, event }))
+                if id == subscription_id =>
+            {
+                // Same event from another relay
+                if !seen.insert(event.id) {
+                    continue;
+                }
 
-                        processed_historical = true;
+                if deadline_passed || (history_flushed && eose_relays.contains(&relay_url)) {
+                    // Real-time event - process immediately
+                    handle(event, &event_handler).await;
+                } else {
+                    // Still collecting historical events
+                    buffered.push(event);
+                }
+            }
+            HistoryStep::Notification(Some(RelayPoolNotification::Message {
+                relay_url,
+                message: nostr::RelayMessage::EndOfStoredEvents(id),
+            })) if *id == subscription_id => {
+                eose_relays.insert(relay_url.clone());
+                log(&format!("  EOSE from {} ({}/{})", relay_url, eose_relays.len(), quorum));
+
+                if history_flushed || eose_relays.len() >= quorum {
+                    flush(&mut buffered, &event_handler).await;
+                    if !history_flushed {
+                        history_flushed = true;
                         log("  ✓ Historical events processed, switching to real-time mode");
                     }
                 }
@@ -301,12 +428,14 @@ pub fn generate_keys() -> Result<String, JsValue> {
 
     let storage = get_local_storage()?;
 
-    // Clear MDK state from previous identity (but keep wallet)
-    storage.remove_item("mdk_state")?;
+    // Clear MDK state from previous identity (but keep wallet and its seed)
+    wallet_seed::pin_legacy_seed_before_identity_change()?;
+    mdk_storage::delete_persisted_state()?;
     log("Cleared old MDK state for fresh start (wallet preserved)");
 
-    // Save new keys
-    storage.set_item("nostr_secret_key", &secret_hex)?;
+    // Save new keys (and sign with them, not a previously connected extension)
+    storage.set_item("nostr_secret_key", &vault::seal(&secret_hex)?)?;
+    forget_external_signer()?;
 
     Ok(keys.public_key().to_bech32().expect("bech32 encoding is infallible"))
 }
@@ -314,9 +443,14 @@ pub fn generate_keys() -> Result<String, JsValue> {
 /// Load existing keys from localStorage, or generate if none exist
 #[wasm_bindgen]
 pub fn get_or_create_keys() -> Result<String, JsValue> {
+    // With an external signer there are no local keys to create
+    if signer::signer_type()? != signer::SIGNER_TYPE_LOCAL {
+        return Ok(get_public_key()?.to_bech32().expect("bech32 encoding is infallible"));
+    }
+
     let storage = get_local_storage()?;
 
-    if let Some(secret_hex) = storage.get_item("nostr_secret_key")? {
+    if let Some(secret_hex) = read_secret_hex(&storage)? {
         // Load existing keys
         let keys = Keys::parse(&secret_hex)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))?;
@@ -330,55 +464,339 @@ pub fn get_or_create_keys() -> Result<String, JsValue> {
 /// Get the npub (public key in bech32 format)
 #[wasm_bindgen]
 pub fn get_npub() -> Result<String, JsValue> {
-    let storage = get_local_storage()?;
-
-    let secret_hex = storage
-        .get_item("nostr_secret_key")?
-        .ok_or_else(|| JsValue::from_str("No keys found in localStorage"))?;
-
-    let keys = Keys::parse(&secret_hex)
-        .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))?;
-
-    Ok(keys.public_key().to_bech32().expect("bech32 encoding is infallible"))
+    Ok(get_public_key()?.to_bech32().expect("bech32 encoding is infallible"))
 }
 
 #[wasm_bindgen]
 pub fn get_nsec() -> Result<String, JsValue> {
-    let storage = get_local_storage()?;
-
-    let secret_hex = storage
-        .get_item("nostr_secret_key")?
-        .ok_or_else(|| JsValue::from_str("No keys found in localStorage"))?;
+    let keys = identity_keys()?;
+    Ok(keys.secret_key().to_bech32().expect("bech32 encoding is infallible"))
+}
 
-    let keys = Keys::parse(&secret_hex)
-        .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))?;
+/// The local keys, when they are the current identity's
+/// With an extension or bunker signing, a key left in localStorage belongs to a previous identity.
+fn identity_keys() -> Result<Keys, JsValue> {
+    let pubkey = get_public_key()?;
+    let signer_type = signer::signer_type()?;
+    if signer_type != signer::SIGNER_TYPE_LOCAL {
+        return Err(JsValue::from_str(&format!(
+            "The secret key of {} is held by the {} signer",
+            pubkey.to_bech32().expect("bech32 encoding is infallible"),
+            signer_type,
+        )));
+    }
 
-    Ok(keys.secret_key().to_bech32().expect("bech32 encoding is infallible"))
+    let keys = get_keys()?;
+    if keys.public_key() != pubkey {
+        return Err(JsValue::from_str("The stored key doesn't match the current identity"));
+    }
+    Ok(keys)
 }
 
 /// Import an existing nsec and set it as the current identity
 #[wasm_bindgen]
-pub fn import_nsec(nsec: &str) -> Result<(), JsValue> {
-    // Parse the nsec to validate it and convert to hex
-    let secret_key = SecretKey::from_bech32(nsec)
-        .map_err(|e| JsValue::from_str(&format!("Invalid nsec: {}", e)))?;
+pub fn import_nsec(nsec: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            // Parse the nsec to validate it and convert to hex
+            let secret_key = SecretKey::from_bech32(&nsec)
+                .map_err(|e| JsValue::from_str(&format!("Invalid nsec: {}", e)))?;
+
+            save_identity(secret_key).await
+        }
+        .await;
+
+        result.map(|_| JsValue::undefined())
+    })
+}
 
+/// Store a secret key as the current identity
+async fn save_identity(secret_key: SecretKey) -> Result<(), JsValue> {
     let keys = Keys::new(secret_key);
 
+    // MDK state belongs to the previous identity (wallet and its seed are kept)
+    wallet_seed::pin_legacy_seed_before_identity_change()?;
+    if get_public_key().ok() != Some(keys.public_key()) {
+        clear_storage_cache().await;
+        mdk_storage::delete_persisted_state()?;
+    }
+
     // Store in localStorage as hex
     let storage = get_local_storage()?;
-    storage.set_item("nostr_secret_key", &keys.secret_key().to_secret_hex())
+    storage.set_item("nostr_secret_key", &vault::seal(&keys.secret_key().to_secret_hex())?)
         .map_err(|e| JsValue::from_str(&format!("Failed to store keys: {:?}", e)))?;
+    forget_external_signer()?;
 
     log(&format!("Imported identity: {}", keys.public_key().to_hex()));
     Ok(())
 }
 
+/// Default NIP-49 scrypt cost (log2 N) - about a second in the browser
+const NCRYPTSEC_DEFAULT_LOG_N: u8 = 16;
+
+/// Highest accepted scrypt cost; beyond this the browser needs gigabytes of memory
+const NCRYPTSEC_MAX_LOG_N: u8 = 22;
+
+/// Export the current identity as a NIP-49 ncryptsec (password-encrypted nsec)
+/// `log_n` is the scrypt cost (default 16); each step doubles the time to encrypt and decrypt
+#[wasm_bindgen]
+pub fn export_ncryptsec(password: &str, log_n: Option<u8>) -> Result<String, JsValue> {
+    use nostr::nips::nip49::{EncryptedSecretKey, KeySecurity};
+
+    if password.is_empty() {
+        return Err(JsValue::from_str("Password must not be empty"));
+    }
+
+    let log_n = log_n.unwrap_or(NCRYPTSEC_DEFAULT_LOG_N);
+    if log_n == 0 || log_n > NCRYPTSEC_MAX_LOG_N {
+        return Err(JsValue::from_str(&format!(
+            "scrypt cost must be between 1 and {}", NCRYPTSEC_MAX_LOG_N
+        )));
+    }
+
+    let keys = identity_keys()?;
+    let encrypted = EncryptedSecretKey::new(keys.secret_key(), password, log_n, KeySecurity::Unknown)
+        .map_err(|e| JsValue::from_str(&format!("Failed to encrypt key: {}", e)))?;
+
+    encrypted.to_bech32()
+        .map_err(|e| JsValue::from_str(&format!("Failed to encode ncryptsec: {}", e)))
+}
+
+/// Import a NIP-49 ncryptsec and set it as the current identity
+#[wasm_bindgen]
+pub fn import_ncryptsec(ncryptsec: String, password: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let secret_key = decrypt_ncryptsec(&ncryptsec, &password)?;
+            save_identity(secret_key).await
+        }
+        .await;
+
+        result.map(|_| JsValue::undefined())
+    })
+}
+
+/// Decrypt a NIP-49 ncryptsec
+fn decrypt_ncryptsec(ncryptsec: &str, password: &str) -> Result<SecretKey, JsValue> {
+    use nostr::nips::nip49::EncryptedSecretKey;
+
+    let encrypted = EncryptedSecretKey::from_bech32(ncryptsec.trim())
+        .map_err(|e| JsValue::from_str(&format!("Invalid ncryptsec: {}", e)))?;
+
+    // The cost comes from the file, so bound the memory a crafted one can demand
+    if encrypted.log_n() > NCRYPTSEC_MAX_LOG_N {
+        return Err(JsValue::from_str(&format!(
+            "scrypt cost {} is too high (at most {})", encrypted.log_n(), NCRYPTSEC_MAX_LOG_N
+        )));
+    }
+
+    encrypted.decrypt(password)
+        .map_err(|_| JsValue::from_str("Wrong password or corrupt ncryptsec"))
+}
+
+/// Whether a NIP-07 browser extension (window.nostr) is available
+#[wasm_bindgen]
+pub fn is_browser_signer_available() -> bool {
+    signer::BrowserSigner::is_available()
+}
+
+/// Which signer is in use: "local", "nip07" or "nip46"
+#[wasm_bindgen]
+pub fn get_signer_type() -> Result<String, JsValue> {
+    signer::signer_type()
+}
+
+/// Sign with the NIP-07 browser extension from now on
+/// Returns the extension's npub. If the extension holds a different key this
+/// switches identity like import_nsec (MDK state is cleared, wallet kept).
+/// The ecash wallet keeps its own seed (see get_wallet_seed_type).
+#[wasm_bindgen]
+pub fn connect_browser_signer() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use nostr::signer::NostrSigner;
+
+            let pubkey = signer::BrowserSigner.get_public_key().await
+                .map_err(|e| JsValue::from_str(&format!("Failed to connect to extension: {}", e)))?;
+
+            // MDK state belongs to the previous identity (wallet is kept)
+            if get_public_key().ok() != Some(pubkey) {
+                clear_storage_cache().await;
+                mdk_storage::delete_persisted_state()?;
+            }
+
+            let storage = get_local_storage()?;
+            storage.set_item(signer::SIGNER_PUBKEY_KEY, &pubkey.to_hex())?;
+            storage.set_item(signer::SIGNER_TYPE_KEY, signer::SIGNER_TYPE_NIP07)?;
+
+            log(&format!("Using browser extension signer: {}", pubkey.to_hex()));
+            Ok::<String, JsValue>(pubkey.to_bech32().expect("bech32 encoding is infallible"))
+        }
+        .await;
+
+        result.map(|npub| JsValue::from_str(&npub))
+    })
+}
+
+/// Start a nostrconnect:// handshake: returns a URI to paste into (or scan with) the bunker
+/// Then call connect_remote_signer with the same URI to wait for the bunker to connect.
+#[wasm_bindgen]
+pub fn create_nostrconnect_uri(relay_url: String) -> Result<String, JsValue> {
+    use nostr::nips::nip46::NostrConnectURI;
+
+    let relay = RelayUrl::parse(&relay_url)
+        .map_err(|e| JsValue::from_str(&format!("Invalid relay URL: {}", e)))?;
+
+    let app_keys = Keys::generate();
+    get_local_storage()?.set_item(
+        signer::NIP46_PENDING_KEY,
+        &vault::seal(&app_keys.secret_key().to_secret_hex())?,
+    )?;
+
+    Ok(NostrConnectURI::client(app_keys.public_key(), vec![relay], "Cashu MLS Chat").to_string())
+}
+
+/// Sign with a NIP-46 remote signer from now on
+/// Accepts a bunker:// URI from the bunker, or a nostrconnect:// URI from create_nostrconnect_uri.
+/// Returns the remote npub; the session is persisted so it survives reloads.
+/// Like connect_browser_signer, switching to a different key clears MDK state (wallet kept).
+#[wasm_bindgen]
+pub fn connect_remote_signer(uri: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use nostr::signer::NostrSigner;
+
+            let storage = get_local_storage()?;
+            let uri = uri.trim().to_string();
+
+            // nostrconnect:// handshakes reuse the app key advertised in the URI
+            let app_keys = if uri.starts_with("nostrconnect://") {
+                let pending = storage.get_item(signer::NIP46_PENDING_KEY)?
+                    .ok_or_else(|| JsValue::from_str("No pending nostrconnect handshake - create a new URI"))?;
+                Keys::parse(&vault::open(&pending)?)
+                    .map_err(|e| JsValue::from_str(&format!("Invalid pending app key: {}", e)))?
+            } else {
+                Keys::generate()
+            };
+
+            log("Connecting to remote signer...");
+            let remote = Arc::new(signer::new_remote_signer(&uri, app_keys.clone())?);
+            let pubkey = remote.get_public_key().await
+                .map_err(|e| JsValue::from_str(&format!("Remote signer did not respond: {}", e)))?;
+
+            // The bunker URI (with the signer's own pubkey) is what we reconnect with later
+            let bunker_uri = remote.bunker_uri().await
+                .map_err(|e| JsValue::from_str(&format!("Failed to get bunker URI: {}", e)))?;
+
+            // MDK state belongs to the previous identity (wallet is kept)
+            if get_public_key().ok() != Some(pubkey) {
+                clear_storage_cache().await;
+                mdk_storage::delete_persisted_state()?;
+            }
+
+            signer::save_nip46_session(&signer::Nip46Session {
+                bunker_uri: bunker_uri.to_string(),
+                app_secret_key: app_keys.secret_key().to_secret_hex(),
+            })?;
+            storage.remove_item(signer::NIP46_PENDING_KEY)?;
+            storage.set_item(signer::SIGNER_PUBKEY_KEY, &pubkey.to_hex())?;
+            storage.set_item(signer::SIGNER_TYPE_KEY, signer::SIGNER_TYPE_NIP46)?;
+            signer::set_remote_signer(Some(remote));
+
+            log(&format!("Using remote signer: {}", pubkey.to_hex()));
+            Ok::<String, JsValue>(pubkey.to_bech32().expect("bech32 encoding is infallible"))
+        }
+        .await;
+
+        result.map(|npub| JsValue::from_str(&npub))
+    })
+}
+
+/// Go back to signing with the local keys in localStorage
+/// Like connect_browser_signer, switching to a different key clears MDK state (wallet kept).
+#[wasm_bindgen]
+pub fn use_local_signer() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let local = get_keys().ok().map(|keys| keys.public_key());
+
+            // MDK state belongs to the previous identity (wallet is kept)
+            if get_public_key().ok() != local {
+                clear_storage_cache().await;
+                mdk_storage::delete_persisted_state()?;
+            }
+
+            forget_external_signer()
+        }
+        .await;
+
+        result.map(|_| JsValue::undefined())
+    })
+}
+
+/// Drop the extension or bunker signer, so the local keys sign again
+fn forget_external_signer() -> Result<(), JsValue> {
+    let storage = get_local_storage()?;
+    storage.remove_item(signer::SIGNER_TYPE_KEY)?;
+    storage.remove_item(signer::SIGNER_PUBKEY_KEY)?;
+    storage.remove_item(signer::NIP46_SESSION_KEY)?;
+    signer::set_remote_signer(None);
+    log("Using local keys for signing");
+    Ok(())
+}
+
+/// DEBUG: Run an auto-approving NIP-46 bunker in this page with a fresh identity
+/// Stand-in remote signer for tests against the local relay.
+/// Returns JSON: { bunker_uri, npub }
+#[wasm_bindgen]
+pub fn debug_start_test_bunker(relay_url: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use nostr_connect::prelude::{
+                NostrConnectKeys, NostrConnectRemoteSigner, NostrConnectRequest, NostrConnectSignerActions,
+            };
+
+            struct AutoApprove;
+
+            impl NostrConnectSignerActions for AutoApprove {
+                fn approve(&self, _public_key: &nostr::PublicKey, _req: &NostrConnectRequest) -> bool {
+                    true
+                }
+            }
+
+            let keys = NostrConnectKeys {
+                signer: Keys::generate(),
+                user: Keys::generate(),
+            };
+            let npub = keys.user.public_key().to_bech32().expect("bech32 encoding is infallible");
+
+            let bunker = NostrConnectRemoteSigner::new(keys, [relay_url.as_str()], None, None)
+                .map_err(|e| JsValue::from_str(&format!("Failed to create bunker: {}", e)))?;
+            let bunker_uri = bunker.bunker_uri().to_string();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                if let Err(e) = bunker.serve(AutoApprove).await {
+                    log(&format!("Test bunker stopped: {}", e));
+                }
+            });
+
+            log(&format!("🧪 Test bunker running: {}", bunker_uri));
+            let result = serde_json::json!({
+                "bunker_uri": bunker_uri,
+                "npub": npub
+            });
+            Ok::<String, JsValue>(result.to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
 /// Get the public key in hex format
 #[wasm_bindgen]
 pub fn get_pubkey_hex() -> Result<String, JsValue> {
-    let keys = get_keys()?;
-    Ok(keys.public_key().to_hex())
+    Ok(get_public_key()?.to_hex())
 }
 
 /// Fetch profile metadata (Kind 0) for a given npub
@@ -390,8 +808,8 @@ pub fn fetch_profile_metadata(npub: String) -> js_sys::Promise {
         let pubkey = nostr::PublicKey::from_bech32(&npub)
             .map_err(|e| JsValue::from_str(&format!("Invalid npub: {}", e)))?;
 
-        // Create and connect client
-        let client = create_connected_client().await?;
+        // Shared relay pool
+        let client = relay_pool::client().await?;
 
         // Fetch Kind 0 events for this pubkey
         let filter = Filter::new()
@@ -402,9 +820,6 @@ pub fn fetch_profile_metadata(npub: String) -> js_sys::Promise {
         let events = client.fetch_events(filter, Duration::from_secs(5)).await
             .map_err(|e| JsValue::from_str(&format!("Failed to fetch events: {}", e)))?;
 
-        // Disconnect
-        let _ = client.disconnect().await;
-
         if events.is_empty() {
             return Ok(JsValue::NULL);
         }
@@ -424,7 +839,7 @@ pub fn fetch_profile_metadata(npub: String) -> js_sys::Promise {
 #[wasm_bindgen]
 pub fn publish_profile_metadata(metadata_json: String) -> js_sys::Promise {
     future_to_promise(async move {
-        let keys = get_keys()?;
+        let signer = get_signer()?;
 
         // Parse JSON into Metadata struct
         let metadata: nostr::Metadata = serde_json::from_str(&metadata_json)
@@ -432,19 +847,16 @@ pub fn publish_profile_metadata(metadata_json: String) -> js_sys::Promise {
 
         // Create Kind 0 event
         let event = EventBuilder::metadata(&metadata)
-            .sign_with_keys(&keys)
+            .sign(&signer).await
             .map_err(|e| JsValue::from_str(&format!("Failed to sign event: {}", e)))?;
 
-        // Create and connect client
-        let client = create_connected_client().await?;
+        // Shared relay pool
+        let client = relay_pool::client().await?;
 
         // Publish event
         client.send_event(&event).await
             .map_err(|e| JsValue::from_str(&format!("Failed to publish metadata: {}", e)))?;
 
-        // Disconnect
-        let _ = client.disconnect().await;
-
         log("✅ Profile metadata published");
         Ok(JsValue::from_str("success"))
     })
@@ -454,46 +866,609 @@ pub fn publish_profile_metadata(metadata_json: String) -> js_sys::Promise {
 #[wasm_bindgen]
 pub fn clear_keys() -> Result<(), JsValue> {
     let storage = get_local_storage()?;
+    wallet_seed::pin_legacy_seed_before_identity_change()?;
     storage.remove_item("nostr_secret_key")?;
-    storage.remove_item("mdk_state")?;
+    mdk_storage::delete_persisted_state()?;
     log("Cleared Nostr keys and MDK state (wallet preserved)");
     Ok(())
 }
 
 // ============================================================================
-// Trusted Mints Management
+// Encryption at Rest
 // ============================================================================
 
-/// Get list of trusted mint URLs
-/// Returns JSON array of strings
+/// Whether a storage passphrase has been set
 #[wasm_bindgen]
-pub fn get_trusted_mints() -> Result<String, JsValue> {
-    let storage = get_local_storage()?;
+pub fn is_encryption_enabled() -> bool {
+    vault::is_enabled()
+}
 
-    let mints_json = storage
-        .get_item("trusted_mints")?
-        .unwrap_or_else(|| "[]".to_string());
+/// Whether storage is encrypted and still needs `unlock`
+#[wasm_bindgen]
+pub fn is_storage_locked() -> bool {
+    vault::is_locked()
+}
 
-    Ok(mints_json)
+/// Unlock encrypted storage (Nostr key, MDK state and wallet) with the passphrase
+#[wasm_bindgen]
+pub fn unlock(passphrase: &str) -> Result<(), JsValue> {
+    vault::unlock(passphrase)?;
+    log("🔓 Storage unlocked");
+    Ok(())
 }
 
-/// Add a mint to the trusted list
-/// Validates the mint by connecting and fetching keysets
-/// Returns a Promise that resolves to true if added, false if already in list
+/// Lock encrypted storage: saves pending changes, then drops the key
+/// and all decrypted state from memory
 #[wasm_bindgen]
-pub fn add_trusted_mint(mint_url: String) -> js_sys::Promise {
+pub fn lock() -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
-            log(&format!("🔍 Validating mint: {}", mint_url));
+            if !vault::is_enabled() {
+                return Err(JsValue::from_str("Encryption is not enabled - set a passphrase first"));
+            }
 
-            // Validate mint URL format
-            let _mint_url_parsed = MintUrl::from_str(&mint_url)
-                .map_err(|e| {
-                    log(&format!("❌ Invalid URL format: {}", e));
-                    JsValue::from_str(&format!("Invalid mint URL: {}", e))
-                })?;
+            let mut cache = STORAGE_CACHE.lock().await;
+            if let Some(storage) = cache.as_ref() {
+                storage.save_snapshot().await
+                    .map_err(|e| JsValue::from_str(&format!("Failed to save storage before locking: {:?}", e)))?;
+            }
+            *cache = None;
+            *WALLET_DB.lock().await = None;
 
-            log("📡 Creating wallet and connecting to mint...");
+            vault::lock();
+            log("🔒 Storage locked");
+            Ok::<(), JsValue>(())
+        }
+        .await;
+
+        result.map(|_| JsValue::undefined())
+    })
+}
+
+/// Set, change or remove the storage passphrase
+/// - No passphrase yet: `old_passphrase` is ignored and everything is encrypted under the new one
+/// - Empty `new_passphrase`: everything is decrypted and the passphrase removed
+#[wasm_bindgen]
+pub fn change_passphrase(old_passphrase: String, new_passphrase: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            if !vault::is_enabled() {
+                if new_passphrase.is_empty() {
+                    return Err(JsValue::from_str("Passphrase must not be empty"));
+                }
+                vault::enable(&new_passphrase)?;
+                reseal_all_storage().await?;
+                log("✅ Storage encryption enabled");
+                return Ok(());
+            }
+
+            // Also verifies the old passphrase
+            vault::unlock(&old_passphrase)?;
+
+            if new_passphrase.is_empty() {
+                // Rewrite everything in plaintext before the key is dropped
+                vault::set_plaintext_writes(true);
+                if let Err(e) = reseal_all_storage().await {
+                    vault::set_plaintext_writes(false);
+                    return Err(e);
+                }
+                vault::disable()?;
+                log("✅ Storage encryption removed");
+            } else {
+                // Only the wrapped data key changes; records stay as they are
+                vault::rewrap(&new_passphrase)?;
+                log("✅ Storage passphrase changed");
+            }
+
+            Ok::<(), JsValue>(())
+        }
+        .await;
+
+        result.map(|_| JsValue::undefined())
+    })
+}
+
+/// Rewrite the Nostr key, MDK state and wallet under the vault's current setting
+async fn reseal_all_storage() -> Result<(), JsValue> {
+    let storage = get_local_storage()?;
+    if let Some(secret_hex) = read_secret_hex(&storage)? {
+        storage.set_item("nostr_secret_key", &vault::seal(&secret_hex)?)?;
+    }
+    wallet_seed::reseal()?;
+    signer::reseal()?;
+
+    get_or_create_storage().await?.inner().reseal().await?;
+    outbox::reseal().await?;
+    mdk_storage::reseal_json_store(mdk_storage::CHECKPOINTS_STORE).await?;
+    mdk_storage::reseal_json_store(mdk_storage::HISTORY_STORE).await?;
+    mdk_storage::reseal_json_store(mdk_storage::CLAIMS_STORE).await?;
+    mdk_storage::reseal_json_store(mdk_storage::TOKEN_STATUS_STORE).await?;
+    mdk_storage::reseal_json_store(mdk_storage::DELIVERY_STORE).await?;
+    get_or_create_wallet_db().await?.reseal().await?;
+    Ok(())
+}
+
+// ============================================================================
+// Wallet Seed
+// ============================================================================
+
+/// Where the ecash wallet seed comes from: "mnemonic" or "legacy_nsec"
+/// Creates the seed if the wallet has none yet, so call this before clearing
+/// the Nostr key of a wallet that may still use its nsec-derived seed.
+#[wasm_bindgen]
+pub fn get_wallet_seed_type() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let db = get_or_create_wallet_db().await?;
+            wallet_seed::wallet_seed(&db).await?;
+            Ok::<&str, JsValue>(wallet_seed::seed_source()?.as_str())
+        }
+        .await;
+
+        result.map(JsValue::from_str)
+    })
+}
+
+/// Create a BIP-39 mnemonic for a wallet that has no seed yet
+/// Returns a Promise that resolves to the mnemonic words
+#[wasm_bindgen]
+pub fn generate_wallet_mnemonic() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            match wallet_seed::seed_source()? {
+                wallet_seed::SeedSource::Mnemonic => {
+                    return Err(JsValue::from_str("Wallet already has a mnemonic (use import_wallet_mnemonic to replace it)"));
+                }
+                wallet_seed::SeedSource::LegacyNsec => {
+                    return Err(JsValue::from_str("Wallet uses its nsec-derived seed (use migrate_wallet_to_mnemonic)"));
+                }
+                wallet_seed::SeedSource::None => {}
+            }
+
+            let db = get_or_create_wallet_db().await?;
+            let mnemonic = wallet_seed::generate_mnemonic()?;
+            wallet_seed::save_mnemonic(&mnemonic)?;
+            db.reset_keyset_counters().await?;
+
+            log("✅ Generated wallet mnemonic");
+            Ok::<String, JsValue>(mnemonic.to_string())
+        }
+        .await;
+
+        result.map(|words| JsValue::from_str(&words))
+    })
+}
+
+/// Export the wallet mnemonic words
+#[wasm_bindgen]
+pub fn export_wallet_mnemonic() -> Result<String, JsValue> {
+    wallet_seed::load_mnemonic()?
+        .map(|mnemonic| mnemonic.to_string())
+        .ok_or_else(|| JsValue::from_str("Wallet has no mnemonic"))
+}
+
+/// Use an existing BIP-39 mnemonic as the wallet seed
+/// Replacing a different mnemonic is refused unless `replace` is true: funds only
+/// known to the old mnemonic can't be restored once it is gone (export it first).
+/// Stored proofs stay spendable. The mnemonic may already have been used elsewhere,
+/// so the wallet is restored from every mint it knows (and every trusted mint), which
+/// moves the keyset counters past the outputs those mints already signed.
+/// Call restore_wallet for any mint listed under failed before spending from it.
+/// Returns a Promise that resolves to JSON {restored: [{mint, amount}], failed: [{mint, error}]}
+#[wasm_bindgen]
+pub fn import_wallet_mnemonic(words: String, replace: bool) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use std::collections::BTreeSet;
+            use cdk_common::database::WalletDatabase;
+
+            let mnemonic = wallet_seed::parse_mnemonic(&words)?;
+            match wallet_seed::load_mnemonic()? {
+                Some(current) if current == mnemonic => {
+                    return Ok(serde_json::json!({ "restored": [], "failed": [] }).to_string());
+                }
+                Some(_) if !replace => {
+                    return Err(JsValue::from_str(
+                        "Wallet already has a different mnemonic. Export it first, then import with replace to discard it",
+                    ));
+                }
+                _ => {}
+            }
+
+            let db = get_or_create_wallet_db().await?;
+            wallet_seed::save_mnemonic(&mnemonic)?;
+            // Counters of the previous seed mean nothing for this one
+            db.reset_keyset_counters().await?;
+            log("✅ Imported wallet mnemonic");
+
+            // Starting from zero would re-derive outputs the mint already signed for this seed
+            let mut mints: BTreeSet<String> = db.get_mints().await
+                .map_err(|e| JsValue::from_str(&format!("Failed to get mints: {}", e)))?
+                .into_keys()
+                .map(|mint_url| mint_url.to_string())
+                .collect();
+            let trusted_mints: Vec<String> = serde_json::from_str(&get_trusted_mints()?)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse trusted mints: {}", e)))?;
+            mints.extend(trusted_mints);
+
+            let json = restore_from_mints(&db, mints).await;
+            Ok::<String, JsValue>(json.to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Move a wallet off its nsec-derived seed: creates a mnemonic and swaps all
+/// unspent proofs at every mint for new ones derived from it.
+/// The old seed is kept so outputs it derived can still be restored.
+/// Returns a Promise that resolves to JSON {mnemonic, swept: [{mint, amount}], failed: [{mint, error}]}
+#[wasm_bindgen]
+pub fn migrate_wallet_to_mnemonic() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use std::collections::BTreeSet;
+            use cdk_common::database::WalletDatabase;
+            use cdk::nuts::State;
+
+            let db = get_or_create_wallet_db().await?;
+
+            // Pins the legacy seed if this wallet never opened since seeds were decoupled
+            wallet_seed::wallet_seed(&db).await?;
+            if wallet_seed::seed_source()? != wallet_seed::SeedSource::LegacyNsec {
+                return Err(JsValue::from_str("Wallet does not use an nsec-derived seed"));
+            }
+
+            let mnemonic = wallet_seed::generate_mnemonic()?;
+            wallet_seed::save_mnemonic(&mnemonic)?;
+            // The new seed has never been used, so its outputs start at zero (and are restorable)
+            db.reset_keyset_counters().await?;
+            log("🔑 Created wallet mnemonic, sweeping funds...");
+
+            let proofs = db.get_proofs(None, Some(CurrencyUnit::Sat), Some(vec![State::Unspent]), None)
+                .await
+                .map_err(|e| JsValue::from_str(&format!("Failed to get proofs: {}", e)))?;
+            let mints: BTreeSet<String> = proofs.iter().map(|p| p.mint_url.to_string()).collect();
+
+            #[derive(Serialize)]
+            struct Swept {
+                mint: String,
+                amount: u64,
+            }
+
+            #[derive(Serialize)]
+            struct Failed {
+                mint: String,
+                error: String,
+            }
+
+            let mut swept = Vec::new();
+            let mut failed = Vec::new();
+
+            for mint in mints {
+                let sweep = async {
+                    let wallet = create_wallet_for_mint(mint.clone()).await?;
+                    let mint_url = wallet.mint_url.clone();
+                    db.atomic(|db| async move {
+                        let wallet = wallet_on(db, mint_url).await?;
+                        let proofs = wallet.get_unspent_proofs().await
+                            .map_err(|e| JsValue::from_str(&format!("Failed to get proofs: {}", e)))?;
+                        wallet.swap(None, SplitTarget::default(), proofs, None, false).await
+                            .map_err(|e| JsValue::from_str(&format!("Failed to swap: {}", e)))
+                    }).await?;
+
+                    let balance = wallet.total_balance().await
+                        .map_err(|e| JsValue::from_str(&format!("Failed to get balance: {}", e)))?;
+                    Ok::<u64, JsValue>(u64::from(balance))
+                }
+                .await;
+
+                match sweep {
+                    Ok(amount) => {
+                        log(&format!("✅ Swept {} sats at {}", amount, mint));
+                        swept.push(Swept { mint, amount });
+                    }
+                    Err(e) => {
+                        let error = e.as_string().unwrap_or_else(|| format!("{:?}", e));
+                        log(&format!("⚠️ Could not sweep {}: {}", mint, error));
+                        failed.push(Failed { mint, error });
+                    }
+                }
+            }
+
+            let json = serde_json::json!({
+                "mnemonic": mnemonic.to_string(),
+                "swept": swept,
+                "failed": failed,
+            });
+
+            Ok::<String, JsValue>(json.to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Run NUT-13 restore against each mint in turn, collecting per-mint results.
+/// Restoring also advances the keyset counters past every output the mint has signed.
+async fn restore_from_mints(
+    db: &HybridWalletDatabase,
+    mint_urls: impl IntoIterator<Item = String>,
+) -> serde_json::Value {
+    #[derive(Serialize)]
+    struct Restored {
+        mint: String,
+        amount: u64,
+    }
+
+    #[derive(Serialize)]
+    struct Failed {
+        mint: String,
+        error: String,
+    }
+
+    let mut restored = Vec::new();
+    let mut failed = Vec::new();
+
+    for mint in mint_urls {
+        log(&format!("🔄 Restoring wallet from {}...", mint));
+
+        let restore = async {
+            let mint_url = MintUrl::from_str(&mint)
+                .map_err(|e| JsValue::from_str(&format!("Invalid mint URL: {}", e)))?;
+            let amount = db.atomic(|db| async move {
+                wallet_on(db, mint_url).await?.restore().await
+                    .map_err(|e| JsValue::from_str(&format!("Failed to restore: {}", e)))
+            }).await?;
+            Ok::<u64, JsValue>(u64::from(amount))
+        }
+        .await;
+
+        match restore {
+            Ok(amount) => {
+                log(&format!("✅ Restored {} sats from {}", amount, mint));
+                restored.push(Restored { mint, amount });
+            }
+            Err(e) => {
+                let error = e.as_string().unwrap_or_else(|| format!("{:?}", e));
+                log(&format!("⚠️ Could not restore from {}: {}", mint, error));
+                failed.push(Failed { mint, error });
+            }
+        }
+    }
+
+    serde_json::json!({
+        "restored": restored,
+        "failed": failed,
+    })
+}
+
+/// Recover funds from the wallet seed (NUT-13)
+/// For each mint, re-derives the blinded outputs of every keyset, asks the mint
+/// which ones it signed, checks their state and stores the unspent proofs.
+/// Keyset counters are advanced past everything found.
+/// Takes a JSON array of mint URLs.
+/// Returns a Promise that resolves to JSON {restored: [{mint, amount}], failed: [{mint, error}]}
+#[wasm_bindgen]
+pub fn restore_wallet(mint_urls_json: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let mint_urls: Vec<String> = serde_json::from_str(&mint_urls_json)
+                .map_err(|e| JsValue::from_str(&format!("Invalid mint URLs: {}", e)))?;
+
+            let db = get_or_create_wallet_db().await?;
+            let json = restore_from_mints(&db, mint_urls).await;
+
+            Ok::<String, JsValue>(json.to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+// ============================================================================
+// Backup
+// ============================================================================
+
+/// Export identity, groups, wallet, trusted mints and relays as one encrypted file
+/// Returns a Promise that resolves to the backup (a JSON string to save as a file)
+#[wasm_bindgen]
+pub fn export_backup(passphrase: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            log("📦 Creating backup...");
+            let storage = get_local_storage()?;
+
+            let identity = backup::Identity {
+                signer_type: signer::signer_type()?,
+                secret_key: read_secret_hex(&storage)?,
+                signer_pubkey: storage.get_item(signer::SIGNER_PUBKEY_KEY)?,
+                nip46_session: signer::load_nip46_session()?,
+            };
+
+            // Persist pending MDK changes so the backup matches what is on disk
+            let mdk_storage = get_or_create_storage().await?;
+            mdk_storage.inner().save_snapshot().await?;
+            let mdk = mdk_storage.inner().export_backup()?;
+
+            // Make sure the wallet has a seed to back up
+            let db = get_or_create_wallet_db().await?;
+            wallet_seed::wallet_seed(&db).await?;
+            let wallet = db.export_backup().await?;
+
+            let trusted_mints: Vec<String> = serde_json::from_str(&get_trusted_mints()?)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse trusted mints: {}", e)))?;
+
+            let payload = backup::BackupPayload::new(
+                identity,
+                wallet_seed::stored_seeds()?,
+                mdk,
+                wallet,
+                trusted_mints,
+                get_relays_internal()?,
+            );
+            let blob = backup::seal(&payload, &passphrase)?;
+
+            log("✅ Backup created");
+            Ok::<String, JsValue>(blob)
+        }
+        .await;
+
+        result.map(|blob| JsValue::from_str(&blob))
+    })
+}
+
+/// Restore a backup from export_backup, replacing the current identity and groups.
+/// Wallet proofs are merged into the existing wallet (nothing is lost) and the
+/// wallet seed, trusted mints and relays are taken from the backup.
+/// A different wallet seed is only replaced if `replace` is true (export it first).
+/// Returns a Promise that resolves to JSON describing what was restored:
+/// {npub, signer_type, created_at, groups, messages, proofs, wallet_seed, trusted_mints, relays}
+#[wasm_bindgen]
+pub fn import_backup(blob: String, passphrase: String, replace: bool) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            vault::ensure_unlocked()?;
+
+            // Decrypts, checks every version and parses every section before anything is written
+            let backup::OpenedBackup { payload, mdk, wallet } = backup::open(&blob, &passphrase)?;
+            payload.wallet_seed.check_replaces(replace)?;
+            let npub = payload.public_key()?.to_bech32().expect("bech32 encoding is infallible");
+            log(&format!("📦 Restoring backup of {}...", npub));
+
+            // Identity (the old identity's MDK state goes with it)
+            clear_storage_cache().await;
+            mdk_storage::delete_persisted_state()?;
+
+            let storage = get_local_storage()?;
+            let identity = &payload.identity;
+            forget_external_signer()?;
+            match &identity.secret_key {
+                Some(secret_hex) => storage.set_item("nostr_secret_key", &vault::seal(secret_hex)?)?,
+                None => storage.remove_item("nostr_secret_key")?,
+            }
+            if identity.signer_type != signer::SIGNER_TYPE_LOCAL {
+                storage.set_item(signer::SIGNER_TYPE_KEY, &identity.signer_type)?;
+            }
+            if let Some(pubkey) = &identity.signer_pubkey {
+                storage.set_item(signer::SIGNER_PUBKEY_KEY, pubkey)?;
+            }
+            if let Some(session) = &identity.nip46_session {
+                signer::save_nip46_session(session)?;
+            }
+
+            // Groups
+            let (groups, messages) = MdkHybridStorage::import_backup(mdk).await?;
+
+            // Wallet
+            wallet_seed::restore_seeds(&payload.wallet_seed, replace)?;
+            let proofs = get_or_create_wallet_db().await?.import_backup(wallet).await?;
+
+            // Configuration: trusted mints are merged, relays replaced
+            let mut trusted_mints: Vec<String> = serde_json::from_str(&get_trusted_mints()?)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse trusted mints: {}", e)))?;
+            for mint in payload.trusted_mints {
+                if !trusted_mints.contains(&mint) {
+                    trusted_mints.push(mint);
+                }
+            }
+            let mints_json = serde_json::to_string(&trusted_mints)
+                .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))?;
+            storage.set_item("trusted_mints", &mints_json)?;
+
+            let relays_json = serde_json::to_string(&payload.relays)
+                .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))?;
+            storage.set_item("nostr_relays", &relays_json)?;
+            relay_pool::sync().await?;
+
+            log(&format!(
+                "✅ Restored backup: {} group(s), {} message(s), {} proof(s)",
+                groups, messages, proofs
+            ));
+
+            let report = serde_json::json!({
+                "npub": npub,
+                "signer_type": identity.signer_type,
+                "created_at": payload.created_at,
+                "groups": groups,
+                "messages": messages,
+                "proofs": proofs,
+                "wallet_seed": wallet_seed::seed_source()?.as_str(),
+                "trusted_mints": trusted_mints.len(),
+                "relays": payload.relays.len(),
+            });
+
+            Ok::<String, JsValue>(report.to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// List stored records that couldn't be read and were set aside instead of loaded
+/// (e.g. written by a newer app version or corrupted). They are kept, not deleted.
+/// Returns a Promise that resolves to a JSON array of
+/// {database, store, key, error, quarantined_at}
+#[wasm_bindgen]
+pub fn get_quarantine() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            // Loading runs the checks that quarantine unreadable records
+            get_or_create_storage().await?;
+            get_or_create_wallet_db().await?;
+
+            let mut entries = mdk_storage::quarantined().await?;
+            entries.extend(wallet_db::quarantined().await?);
+            entries.extend(schema::quarantined_local(&get_local_storage()?)?);
+            entries.sort_by(|a, b| a.quarantined_at.total_cmp(&b.quarantined_at));
+
+            serde_json::to_string(&entries)
+                .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+// ============================================================================
+// Trusted Mints Management
+// ============================================================================
+
+/// Get list of trusted mint URLs
+/// Returns JSON array of strings
+#[wasm_bindgen]
+pub fn get_trusted_mints() -> Result<String, JsValue> {
+    let storage = get_local_storage()?;
+
+    let mints_json = storage
+        .get_item("trusted_mints")?
+        .unwrap_or_else(|| "[]".to_string());
+
+    Ok(mints_json)
+}
+
+/// Add a mint to the trusted list
+/// Validates the mint by connecting and fetching keysets
+/// Returns a Promise that resolves to true if added, false if already in list
+#[wasm_bindgen]
+pub fn add_trusted_mint(mint_url: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            log(&format!("🔍 Validating mint: {}", mint_url));
+
+            // Validate mint URL format
+            let _mint_url_parsed = MintUrl::from_str(&mint_url)
+                .map_err(|e| {
+                    log(&format!("❌ Invalid URL format: {}", e));
+                    JsValue::from_str(&format!("Invalid mint URL: {}", e))
+                })?;
+
+            log("📡 Creating wallet and connecting to mint...");
 
             // Try to connect to the mint and fetch its info
             // This requires actual network communication with the mint
@@ -718,42 +1693,15 @@ pub fn add_relay(url: String) -> js_sys::Promise {
         let relay_url = RelayUrl::parse(&url)
             .map_err(|e| JsValue::from_str(&format!("Invalid relay URL: {}", e)))?;
 
-        // Test connection (simple validation - timeout not supported in WASM)
+        // Test the connection through the live pool
         log(&format!("Testing connection to {}...", url));
-        let client = Client::default();
-        client.add_relay(relay_url.clone()).await
-            .map_err(|e| JsValue::from_str(&format!("Failed to add relay: {}", e)))?;
+        if !relay_pool::probe(&relay_url, Duration::from_secs(2)).await? {
+            return Err(JsValue::from_str(&format!("Failed to connect to relay: {}", url)));
+        }
+        log(&format!("✓ Successfully connected to {}", url));
 
-        client.connect().await;
-
-        // Give it a moment to connect, then check status
-        wasm_bindgen_futures::JsFuture::from(js_sys::Promise::new(&mut |resolve, _| {
-            web_sys::window()
-                .unwrap()
-                .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, 2000)
-                .unwrap();
-        })).await.ok();
-
-        // Check if relay is actually connected
-        match client.relay(relay_url.clone()).await {
-            Ok(relay) => {
-                if relay.is_connected() {
-                    log(&format!("✓ Successfully connected to {}", url));
-                } else {
-                    let _ = client.disconnect().await;
-                    return Err(JsValue::from_str(&format!("Failed to connect to relay: {}", url)));
-                }
-            }
-            Err(e) => {
-                let _ = client.disconnect().await;
-                return Err(JsValue::from_str(&format!("Relay error: {}", e)));
-            }
-        }
-
-        let _ = client.disconnect().await;
-
-        // Add to list
-        let mut relays = get_relays_internal()?;
+        // Add to list
+        let mut relays = get_relays_internal()?;
 
         if relays.contains(&url) {
             return Err(JsValue::from_str("Relay already in list"));
@@ -765,6 +1713,7 @@ pub fn add_relay(url: String) -> js_sys::Promise {
         let json = serde_json::to_string(&relays)
             .map_err(|e| JsValue::from_str(&format!("Failed to serialize relays: {}", e)))?;
         storage.set_item("nostr_relays", &json)?;
+        relay_pool::sync().await?;
 
         log(&format!("✅ Added relay: {}", url));
         Ok(JsValue::from_str("success"))
@@ -793,13 +1742,69 @@ pub fn remove_relay(url: String) -> js_sys::Promise {
         let json = serde_json::to_string(&relays)
             .map_err(|e| JsValue::from_str(&format!("Failed to serialize relays: {}", e)))?;
         storage.set_item("nostr_relays", &json)?;
+        relay_pool::sync().await?;
 
         log(&format!("✅ Removed relay: {}", url));
         Ok(JsValue::from_str("success"))
     })
 }
 
-/// Initialize a real CDK wallet with hybrid in-memory + localStorage storage
+/// Get the connection state of each relay in the shared pool
+/// Returns a Promise that resolves to a JSON array of
+/// {url, status, connected, attempts, successes, connected_at}
+#[wasm_bindgen]
+pub fn get_relay_health() -> js_sys::Promise {
+    future_to_promise(async move {
+        let health = relay_pool::health().await;
+        let json = serde_json::to_string(&health)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize relay health: {}", e)))?;
+        Ok(JsValue::from_str(&json))
+    })
+}
+
+/// Get the outbox: signed messages, commits and Welcomes with their delivery state
+/// Returns a Promise that resolves to a JSON array (newest first) of
+/// {event_id, purpose, group_id, created_at, status, relays: {url: {status, error, attempts, last_attempt_at}}, next_retry_at}
+/// where status is "pending", "sent" or "failed"
+#[wasm_bindgen]
+pub fn get_outbox() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let items: Vec<serde_json::Value> = outbox::entries().await?
+                .iter()
+                .rev()
+                .map(|entry| serde_json::json!({
+                    "event_id": entry.event_id,
+                    "purpose": entry.purpose,
+                    "group_id": entry.group_id,
+                    "created_at": entry.created_at,
+                    "status": entry.status(),
+                    "relays": entry.relays,
+                    "next_retry_at": entry.next_retry_at,
+                }))
+                .collect();
+
+            serde_json::to_string(&items)
+                .map_err(|e| JsValue::from_str(&format!("Failed to serialize outbox: {}", e)))
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Retry every pending outbox event now instead of waiting for its backoff
+/// Returns a Promise that resolves to the number of events retried
+#[wasm_bindgen]
+pub fn retry_outbox() -> js_sys::Promise {
+    future_to_promise(async move {
+        let client = relay_pool::client().await?;
+        let retried = outbox::retry(&client, true).await?;
+        Ok(JsValue::from(retried as u32))
+    })
+}
+
+/// Initialize a real CDK wallet backed by the IndexedDB wallet database
 /// Returns a Promise that resolves to the initial balance
 #[wasm_bindgen]
 pub fn init_wallet() -> js_sys::Promise {
@@ -828,7 +1833,7 @@ pub fn init_wallet() -> js_sys::Promise {
     })
 }
 
-/// Get wallet balance (recreates wallet from localStorage each time)
+/// Get wallet balance (recreates wallet from the stored database each time)
 /// Returns a Promise that resolves to the current balance
 #[wasm_bindgen]
 pub fn get_balance() -> js_sys::Promise {
@@ -836,7 +1841,7 @@ pub fn get_balance() -> js_sys::Promise {
         let result = async {
             log("Fetching balance from wallet...");
 
-            // Create wallet (loads from localStorage)
+            // Create wallet (reads proofs from IndexedDB)
             let wallet = create_wallet().await?;
 
             // Get balance
@@ -977,6 +1982,7 @@ pub fn get_all_mint_balances() -> js_sys::Promise {
 
 /// Parse token information without receiving it
 /// Returns a Promise that resolves to JSON with token info including trust status
+/// P2PK tokens with a locktime also report `locktime`, `lock_expired`, `refund_keys` (hex) and `refund_npubs`
 #[wasm_bindgen]
 pub fn parse_token_info(token_str: String) -> js_sys::Promise {
     future_to_promise(async move {
@@ -1044,27 +2050,53 @@ pub fn parse_token_info(token_str: String) -> js_sys::Promise {
                 }
             }
 
+            // Y values identify the proofs at the mint (NUT-07), e.g. to see if they were claimed
+            let ys: Vec<String> = token.proofs(&[])
+                .map(|proofs| proofs.iter().filter_map(|proof| proof.y().ok()).map(|y| y.to_hex()).collect())
+                .unwrap_or_default();
+
             // Create JSON response
             #[derive(Serialize)]
             struct TokenInfo {
                 amount: u64,
                 mint: String,
                 is_trusted: bool,
+                ys: Vec<String>,
                 #[serde(skip_serializing_if = "Option::is_none")]
                 secret_kind: Option<String>,
                 #[serde(skip_serializing_if = "Option::is_none")]
                 secret_data: Option<String>,
                 #[serde(skip_serializing_if = "Option::is_none")]
                 secret_npub: Option<String>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                locktime: Option<u64>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                lock_expired: Option<bool>,
+                #[serde(skip_serializing_if = "Vec::is_empty")]
+                refund_keys: Vec<String>,
+                #[serde(skip_serializing_if = "Vec::is_empty")]
+                refund_npubs: Vec<String>,
             }
 
+            // NUT-11 locktime and refund keys of a P2PK lock
+            let lock = token.proofs(&[]).ok()
+                .and_then(|proofs| proofs.first().and_then(p2pk::Lock::of));
+            let locktime = lock.as_ref().and_then(|lock| lock.locktime);
+            let lock_expired = lock.as_ref().filter(|lock| lock.locktime.is_some()).map(|lock| lock.expired());
+            let refund_keys = lock.map(|lock| lock.refund_keys).unwrap_or_default();
+
             let info = TokenInfo {
                 amount: u64::from(amount),
                 mint: mint_str,
                 is_trusted,
+                ys,
                 secret_kind,
                 secret_data,
                 secret_npub,
+                locktime,
+                lock_expired,
+                refund_npubs: refund_keys.iter().filter_map(p2pk::npub).collect(),
+                refund_keys: refund_keys.iter().map(|key| key.to_hex()).collect(),
             };
 
             let json = serde_json::to_string(&info)
@@ -1084,30 +2116,8 @@ pub fn parse_token_info(token_str: String) -> js_sys::Promise {
 pub fn send_ecash(amount: u64) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
-            use cdk::wallet::SendOptions;
-
-            log(&format!("Creating token for {} sats", amount));
-
-            // Create wallet (uses current mint)
-            let wallet = create_wallet().await?;
-
-            // Prepare send
-            let prepared = wallet
-                .prepare_send(cdk::Amount::from(amount), SendOptions::default())
-                .await
-                .map_err(|e| JsValue::from_str(&format!("Failed to prepare send: {}", e)))?;
-
-            // Confirm and create token
-            let token = prepared
-                .confirm(None)
-                .await
-                .map_err(|e| JsValue::from_str(&format!("Failed to create token: {}", e)))?;
-
-            let token_str = token.to_string();
-
-            log(&format!("✅ Created token: {} sats", amount));
-
-            Ok::<String, JsValue>(token_str)
+            let token = create_token(amount).await?;
+            Ok::<String, JsValue>(token.to_string())
         }
         .await;
 
@@ -1115,67 +2125,84 @@ pub fn send_ecash(amount: u64) -> js_sys::Promise {
     })
 }
 
+/// Take `amount` out of the wallet (current mint) as a token
+async fn create_token(amount: u64) -> Result<Token, JsValue> {
+    use cdk::wallet::SendOptions;
+
+    log(&format!("Creating token for {} sats", amount));
+
+    // Create wallet (uses current mint)
+    let wallet = create_wallet().await?;
+    let db = get_or_create_wallet_db().await?;
+    let keysets = wallet.get_mint_keysets().await
+        .map_err(|e| JsValue::from_str(&format!("Failed to get keysets: {}", e)))?;
+
+    // Reserving the proofs, spending them and keeping them for a reclaim is persisted as one unit
+    let token = db.atomic(|db| async move {
+        let wallet = wallet_on(db.clone(), wallet.mint_url.clone()).await?;
+
+        // Prepare send
+        let prepared = wallet
+            .prepare_send(cdk::Amount::from(amount), SendOptions::default())
+            .await
+            .map_err(|e| JsValue::from_str(&format!("Failed to prepare send: {}", e)))?;
+
+        // Confirm and create token
+        let token = prepared
+            .confirm(None)
+            .await
+            .map_err(|e| JsValue::from_str(&format!("Failed to create token: {}", e)))?;
+
+        let proofs = token.proofs(&keysets)
+            .map_err(|e| JsValue::from_str(&format!("Failed to read token proofs: {}", e)))?;
+        db.put_sent_token(sent_tokens::sent_token(&token, proofs, None)?).await?;
+        Ok(token)
+    }).await?;
+
+    log(&format!("✅ Created token: {} sats", amount));
+    Ok(token)
+}
+
 /// Send ecash with P2PK - creates a token locked to recipient's public key
+/// With a `locktime` (unix seconds) the token can also be spent by the refund keys once it
+/// passed (NUT-11): `refund_npubs` is a JSON array of npubs, our own key if omitted.
 /// Returns the token string
 #[wasm_bindgen]
-pub fn send_ecash_p2pk(amount: u64, recipient_npub: String) -> js_sys::Promise {
+pub fn send_ecash_p2pk(amount: u64, recipient_npub: String, locktime: Option<u32>, refund_npubs: Option<String>) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
-            use cdk::nuts::SpendingConditions;
-
             log(&format!("Creating P2PK token for {} sats to {}", amount, &recipient_npub[..16]));
 
             // Parse recipient npub to get public key
             let recipient_pubkey = nostr::PublicKey::from_bech32(&recipient_npub)
                 .map_err(|e| JsValue::from_str(&format!("Invalid npub: {}", e)))?;
+            let p2pk_pubkey = p2pk::pubkey_from_nostr(&recipient_pubkey)?;
 
-            // Convert Nostr pubkey (32 bytes) to secp256k1 compressed format (33 bytes)
-            // Nostr uses x-only pubkeys, we need to convert to full secp256k1 pubkey
-            let pubkey_bytes = recipient_pubkey.to_bytes();
-
-            // Create secp256k1 XOnlyPublicKey from the 32-byte Nostr pubkey
-            use nostr::secp256k1::XOnlyPublicKey;
-            let x_only = XOnlyPublicKey::from_slice(&pubkey_bytes)
-                .map_err(|e| JsValue::from_str(&format!("Failed to parse x-only pubkey: {}", e)))?;
-
-            // Convert to full public key (assumes even parity, which is standard for Nostr)
-            let full_pubkey = nostr::secp256k1::PublicKey::from_x_only_public_key(x_only, nostr::secp256k1::Parity::Even);
-
-            // Serialize to compressed format (33 bytes)
-            let compressed_bytes = full_pubkey.serialize();
-
-            // Convert to CDK PublicKey
-            let p2pk_pubkey = cdk::nuts::PublicKey::from_slice(&compressed_bytes)
-                .map_err(|e| JsValue::from_str(&format!("Failed to convert pubkey: {}", e)))?;
+            let locktime = locktime.map(u64::from);
+            if locktime.is_some_and(|locktime| locktime <= nostr::Timestamp::now().as_u64()) {
+                return Err(JsValue::from_str("The locktime must be in the future"));
+            }
+            let refund_keys = match refund_npubs {
+                Some(json) => {
+                    let npubs: Vec<String> = serde_json::from_str(&json)
+                        .map_err(|e| JsValue::from_str(&format!("Invalid refund npubs: {}", e)))?;
+                    npubs.iter()
+                        .map(|npub| {
+                            let pubkey = nostr::PublicKey::from_bech32(npub)
+                                .map_err(|e| JsValue::from_str(&format!("Invalid refund npub {}: {}", npub, e)))?;
+                            p2pk::pubkey_from_nostr(&pubkey)
+                        })
+                        .collect::<Result<Vec<_>, JsValue>>()?
+                }
+                // Refunds go back to us by default
+                None if locktime.is_some() => vec![p2pk_signing_key()?.public_key()],
+                None => Vec::new(),
+            };
 
             // Create P2PK spending conditions
-            let spending_conditions = SpendingConditions::new_p2pk(p2pk_pubkey, None);
-
-            // Create wallet (uses current mint)
-            let wallet = create_wallet().await?;
-
-            // For P2PK, we must swap ALL proofs to apply the spending conditions
-            // Using prepare_send doesn't work because it may send proofs directly without swapping
-            // So we use swap_from_unspent directly
-            log("Swapping from unspent with P2PK conditions...");
-            let proofs = wallet
-                .swap_from_unspent(
-                    cdk::Amount::from(amount),
-                    Some(spending_conditions),
-                    false,  // include_fees
-                )
-                .await
-                .map_err(|e| JsValue::from_str(&format!("Failed to swap with P2PK: {}", e)))?;
-
-            // Create token from the swapped proofs
-            use cdk::nuts::Token;
-            let token = Token::new(
-                wallet.mint_url.clone(),
-                proofs,
-                None,  // memo
-                wallet.unit.clone(),
-            );
+            let spending_conditions = p2pk::conditions(p2pk_pubkey, locktime, refund_keys)?;
 
+            let token = create_locked_token(amount, spending_conditions, recipient_npub.clone()).await?;
             let token_str = token.to_string();
 
             // Verify the token has P2PK by checking the first proof's secret
@@ -1199,6 +2226,41 @@ pub fn send_ecash_p2pk(amount: u64, recipient_npub: String) -> js_sys::Promise {
     })
 }
 
+/// Take `amount` out of the wallet (current mint) as a token spendable under `spending_conditions`
+/// `locked_to` says who can spend it, for the sent tokens list.
+async fn create_locked_token(amount: u64, spending_conditions: cdk::nuts::SpendingConditions, locked_to: String) -> Result<Token, JsValue> {
+    // Create wallet (uses current mint)
+    let wallet = create_wallet().await?;
+    let db = get_or_create_wallet_db().await?;
+
+    // For P2PK, we must swap ALL proofs to apply the spending conditions
+    // Using prepare_send doesn't work because it may send proofs directly without swapping
+    // So we use swap_from_unspent directly
+    log("Swapping from unspent with P2PK conditions...");
+    db.atomic(|db| async move {
+        let wallet = wallet_on(db.clone(), wallet.mint_url.clone()).await?;
+        let proofs = wallet
+            .swap_from_unspent(
+                cdk::Amount::from(amount),
+                Some(spending_conditions),
+                false,  // include_fees
+            )
+            .await
+            .map_err(|e| JsValue::from_str(&format!("Failed to swap with P2PK: {}", e)))?;
+
+        // Create token from the swapped proofs
+        let token = Token::new(
+            wallet.mint_url.clone(),
+            proofs.clone(),
+            None,  // memo
+            wallet.unit.clone(),
+        );
+
+        db.put_sent_token(sent_tokens::sent_token(&token, proofs, Some(locked_to))?).await?;
+        Ok(token)
+    }).await
+}
+
 /// Receive ecash token
 /// Returns a Promise that resolves to the amount received
 /// Creates a wallet for the token's mint (not the current mint)
@@ -1207,55 +2269,191 @@ pub fn send_ecash_p2pk(amount: u64, recipient_npub: String) -> js_sys::Promise {
 pub fn receive_token(token_str: String) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
-            log(&format!("Receiving token: {}", &token_str[..20.min(token_str.len())]));
+            let amount = redeem_token(&token_str).await?;
+            Ok::<u64, JsValue>(amount)
+        }
+        .await;
 
-            // Parse token to get its mint URL
-            let token = Token::from_str(&token_str)
-                .map_err(|e| JsValue::from_str(&format!("Invalid token: {}", e)))?;
+        result.map(|amount| JsValue::from_f64(amount as f64))
+    })
+}
 
-            let token_mint_url = token.mint_url()
-                .map_err(|e| JsValue::from_str(&format!("Failed to get mint URL: {}", e)))?;
+/// Key for signing P2PK-locked proofs: our Nostr key, in CDK format
+/// P2PK needs the raw key, so this fails when signing with an extension or bunker
+/// (the local key left in localStorage then belongs to a previous identity).
+fn p2pk_signing_key() -> Result<cdk::nuts::SecretKey, JsValue> {
+    let pubkey = get_public_key()?;
+    let not_held = JsValue::from_str(&format!(
+        "P2PK ecash needs the secret key of {}, which is held by the {} signer. Import its nsec to use P2PK.",
+        pubkey.to_bech32().expect("bech32 encoding is infallible"),
+        signer::signer_type()?,
+    ));
 
-            log(&format!("Token is from mint: {}", token_mint_url));
+    let storage = get_local_storage()?;
+    let Some(secret_hex) = read_secret_hex(&storage)? else {
+        return Err(not_held);
+    };
+
+    // Parse secret key from hex
+    let secret_key = SecretKey::from_str(&secret_hex)
+        .map_err(|e| JsValue::from_str(&format!("Invalid secret key: {}", e)))?;
+    if Keys::new(secret_key.clone()).public_key() != pubkey {
+        return Err(not_held);
+    }
 
-            // Get Nostr keys for P2PK signing (if token is P2PK-locked)
-            let storage = window().unwrap().local_storage().unwrap().unwrap();
-            let secret_hex = storage.get_item("nostr_secret_key").unwrap()
-                .ok_or_else(|| JsValue::from_str("No Nostr key found. Please generate keys first."))?;
+    // Convert Nostr secret key bytes to CDK format
+    let secret_key_bytes = secret_key.as_secret_bytes();
+    cdk::nuts::SecretKey::from_slice(secret_key_bytes)
+        .map_err(|e| JsValue::from_str(&format!("Failed to convert secret key: {}", e)))
+}
 
-            // Parse secret key from hex
-            let secret_key = SecretKey::from_str(&secret_hex)
-                .map_err(|e| JsValue::from_str(&format!("Invalid secret key: {}", e)))?;
+/// Redeem a token into the wallet of its mint, signing P2PK locks with our Nostr key
+/// (also as a refund key, once a lock's locktime passed).
+/// Redeeming a token we sent ourselves reclaims it.
+/// Returns the amount received.
+async fn redeem_token(token_str: &str) -> Result<u64, JsValue> {
+    log(&format!("Receiving token: {}", &token_str[..20.min(token_str.len())]));
 
-            // Convert Nostr secret key bytes to CDK format
-            let secret_key_bytes = secret_key.as_secret_bytes();
-            let cdk_secret_key = cdk::nuts::SecretKey::from_slice(secret_key_bytes)
-                .map_err(|e| JsValue::from_str(&format!("Failed to convert secret key: {}", e)))?;
+    // Parse token to get its mint URL
+    let token = Token::from_str(token_str)
+        .map_err(|e| JsValue::from_str(&format!("Invalid token: {}", e)))?;
 
-            // Create wallet for the TOKEN'S mint (not current mint)
-            let wallet = create_wallet_for_mint(token_mint_url.to_string()).await?;
+    let token_mint_url = token.mint_url()
+        .map_err(|e| JsValue::from_str(&format!("Failed to get mint URL: {}", e)))?;
 
-            // Receive the token with P2PK signing key
-            let receive_options = ReceiveOptions {
-                p2pk_signing_keys: vec![cdk_secret_key],
-                ..Default::default()
-            };
+    log(&format!("Token is from mint: {}", token_mint_url));
 
-            let amount = wallet
-                .receive(&token_str, receive_options)
-                .await
-                .map_err(|e| JsValue::from_str(&format!("Failed to receive token: {}", e)))?;
+    // Create wallet for the TOKEN'S mint (not current mint)
+    let wallet = create_wallet_for_mint(token_mint_url.to_string()).await?;
 
-            log(&format!("✅ Received {} sats!", amount));
+    let keysets = wallet.get_mint_keysets().await
+        .map_err(|e| JsValue::from_str(&format!("Failed to get keysets: {}", e)))?;
+    let mut refund_proofs = token.proofs(&keysets)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get proofs: {}", e)))?;
 
-            Ok::<u64, JsValue>(u64::from(amount))
+    // Get Nostr keys for P2PK signing (only if the token is P2PK-locked, so
+    // plain tokens can be received with an extension or bunker signer too)
+    let p2pk_signing_keys = if refund_proofs.iter().any(|proof| p2pk::Lock::of(proof).is_some()) {
+        vec![p2pk_signing_key()?]
+    } else {
+        Vec::new()
+    };
+
+    // CDK doesn't sign as a refund key, so expired locks refunding to us are signed here
+    let mut refunds = 0;
+    for key in &p2pk_signing_keys {
+        refunds += p2pk::sign_refunds(&mut refund_proofs, key)?;
+    }
+    if refunds > 0 {
+        log(&format!("🔓 Signed {} proof(s) as refund key, their lock expired", refunds));
+    }
+
+    // Receive the token with P2PK signing key
+    let receive_options = ReceiveOptions {
+        p2pk_signing_keys,
+        ..Default::default()
+    };
+
+    let db = get_or_create_wallet_db().await?;
+    let sent = sent_tokens::find_pending(&db, token_str).await?;
+    let amount = db.atomic(|db| async move {
+        let wallet = wallet_on(db.clone(), wallet.mint_url.clone()).await?;
+        let received = if refunds > 0 {
+            wallet.receive_proofs(refund_proofs, receive_options, token.memo().clone()).await
+        } else {
+            wallet.receive(token_str, receive_options).await
+        };
+        let amount = received
+            .map_err(|e| JsValue::from_str(&format!("Failed to receive token: {}", e)))?;
+
+        if let Some(mut sent) = sent {
+            sent.status = wallet_db::SentTokenStatus::Reclaimed;
+            db.put_sent_token(sent).await?;
+        }
+        Ok(amount)
+    }).await?;
+
+    log(&format!("✅ Received {} sats!", amount));
+
+    Ok(u64::from(amount))
+}
+
+/// List the tokens we sent that nobody has claimed yet (as far as we know), oldest first
+/// Returns a Promise that resolves to a JSON array of
+/// { id, token, amount, unit, mint, created_at, locked_to, group_id, message_id }
+#[wasm_bindgen]
+pub fn list_pending_sent_tokens() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let db = get_or_create_wallet_db().await?;
+            let pending: Vec<serde_json::Value> = db.sent_tokens().await?
+                .into_iter()
+                .filter(|sent| sent.status == wallet_db::SentTokenStatus::Pending)
+                .map(|sent| serde_json::json!({
+                    "id": sent.id,
+                    "token": sent.token,
+                    "amount": sent.amount,
+                    "unit": sent.unit.to_string(),
+                    "mint": sent.mint_url.to_string(),
+                    "created_at": sent.created_at,
+                    "locked_to": sent.locked_to,
+                    "group_id": sent.group_id,
+                    "message_id": sent.message_id,
+                }))
+                .collect();
+
+            Ok::<String, JsValue>(serde_json::Value::from(pending).to_string())
         }
         .await;
 
-        result.map(|amount| JsValue::from_f64(amount as f64))
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Take back the unclaimed part of a token we sent (see list_pending_sent_tokens)
+/// If it was posted to a group, the members see it as reclaimed by the sender.
+/// Returns a Promise that resolves to JSON { amount }
+#[wasm_bindgen]
+pub fn reclaim_token(id: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = sent_tokens::reclaim(&id).await;
+
+        result.map(|amount| JsValue::from_str(&serde_json::json!({ "amount": amount }).to_string()))
+    })
+}
+
+/// Ask the mints which sent tokens were claimed, and reclaim the ones older than
+/// the auto-reclaim timeout (see set_auto_reclaim_timeout)
+/// Returns a Promise that resolves to JSON { claimed: [id], reclaimed: { id: amount } }
+#[wasm_bindgen]
+pub fn check_sent_tokens() -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let checked = sent_tokens::check().await?;
+            serde_json::to_string(&checked)
+                .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
     })
 }
 
+/// Reclaim sent tokens nobody claimed within `secs` seconds (pass null to turn it off)
+#[wasm_bindgen]
+pub fn set_auto_reclaim_timeout(secs: Option<u32>) -> Result<(), JsValue> {
+    if secs == Some(0) {
+        return Err(JsValue::from_str("The timeout must be at least 1 second"));
+    }
+    sent_tokens::set_auto_reclaim_timeout(secs.map(u64::from))
+}
+
+/// The auto-reclaim timeout in seconds, or null when it is off
+#[wasm_bindgen]
+pub fn get_auto_reclaim_timeout() -> Result<Option<u32>, JsValue> {
+    Ok(sent_tokens::auto_reclaim_timeout()?.map(|secs| secs.min(u32::MAX as u64) as u32))
+}
+
 /// Decode a Lightning invoice to extract amount, description, and fee
 /// Returns JSON with: { amount_msat, description, fee_sats }
 #[wasm_bindgen]
@@ -1310,12 +2508,17 @@ pub fn pay_lightning_invoice_with_quote(quote_id: String) -> js_sys::Promise {
             // Create wallet for current mint
             let wallet = create_wallet().await?;
 
+            let db = get_or_create_wallet_db().await?;
+
             // Pay the invoice using the quote
             log("Melting tokens to pay invoice...");
-            let melt_response = wallet
-                .melt(&quote_id)
-                .await
-                .map_err(|e| JsValue::from_str(&format!("Failed to pay invoice: {}", e)))?;
+            let quote_ref = &quote_id;
+            let melt_response = db.atomic(|db| async move {
+                wallet_on(db, wallet.mint_url.clone()).await?
+                    .melt(quote_ref)
+                    .await
+                    .map_err(|e| JsValue::from_str(&format!("Failed to pay invoice: {}", e)))
+            }).await?;
 
             let preimage = melt_response.preimage
                 .ok_or_else(|| JsValue::from_str("No preimage returned"))?;
@@ -1392,10 +2595,14 @@ pub fn check_mint_quote(mint_url: String, quote_id: String) -> js_sys::Promise {
                 log("Quote is paid! Minting tokens...");
 
                 // Mint the tokens
-                let proofs = wallet
-                    .mint(&quote_id, SplitTarget::default(), None)
-                    .await
-                    .map_err(|e| JsValue::from_str(&format!("Failed to mint tokens: {}", e)))?;
+                let db = get_or_create_wallet_db().await?;
+                let quote_ref = &quote_id;
+                let proofs = db.atomic(|db| async move {
+                    wallet_on(db, wallet.mint_url.clone()).await?
+                        .mint(quote_ref, SplitTarget::default(), None)
+                        .await
+                        .map_err(|e| JsValue::from_str(&format!("Failed to mint tokens: {}", e)))
+                }).await?;
 
                 // Calculate total amount from proofs
                 let total_amount: u64 = proofs.iter().map(|p| u64::from(p.amount)).sum();
@@ -1433,8 +2640,7 @@ pub fn get_groups() -> js_sys::Promise {
             log("Fetching groups from MDK...");
 
             // Get current user's public key
-            let keys = get_keys()?;
-            let current_user_pubkey = keys.public_key();
+            let current_user_pubkey = get_public_key()?;
 
             let mdk = create_mdk().await?;
             let groups = mdk
@@ -1498,12 +2704,12 @@ pub fn fetch_welcome_events() -> js_sys::Promise {
         let result = async {
             log("Fetching Welcome events from Nostr relays...");
 
-            // Get our keys
-            let keys = get_keys()?;
-            let pubkey = keys.public_key();
+            // Get our signer (local keys or browser extension)
+            let signer = get_signer()?;
+            let pubkey = get_public_key()?;
 
-            // Create client and connect to relays
-            let client = create_connected_client().await?;
+            // Shared relay pool
+            let client = relay_pool::client().await?;
 
             // Step 1: Get our KeyPackage event IDs that we have private keys for
             log("Finding our KeyPackage events...");
@@ -1548,9 +2754,6 @@ pub fn fetch_welcome_events() -> js_sys::Promise {
 
             log(&format!("Found {} Welcome event(s) for us (out of {} total)", events.len(), total_welcomes));
 
-            // Disconnect from relays
-            let _ = client.disconnect().await;
-
             // Process each Welcome event with MDK
             let mdk = create_mdk().await?;
             let mut processed = 0;
@@ -1600,7 +2803,7 @@ pub fn fetch_welcome_events() -> js_sys::Promise {
 
                 // Try to extract the rumor from the event
                 // Welcome events might be gift-wrapped (kind 1059) or direct (kind 444)
-                match nostr::nips::nip59::UnwrappedGift::from_gift_wrap(&keys, &event).await {
+                match nostr::nips::nip59::UnwrappedGift::from_gift_wrap(&signer, &event).await {
                     Ok(unwrapped) => {
                         // Successfully unwrapped - process the rumor
                         log("  Unwrapped gift-wrapped Welcome");
@@ -1667,9 +2870,9 @@ pub fn create_and_publish_keypackage() -> js_sys::Promise {
         let result = async {
             log("🔑 Creating and publishing KeyPackage...");
 
-            // Get keys
-            let keys = get_keys()?;
-            let pubkey = keys.public_key();
+            // Get our signer (local keys or browser extension)
+            let signer = get_signer()?;
+            let pubkey = get_public_key()?;
 
             // Get storage first so we can save it after creating KeyPackage
             let storage = get_or_create_storage().await?;
@@ -1693,14 +2896,14 @@ pub fn create_and_publish_keypackage() -> js_sys::Promise {
 
             // Explicitly save the storage to persist the KeyPackage private key
             // This must be done BEFORE publishing, so the private key is available for later Welcome processing
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save MDK storage: {:?}", e)))?;
             log("✓ KeyPackage private key saved to storage");
 
             // Build and sign event
             let event = EventBuilder::new(Kind::Custom(443), key_package_hex)
                 .tags(tags.to_vec())
-                .sign_with_keys(&keys)
+                .sign(&signer).await
                 .map_err(|e| JsValue::from_str(&format!("Failed to sign event: {}", e)))?;
 
             let kp_event_id = event.id.to_hex();
@@ -1708,7 +2911,7 @@ pub fn create_and_publish_keypackage() -> js_sys::Promise {
             log(&format!("KeyPackage event ID: {}", kp_event_id));
 
             // Connect to relays and publish
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
             log("Publishing KeyPackage to relays...");
             let send_result = client.send_event(&event).await
                 .map_err(|e| JsValue::from_str(&format!("Failed to publish: {}", e)))?;
@@ -1721,9 +2924,6 @@ pub fn create_and_publish_keypackage() -> js_sys::Promise {
                 log(&format!("  ✗ {} rejected: {}", relay_url, error));
             }
 
-            // Disconnect
-            let _ = client.disconnect().await;
-
             // Return event ID, timestamp, and relay results as JSON
             #[derive(Serialize)]
             struct RelayResult {
@@ -1783,8 +2983,8 @@ pub fn delete_keypackage(event_id: String) -> js_sys::Promise {
         let result = async {
             log(&format!("🗑️  Deleting KeyPackage: {}", event_id));
 
-            // Get keys
-            let keys = get_keys()?;
+            // Get our signer (local keys or browser extension)
+            let signer = get_signer()?;
 
             // Parse the event ID
             let event_id_obj = nostr::EventId::from_hex(&event_id)
@@ -1793,11 +2993,11 @@ pub fn delete_keypackage(event_id: String) -> js_sys::Promise {
             // Create Kind 5 (deletion) event
             let deletion_event = EventBuilder::new(Kind::EventDeletion, "KeyPackage consumed")
                 .tag(nostr::Tag::event(event_id_obj))
-                .sign_with_keys(&keys)
+                .sign(&signer).await
                 .map_err(|e| JsValue::from_str(&format!("Failed to sign deletion event: {}", e)))?;
 
             // Connect to relays and publish
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
             let send_result = client.send_event(&deletion_event).await
                 .map_err(|e| JsValue::from_str(&format!("Failed to publish deletion: {}", e)))?;
 
@@ -1806,9 +3006,6 @@ pub fn delete_keypackage(event_id: String) -> js_sys::Promise {
                 log(&format!("  ✓ {} accepted deletion", relay_url));
             }
 
-            // Disconnect
-            let _ = client.disconnect().await;
-
             Ok::<(), JsValue>(())
         }
         .await;
@@ -1826,10 +3023,9 @@ pub fn debug_fetch_welcome_events() -> js_sys::Promise {
             log("🔍 DEBUG: Fetching Welcome events addressed to us (Kind 444)...");
 
             // Get our pubkey to filter by p tag
-            let keys = get_keys()?;
-            let our_pubkey = keys.public_key();
+            let our_pubkey = get_public_key()?;
 
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             // Fetch Kind 444 events addressed to us via p tag
             let filter = nostr::Filter::new()
@@ -1876,8 +3072,6 @@ pub fn debug_fetch_welcome_events() -> js_sys::Promise {
             let json = serde_json::to_string(&debug_events)
                 .map_err(|e| JsValue::from_str(&format!("JSON serialization error: {}", e)))?;
 
-            client.disconnect().await;
-
             Ok::<String, JsValue>(json)
         }
         .await;
@@ -1886,6 +3080,91 @@ pub fn debug_fetch_welcome_events() -> js_sys::Promise {
     })
 }
 
+/// DEBUG: Create a commit promoting `member_npub` to admin without publishing it
+/// With `apply` the commit is merged here (so our next messages are from its epoch),
+/// otherwise it is forgotten: a stand-in for a competing commit that reaches the relays late.
+/// Returns the signed commit event (JSON), to publish later with debug_publish_event.
+#[wasm_bindgen]
+pub fn debug_create_detached_commit(group_id_hex: String, member_npub: String, apply: bool) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use nostr::JsonUtil;
+
+            let member_pubkey = nostr::PublicKey::from_bech32(&member_npub)
+                .map_err(|e| JsValue::from_str(&format!("Invalid npub: {}", e)))?;
+            let group_id_bytes = hex::decode(&group_id_hex)
+                .map_err(|e| JsValue::from_str(&format!("Invalid group ID: {}", e)))?;
+            let group_id = mdk_core::prelude::GroupId::from_slice(&group_id_bytes);
+
+            let mdk = create_mdk().await?;
+            let storage = get_or_create_storage().await?;
+            let before = storage.inner().snapshot_group(&group_id)
+                .ok_or_else(|| JsValue::from_str("Group not found"))?;
+
+            let result = add_admin(&mdk, &group_id, member_pubkey)?;
+
+            if apply {
+                mdk.merge_pending_commit(&group_id)
+                    .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
+            } else {
+                // Forget the pending commit, as if it had been made on another device
+                storage.inner().restore_group(&before)?;
+            }
+            storage.inner().save_snapshot().await?;
+
+            log(&format!("🧪 Detached commit {}", result.evolution_event.id.to_hex()));
+            Ok::<String, JsValue>(result.evolution_event.as_json())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// DEBUG: Re-seal a backup with one storage section ("mdk" or "wallet") replaced by garbage
+#[wasm_bindgen]
+pub fn debug_corrupt_backup_section(blob: String, passphrase: String, section: String) -> Result<String, JsValue> {
+    let mut payload = backup::decrypt(&blob, &passphrase)?;
+    let target = match section.as_str() {
+        "mdk" => &mut payload.mdk,
+        "wallet" => &mut payload.wallet,
+        other => return Err(JsValue::from_str(&format!("Unknown backup section: {}", other))),
+    };
+    target.data = serde_json::json!({ "corrupt": true });
+    backup::seal(&payload, &passphrase)
+}
+
+/// DEBUG: The REQs the relay pool keeps open, as JSON {subscription_id: [filter]}
+#[wasm_bindgen]
+pub fn debug_open_requests() -> Result<String, JsValue> {
+    let requests: std::collections::HashMap<String, Vec<Filter>> = relay_pool::requests()
+        .into_iter()
+        .map(|(id, filters)| (id.to_string(), filters))
+        .collect();
+    serde_json::to_string(&requests)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))
+}
+
+/// DEBUG: Publish a signed event (JSON) to every relay in the pool as is
+#[wasm_bindgen]
+pub fn debug_publish_event(event_json: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            use nostr::JsonUtil;
+
+            let event = nostr::Event::from_json(&event_json)
+                .map_err(|e| JsValue::from_str(&format!("Invalid event: {}", e)))?;
+            let client = relay_pool::client().await?;
+            client.send_event(&event).await
+                .map_err(|e| JsValue::from_str(&format!("Failed to publish: {}", e)))?;
+            Ok::<(), JsValue>(())
+        }
+        .await;
+
+        result.map(|_| JsValue::undefined())
+    })
+}
+
 /// Process a Welcome event by fetching it from relays and joining the group
 /// Returns JSON: { group_id, group_name, kp_event_id }
 #[wasm_bindgen]
@@ -1900,7 +3179,7 @@ pub fn process_welcome_event(welcome_event_id: String, kp_event_id: String) -> j
 
             // Fetch the Welcome event from relays
             log("  Fetching Welcome event from relays...");
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let filter = nostr::Filter::new()
                 .kind(Kind::Custom(444))
@@ -1964,14 +3243,11 @@ pub fn process_welcome_event(welcome_event_id: String, kp_event_id: String) -> j
 
             // Explicitly save after accepting Welcome (critical operation)
             let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save after accept_welcome: {:?}", e)))?;
 
             log(&format!("✅ Successfully joined group: {}", group_name));
 
-            // Disconnect
-            client.disconnect().await;
-
             // Return result as JSON
             #[derive(Serialize)]
             struct WelcomeResult {
@@ -1999,182 +3275,181 @@ pub fn process_welcome_event(welcome_event_id: String, kp_event_id: String) -> j
 
 /// Subscribe to Welcome messages (persistent subscription for passive mode)
 /// Callback receives JSON: { group_id, group_name, kp_event_id }
+/// Returns a Promise that resolves to a handle for `unsubscribe`
 #[wasm_bindgen]
 pub fn subscribe_to_welcome_messages(callback: js_sys::Function) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
             log("📡 Subscribing to Welcome messages (Kind 444) addressed to us...");
 
-            // Get our keys to filter Welcomes addressed to us
-            let keys = get_keys()?;
-            let pubkey = keys.public_key();
-
-            let client = Arc::new(create_connected_client().await?);
+            let handle = subscriptions::register_welcomes(callback);
+            if let Err(e) = update_welcome_subscription().await {
+                subscriptions::unregister(handle);
+                return Err(e);
+            }
 
-            // Subscribe to Welcomes (Kind 444) with #p tag filtering (addressed to us)
-            // No 'since' filter - get all historical Welcomes addressed to us
-            let filter = nostr::Filter::new()
-                .kind(Kind::Custom(444))
-                .pubkey(pubkey); // Filter by #p tag (addressed to us)
+            Ok::<u32, JsValue>(handle)
+        }
+        .await;
 
-            log(&format!("✓ Subscribing to Welcome messages for pubkey: {}", pubkey.to_hex()[..16].to_string()));
+        result.map(JsValue::from)
+    })
+}
 
-            // Spawn background task to listen for notifications with ordered history
-            let client_clone = client.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                log("📻 Welcome listener started with ordered history");
-
-                let result = subscribe_with_ordered_history(&client_clone, filter, move |welcome_event| {
-                    let callback_clone = callback.clone();
-                    async move {
-                        log(&format!("📩 Processing Welcome event: {}", welcome_event.id.to_hex()));
-
-                        // Extract KeyPackage reference from #e tags
-                        let kp_ref: Option<String> = welcome_event.tags.iter()
-                            .find_map(|tag| {
-                                let tag_vec = tag.clone().to_vec();
-                                if tag_vec.get(0).map(|s| s.as_str()) == Some("e") {
-                                    tag_vec.get(1).cloned()
-                                } else {
-                                    None
-                                }
-                            });
+/// Set (or drop) the Welcome filter of the shared REQ: Welcomes addressed to our pubkey
+async fn update_welcome_subscription() -> Result<(), JsValue> {
+    // Get our pubkey to filter Welcomes addressed to us
+    let pubkey = get_public_key()?;
 
-                        if kp_ref.is_none() {
-                            log("  No KeyPackage reference found, ignoring");
-                            return Ok(());
-                        }
+    // No 'since' filter - get all historical Welcomes addressed to us
+    let filter = nostr::Filter::new()
+        .kind(Kind::Custom(444))
+        .pubkey(pubkey); // Filter by #p tag (addressed to us)
 
-                        let kp_event_id = kp_ref.unwrap();
-                        log(&format!("  ✅ Welcome references KeyPackage: {}", &kp_event_id[..16.min(kp_event_id.len())]));
-
-                        // Process the Welcome
-                        match create_mdk().await {
-                            Ok(mdk) => {
-                                // Convert to UnsignedEvent
-                                let mut rumor = nostr::UnsignedEvent {
-                                    id: None,
-                                    pubkey: welcome_event.pubkey,
-                                    created_at: welcome_event.created_at,
-                                    kind: welcome_event.kind,
-                                    tags: welcome_event.tags.clone(),
-                                    content: welcome_event.content.clone(),
-                                };
-                                rumor.ensure_id();
-
-                                match mdk.process_welcome(&welcome_event.id, &rumor) {
-                                    Ok(welcome) => {
-                                        let group_id = hex::encode(welcome.mls_group_id.as_slice());
-                                        let group_name = welcome.group_name.clone();
-                                        log(&format!("  ✓ Processed Welcome! Group: {}", group_name));
-
-                                        // Check if already accepted (de-duplicate)
-                                        use mdk_storage_traits::welcomes::types::WelcomeState;
-                                        if welcome.state == WelcomeState::Accepted {
-                                            log(&format!("  ℹ️  Welcome already accepted, skipping"));
-                                            return Ok(());
-                                        }
+    let scope = std::iter::once(pubkey.to_hex()).collect();
+    subscriptions::update(subscriptions::Feed::Welcomes, scope, filter).await
+}
 
-                                        // Accept Welcome (join the group)
-                                        log("  Accepting Welcome (joining group)...");
-                                        match mdk.accept_welcome(&welcome) {
-                                            Ok(_) => {
-                                                log(&format!("✅ Successfully joined group: {}", group_name));
-
-                                                // Explicitly save after accepting Welcome
-                                                if let Ok(storage) = get_or_create_storage().await {
-                                                    if let Err(e) = storage.inner().save_snapshot() {
-                                                        log(&format!("⚠️ Failed to save after accept_welcome: {:?}", e));
-                                                    } else {
-                                                        log("  ✓ Storage saved");
-                                                    }
-                                                }
+/// Process a Welcome from the shared subscription and pass the result to the Welcome callbacks
+async fn handle_welcome_event(welcome_event: Box<nostr::Event>) -> Result<(), JsValue> {
+    log(&format!("📩 Processing Welcome event: {}", welcome_event.id.to_hex()));
 
-                                                // Call JavaScript callback with result
-                                                #[derive(Serialize)]
-                                                struct WelcomeResult {
-                                                    group_id: String,
-                                                    group_name: String,
-                                                    kp_event_id: String,
-                                                }
+    let notify = |json: String| {
+        let js_string = JsValue::from_str(&json);
+        for callback in subscriptions::welcome_callbacks() {
+            let _ = callback.call1(&JsValue::NULL, &js_string);
+        }
+    };
+
+    // Extract KeyPackage reference from #e tags
+    let kp_ref: Option<String> = welcome_event.tags.iter()
+        .find_map(|tag| {
+            let tag_vec = tag.clone().to_vec();
+            if tag_vec.get(0).map(|s| s.as_str()) == Some("e") {
+                tag_vec.get(1).cloned()
+            } else {
+                None
+            }
+        });
 
-                                                let result = WelcomeResult {
-                                                    group_id,
-                                                    group_name,
-                                                    kp_event_id,
-                                                };
+    if kp_ref.is_none() {
+        log("  No KeyPackage reference found, ignoring");
+        return Ok(());
+    }
 
-                                                if let Ok(json) = serde_json::to_string(&result) {
-                                                    let js_string = JsValue::from_str(&json);
-                                                    let _ = callback_clone.call1(&JsValue::NULL, &js_string);
-                                                }
-                                            }
-                                            Err(e) => {
-                                                let error_str = format!("Failed to accept Welcome: {}", e);
-                                                log(&format!("❌ {}", error_str));
-
-                                                // Notify JS about the error
-                                                #[derive(Serialize)]
-                                                struct ErrorResult {
-                                                    error: String,
-                                                    kp_event_id: String,
-                                                }
+    let kp_event_id = kp_ref.unwrap();
+    log(&format!("  ✅ Welcome references KeyPackage: {}", &kp_event_id[..16.min(kp_event_id.len())]));
 
-                                                let result = ErrorResult {
-                                                    error: error_str,
-                                                    kp_event_id: kp_event_id.clone(),
-                                                };
+    // Process the Welcome
+    match create_mdk().await {
+        Ok(mdk) => {
+            // Convert to UnsignedEvent
+            let mut rumor = nostr::UnsignedEvent {
+                id: None,
+                pubkey: welcome_event.pubkey,
+                created_at: welcome_event.created_at,
+                kind: welcome_event.kind,
+                tags: welcome_event.tags.clone(),
+                content: welcome_event.content.clone(),
+            };
+            rumor.ensure_id();
 
-                                                if let Ok(json) = serde_json::to_string(&result) {
-                                                    let js_string = JsValue::from_str(&json);
-                                                    let _ = callback_clone.call1(&JsValue::NULL, &js_string);
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let error_str = format!("{}", e);
-                                        log(&format!("❌ Failed to process Welcome: {}", error_str));
-
-                                        // Still notify JS (so it can delete the KeyPackage)
-                                        #[derive(Serialize)]
-                                        struct ErrorResult {
-                                            error: String,
-                                            kp_event_id: String,
-                                        }
+            match mdk.process_welcome(&welcome_event.id, &rumor) {
+                Ok(welcome) => {
+                    let group_id = hex::encode(welcome.mls_group_id.as_slice());
+                    let group_name = welcome.group_name.clone();
+                    log(&format!("  ✓ Processed Welcome! Group: {}", group_name));
+
+                    // Check if already accepted (de-duplicate)
+                    use mdk_storage_traits::welcomes::types::WelcomeState;
+                    if welcome.state == WelcomeState::Accepted {
+                        log(&format!("  ℹ️  Welcome already accepted, skipping"));
+                        return Ok(());
+                    }
 
-                                        let result = ErrorResult {
-                                            error: error_str,
-                                            kp_event_id: kp_event_id.clone(),
-                                        };
+                    // Accept Welcome (join the group)
+                    log("  Accepting Welcome (joining group)...");
+                    match mdk.accept_welcome(&welcome) {
+                        Ok(_) => {
+                            log(&format!("✅ Successfully joined group: {}", group_name));
 
-                                        if let Ok(json) = serde_json::to_string(&result) {
-                                            let js_string = JsValue::from_str(&json);
-                                            let _ = callback_clone.call1(&JsValue::NULL, &js_string);
-                                        }
-                                    }
+                            // Explicitly save after accepting Welcome
+                            if let Ok(storage) = get_or_create_storage().await {
+                                if let Err(e) = storage.inner().save_snapshot().await {
+                                    log(&format!("⚠️ Failed to save after accept_welcome: {:?}", e));
+                                } else {
+                                    log("  ✓ Storage saved");
                                 }
                             }
-                            Err(e) => {
-                                log(&format!("❌ Failed to create MDK: {:?}", e));
+
+                            // Call JavaScript callback with result
+                            #[derive(Serialize)]
+                            struct WelcomeResult {
+                                group_id: String,
+                                group_name: String,
+                                kp_event_id: String,
+                            }
+
+                            let result = WelcomeResult {
+                                group_id,
+                                group_name,
+                                kp_event_id,
+                            };
+
+                            if let Ok(json) = serde_json::to_string(&result) {
+                                notify(json);
                             }
                         }
+                        Err(e) => {
+                            let error_str = format!("Failed to accept Welcome: {}", e);
+                            log(&format!("❌ {}", error_str));
+
+                            // Notify JS about the error
+                            #[derive(Serialize)]
+                            struct ErrorResult {
+                                error: String,
+                                kp_event_id: String,
+                            }
 
-                        Ok(())
-                    }
-                }).await;
+                            let result = ErrorResult {
+                                error: error_str,
+                                kp_event_id: kp_event_id.clone(),
+                            };
 
-                if let Err(e) = result {
-                    log(&format!("❌ Welcome subscription error: {:?}", e));
+                            if let Ok(json) = serde_json::to_string(&result) {
+                                notify(json);
+                            }
+                        }
+                    }
                 }
-            });
+                Err(e) => {
+                    let error_str = format!("{}", e);
+                    log(&format!("❌ Failed to process Welcome: {}", error_str));
+
+                    // Still notify JS (so it can delete the KeyPackage)
+                    #[derive(Serialize)]
+                    struct ErrorResult {
+                        error: String,
+                        kp_event_id: String,
+                    }
 
-            Ok::<(), JsValue>(())
+                    let result = ErrorResult {
+                        error: error_str,
+                        kp_event_id: kp_event_id.clone(),
+                    };
+
+                    if let Ok(json) = serde_json::to_string(&result) {
+                        notify(json);
+                    }
+                }
+            }
         }
-        .await;
+        Err(e) => {
+            log(&format!("❌ Failed to create MDK: {:?}", e));
+        }
+    }
 
-        result.map(|_| JsValue::NULL)
-    })
+    Ok(())
 }
 
 /// Create a new group and invite members
@@ -2195,14 +3470,14 @@ pub fn create_group_with_members(name: String, description: String, member_npubs
             let members: Vec<MemberData> = serde_json::from_str(&member_npubs_json)
                 .map_err(|e| JsValue::from_str(&format!("Invalid members JSON: {}", e)))?;
 
-            // Get our keys
-            let keys = get_keys()?;
-            let our_pubkey = keys.public_key();
+            // Get our signer (local keys or browser extension)
+            let signer = get_signer()?;
+            let our_pubkey = get_public_key()?;
 
             // Fetch KeyPackages for each member
             log(&format!("Fetching KeyPackages for {} member(s)...", members.len()));
 
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let mut key_package_events = Vec::new();
             let mut admin_pubkeys = vec![our_pubkey]; // Creator is always admin
@@ -2350,13 +3625,17 @@ pub fn create_group_with_members(name: String, description: String, member_npubs
                         welcome_unsigned.ensure_id();
 
                         // Sign the UnsignedEvent
-                        let welcome_event = welcome_unsigned.sign(&keys).await
+                        let welcome_event = welcome_unsigned.sign(&signer).await
                             .map_err(|e| JsValue::from_str(&format!("Failed to sign Welcome: {}", e)))?;
 
                         let welcome_event_id = welcome_event.id.to_hex();
 
-                        let send_result = client.send_event(&welcome_event).await
-                            .map_err(|e| JsValue::from_str(&format!("Failed to send Welcome: {}", e)))?;
+                        let send_result = outbox::publish(
+                            &client,
+                            &welcome_event,
+                            outbox::EventPurpose::Welcome,
+                            Some(&group_result.group.mls_group_id),
+                        ).await?;
 
                         log("Publishing Welcome message:");
                         for relay_url in send_result.success.iter() {
@@ -2384,13 +3663,10 @@ pub fn create_group_with_members(name: String, description: String, member_npubs
 
             // Explicitly save after creating group (critical operation)
             let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save after create_group: {:?}", e)))?;
             log("✓ State saved to storage");
 
-            // Disconnect
-            let _ = client.disconnect().await;
-
             // Return group ID and invitations
             let result = serde_json::json!({
                 "group_id": group_id,
@@ -2426,11 +3702,10 @@ pub fn create_group_and_publish(name: String, description: String, keypackage_ev
             }
 
             // Get our keys
-            let keys = get_keys()?;
-            let our_pubkey = keys.public_key();
+            let our_pubkey = get_public_key()?;
 
             // Fetch KeyPackage events by ID
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
             let mut key_package_events = Vec::new();
             let mut admin_pubkeys = vec![our_pubkey]; // Creator is always admin
 
@@ -2484,13 +3759,10 @@ pub fn create_group_and_publish(name: String, description: String, keypackage_ev
 
             // Save state
             let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save: {:?}", e)))?;
             log("✓ State saved to storage");
 
-            // Disconnect
-            let _ = client.disconnect().await;
-
             // Convert welcome_rumors to JSON strings
             let welcome_rumors_strings: Vec<String> = group_result.welcome_rumors.iter()
                 .map(|rumor| serde_json::to_string(rumor).unwrap())
@@ -2520,7 +3792,7 @@ pub fn fetch_keypackages_for_npub(member_npub: String) -> js_sys::Promise {
             let member_pubkey = nostr::PublicKey::from_bech32(&member_npub)
                 .map_err(|e| JsValue::from_str(&format!("Invalid npub: {}", e)))?;
 
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let filter = nostr::Filter::new()
                 .kind(Kind::Custom(443))
@@ -2530,8 +3802,6 @@ pub fn fetch_keypackages_for_npub(member_npub: String) -> js_sys::Promise {
             let events = client.fetch_events(filter, Duration::from_secs(10)).await
                 .map_err(|e| JsValue::from_str(&format!("Failed to fetch KeyPackages: {}", e)))?;
 
-            let _ = client.disconnect().await;
-
             let keypackages: Vec<_> = events.iter().map(|kp| {
                 serde_json::json!({
                     "event_id": kp.id.to_hex(),
@@ -2559,7 +3829,7 @@ pub fn fetch_deletions_for_npub(member_npub: String) -> js_sys::Promise {
             let member_pubkey = nostr::PublicKey::from_bech32(&member_npub)
                 .map_err(|e| JsValue::from_str(&format!("Invalid npub: {}", e)))?;
 
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let deletion_filter = nostr::Filter::new()
                 .kind(Kind::EventDeletion)
@@ -2569,8 +3839,6 @@ pub fn fetch_deletions_for_npub(member_npub: String) -> js_sys::Promise {
             let deletion_events = client.fetch_events(deletion_filter, Duration::from_secs(5)).await
                 .map_err(|e| JsValue::from_str(&format!("Failed to fetch deletions: {}", e)))?;
 
-            let _ = client.disconnect().await;
-
             let deletions: Vec<_> = deletion_events.iter().map(|del| {
                 let referenced_ids: Vec<String> = del.tags.iter().filter_map(|tag| {
                     let tag_vec = tag.clone().to_vec();
@@ -2600,7 +3868,8 @@ pub fn fetch_deletions_for_npub(member_npub: String) -> js_sys::Promise {
 }
 
 /// Step 1: Add member to group and publish evolution event
-/// Returns welcome_rumors and relay status
+/// Returns welcome_rumors and relay status; `confirmed` is false if a relay
+/// accepted the commit but none served it yet (it is merged once it shows up)
 #[wasm_bindgen]
 pub fn add_member_and_publish(group_id_hex: String, keypackage_event_id: String, member_npub: String) -> js_sys::Promise {
     future_to_promise(async move {
@@ -2618,7 +3887,7 @@ pub fn add_member_and_publish(group_id_hex: String, keypackage_event_id: String,
 
             // Fetch the specific KeyPackage event by ID
             log(&format!("Fetching KeyPackage {}...", &keypackage_event_id[..16]));
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let event_id = nostr::EventId::from_hex(&keypackage_event_id)
                 .map_err(|e| JsValue::from_str(&format!("Invalid event ID: {}", e)))?;
@@ -2638,19 +3907,17 @@ pub fn add_member_and_publish(group_id_hex: String, keypackage_event_id: String,
             // Create MDK
             let mdk = create_mdk().await?;
 
-            // Add member to group (creates MLS commit)
+            // Add member to group (creates MLS commit), merged once a relay has it
             log("Creating MLS commit to add member...");
-            let invite_result = mdk.add_members(&group_id, &[keypackage_event.clone()])
-                .map_err(|e| JsValue::from_str(&format!("Failed to add member: {}", e)))?;
-
-            // Merge the commit locally
-            mdk.merge_pending_commit(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
-
-            // Publish evolution event
-            log("Publishing evolution event to relays...");
-            let send_result = client.send_event(&invite_result.evolution_event).await
-                .map_err(|e| JsValue::from_str(&format!("Failed to publish evolution: {}", e)))?;
+            let storage = get_or_create_storage().await?;
+            let confirmed = commits::commit(&mdk, storage.inner(), &client, &group_id, |mdk| {
+                mdk.add_members(&group_id, &[keypackage_event.clone()])
+                    .map_err(|e| JsValue::from_str(&format!("Failed to add member: {}", e)))
+            })
+            .await?;
+            let invite_result = confirmed.result;
+            let send_result = confirmed.send_result;
+            let commit_confirmed = confirmed.confirmed;
 
             let success_relays: Vec<String> = send_result.success.iter().map(|url| url.to_string()).collect();
             let failed_relays: Vec<_> = send_result.failed.iter()
@@ -2675,17 +3942,15 @@ pub fn add_member_and_publish(group_id_hex: String, keypackage_event_id: String,
             };
 
             // Save state
-            let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save: {:?}", e)))?;
 
-            let _ = client.disconnect().await;
-
             let response = serde_json::json!({
                 "member_pubkey": member_pubkey.to_hex(),
                 "welcome_rumors": welcome_rumors_json,
                 "evolution_relays_success": success_relays,
                 "evolution_relays_failed": failed_relays,
+                "confirmed": commit_confirmed,
             });
 
             Ok::<String, JsValue>(response.to_string())
@@ -2715,11 +3980,11 @@ pub fn publish_welcome_message(welcome_rumors_json: String, member_npub: String)
                 return Err(JsValue::from_str("No Welcome rumors to publish"));
             }
 
-            // Get keys for signing
-            let keys = get_keys()?;
+            // Get our signer (local keys or browser extension)
+            let signer = get_signer()?;
 
             // Connect to relays
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let mut welcome_event_id = String::new();
             let mut all_success_relays = Vec::new();
@@ -2737,14 +4002,13 @@ pub fn publish_welcome_message(welcome_rumors_json: String, member_npub: String)
                 welcome_unsigned.ensure_id();
 
                 // Sign the Welcome event
-                let welcome_event = welcome_unsigned.sign(&keys).await
+                let welcome_event = welcome_unsigned.sign(&signer).await
                     .map_err(|e| JsValue::from_str(&format!("Failed to sign Welcome: {}", e)))?;
 
                 welcome_event_id = welcome_event.id.to_hex();
 
                 // Publish to relays
-                let send_result = client.send_event(&welcome_event).await
-                    .map_err(|e| JsValue::from_str(&format!("Failed to send Welcome: {}", e)))?;
+                let send_result = outbox::publish(&client, &welcome_event, outbox::EventPurpose::Welcome, None).await?;
 
                 for relay_url in send_result.success.iter() {
                     log(&format!("  ✓ {} accepted Welcome", relay_url));
@@ -2759,8 +4023,6 @@ pub fn publish_welcome_message(welcome_rumors_json: String, member_npub: String)
                 }
             }
 
-            let _ = client.disconnect().await;
-
             log(&format!("✅ Welcome sent to {}!", &member_npub[..16]));
 
             let response = serde_json::json!({
@@ -2777,7 +4039,34 @@ pub fn publish_welcome_message(welcome_rumors_json: String, member_npub: String)
     })
 }
 
+/// Create the commit that adds `member` to the group's admins.
+/// Built from the current admin list, so it still holds when re-created on a newer epoch.
+fn add_admin(
+    mdk: &MDK<SharedMdkStorage>,
+    group_id: &GroupId,
+    member: nostr::PublicKey,
+) -> Result<mdk_core::groups::UpdateGroupResult, JsValue> {
+    let group = mdk.get_group(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+
+    // Add the new member to admins
+    let mut new_admins: Vec<nostr::PublicKey> = group.admin_pubkeys.into_iter().collect();
+    new_admins.push(member);
+
+    // Update group data with new admin list
+    use mdk_core::prelude::NostrGroupDataUpdate;
+    let update = NostrGroupDataUpdate {
+        admins: Some(new_admins),
+        ..Default::default()
+    };
+
+    mdk.update_group_data(group_id, update)
+        .map_err(|e| JsValue::from_str(&format!("Failed to update admins: {}", e)))
+}
+
 /// Step 3: Promote member to admin and publish
+/// Returns relay status and `confirmed`, like add_member_and_publish
 #[wasm_bindgen]
 pub fn promote_to_admin_and_publish(group_id_hex: String, member_npub: String) -> js_sys::Promise {
     future_to_promise(async move {
@@ -2796,33 +4085,14 @@ pub fn promote_to_admin_and_publish(group_id_hex: String, member_npub: String) -
             // Create MDK
             let mdk = create_mdk().await?;
 
-            // Get current group data
-            let group = mdk.get_group(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
-                .ok_or_else(|| JsValue::from_str("Group not found"))?;
-
-            // Add the new member to admins
-            let mut new_admins: Vec<nostr::PublicKey> = group.admin_pubkeys.into_iter().collect();
-            new_admins.push(member_pubkey);
-
-            // Update group data with new admin list
-            use mdk_core::prelude::NostrGroupDataUpdate;
-            let update = NostrGroupDataUpdate {
-                admins: Some(new_admins),
-                ..Default::default()
-            };
-
-            let update_result = mdk.update_group_data(&group_id, update)
-                .map_err(|e| JsValue::from_str(&format!("Failed to update admins: {}", e)))?;
-
-            // Merge the update commit
-            mdk.merge_pending_commit(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to merge admin update: {}", e)))?;
-
-            // Publish the admin update evolution event
-            let client = create_connected_client().await?;
-            let send_result = client.send_event(&update_result.evolution_event).await
-                .map_err(|e| JsValue::from_str(&format!("Failed to publish admin update: {}", e)))?;
+            // Publish the admin update, merged once a relay has it
+            let storage = get_or_create_storage().await?;
+            let client = relay_pool::client().await?;
+            let confirmed = commits::commit(&mdk, storage.inner(), &client, &group_id, |mdk| {
+                add_admin(mdk, &group_id, member_pubkey)
+            })
+            .await?;
+            let send_result = confirmed.send_result;
 
             let success_relays: Vec<String> = send_result.success.iter().map(|url| url.to_string()).collect();
             let failed_relays: Vec<_> = send_result.failed.iter()
@@ -2834,17 +4104,15 @@ pub fn promote_to_admin_and_publish(group_id_hex: String, member_npub: String) -
             }
 
             // Save state
-            let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save: {:?}", e)))?;
 
-            let _ = client.disconnect().await;
-
             log("✅ Member promoted to admin!");
 
             let response = serde_json::json!({
                 "relays_success": success_relays,
                 "relays_failed": failed_relays,
+                "confirmed": confirmed.confirmed,
             });
 
             Ok::<String, JsValue>(response.to_string())
@@ -2855,6 +4123,9 @@ pub fn promote_to_admin_and_publish(group_id_hex: String, member_npub: String) -
     })
 }
 
+/// Invite a member by npub: fetches their key package and adds them, merged once a relay has the commit
+/// (`confirmed` in the result is false if it didn't show up in time, see add_member_and_publish)
+#[wasm_bindgen]
 pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admin: bool) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
@@ -2873,7 +4144,7 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
             // Fetch member's KeyPackage
             log(&format!("Fetching KeyPackage for {}...", &member_npub[..16]));
 
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
             let filter = nostr::Filter::new()
                 .kind(Kind::Custom(443))
@@ -2927,24 +4198,25 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
             log(&format!("  ✓ Found available KeyPackage: {} ({} deleted, {} available)",
                 newest.id.to_hex(), deleted_ids.len(), available_kps.len()));
 
-            // Get our keys
-            let keys = get_keys()?;
+            // Get our signer (local keys or browser extension)
+            let signer = get_signer()?;
 
             // Create MDK
             let mdk = create_mdk().await?;
 
-            // Step 1: Add member to group
+            // Step 1: Add member to group, merged once a relay has the commit
+            // (the Welcome is only valid for the commit that ends up in the group)
             log("Adding member to group...");
-            let invite_result = mdk.add_members(&group_id, &[(**newest).clone()])
-                .map_err(|e| JsValue::from_str(&format!("Failed to add member: {}", e)))?;
-
-            mdk.merge_pending_commit(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
-
-            client.send_event(&invite_result.evolution_event).await
-                .map_err(|e| JsValue::from_str(&format!("Failed to publish evolution: {}", e)))?;
-
-            // Step 3: Publish Welcome message
+            let storage = get_or_create_storage().await?;
+            let confirmed = commits::commit(&mdk, storage.inner(), &client, &group_id, |mdk| {
+                mdk.add_members(&group_id, &[(**newest).clone()])
+                    .map_err(|e| JsValue::from_str(&format!("Failed to add member: {}", e)))
+            })
+            .await?;
+            let mut commits_confirmed = confirmed.confirmed;
+            let invite_result = confirmed.result;
+
+            // Step 2: Publish Welcome message
             let mut welcome_event_id = String::new();
             if let Some(welcome_rumors) = invite_result.welcome_rumors {
                 log(&format!("Publishing Welcome message to {}...", &member_npub[..16]));
@@ -2960,14 +4232,13 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
                     // Ensure ID is set before signing
                     welcome_unsigned.ensure_id();
 
-                    let welcome_event = welcome_unsigned.sign(&keys).await
+                    let welcome_event = welcome_unsigned.sign(&signer).await
                         .map_err(|e| JsValue::from_str(&format!("Failed to sign Welcome: {}", e)))?;
 
                     // Store the Welcome event ID
                     welcome_event_id = welcome_event.id.to_hex();
 
-                    let send_result = client.send_event(&welcome_event).await
-                        .map_err(|e| JsValue::from_str(&format!("Failed to send Welcome: {}", e)))?;
+                    let send_result = outbox::publish(&client, &welcome_event, outbox::EventPurpose::Welcome, Some(&group_id)).await?;
 
                     for relay_url in send_result.success.iter() {
                         log(&format!("  ✓ {} accepted Welcome", relay_url));
@@ -2984,39 +4255,17 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
             if is_admin {
                 log("Adding new member as admin...");
 
-                // Get current group data
-                let group = mdk.get_group(&group_id)
-                    .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
-                    .ok_or_else(|| JsValue::from_str("Group not found"))?;
-
-                // Add the new member to admins
-                let mut new_admins: Vec<nostr::PublicKey> = group.admin_pubkeys.into_iter().collect();
-                new_admins.push(member_pubkey);
-
-                // Update group data with new admin list
-                use mdk_core::prelude::NostrGroupDataUpdate;
-                let update = NostrGroupDataUpdate {
-                    admins: Some(new_admins),
-                    ..Default::default()
-                };
-
-                let update_result = mdk.update_group_data(&group_id, update)
-                    .map_err(|e| JsValue::from_str(&format!("Failed to update admins: {}", e)))?;
-
-                // Merge the update commit BEFORE publishing
-                mdk.merge_pending_commit(&group_id)
-                    .map_err(|e| JsValue::from_str(&format!("Failed to merge admin update: {}", e)))?;
-
-                // Publish the update evolution event
-                client.send_event(&update_result.evolution_event).await
-                    .map_err(|e| JsValue::from_str(&format!("Failed to publish admin update: {}", e)))?;
+                let confirmed = commits::commit(&mdk, storage.inner(), &client, &group_id, |mdk| {
+                    add_admin(mdk, &group_id, member_pubkey)
+                })
+                .await?;
+                commits_confirmed &= confirmed.confirmed;
 
                 log("✅ Member added as admin!");
             }
 
             // Explicitly save after inviting member (critical operation)
-            let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save after invite_member: {:?}", e)))?;
             log("✓ State saved to storage");
 
@@ -3027,9 +4276,6 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
                 .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
                 .ok_or_else(|| JsValue::from_str("Group not found"))?;
 
-            // Disconnect
-            let _ = client.disconnect().await;
-
             // Return detailed invitation information
             let group_name = if group_data.name.is_empty() {
                 "Unnamed Group".to_string()
@@ -3078,6 +4324,7 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
                 "welcome_event_id": welcome_event_id,
                 "group_id": group_id_hex,
                 "group_name": group_name,
+                "confirmed": commits_confirmed,
                 "timestamp": nostr::Timestamp::now().as_u64(),
             });
 
@@ -3090,7 +4337,8 @@ pub fn invite_member_to_group(group_id_hex: String, member_npub: String, is_admi
 }
 
 /// Remove a member from a group (or leave the group if removing yourself)
-/// Returns a Promise that resolves when the removal is complete
+/// Returns a Promise that resolves to JSON {success, group_id, removed_member, is_self_removal, confirmed}
+/// (`confirmed` is false if the commit didn't show up on a relay in time, see add_member_and_publish)
 #[wasm_bindgen]
 pub fn remove_member_from_group(group_id_hex: String, member_npub: String) -> js_sys::Promise {
     future_to_promise(async move {
@@ -3107,8 +4355,7 @@ pub fn remove_member_from_group(group_id_hex: String, member_npub: String) -> js
             let group_id = mdk_core::prelude::GroupId::from_slice(&group_id_bytes);
 
             // Get our keys
-            let keys = get_keys()?;
-            let our_pubkey = keys.public_key();
+            let our_pubkey = get_public_key()?;
 
             // Create MDK
             let mdk = create_mdk().await?;
@@ -3137,37 +4384,30 @@ pub fn remove_member_from_group(group_id_hex: String, member_npub: String) -> js
                 }
             }
 
-            let client = create_connected_client().await?;
+            let client = relay_pool::client().await?;
 
-            // Remove the member (use leave_group for self, remove_members for others)
+            // Remove the member (use leave_group for self, remove_members for others).
+            // The evolution event is published first and merged once a relay has it.
             // Note: We don't send a message beforehand because it would create epoch conflicts
-            let remove_result = if is_self_removal {
-                log("Leaving group...");
-                mdk.leave_group(&group_id)
-                    .map_err(|e| JsValue::from_str(&format!("Failed to leave group: {}", e)))?
-            } else {
-                log("Removing member from group...");
-                mdk.remove_members(&group_id, &[member_pubkey])
-                    .map_err(|e| JsValue::from_str(&format!("Failed to remove member: {}", e)))?
-            };
-
-            // Publish the evolution event FIRST so others can process it
-            client.send_event(&remove_result.evolution_event).await
-                .map_err(|e| JsValue::from_str(&format!("Failed to publish removal evolution: {}", e)))?;
-
-            // Then merge our local state
-            mdk.merge_pending_commit(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to merge removal commit: {}", e)))?;
+            let storage = get_or_create_storage().await?;
+            let confirmed = commits::commit(&mdk, storage.inner(), &client, &group_id, |mdk| {
+                if is_self_removal {
+                    log("Leaving group...");
+                    mdk.leave_group(&group_id)
+                        .map_err(|e| JsValue::from_str(&format!("Failed to leave group: {}", e)))
+                } else {
+                    log("Removing member from group...");
+                    mdk.remove_members(&group_id, &[member_pubkey])
+                        .map_err(|e| JsValue::from_str(&format!("Failed to remove member: {}", e)))
+                }
+            })
+            .await?;
 
             // Explicitly save after removing member
-            let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
+            storage.inner().save_snapshot().await
                 .map_err(|e| JsValue::from_str(&format!("Failed to save after remove_member: {:?}", e)))?;
             log("✓ State saved to storage");
 
-            // Disconnect
-            let _ = client.disconnect().await;
-
             log(&format!("✅ {} removed from group", &member_npub[..16]));
 
             // Return result as JSON
@@ -3177,6 +4417,7 @@ pub fn remove_member_from_group(group_id_hex: String, member_npub: String) -> js
                 group_id: String,
                 removed_member: String,
                 is_self_removal: bool,
+                confirmed: bool,
             }
 
             let result = RemovalResult {
@@ -3184,6 +4425,7 @@ pub fn remove_member_from_group(group_id_hex: String, member_npub: String) -> js
                 group_id: group_id_hex,
                 removed_member: member_npub,
                 is_self_removal,
+                confirmed: confirmed.confirmed,
             };
 
             let json = serde_json::to_string(&result)
@@ -3232,7 +4474,7 @@ pub fn process_pending_welcomes() -> js_sys::Promise {
             // Explicitly save after accepting all Welcomes (critical operation)
             if accepted > 0 {
                 let storage = get_or_create_storage().await?;
-                storage.inner().save_snapshot()
+                storage.inner().save_snapshot().await
                     .map_err(|e| JsValue::from_str(&format!("Failed to save after accept_welcome: {:?}", e)))?;
                 log(&format!("✅ Joined {} new group(s)", accepted));
             } else {
@@ -3248,98 +4490,397 @@ pub fn process_pending_welcomes() -> js_sys::Promise {
 }
 
 /// Send a message to a group
-/// Returns a Promise that resolves when the message is sent
+/// Returns a Promise that resolves to JSON {event_id, delivery} once the message is published,
+/// where delivery lists the relays that accepted or rejected it (see get_messages_for_group)
 #[wasm_bindgen]
 pub fn send_message_to_group(group_id_hex: String, message_content: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = send_to_group(&group_id_hex, Kind::GiftWrap, nostr::Tags::new(), message_content).await;
+
+        result.map(|(_, json)| JsValue::from_str(&json))
+    })
+}
+
+/// Encrypt a rumor for a group and publish it.
+/// Returns the message (rumor) ID and JSON {event_id, message_id, delivery}.
+async fn send_to_group(group_id_hex: &str, kind: Kind, tags: nostr::Tags, content: String) -> Result<(nostr::EventId, String), JsValue> {
+    log(&format!("📤 Sending message to group {}", &group_id_hex[..16]));
+    log(&format!("  Message content: {}", content));
+
+    // Get our pubkey
+    let pubkey = get_public_key()?;
+    log(&format!("  Sender npub: {}", pubkey.to_bech32().expect("valid bech32")));
+
+    // Decode group ID from hex
+    let group_id_bytes = hex::decode(group_id_hex)
+        .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
+    let group_id = GroupId::from_slice(&group_id_bytes);
+    log(&format!("  Decoded group ID: {} bytes", group_id_bytes.len()));
+
+    // Create MDK
+    log("  Creating MDK instance...");
+    let mdk = create_mdk().await?;
+    log("  ✓ MDK instance created");
+
+    // Verify group exists
+    log("  Checking if group exists...");
+    let group = mdk.get_group(&group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+    log(&format!("  ✓ Group found: {}", &group.name));
+
+    // Create message rumor
+    log("  Creating message rumor...");
+    let mut rumor = nostr::UnsignedEvent {
+        id: None,
+        pubkey,
+        created_at: nostr::Timestamp::now(),
+        kind,
+        tags,
+        content,
+    };
+    rumor.ensure_id();
+    let message_id = rumor.id.expect("rumor ID was just computed");
+    log("  ✓ Message rumor created");
+
+    // Create encrypted message
+    log("  Encrypting message with MLS...");
+    let message_event = mdk.create_message(&group_id, rumor)
+        .map_err(|e| {
+            use mdk_core::error::Error;
+            if matches!(e, Error::OwnLeafNotFound) {
+                JsValue::from_str("You have been removed from this group and can no longer send messages")
+            } else {
+                JsValue::from_str(&format!("Failed to create message: {}", e))
+            }
+        })?;
+    log(&format!("  ✓ Message encrypted, event ID: {}", message_event.id.to_hex()));
+
+    // Publish to relays
+    log("  Connecting to relays...");
+    let client = relay_pool::client().await?;
+    log("  ✓ Connected to relays");
+
+    // Merge pending commit to finalize our state BEFORE publishing
+    log("  Finalizing message state...");
+    mdk.merge_pending_commit(&group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
+    log("  ✓ State finalized");
+
+    // Explicitly save after sending message (critical operation)
+    let storage = get_or_create_storage().await?;
+    storage.inner().save_snapshot().await
+        .map_err(|e| JsValue::from_str(&format!("Failed to save after send_message: {:?}", e)))?;
+    log("  ✓ State saved to storage");
+
+    // Recorded in the outbox first, so a failed publish is retried rather than lost
+    log("  Publishing message event...");
+    let send_result = outbox::publish(&client, &message_event, outbox::EventPurpose::Message, Some(&group_id)).await?;
+    log(&format!("  ✓ Message event published ({} relay(s) accepted)", send_result.success.len()));
+    for (relay_url, error) in send_result.failed.iter() {
+        log(&format!("    ✗ {} rejected: {}", relay_url, error));
+    }
+    log("✅ Message sent successfully!");
+
+    let delivery = outbox::receipt(&message_event.id).await?;
+    let json = serde_json::json!({
+        "event_id": message_event.id.to_hex(),
+        "message_id": message_id.to_hex(),
+        "delivery": delivery,
+    });
+
+    Ok((message_id, json.to_string()))
+}
+
+/// Post the claim notice for a token message (or treasury deposit) and update its status
+/// (failures are only logged, the sats are ours either way)
+async fn announce_claim(group_id: &GroupId, message_id: &nostr::EventId) {
+    let group_id_hex = hex::encode(group_id.as_slice());
+    let notice = payments::claim_notice_tags(message_id);
+    if let Err(e) = send_to_group(&group_id_hex, payments::CLAIM_NOTICE_KIND, notice, String::new()).await {
+        log(&format!("⚠️ Failed to announce the claim: {:?}", e));
+    }
+
+    let checked = async {
+        let storage = get_or_create_storage().await?;
+        payments::check(storage.inner(), Some(vec![group_id.clone()]), false).await
+    }
+    .await;
+    if let Err(e) = checked {
+        log(&format!("⚠️ Failed to update token status: {:?}", e));
+    }
+}
+
+/// Send an ecash token to a group as a payment message (see claim_token_message)
+/// `memo` is shown with the payment. A token we sent is reclaimed with a claim notice in the group.
+/// Returns a Promise that resolves to JSON {event_id, message_id, delivery}, like send_message_to_group
+#[wasm_bindgen]
+pub fn send_token_to_group(group_id_hex: String, token_str: String, memo: Option<String>) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
-            log(&format!("📤 Sending message to group {}", &group_id_hex[..16]));
-            log(&format!("  Message content: {}", message_content));
+            let group_id_bytes = hex::decode(&group_id_hex)
+                .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
+            let group_id = GroupId::from_slice(&group_id_bytes);
 
-            // Get keys
-            let keys = get_keys()?;
-            let pubkey = keys.public_key();
-            log(&format!("  Sender npub: {}", pubkey.to_bech32().expect("valid bech32")));
+            let token = Token::from_str(token_str.trim())
+                .map_err(|e| JsValue::from_str(&format!("Invalid token: {}", e)))?;
+            let tags = payments::token_tags(&token)?;
+            let (message_id, json) = send_to_group(&group_id_hex, payments::TOKEN_MESSAGE_KIND, tags, memo.unwrap_or_default()).await?;
 
-            // Decode group ID from hex
+            sent_tokens::link_message(&token_str, &group_id, &message_id).await?;
+            Ok::<String, JsValue>(json)
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Take `amount` sats from the current mint and send them to a group as a payment message
+/// Returns a Promise that resolves to JSON {event_id, message_id, delivery}, like send_message_to_group
+#[wasm_bindgen]
+pub fn send_ecash_to_group(group_id_hex: String, amount: u64, memo: Option<String>) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
             let group_id_bytes = hex::decode(&group_id_hex)
                 .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
             let group_id = GroupId::from_slice(&group_id_bytes);
-            log(&format!("  Decoded group ID: {} bytes", group_id_bytes.len()));
 
-            // Create MDK
-            log("  Creating MDK instance...");
-            let mdk = create_mdk().await?;
-            log("  ✓ MDK instance created");
+            let token = create_token(amount).await?;
+            let tags = payments::token_tags(&token)?;
 
-            // Verify group exists
-            log("  Checking if group exists...");
-            let group = mdk.get_group(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
-                .ok_or_else(|| JsValue::from_str("Group not found"))?;
-            log(&format!("  ✓ Group found: {}", &group.name));
+            // The proofs already left the wallet; if sending fails they can be reclaimed
+            let (message_id, json) = send_to_group(&group_id_hex, payments::TOKEN_MESSAGE_KIND, tags, memo.unwrap_or_default()).await
+                .map_err(|e| JsValue::from_str(&format!(
+                    "Token created but not sent ({}). Reclaim it from the sent tokens in your wallet.",
+                    e.as_string().unwrap_or_default()
+                )))?;
 
-            // Create message rumor
-            log("  Creating message rumor...");
-            let rumor = nostr::UnsignedEvent {
-                id: None,
-                pubkey,
-                created_at: nostr::Timestamp::now(),
-                kind: Kind::GiftWrap,
-                tags: nostr::Tags::new(),
-                content: message_content.clone(),
-            };
-            log("  ✓ Message rumor created");
+            sent_tokens::link_message(&token.to_string(), &group_id, &message_id).await?;
+            Ok::<String, JsValue>(json)
+        }
+        .await;
 
-            // Create encrypted message
-            log("  Encrypting message with MLS...");
-            let message_event = mdk.create_message(&group_id, rumor)
-                .map_err(|e| {
-                    use mdk_core::error::Error;
-                    if matches!(e, Error::OwnLeafNotFound) {
-                        JsValue::from_str("You have been removed from this group and can no longer send messages")
-                    } else {
-                        JsValue::from_str(&format!("Failed to create message: {}", e))
-                    }
-                })?;
-            log(&format!("  ✓ Message encrypted, event ID: {}", message_event.id.to_hex()));
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
 
-            // Publish to relays
-            log("  Connecting to relays...");
-            let client = create_connected_client().await?;
-            log("  ✓ Connected to relays");
+/// Redeem the token of a payment message into our wallet and mark the message as claimed
+/// Returns a Promise that resolves to JSON {amount, claimed_at}
+#[wasm_bindgen]
+pub fn claim_token_message(message_id: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let message_id = nostr::EventId::from_hex(&message_id)
+                .map_err(|e| JsValue::from_str(&format!("Invalid message ID: {}", e)))?;
 
-            // Merge pending commit to finalize our state BEFORE publishing
-            log("  Finalizing message state...");
-            mdk.merge_pending_commit(&group_id)
-                .map_err(|e| JsValue::from_str(&format!("Failed to merge commit: {}", e)))?;
-            log("  ✓ State finalized");
+            if let Some(claim) = payments::claim(&message_id).await? {
+                return Err(JsValue::from_str(&format!("Already claimed {} sats from this message", claim.amount)));
+            }
 
-            // Explicitly save after sending message (critical operation)
             let storage = get_or_create_storage().await?;
-            storage.inner().save_snapshot()
-                .map_err(|e| JsValue::from_str(&format!("Failed to save after send_message: {:?}", e)))?;
-            log("  ✓ State saved to storage");
+            use mdk_storage_traits::messages::MessageStorage;
+            let message = storage.inner().find_message_by_event_id(&message_id)
+                .map_err(|e| JsValue::from_str(&format!("Failed to get message: {}", e)))?
+                .ok_or_else(|| JsValue::from_str("Message not found"))?;
+            let payment = payments::payment(&message, None, None)
+                .ok_or_else(|| JsValue::from_str("Message doesn't carry a token"))?;
+
+            log(&format!("💰 Claiming {} {} from message {}", payment.amount, payment.unit, message_id.to_hex()));
+            let amount = redeem_token(&payment.token).await?;
+            let claim = payments::record_claim(&message_id, &message.mls_group_id, amount).await?;
+
+            // Let the other members know who took it
+            announce_claim(&message.mls_group_id, &message_id).await;
+
+            let json = serde_json::json!({
+                "amount": claim.amount,
+                "claimed_at": claim.claimed_at,
+            });
 
-            log("  Publishing message event...");
-            client.send_event(&message_event).await
-                .map_err(|e| JsValue::from_str(&format!("Failed to send event: {}", e)))?;
-            log("  ✓ Message event published");
+            Ok::<String, JsValue>(json.to_string())
+        }
+        .await;
 
-            // Disconnect
-            let _ = client.disconnect().await;
-            log("✅ Message sent successfully!");
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
 
-            Ok::<(), JsValue>(())
+/// Lock `amount` sats from the current mint to the group's admins, any `threshold` of whom
+/// have to sign to spend them (NUT-11 multisig), and post them to the group's treasury
+/// Returns a Promise that resolves to JSON {event_id, message_id, delivery}, like send_message_to_group
+#[wasm_bindgen]
+pub fn send_ecash_to_group_multisig(group_id_hex: String, amount: u64, threshold: u32) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let group_id_bytes = hex::decode(&group_id_hex)
+                .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
+            let group_id = GroupId::from_slice(&group_id_bytes);
+
+            let storage = get_or_create_storage().await?;
+            let conditions = treasury::admin_lock(storage.inner(), &group_id, u64::from(threshold))?;
+            log(&format!("🏦 Depositing {} sats, spendable by {} admin(s) together", amount, threshold));
+
+            let locked_to = format!("{} admin(s) of group {}", threshold, &group_id_hex[..8.min(group_id_hex.len())]);
+            let token = create_locked_token(amount, conditions, locked_to).await?;
+            let tags = payments::token_tags(&token)?;
+
+            let (message_id, json) = send_to_group(&group_id_hex, treasury::DEPOSIT_KIND, tags, String::new()).await
+                .map_err(|e| JsValue::from_str(&format!(
+                    "Deposit created but not sent ({}). It is listed with your sent tokens.",
+                    e.as_string().unwrap_or_default()
+                )))?;
+            sent_tokens::link_message(&token.to_string(), &group_id, &message_id).await?;
+            Ok::<String, JsValue>(json)
         }
         .await;
 
-        result.map(|_| JsValue::undefined())
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Get a group's treasury: its deposits, oldest first, with whether their mint reports them spent
+/// Returns a Promise that resolves to a JSON array of
+/// { id, amount, unit, mint, memo, sender, created_at, threshold, signers, spent, spent_by,
+///   requests: [{ id, requested_by, memo, created_at, signed_by, ready }] } (npubs for people)
+#[wasm_bindgen]
+pub fn get_treasury(group_id_hex: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let group_id_bytes = hex::decode(&group_id_hex)
+                .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
+
+            let storage = get_or_create_storage().await?;
+            let deposits = treasury::deposits(storage.inner(), &GroupId::from_slice(&group_id_bytes)).await?;
+            serde_json::to_string(&deposits)
+                .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Ask the signers of a treasury deposit to release it to us (see sign_treasury_spend):
+/// the request carries outputs from our wallet seed for the deposit, less the mint's fee
+/// Returns a Promise that resolves to JSON {event_id, message_id, delivery}, like send_message_to_group
+#[wasm_bindgen]
+pub fn request_treasury_spend(deposit_id: String, memo: Option<String>) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let deposit_id = nostr::EventId::from_hex(&deposit_id)
+                .map_err(|e| JsValue::from_str(&format!("Invalid deposit ID: {}", e)))?;
+
+            let storage = get_or_create_storage().await?;
+            let (group_id, spend) = treasury::request_spend(storage.inner(), &deposit_id, memo).await?;
+
+            let group_id_hex = hex::encode(group_id.as_slice());
+            let tags = treasury::reply_tags(&deposit_id);
+            let (_, json) = send_to_group(&group_id_hex, treasury::SPEND_REQUEST_KIND, tags, spend).await?;
+            Ok::<String, JsValue>(json)
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
     })
 }
 
+/// Sign a spend request's swap as one of its deposit's signers, posting our signature to the group
+/// Returns a Promise that resolves to JSON {event_id, message_id, delivery}, like send_message_to_group
+#[wasm_bindgen]
+pub fn sign_treasury_spend(request_id: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let request_id = nostr::EventId::from_hex(&request_id)
+                .map_err(|e| JsValue::from_str(&format!("Invalid request ID: {}", e)))?;
+
+            let storage = get_or_create_storage().await?;
+            let (group_id, signatures) = treasury::sign(storage.inner(), &request_id).await?;
+
+            let group_id_hex = hex::encode(group_id.as_slice());
+            let tags = treasury::reply_tags(&request_id);
+            let (_, json) = send_to_group(&group_id_hex, treasury::SIGNATURES_KIND, tags, signatures).await?;
+            Ok::<String, JsValue>(json)
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Spend the deposit of our own spend request into our wallet, once enough signers signed it
+/// (the members see it spent by us)
+/// Returns a Promise that resolves to JSON { amount }
+#[wasm_bindgen]
+pub fn spend_treasury_deposit(request_id: String) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let request_id = nostr::EventId::from_hex(&request_id)
+                .map_err(|e| JsValue::from_str(&format!("Invalid request ID: {}", e)))?;
+
+            let storage = get_or_create_storage().await?;
+            let (group_id, deposit_id, amount) = treasury::spend(storage.inner(), &request_id).await?;
+            log(&format!("✅ Received {} sats from the treasury", amount));
+
+            announce_claim(&group_id, &deposit_id).await;
+            Ok::<String, JsValue>(serde_json::json!({ "amount": amount }).to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Set the callback told when a group's treasury changes (pass null to remove it)
+/// The callback receives JSON: { group_id, message_id }; call get_treasury for the new state
+#[wasm_bindgen]
+pub fn set_treasury_callback(callback: Option<js_sys::Function>) {
+    treasury::set_callback(callback);
+}
+
+/// Check who claimed the token messages of a group (all groups if omitted): from the
+/// claim notices members post and the state of the tokens' proofs at their mints (NUT-07).
+/// Call this periodically; changes also go to the callback set with set_token_status_callback.
+/// Returns a Promise that resolves to a JSON array of the changes:
+/// [{message_id, group_id, status, claimed_by}], where status is one of "unclaimed",
+/// "claimed_by_me", "claimed_by_other" and "reclaimed_by_sender" ("unknown" before the first check)
+#[wasm_bindgen]
+pub fn check_token_claims(group_id_hex: Option<String>) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            let groups = match group_id_hex {
+                Some(group_id_hex) => {
+                    let group_id_bytes = hex::decode(&group_id_hex)
+                        .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
+                    Some(vec![GroupId::from_slice(&group_id_bytes)])
+                }
+                None => None,
+            };
+
+            let storage = get_or_create_storage().await?;
+            let changed = payments::check(storage.inner(), groups, true).await?;
+            let reports: Vec<serde_json::Value> = changed.iter().map(|status| status.report()).collect();
+
+            Ok::<String, JsValue>(serde_json::Value::from(reports).to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
+/// Set the callback told when the claim status of a token message changes (pass null to remove it)
+/// The callback receives JSON: { message_id, group_id, status, claimed_by } (see check_token_claims)
+#[wasm_bindgen]
+pub fn set_token_status_callback(callback: Option<js_sys::Function>) {
+    payments::set_callback(callback);
+}
+
 /// Get messages for a group from storage
-/// Returns a Promise that resolves to a JSON array of messages
+/// Returns a Promise that resolves to a JSON array of messages.
+/// Messages sent from this device carry `delivery`: {status, relays: {url: {status, error, attempts, last_attempt_at}}}
+/// Token messages carry `payment`: {token, amount, unit, mint, memo, claimed_at, status, claimed_by}
+/// (status: see check_token_claims)
 #[wasm_bindgen]
 pub fn get_messages_for_group(group_id_hex: String) -> js_sys::Promise {
     future_to_promise(async move {
@@ -3357,25 +4898,16 @@ pub fn get_messages_for_group(group_id_hex: String) -> js_sys::Promise {
             let messages = storage.inner().messages(&group_id)
                 .map_err(|e| JsValue::from_str(&format!("Failed to get messages: {}", e)))?;
 
-            // Convert messages to JSON
-            #[derive(Serialize)]
-            struct MessageJson {
-                id: String,
-                pubkey: String,
-                content: String,
-                created_at: u64,
-                state: String,
-            }
+            // Receipts of the messages we sent, by wrapper event ID
+            let mut receipts = outbox::receipts().await?;
+            let claims = payments::claims().await?;
+            let statuses = payments::statuses().await?;
 
-            let messages_json: Vec<MessageJson> = messages.iter().map(|msg| {
-                MessageJson {
-                    id: msg.id.to_hex(),
-                    pubkey: msg.pubkey.to_bech32().unwrap_or_else(|_| msg.pubkey.to_hex()),
-                    content: msg.content.clone(),
-                    created_at: msg.created_at.as_u64(),
-                    state: msg.state.to_string(),
-                }
-            }).collect();
+            // Convert messages to JSON
+            let messages_json: Vec<MessageJson> = messages.iter()
+                .filter(|msg| !payments::is_hidden(msg))
+                .map(|msg| MessageJson::new(msg, &mut receipts, &claims, &statuses))
+                .collect();
 
             let json = serde_json::to_string(&messages_json)
                 .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))?;
@@ -3388,6 +4920,87 @@ pub fn get_messages_for_group(group_id_hex: String) -> js_sys::Promise {
     })
 }
 
+/// A stored message as returned by get_messages_for_group and load_older_messages
+#[derive(Serialize)]
+struct MessageJson {
+    id: String,
+    pubkey: String,
+    content: String,
+    created_at: u64,
+    state: String,
+    delivery: Option<outbox::DeliveryReceipt>,
+    /// Set for token messages (see send_token_to_group)
+    payment: Option<payments::TokenPayment>,
+}
+
+impl MessageJson {
+    /// Takes the message's receipt out of `receipts` (keyed by wrapper event ID)
+    fn new(
+        msg: &mdk_storage_traits::messages::types::Message,
+        receipts: &mut std::collections::HashMap<String, outbox::DeliveryReceipt>,
+        claims: &std::collections::HashMap<String, payments::Claim>,
+        statuses: &std::collections::HashMap<String, payments::TokenStatus>,
+    ) -> Self {
+        let id = msg.id.to_hex();
+        Self {
+            pubkey: msg.pubkey.to_bech32().unwrap_or_else(|_| msg.pubkey.to_hex()),
+            content: msg.content.clone(),
+            created_at: msg.created_at.as_u64(),
+            state: msg.state.to_string(),
+            delivery: receipts.remove(&msg.wrapper_event_id.to_hex()),
+            payment: payments::payment(msg, claims.get(&id), statuses.get(&id)),
+            id,
+        }
+    }
+}
+
+/// Load a page of messages older than `before` (unix seconds, omit for now), oldest first.
+/// Stored messages come first; periods of the group's history that were never fetched
+/// (e.g. while offline) are backfilled from the relays.
+/// Returns a Promise that resolves to JSON: { messages, has_more, fetched, unreadable }
+/// Pass the `created_at` of the oldest message as `before` to get the next page.
+#[wasm_bindgen]
+pub fn load_older_messages(group_id_hex: String, before: Option<u32>, limit: u32) -> js_sys::Promise {
+    future_to_promise(async move {
+        let result = async {
+            if limit == 0 {
+                return Err(JsValue::from_str("Limit must be at least 1"));
+            }
+
+            let group_id_bytes = hex::decode(&group_id_hex)
+                .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
+            let group_id = GroupId::from_slice(&group_id_bytes);
+
+            let mdk = create_mdk().await?;
+            let storage = get_or_create_storage().await?;
+            let before = before.map(u64::from).unwrap_or_else(|| nostr::Timestamp::now().as_u64() + 1);
+
+            let page = history::older_messages(&mdk, storage.inner(), &group_id, before, limit as usize).await?;
+            log(&format!("📜 Loaded {} older message(s), {} event(s) backfilled", page.messages.len(), page.fetched));
+
+            let mut receipts = outbox::receipts().await?;
+            let claims = payments::claims().await?;
+            let statuses = payments::statuses().await?;
+            let messages: Vec<MessageJson> = page.messages.iter()
+                .filter(|msg| !payments::is_hidden(msg))
+                .map(|msg| MessageJson::new(msg, &mut receipts, &claims, &statuses))
+                .collect();
+
+            let response = serde_json::json!({
+                "messages": messages,
+                "has_more": page.has_more,
+                "fetched": page.fetched,
+                "unreadable": page.unreadable,
+            });
+
+            Ok::<String, JsValue>(response.to_string())
+        }
+        .await;
+
+        result.map(|json| JsValue::from_str(&json))
+    })
+}
+
 /// Message JSON structure for JavaScript callback
 #[derive(Serialize)]
 struct MessageCallback {
@@ -3396,161 +5009,272 @@ struct MessageCallback {
     content: String,
     created_at: u64,
     state: String,
+    /// Per-relay delivery, for messages sent from this device
+    delivery: Option<outbox::DeliveryReceipt>,
+    /// Set for token messages (see send_token_to_group)
+    payment: Option<payments::TokenPayment>,
 }
 
 /// Subscribe to group messages and call a JavaScript callback for each new message
-/// The callback will receive a JSON object with message details
+/// The callback will receive a JSON object with message details.
+/// All groups share one subscription, so subscribing to a group twice only adds a callback.
+/// Returns a Promise that resolves to a handle for `unsubscribe`
 #[wasm_bindgen]
 pub fn subscribe_to_group_messages(group_id_hex: String, callback: js_sys::Function) -> js_sys::Promise {
     future_to_promise(async move {
         let result = async {
-            log(&format!("📡 Subscribing to messages for group {}", &group_id_hex[..16]));
+            log(&format!("📡 Subscribing to messages for group {}", &group_id_hex[..16.min(group_id_hex.len())]));
 
             // Decode group ID
             let group_id_bytes = hex::decode(&group_id_hex)
                 .map_err(|e| JsValue::from_str(&format!("Invalid group ID hex: {}", e)))?;
             let group_id = GroupId::from_slice(&group_id_bytes);
 
-            // Get the group to find its nostr_group_id (used in event tags)
             let mdk = create_mdk().await?;
-            let group = mdk.get_group(&group_id)
+            mdk.get_group(&group_id)
                 .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
                 .ok_or_else(|| JsValue::from_str("Group not found"))?;
 
-            let nostr_group_id_hex = hex::encode(group.nostr_group_id);
-            log(&format!("  Filtering by nostr_group_id: {}", &nostr_group_id_hex[..16]));
+            let handle = subscriptions::register_group(group_id, callback);
+            if let Err(e) = update_group_subscription().await {
+                subscriptions::unregister(handle);
+                return Err(e);
+            }
 
-            // Create client and connect to relays
-            let client = Arc::new(create_connected_client().await?);
-            log("  ✓ Connected to relays");
+            Ok::<u32, JsValue>(handle)
+        }
+        .await;
 
-            // Subscribe to MLS group messages (kind 445) filtered by this specific group
-            // Optimization: If we have message history, only fetch recent messages (last 10 min + buffer)
-            let filter = if let Some(last_msg_time) = group.last_message_at {
-                let ten_minutes = 600; // 10 minutes in seconds
-                let since = nostr::Timestamp::from(last_msg_time.as_u64().saturating_sub(ten_minutes));
+        result.map(JsValue::from)
+    })
+}
 
-                log(&format!("  Subscribing since {} (last_message_at - 10 min)", since.as_u64()));
+/// Stop a subscription returned by subscribe_to_group_messages or subscribe_to_welcome_messages
+/// Returns a Promise that resolves to false if the handle was unknown
+#[wasm_bindgen]
+pub fn unsubscribe(handle: u32) -> js_sys::Promise {
+    future_to_promise(async move {
+        let Some(feed) = subscriptions::unregister(handle) else {
+            return Ok(JsValue::FALSE);
+        };
+
+        // Closes the shared REQ once its last callback is gone
+        match feed {
+            subscriptions::Feed::GroupMessages => update_group_subscription().await?,
+            subscriptions::Feed::Welcomes => update_welcome_subscription().await?,
+        }
 
-                nostr::Filter::new()
-                    .kind(Kind::MlsGroupMessage)
-                    .custom_tag(nostr::SingleLetterTag::lowercase(nostr::Alphabet::H), nostr_group_id_hex)
-                    .since(since)
-            } else {
-                log("  First join - fetching all history");
+        Ok(JsValue::TRUE)
+    })
+}
 
-                nostr::Filter::new()
-                    .kind(Kind::MlsGroupMessage)
-                    .custom_tag(nostr::SingleLetterTag::lowercase(nostr::Alphabet::H), nostr_group_id_hex)
-            };
+/// Set the callback told about epoch fork recoveries (pass null to remove it)
+/// The callback receives JSON: { group_id, outcome: "rolled_back" | "unresolved", fork_epoch, epoch,
+/// canonical_commit, discarded_commit, own_commit_discarded, replayed }
+#[wasm_bindgen]
+pub fn set_group_recovery_callback(callback: Option<js_sys::Function>) {
+    recovery::set_callback(callback);
+}
 
-            log("  Subscribing to MLS group messages (kind 445) with ordered history...");
+/// Set (or drop) the group message filter of the shared REQ: all joined groups
+async fn update_group_subscription() -> Result<(), JsValue> {
+    let groups = create_mdk().await?
+        .get_groups()
+        .map_err(|e| JsValue::from_str(&format!("Failed to get groups: {}", e)))?;
+
+    let scope: std::collections::BTreeSet<String> = groups.iter()
+        .map(|group| hex::encode(group.nostr_group_id))
+        .collect();
+
+    // MLS group messages (kind 445) filtered by the groups' nostr_group_id
+    let mut filter = nostr::Filter::new()
+        .kind(Kind::MlsGroupMessage)
+        .custom_tags(nostr::SingleLetterTag::lowercase(nostr::Alphabet::H), scope.iter().cloned());
+
+    // Optimization: if every group has message history, only fetch recent messages (last 10 min + buffer)
+    let last_message_times: Option<Vec<u64>> = groups.iter()
+        .map(|group| group.last_message_at.map(|t| t.as_u64()))
+        .collect();
+    match last_message_times.and_then(|times| times.into_iter().min()) {
+        Some(oldest) => {
+            let ten_minutes = 600; // 10 minutes in seconds
+            let since = nostr::Timestamp::from(oldest.saturating_sub(ten_minutes));
+            log(&format!("  Subscribing since {} (last_message_at - 10 min)", since.as_u64()));
+            filter = filter.since(since);
+        }
+        None => log("  A group has no history yet - fetching all history"),
+    }
 
-            // Spawn a background task to listen for notifications with ordered history
-            let client_clone = client.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                log("  📻 Starting notification listener with ordered history...");
-
-                let result = subscribe_with_ordered_history(&client_clone, filter, move |event| {
-                    let callback_clone = callback.clone();
-                    let group_id_clone = group_id.clone();
-                    async move {
-                        log(&format!("  📩 Processing event: {}", event.id.to_hex()));
-
-                        // Create MDK instance and process the message
-                        match create_mdk().await {
-                            Ok(mdk) => {
-                                match mdk.process_message(&event) {
-                                    Ok(result) => {
-                                        use mdk_core::prelude::MessageProcessingResult;
-                                        if let MessageProcessingResult::ApplicationMessage(msg) = result {
-                                            log(&format!("  ✅ Application message: '{}'", msg.content));
-                                            log(&format!("     Message group ID: {}", hex::encode(msg.mls_group_id.as_slice())));
-                                            log(&format!("     Target group ID: {}", hex::encode(group_id_clone.as_slice())));
-
-                                            // Check if this message belongs to the current group
-                                            if msg.mls_group_id == group_id_clone {
-                                                log("  🎯 Message matches current group!");
-
-                                                // Prepare callback data
-                                                let msg_data = MessageCallback {
-                                                    id: msg.id.to_hex(),
-                                                    pubkey: msg.pubkey.to_bech32().unwrap_or_else(|_| msg.pubkey.to_hex()),
-                                                    content: msg.content,
-                                                    created_at: msg.created_at.as_u64(),
-                                                    state: msg.state.to_string(),
-                                                };
-
-                                                // Call the JavaScript callback
-                                                if let Ok(js_value) = serde_wasm_bindgen::to_value(&msg_data) {
-                                                    match callback_clone.call1(&JsValue::NULL, &js_value) {
-                                                        Ok(_) => log("  ✅ Callback invoked successfully"),
-                                                        Err(e) => log(&format!("  ❌ Callback failed: {:?}", e)),
-                                                    }
-                                                } else {
-                                                    log("  ❌ Failed to serialize message to JS value");
-                                                }
-                                            } else {
-                                                log("  ⏭️  Message is for a different group, skipping");
-                                            }
-                                        } else {
-                                            log(&format!("  ℹ️  Non-application message: {:?}", result));
-                                        }
-                                    }
-                                    Err(e) => {
-                                        use mdk_core::error::Error;
-
-                                        // Check if this is an epoch conflict
-                                        if matches!(e, Error::ProcessMessageWrongEpoch) {
-                                            log(&format!("  ⚠️  EPOCH CONFLICT DETECTED: Another group member's action was processed first"));
-                                            log(&format!("     Event ID: {}", event.id.to_hex()));
-                                            log(&format!("     Your local state may have diverged from the group"));
-
-                                            // Show user-friendly modal
-                                            if let Some(window) = web_sys::window() {
-                                                let _ = window.alert_with_message(
-                                                    "⚠️ Group Conflict Detected\n\n\
-                                                    Another group member performed an action at the same time as you.\n\
-                                                    Their action was processed first.\n\n\
-                                                    Please try your action again (send message, invite member, etc.)."
-                                                );
-                                            }
-                                        } else if matches!(e, Error::OwnLeafNotFound) {
-                                            log(&format!("  ℹ️  You have been removed from this group"));
-
-                                            // Show user-friendly notification
-                                            if let Some(window) = web_sys::window() {
-                                                let _ = window.alert_with_message(
-                                                    "🚪 You've been removed from this group\n\n\
-                                                    An admin has removed you from the group.\n\
-                                                    You can no longer send or receive messages."
-                                                );
-                                            }
-                                        } else {
-                                            log(&format!("  ⚠️  Failed to process message: {}", e));
-                                        }
-                                    }
+    subscriptions::update(subscriptions::Feed::GroupMessages, scope, filter).await?;
+
+    // From now on the groups' events arrive live (see history.rs)
+    let live: Vec<GroupId> = if subscriptions::has_callbacks(subscriptions::Feed::GroupMessages) {
+        groups.iter().map(|group| group.mls_group_id.clone()).collect()
+    } else {
+        Vec::new()
+    };
+    history::set_live(&live);
+    Ok(())
+}
+
+/// Route an event from one of the shared subscriptions to its handler
+async fn handle_subscription_event(feed: subscriptions::Feed, event: Box<nostr::Event>) -> Result<(), JsValue> {
+    match feed {
+        subscriptions::Feed::GroupMessages => handle_group_event(event).await,
+        subscriptions::Feed::Welcomes => handle_welcome_event(event).await,
+    }
+}
+
+/// Process a group message from the shared subscription and pass application
+/// messages to the callbacks registered for their group
+async fn handle_group_event(event: Box<nostr::Event>) -> Result<(), JsValue> {
+    log(&format!("  📩 Processing event: {}", event.id.to_hex()));
+
+    // Create MDK instance and process the message
+    match create_mdk().await {
+        Ok(mdk) => {
+            // Checkpoint the group first, so a commit can be rolled back if it loses a fork
+            let storage = get_or_create_storage().await?;
+            let group = recovery::prepare(storage.inner(), &event).await;
+
+            if let Some(group) = &group {
+                if commits::holds_back(&group.mls_group_id, &event) {
+                    log("  ⏸️  Sorts after our pending commit, held until it is confirmed");
+                    recovery::buffer(storage.inner(), &group.mls_group_id, &event);
+                    return Ok(());
+                }
+                if commits::confirm_late(&mdk, storage.inner(), &group.mls_group_id, &event).await? {
+                    // Events of the new epoch that arrived first can be read now
+                    recovery::retry_pending(storage.inner(), &group.mls_group_id).await?;
+                    return Ok(());
+                }
+            }
+
+            match mdk.process_message(&event) {
+                Ok(result) => {
+                    storage.inner().remove_pending_event(&event.id);
+
+                    // A new epoch may make queued events readable
+                    if let Some(group) = &group {
+                        if recovery::advanced(storage.inner(), group, &event).await {
+                            recovery::retry_pending(storage.inner(), &group.mls_group_id).await?;
+                        }
+                    }
+
+                    use mdk_core::prelude::MessageProcessingResult;
+                    if let MessageProcessingResult::ApplicationMessage(msg) = result {
+                        log(&format!("  ✅ Application message: '{}'", msg.content));
+                        log(&format!("     Message group ID: {}", hex::encode(msg.mls_group_id.as_slice())));
+
+                        // Treasury messages only change the group's treasury
+                        if treasury::is_message(&msg) {
+                            treasury::report(&msg);
+                            return Ok(());
+                        }
+
+                        // Claim notices only update the status of the token message (or deposit) they are about
+                        if payments::is_hidden(&msg) {
+                            if let Err(e) = payments::check(storage.inner(), Some(vec![msg.mls_group_id.clone()]), false).await {
+                                log(&format!("  ⚠️  Failed to update token status: {:?}", e));
+                            }
+                            treasury::report(&msg);
+                            return Ok(());
+                        }
+
+                        let callbacks = subscriptions::group_callbacks(&msg.mls_group_id);
+                        if callbacks.is_empty() {
+                            log("  ⏭️  No callback for this group, message stored only");
+                            return Ok(());
+                        }
+
+                        // Our own messages come back with their delivery receipt
+                        let delivery = outbox::receipt(&event.id).await.unwrap_or_else(|e| {
+                            log(&format!("  ⚠️  Failed to read delivery receipt: {:?}", e));
+                            None
+                        });
+
+                        // Token messages come as a typed payment (claimed, if this is a replay)
+                        let (claim, status) = if msg.kind == payments::TOKEN_MESSAGE_KIND {
+                            let claim = payments::claim(&msg.id).await.unwrap_or_else(|e| {
+                                log(&format!("  ⚠️  Failed to read token claim: {:?}", e));
+                                None
+                            });
+                            let status = payments::status(&msg.id).await.unwrap_or_else(|e| {
+                                log(&format!("  ⚠️  Failed to read token status: {:?}", e));
+                                None
+                            });
+                            (claim, status)
+                        } else {
+                            (None, None)
+                        };
+                        let payment = payments::payment(&msg, claim.as_ref(), status.as_ref());
+
+                        // Prepare callback data
+                        let msg_data = MessageCallback {
+                            id: msg.id.to_hex(),
+                            pubkey: msg.pubkey.to_bech32().unwrap_or_else(|_| msg.pubkey.to_hex()),
+                            content: msg.content,
+                            created_at: msg.created_at.as_u64(),
+                            state: msg.state.to_string(),
+                            delivery,
+                            payment,
+                        };
+
+                        // Call the JavaScript callbacks
+                        if let Ok(js_value) = serde_wasm_bindgen::to_value(&msg_data) {
+                            for callback in callbacks {
+                                match callback.call1(&JsValue::NULL, &js_value) {
+                                    Ok(_) => log("  ✅ Callback invoked successfully"),
+                                    Err(e) => log(&format!("  ❌ Callback failed: {:?}", e)),
                                 }
                             }
-                            Err(e) => {
-                                log(&format!("  ⚠️  Failed to create MDK: {:?}", e));
+                        } else {
+                            log("  ❌ Failed to serialize message to JS value");
+                        }
+                    } else {
+                        log(&format!("  ℹ️  Non-application message: {:?}", result));
+                    }
+                }
+                Err(e) => {
+                    use mdk_core::error::Error;
+
+                    // Check if this is an epoch conflict
+                    if matches!(e, Error::ProcessMessageWrongEpoch) {
+                        log(&format!("  ⚠️  EPOCH CONFLICT DETECTED: {} is for another epoch", event.id.to_hex()));
+
+                        // Switch to the canonical branch if this commit wins (reported to the recovery callback),
+                        // otherwise it may be from an epoch we haven't reached yet (e.g. a message that beat its commit)
+                        if let Some(group) = &group {
+                            if !recovery::resolve_fork(&mdk, storage.inner(), group, &event).await {
+                                recovery::buffer(storage.inner(), &group.mls_group_id, &event);
                             }
                         }
+                    } else if matches!(e, Error::OwnLeafNotFound) {
+                        log(&format!("  ℹ️  You have been removed from this group"));
+
+                        // Show user-friendly notification
+                        if let Some(window) = web_sys::window() {
+                            let _ = window.alert_with_message(
+                                "🚪 You've been removed from this group\n\n\
+                                An admin has removed you from the group.\n\
+                                You can no longer send or receive messages."
+                            );
+                        }
+                    } else {
+                        log(&format!("  ⚠️  Failed to process message: {}", e));
 
-                        Ok(())
+                        // Possibly from an epoch we haven't reached yet
+                        if let Some(group) = &group {
+                            recovery::buffer(storage.inner(), &group.mls_group_id, &event);
+                        }
                     }
-                }).await;
-
-                if let Err(e) = result {
-                    log(&format!("❌ Group message subscription error: {:?}", e));
                 }
-            });
-
-            Ok::<(), JsValue>(())
+            }
         }
-        .await;
+        Err(e) => {
+            log(&format!("  ⚠️  Failed to create MDK: {:?}", e));
+        }
+    }
 
-        result.map(|_| JsValue::undefined())
-    })
+    Ok(())
 }
-
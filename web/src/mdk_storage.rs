@@ -1,18 +1,27 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::sync::{Arc, Mutex};
+use js_sys::Uint8Array;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use wasm_bindgen::JsValue;
-use web_sys::window;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{window, IdbDatabase, IdbTransaction, Storage};
+
+use crate::idb::{self, DbSchema};
+use crate::schema::{self, QuarantineEntry, QUARANTINE_STORE};
+use crate::vault;
 
 use mdk_storage_traits::GroupId;
 use mdk_storage_traits::groups::{GroupStorage, types::{Group, GroupExporterSecret, GroupRelay}, error::GroupError};
-use mdk_storage_traits::messages::{MessageStorage, types::{Message, ProcessedMessage}, error::MessageError};
+use mdk_storage_traits::messages::{MessageStorage, types::{Message, ProcessedMessage, ProcessedMessageState}, error::MessageError};
 use mdk_storage_traits::welcomes::{WelcomeStorage, types::{Welcome, ProcessedWelcome}, error::WelcomeError};
 use mdk_storage_traits::{Backend, MdkStorageProvider};
-use nostr::{EventId, PublicKey, RelayUrl};
+use nostr::{Event, EventId, PublicKey, RelayUrl, Timestamp};
 use openmls_memory_storage::MemoryStorage;
 
 // Helper types for serialization since some types don't implement Serialize/Deserialize directly
+// (also the layout of the legacy localStorage snapshot, which is migrated on first load)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct SerializableState {
     groups: HashMap<String, Group>,  // GroupId as hex string
@@ -37,6 +46,8 @@ struct MdkState {
     messages_by_group: HashMap<GroupId, Vec<Message>>,
     processed_messages: HashMap<EventId, ProcessedMessage>,
     group_exporter_secrets: HashMap<(GroupId, u64), GroupExporterSecret>,
+    /// Not part of SerializableState: backups don't carry events waiting for an epoch
+    pending_events: HashMap<EventId, PendingEvent>,
 }
 
 impl MdkState {
@@ -135,7 +146,268 @@ impl MdkState {
                     Ok(((gid, epoch), v))
                 })
                 .collect::<Result<_, String>>()?,
+            pending_events: HashMap::new(),
+        })
+    }
+}
+
+/// IndexedDB database holding MDK state, one object store per record type
+pub(crate) const DB_NAME: &str = "mdk_storage";
+
+const GROUPS_STORE: &str = "groups";
+const GROUP_RELAYS_STORE: &str = "group_relays";
+const WELCOMES_STORE: &str = "welcomes";
+const PROCESSED_WELCOMES_STORE: &str = "processed_welcomes";
+const MESSAGES_STORE: &str = "messages";
+const PROCESSED_MESSAGES_STORE: &str = "processed_messages";
+const EXPORTER_SECRETS_STORE: &str = "group_exporter_secrets";
+const OPENMLS_STORE: &str = "openmls";
+const PENDING_EVENTS_STORE: &str = "pending_events";
+
+/// Signed events waiting to reach the relays (see outbox.rs); not part of the MDK state
+pub(crate) const OUTBOX_STORE: &str = "outbox";
+
+/// Outbox entries that still have relays to retry, by event ID: when they are due (see outbox.rs)
+pub(crate) const OUTBOX_PENDING_STORE: &str = "outbox_pending";
+
+/// Group state before each applied commit, for rolling back epoch forks (see recovery.rs)
+pub(crate) const CHECKPOINTS_STORE: &str = "epoch_checkpoints";
+
+/// Periods of each group's history fetched from the relays (see history.rs)
+pub(crate) const HISTORY_STORE: &str = "history_coverage";
+
+/// Token messages we claimed (see payments.rs)
+pub(crate) const CLAIMS_STORE: &str = "token_claims";
+
+/// Claim status of the token messages we have seen (see payments.rs)
+pub(crate) const TOKEN_STATUS_STORE: &str = "token_status";
+
+/// Delivery receipts of our group messages, by event ID; unlike outbox entries they don't expire (see outbox.rs)
+pub(crate) const DELIVERY_STORE: &str = "message_delivery";
+
+const ALL_STORES: &[&str] = &[
+    GROUPS_STORE,
+    GROUP_RELAYS_STORE,
+    WELCOMES_STORE,
+    PROCESSED_WELCOMES_STORE,
+    MESSAGES_STORE,
+    PROCESSED_MESSAGES_STORE,
+    EXPORTER_SECRETS_STORE,
+    OPENMLS_STORE,
+    PENDING_EVENTS_STORE,
+];
+
+static SCHEMA: DbSchema = DbSchema {
+    name: DB_NAME,
+    // 2: quarantine store
+    // 3: outbox store
+    // 4: epoch checkpoints store
+    // 5: pending events store
+    // 6: history coverage store
+    // 7: token claims store
+    // 8: token status store
+    // 9: outbox pending index
+    // 10: message delivery store
+    version: 10,
+    upgrade: upgrade_db,
+};
+
+fn upgrade_db(db: &IdbDatabase, _tx: &IdbTransaction, _old_version: u32) -> Result<(), JsValue> {
+    idb::create_missing_stores(db, ALL_STORES)?;
+    idb::create_missing_stores(db, &[QUARANTINE_STORE, OUTBOX_STORE, CHECKPOINTS_STORE, HISTORY_STORE, CLAIMS_STORE, TOKEN_STATUS_STORE, OUTBOX_PENDING_STORE, DELIVERY_STORE])
+}
+
+/// Connection to the MDK database, for records kept next to the MDK state
+pub(crate) async fn database() -> Result<IdbDatabase, JsValue> {
+    idb::connection(&SCHEMA).await
+}
+
+/// localStorage keys used before MDK state moved to IndexedDB
+const LEGACY_MDK_STATE_KEY: &str = "mdk_state";
+const LEGACY_OPENMLS_KEY: &str = "openmls_storage";
+
+fn group_key(group_id: &GroupId) -> String {
+    hex::encode(group_id.as_slice())
+}
+
+fn exporter_secret_key(group_id: &GroupId, epoch: u64) -> String {
+    format!("{}:{}", hex::encode(group_id.as_slice()), epoch)
+}
+
+/// Keys of the MDK records changed since the last successful save
+#[derive(Debug, Default)]
+struct DirtyRecords {
+    groups: HashSet<GroupId>,
+    group_relays: HashSet<GroupId>,
+    welcomes: HashSet<EventId>,
+    processed_welcomes: HashSet<EventId>,
+    messages: HashSet<EventId>,
+    processed_messages: HashSet<EventId>,
+    group_exporter_secrets: HashSet<(GroupId, u64)>,
+    pending_events: HashSet<EventId>,
+}
+
+impl DirtyRecords {
+    /// Every record in `state` - used when migrating a legacy snapshot
+    fn all(state: &MdkState) -> Self {
+        Self {
+            groups: state.groups.keys().cloned().collect(),
+            group_relays: state.group_relays.keys().cloned().collect(),
+            welcomes: state.welcomes.keys().cloned().collect(),
+            processed_welcomes: state.processed_welcomes.keys().cloned().collect(),
+            messages: state.messages.keys().cloned().collect(),
+            processed_messages: state.processed_messages.keys().cloned().collect(),
+            group_exporter_secrets: state.group_exporter_secrets.keys().cloned().collect(),
+            pending_events: state.pending_events.keys().cloned().collect(),
+        }
+    }
+
+    /// Put back keys whose write failed
+    fn merge(&mut self, other: DirtyRecords) {
+        self.groups.extend(other.groups);
+        self.group_relays.extend(other.group_relays);
+        self.welcomes.extend(other.welcomes);
+        self.processed_welcomes.extend(other.processed_welcomes);
+        self.messages.extend(other.messages);
+        self.processed_messages.extend(other.processed_messages);
+        self.group_exporter_secrets.extend(other.group_exporter_secrets);
+        self.pending_events.extend(other.pending_events);
+    }
+}
+
+/// A single put (Some) or delete (None) against one object store
+struct RecordWrite {
+    store: &'static str,
+    key: String,
+    value: Option<JsValue>,
+}
+
+fn json_record<T: Serialize>(store: &str, value: Option<&T>) -> Result<Option<JsValue>, JsValue> {
+    value
+        .map(|v| {
+            let json = schema::encode(store, v)
+                .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+            Ok(JsValue::from_str(&vault::seal(&json)?))
         })
+        .transpose()
+}
+
+/// Read every record of a JSON store, moving records that no longer decode
+/// (e.g. after an mdk serde change) into quarantine instead of failing the load
+pub(crate) async fn read_json_store<T: DeserializeOwned>(db: &IdbDatabase, store: &str) -> Result<HashMap<String, T>, JsValue> {
+    let mut values = HashMap::new();
+
+    for (k, v) in idb::read_all(db, store).await? {
+        let key = k.as_string()
+            .ok_or_else(|| JsValue::from_str(&format!("Non-string key in {}", store)))?;
+        let parsed = v.as_string()
+            .ok_or_else(|| "Non-string value".to_string())
+            .and_then(|stored| vault::open(&stored).map_err(|e| e.to_string()))
+            .and_then(|json| schema::decode(store, &json));
+
+        match parsed {
+            Ok(value) => {
+                values.insert(key, value);
+            }
+            Err(e) => {
+                // A locked vault is not a broken record
+                vault::ensure_unlocked()?;
+                schema::quarantine_record(db, QuarantineEntry::new(DB_NAME, store, &key, &e), &k, &v).await?;
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+/// Rewrite every record of a JSON store kept next to the MDK state under the
+/// vault's current setting (see `MdkHybridStorage::reseal`)
+pub(crate) async fn reseal_json_store(store: &'static str) -> Result<(), JsValue> {
+    let db = idb::connection(&SCHEMA).await?;
+    let records: HashMap<String, serde_json::Value> = read_json_store(&db, store).await?;
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let tx = idb::write_transaction(&db, &[store])?;
+    let object_store = tx.object_store(store)?;
+    for (key, value) in &records {
+        if let Some(sealed) = json_record(store, Some(value))? {
+            object_store.put_with_key(&sealed, &JsValue::from_str(key))?;
+        }
+    }
+    idb::await_transaction(&tx).await
+}
+
+fn value_hash(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl MdkState {
+    /// Build the puts/deletes for every dirty key (absent from state = deleted)
+    fn record_writes(&self, dirty: &DirtyRecords) -> Result<Vec<RecordWrite>, JsValue> {
+        let mut writes = Vec::new();
+
+        for id in &dirty.groups {
+            writes.push(RecordWrite {
+                store: GROUPS_STORE,
+                key: group_key(id),
+                value: json_record(GROUPS_STORE, self.groups.get(id))?,
+            });
+        }
+        for id in &dirty.group_relays {
+            writes.push(RecordWrite {
+                store: GROUP_RELAYS_STORE,
+                key: group_key(id),
+                value: json_record(GROUP_RELAYS_STORE, self.group_relays.get(id))?,
+            });
+        }
+        for id in &dirty.welcomes {
+            writes.push(RecordWrite {
+                store: WELCOMES_STORE,
+                key: id.to_hex(),
+                value: json_record(WELCOMES_STORE, self.welcomes.get(id))?,
+            });
+        }
+        for id in &dirty.processed_welcomes {
+            writes.push(RecordWrite {
+                store: PROCESSED_WELCOMES_STORE,
+                key: id.to_hex(),
+                value: json_record(PROCESSED_WELCOMES_STORE, self.processed_welcomes.get(id))?,
+            });
+        }
+        for id in &dirty.messages {
+            writes.push(RecordWrite {
+                store: MESSAGES_STORE,
+                key: id.to_hex(),
+                value: json_record(MESSAGES_STORE, self.messages.get(id))?,
+            });
+        }
+        for id in &dirty.processed_messages {
+            writes.push(RecordWrite {
+                store: PROCESSED_MESSAGES_STORE,
+                key: id.to_hex(),
+                value: json_record(PROCESSED_MESSAGES_STORE, self.processed_messages.get(id))?,
+            });
+        }
+        for (gid, epoch) in &dirty.group_exporter_secrets {
+            writes.push(RecordWrite {
+                store: EXPORTER_SECRETS_STORE,
+                key: exporter_secret_key(gid, *epoch),
+                value: json_record(EXPORTER_SECRETS_STORE, self.group_exporter_secrets.get(&(gid.clone(), *epoch)))?,
+            });
+        }
+        for id in &dirty.pending_events {
+            writes.push(RecordWrite {
+                store: PENDING_EVENTS_STORE,
+                key: id.to_hex(),
+                value: json_record(PENDING_EVENTS_STORE, self.pending_events.get(id))?,
+            });
+        }
+
+        Ok(writes)
     }
 }
 
@@ -144,19 +416,20 @@ pub struct MdkHybridStorage {
     state: Mutex<MdkState>,
     // Use Mutex for interior mutability - the entire struct will be wrapped in Arc
     openmls_storage: Mutex<MemoryStorage>,
+    /// MDK records changed since the last successful save_snapshot
+    dirty: Mutex<DirtyRecords>,
+    /// Hash of each OpenMLS value as last written to IndexedDB, diffed on save
+    /// (OpenMLS writes straight into MemoryStorage, so we can't track changes as they happen)
+    persisted_openmls: Mutex<HashMap<Vec<u8>, u64>>,
 }
 
 impl MdkHybridStorage {
-    /// Load MDK state from localStorage
-    fn load_mdk_state() -> Result<MdkState, JsValue> {
-        let storage = window()
-            .ok_or_else(|| JsValue::from_str("No window"))?
-            .local_storage()?
-            .ok_or_else(|| JsValue::from_str("No localStorage"))?;
-
-        let json = storage
-            .get_item("mdk_state")?
-            .ok_or_else(|| JsValue::from_str("No MDK state found"))?;
+    /// Load MDK state from the pre-IndexedDB localStorage snapshot
+    fn load_legacy_mdk_state(storage: &Storage) -> Result<Option<MdkState>, JsValue> {
+        let json = match storage.get_item(LEGACY_MDK_STATE_KEY)? {
+            Some(json) => json,
+            None => return Ok(None),
+        };
 
         let serializable: SerializableState = serde_json::from_str(&json)
             .map_err(|e| JsValue::from_str(&format!("Deserialization error: {}", e)))?;
@@ -164,38 +437,14 @@ impl MdkHybridStorage {
         let state = MdkState::from_serializable(serializable)
             .map_err(|e| JsValue::from_str(&format!("State conversion error: {}", e)))?;
 
-        Ok(state)
-    }
-
-    /// Save MDK state to localStorage
-    fn save_mdk_state(&self) -> Result<(), JsValue> {
-        let state = self.state.lock().unwrap();
-        let serializable = state.to_serializable();
-        let json = serde_json::to_string(&serializable)
-            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
-
-        let storage = window()
-            .ok_or_else(|| JsValue::from_str("No window"))?
-            .local_storage()?
-            .ok_or_else(|| JsValue::from_str("No localStorage"))?;
-
-        storage.set_item("mdk_state", &json)?;
-        Ok(())
+        Ok(Some(state))
     }
 
-    /// Load OpenMLS MemoryStorage from localStorage
-    fn load_openmls_storage() -> Result<MemoryStorage, JsValue> {
-        let storage = window()
-            .ok_or_else(|| JsValue::from_str("No window"))?
-            .local_storage()?
-            .ok_or_else(|| JsValue::from_str("No localStorage"))?;
-
-        let base64_data = match storage.get_item("openmls_storage")? {
+    /// Load OpenMLS values from the pre-IndexedDB localStorage snapshot
+    fn load_legacy_openmls_storage(storage: &Storage) -> Result<Option<HashMap<Vec<u8>, Vec<u8>>>, JsValue> {
+        let base64_data = match storage.get_item(LEGACY_OPENMLS_KEY)? {
             Some(data) => data,
-            None => {
-                log("No OpenMLS storage found, starting fresh");
-                return Ok(MemoryStorage::default());
-            }
+            None => return Ok(None),
         };
 
         // Decode from base64
@@ -204,11 +453,11 @@ impl MdkHybridStorage {
             .map_err(|e| JsValue::from_str(&format!("Base64 decode error: {}", e)))?;
 
         // Deserialize the HashMap with hex string keys
-        let string_map: std::collections::HashMap<String, String> = serde_json::from_slice(&bytes)
+        let string_map: HashMap<String, String> = serde_json::from_slice(&bytes)
             .map_err(|e| JsValue::from_str(&format!("Failed to deserialize OpenMLS storage: {}", e)))?;
 
         // Convert hex string keys/values back to Vec<u8>
-        let map: std::collections::HashMap<Vec<u8>, Vec<u8>> = string_map
+        let map = string_map
             .into_iter()
             .map(|(k, v)| {
                 let key = hex::decode(&k).map_err(|e| JsValue::from_str(&format!("Failed to decode key: {}", e)))?;
@@ -217,81 +466,603 @@ impl MdkHybridStorage {
             })
             .collect::<Result<_, JsValue>>()?;
 
-        // Create MemoryStorage from the HashMap
-        let memory_storage = MemoryStorage {
-            values: std::sync::RwLock::new(map),
-        };
-
-        log(&format!("Loaded OpenMLS storage with {} entries", memory_storage.values.read().unwrap().len()));
-
-        Ok(memory_storage)
+        Ok(Some(map))
     }
 
-    /// Save OpenMLS MemoryStorage to localStorage
-    fn save_openmls_storage(&self) -> Result<(), JsValue> {
-        // Get the values from MemoryStorage
-        let storage = self.openmls_storage.lock().unwrap();
-        let values = storage.values.read().unwrap();
+    /// Load every MDK record and OpenMLS entry from IndexedDB
+    async fn load_from_indexeddb() -> Result<(MdkState, HashMap<Vec<u8>, Vec<u8>>), JsValue> {
+        let db = idb::connection(&SCHEMA).await?;
 
-        log(&format!("Saving OpenMLS storage with {} entries", values.len()));
+        let groups: HashMap<String, Group> = read_json_store(&db, GROUPS_STORE).await?;
+        let messages: HashMap<String, Message> = read_json_store(&db, MESSAGES_STORE).await?;
 
-        // Convert Vec<u8> keys/values to hex strings for JSON compatibility
-        let string_map: std::collections::HashMap<String, String> = values
-            .iter()
-            .map(|(k, v)| (hex::encode(k), hex::encode(v)))
+        // groups_by_nostr_id and messages_by_group are indexes over the stored records
+        let groups_by_nostr_id = groups.values()
+            .map(|g| (hex::encode(g.nostr_group_id), g.clone()))
             .collect();
 
-        // Serialize the HashMap with string keys to JSON
-        let bytes = serde_json::to_vec(&string_map)
-            .map_err(|e| JsValue::from_str(&format!("Failed to serialize OpenMLS storage: {}", e)))?;
+        let mut messages_by_group: HashMap<String, Vec<Message>> = HashMap::new();
+        for message in messages.values() {
+            messages_by_group
+                .entry(group_key(&message.mls_group_id))
+                .or_default()
+                .push(message.clone());
+        }
+        for group_messages in messages_by_group.values_mut() {
+            group_messages.sort_by_key(|m| m.created_at);
+        }
 
-        // Encode to base64 for localStorage
-        use base64::{Engine as _, engine::general_purpose};
-        let base64_data = general_purpose::STANDARD.encode(&bytes);
+        let serializable = SerializableState {
+            groups,
+            groups_by_nostr_id,
+            group_relays: read_json_store(&db, GROUP_RELAYS_STORE).await?,
+            welcomes: read_json_store(&db, WELCOMES_STORE).await?,
+            processed_welcomes: read_json_store(&db, PROCESSED_WELCOMES_STORE).await?,
+            messages,
+            messages_by_group,
+            processed_messages: read_json_store(&db, PROCESSED_MESSAGES_STORE).await?,
+            group_exporter_secrets: read_json_store(&db, EXPORTER_SECRETS_STORE).await?,
+        };
 
-        let storage = window()
-            .ok_or_else(|| JsValue::from_str("No window"))?
-            .local_storage()?
-            .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+        let mut state = MdkState::from_serializable(serializable)
+            .map_err(|e| JsValue::from_str(&format!("State conversion error: {}", e)))?;
 
-        storage.set_item("openmls_storage", &base64_data)?;
+        let pending: HashMap<String, PendingEvent> = read_json_store(&db, PENDING_EVENTS_STORE).await?;
+        state.pending_events = pending.into_values()
+            .map(|pending| (pending.event.id, pending))
+            .collect();
 
-        Ok(())
+        // OpenMLS entries: hex key -> bytes, stored as versioned records of the hex value.
+        // Older entries hold the raw bytes (encrypted as a string, or plain binary); they
+        // are read as version 1 and migrated
+        let mut openmls_values = HashMap::new();
+        for (k, v) in idb::read_all(&db, OPENMLS_STORE).await? {
+            let key_hex = k.as_string()
+                .ok_or_else(|| JsValue::from_str("Non-string key in openmls store"))?;
+            let entry = hex::decode(&key_hex)
+                .map_err(|e| format!("Failed to decode key: {}", e))
+                .and_then(|key| {
+                    let stored = match v.as_string() {
+                        Some(stored) if vault::is_sealed(&stored) => vault::decrypt(&stored).map_err(|e| e.to_string())?,
+                        Some(stored) => stored.into_bytes(),
+                        None => v.clone().dyn_into::<Uint8Array>()
+                            .map_err(|_| "Non-binary value".to_string())?
+                            .to_vec(),
+                    };
+                    let record = serde_json::from_slice(&stored).ok()
+                        .filter(schema::is_envelope)
+                        .unwrap_or_else(|| serde_json::Value::from(stored));
+                    let value_hex: String = schema::decode_value(OPENMLS_STORE, record)?;
+                    let value = hex::decode(value_hex).map_err(|e| format!("Failed to decode value: {}", e))?;
+                    Ok((key, value))
+                });
+
+            match entry {
+                Ok((key, value)) => {
+                    openmls_values.insert(key, value);
+                }
+                Err(e) => {
+                    vault::ensure_unlocked()?;
+                    let entry = QuarantineEntry::new(DB_NAME, OPENMLS_STORE, &key_hex, &e);
+                    schema::quarantine_record(&db, entry, &k, &v).await?;
+                }
+            }
+        }
+
+        Ok((state, openmls_values))
     }
 
     pub async fn new() -> Result<Self, JsValue> {
-        // Load MDK state (group metadata, messages, etc.)
-        let state = match Self::load_mdk_state() {
-            Ok(state) => {
-                log("Loaded MDK state from localStorage");
-                state
+        vault::ensure_unlocked()?;
+
+        let (mut state, mut openmls_values) = Self::load_from_indexeddb().await?;
+        let mut dirty = DirtyRecords::default();
+        let mut migrated_legacy = false;
+
+        // First run after the move to IndexedDB: import the old localStorage snapshot
+        if state.groups.is_empty() && state.welcomes.is_empty() && openmls_values.is_empty() {
+            let storage = window()
+                .ok_or_else(|| JsValue::from_str("No window"))?
+                .local_storage()?
+                .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+
+            match Self::load_legacy_mdk_state(&storage) {
+                Ok(Some(legacy_state)) => {
+                    dirty = DirtyRecords::all(&legacy_state);
+                    state = legacy_state;
+                    migrated_legacy = true;
+                }
+                Ok(None) => {}
+                Err(e) => schema::quarantine_local(&storage, LEGACY_MDK_STATE_KEY, &format!("{:?}", e))?,
             }
-            Err(_) => {
-                log("No existing MDK state, starting fresh");
-                MdkState::default()
+
+            match Self::load_legacy_openmls_storage(&storage) {
+                Ok(Some(legacy_openmls)) => {
+                    openmls_values = legacy_openmls;
+                    migrated_legacy = true;
+                }
+                Ok(None) => {}
+                Err(e) => schema::quarantine_local(&storage, LEGACY_OPENMLS_KEY, &format!("{:?}", e))?,
             }
+        }
+
+        // Everything loaded from IndexedDB is already persisted; legacy values are not
+        let persisted_openmls = if migrated_legacy {
+            HashMap::new()
+        } else {
+            openmls_values.iter().map(|(k, v)| (k.clone(), value_hash(v))).collect()
         };
 
-        // Load OpenMLS storage (MLS encryption state)
-        let openmls_storage = Self::load_openmls_storage()?;
+        log(&format!(
+            "Loaded MDK storage: {} group(s), {} message(s), {} OpenMLS entries",
+            state.groups.len(),
+            state.messages.len(),
+            openmls_values.len()
+        ));
 
         let storage = Self {
             state: Mutex::new(state),
-            openmls_storage: Mutex::new(openmls_storage),
+            openmls_storage: Mutex::new(MemoryStorage {
+                values: std::sync::RwLock::new(openmls_values),
+            }),
+            dirty: Mutex::new(dirty),
+            persisted_openmls: Mutex::new(persisted_openmls),
         };
 
-        // No need to save on init - we just loaded from localStorage
+        if migrated_legacy {
+            log("Migrating MDK state from localStorage to IndexedDB...");
+            storage.save_snapshot().await?;
+
+            // Only drop the old snapshot once IndexedDB has it
+            let local_storage = window()
+                .ok_or_else(|| JsValue::from_str("No window"))?
+                .local_storage()?
+                .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+            local_storage.remove_item(LEGACY_MDK_STATE_KEY)?;
+            local_storage.remove_item(LEGACY_OPENMLS_KEY)?;
+            log("✓ Migrated MDK state to IndexedDB");
+        }
+
         log("Initialized MDK storage");
 
         Ok(storage)
     }
 
-    pub fn save_snapshot(&self) -> Result<(), JsValue> {
-        // Save both MDK state and OpenMLS storage
-        self.save_mdk_state()?;
-        self.save_openmls_storage()?;
+    fn mark_dirty(&self, f: impl FnOnce(&mut DirtyRecords)) {
+        f(&mut self.dirty.lock().unwrap());
+    }
+
+    /// Diff the OpenMLS map against what was last persisted.
+    /// Returns the writes plus the new hash (None = removed) for each changed key.
+    fn openmls_writes(&self) -> Result<(Vec<RecordWrite>, Vec<(Vec<u8>, Option<u64>)>), JsValue> {
+        let storage = self.openmls_storage.lock().unwrap();
+        let values = storage.values.read().unwrap();
+        let persisted = self.persisted_openmls.lock().unwrap();
+
+        let mut writes = Vec::new();
+        let mut changes = Vec::new();
+
+        for (key, value) in values.iter() {
+            let hash = value_hash(value);
+            if persisted.get(key) != Some(&hash) {
+                writes.push(RecordWrite {
+                    store: OPENMLS_STORE,
+                    key: hex::encode(key),
+                    value: json_record(OPENMLS_STORE, Some(&hex::encode(value)))?,
+                });
+                changes.push((key.clone(), Some(hash)));
+            }
+        }
+
+        for key in persisted.keys() {
+            if !values.contains_key(key) {
+                writes.push(RecordWrite {
+                    store: OPENMLS_STORE,
+                    key: hex::encode(key),
+                    value: None,
+                });
+                changes.push((key.clone(), None));
+            }
+        }
+
+        Ok((writes, changes))
+    }
+
+    /// Apply all writes in a single readwrite transaction
+    async fn write_records(writes: &[RecordWrite]) -> Result<(), JsValue> {
+        let db = idb::connection(&SCHEMA).await?;
+        let tx = idb::write_transaction(&db, ALL_STORES)?;
+
+        let queued = writes.iter().try_for_each(|write| {
+            let store = tx.object_store(write.store)?;
+            let key = JsValue::from_str(&write.key);
+            match &write.value {
+                Some(value) => store.put_with_key(value, &key).map(|_| ()),
+                None => store.delete(&key).map(|_| ()),
+            }
+        });
+
+        if let Err(e) = queued {
+            let _ = tx.abort();
+            return Err(e);
+        }
+
+        idb::await_transaction(&tx).await
+    }
+
+    /// Persist every record changed since the last save (and nothing else)
+    pub async fn save_snapshot(&self) -> Result<(), JsValue> {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+
+        let mut writes = match self.state.lock().unwrap().record_writes(&dirty) {
+            Ok(writes) => writes,
+            Err(e) => {
+                self.mark_dirty(|d| d.merge(dirty));
+                return Err(e);
+            }
+        };
+
+        let (openmls_writes, openmls_changes) = match self.openmls_writes() {
+            Ok(result) => result,
+            Err(e) => {
+                self.mark_dirty(|d| d.merge(dirty));
+                return Err(e);
+            }
+        };
+        writes.extend(openmls_writes);
+
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        match Self::write_records(&writes).await {
+            Ok(()) => {
+                let mut persisted = self.persisted_openmls.lock().unwrap();
+                for (key, hash) in openmls_changes {
+                    match hash {
+                        Some(hash) => persisted.insert(key, hash),
+                        None => persisted.remove(&key),
+                    };
+                }
+                log(&format!("Saved {} changed storage record(s)", writes.len()));
+                Ok(())
+            }
+            Err(e) => {
+                // Keep the keys dirty so the next save retries them
+                self.mark_dirty(|d| d.merge(dirty));
+                Err(e)
+            }
+        }
+    }
+}
+
+impl MdkHybridStorage {
+    /// Rewrite every record so it is stored under the vault's current setting
+    /// (encrypted after a passphrase is set, plaintext after it is removed)
+    pub async fn reseal(&self) -> Result<(), JsValue> {
+        // Flush pending changes first so OpenMLS deletions are not lost when the hashes are reset
+        self.save_snapshot().await?;
+
+        let all = DirtyRecords::all(&self.state.lock().unwrap());
+        self.mark_dirty(|d| d.merge(all));
+        let persisted = std::mem::take(&mut *self.persisted_openmls.lock().unwrap());
+
+        let result = self.save_snapshot().await;
+        if result.is_err() {
+            *self.persisted_openmls.lock().unwrap() = persisted;
+        }
+        result
+    }
+}
+
+/// A group's MDK records and OpenMLS entries at one epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GroupSnapshot {
+    group: Group,
+    relays: BTreeSet<GroupRelay>,
+    exporter_secrets: Vec<GroupExporterSecret>,
+    /// OpenMLS entries of the group, hex key -> hex value
+    openmls: HashMap<String, String>,
+}
+
+impl GroupSnapshot {
+    pub(crate) fn epoch(&self) -> u64 {
+        self.group.epoch
+    }
+
+    pub(crate) fn nostr_group_id(&self) -> [u8; 32] {
+        self.group.nostr_group_id
+    }
+}
+
+/// OpenMLS keys of a group's records all contain its JSON-serialized group ID
+fn openmls_group_marker(group_id: &GroupId) -> Vec<u8> {
+    serde_json::to_vec(&openmls::group::GroupId::from_slice(group_id.as_slice()))
+        .expect("group ID serializes")
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+impl MdkHybridStorage {
+    /// Copy everything stored for a group (None if we aren't in it)
+    pub(crate) fn snapshot_group(&self, group_id: &GroupId) -> Option<GroupSnapshot> {
+        let state = self.state.lock().unwrap();
+        let group = state.groups.get(group_id)?.clone();
+
+        let marker = openmls_group_marker(group_id);
+        let storage = self.openmls_storage.lock().unwrap();
+        let openmls = storage.values.read().unwrap().iter()
+            .filter(|(key, _)| contains_bytes(key, &marker))
+            .map(|(key, value)| (hex::encode(key), hex::encode(value)))
+            .collect();
+
+        Some(GroupSnapshot {
+            group,
+            relays: state.group_relays.get(group_id).cloned().unwrap_or_default(),
+            exporter_secrets: state.group_exporter_secrets.iter()
+                .filter(|((gid, _), _)| gid == group_id)
+                .map(|(_, secret)| secret.clone())
+                .collect(),
+            openmls,
+        })
+    }
+
+    /// Put a group back the way it was in `snapshot`, dropping whatever it gained since.
+    /// Messages are kept; call `save_snapshot` to persist.
+    pub(crate) fn restore_group(&self, snapshot: &GroupSnapshot) -> Result<(), JsValue> {
+        let group_id = snapshot.group.mls_group_id.clone();
+
+        let openmls: HashMap<Vec<u8>, Vec<u8>> = snapshot.openmls.iter()
+            .map(|(key, value)| Ok((hex::decode(key)?, hex::decode(value)?)))
+            .collect::<Result<_, hex::FromHexError>>()
+            .map_err(|e| JsValue::from_str(&format!("Invalid group snapshot: {}", e)))?;
+
+        let marker = openmls_group_marker(&group_id);
+        {
+            let storage = self.openmls_storage.lock().unwrap();
+            let mut values = storage.values.write().unwrap();
+            values.retain(|key, _| !contains_bytes(key, &marker));
+            values.extend(openmls);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let mut dirty = DirtyRecords::default();
+
+        let stale_secrets: Vec<(GroupId, u64)> = state.group_exporter_secrets.keys()
+            .filter(|(gid, _)| gid == &group_id)
+            .cloned()
+            .collect();
+        for key in stale_secrets {
+            state.group_exporter_secrets.remove(&key);
+            dirty.group_exporter_secrets.insert(key);
+        }
+        for secret in &snapshot.exporter_secrets {
+            let key = (group_id.clone(), secret.epoch);
+            state.group_exporter_secrets.insert(key.clone(), secret.clone());
+            dirty.group_exporter_secrets.insert(key);
+        }
+
+        state.group_relays.insert(group_id.clone(), snapshot.relays.clone());
+        dirty.group_relays.insert(group_id.clone());
+
+        state.groups_by_nostr_id.insert(snapshot.group.nostr_group_id, snapshot.group.clone());
+        state.groups.insert(group_id.clone(), snapshot.group.clone());
+        dirty.groups.insert(group_id);
+
+        drop(state);
+        self.mark_dirty(|d| d.merge(dirty));
         Ok(())
     }
+
+    /// Forget that events were processed, so they are processed again when replayed.
+    /// Our own events (state Created) are kept: OpenMLS can't process those.
+    pub(crate) fn forget_processed_messages(&self, event_ids: &[EventId]) {
+        let mut state = self.state.lock().unwrap();
+        let mut dirty = DirtyRecords::default();
+        for event_id in event_ids {
+            let forget = state.processed_messages.get(event_id)
+                .is_some_and(|processed| processed.state != ProcessedMessageState::Created);
+            if forget {
+                state.processed_messages.remove(event_id);
+                dirty.processed_messages.insert(*event_id);
+            }
+        }
+        drop(state);
+        self.mark_dirty(|d| d.merge(dirty));
+    }
+}
+
+/// Keep events waiting for an epoch this long at most
+const PENDING_EVENT_MAX_AGE_SECS: u64 = 3 * 24 * 60 * 60;
+
+/// And at most this many per group (the oldest go first)
+const MAX_PENDING_EVENTS_PER_GROUP: usize = 200;
+
+/// A group event that couldn't be processed yet, e.g. a message from an epoch
+/// whose commit hasn't arrived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingEvent {
+    group_id: GroupId,
+    event: Event,
+    /// Unix seconds of when it was first queued
+    queued_at: u64,
+    /// Times processing it failed
+    attempts: u32,
+}
+
+impl MdkHybridStorage {
+    /// Queue a group event that failed to process, to try again when the group's epoch advances.
+    /// Returns how many events the group has waiting.
+    pub(crate) fn queue_pending_event(&self, group_id: &GroupId, event: &Event) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut dirty = DirtyRecords::default();
+        Self::expire_pending_events(&mut state, &mut dirty);
+
+        match state.pending_events.get_mut(&event.id) {
+            Some(pending) => pending.attempts += 1,
+            None => {
+                state.pending_events.insert(event.id, PendingEvent {
+                    group_id: group_id.clone(),
+                    event: event.clone(),
+                    queued_at: Timestamp::now().as_u64(),
+                    attempts: 1,
+                });
+            }
+        }
+        dirty.pending_events.insert(event.id);
+
+        let mut queued: Vec<(u64, EventId)> = state.pending_events.values()
+            .filter(|pending| &pending.group_id == group_id)
+            .map(|pending| (pending.queued_at, pending.event.id))
+            .collect();
+        queued.sort();
+        let excess = queued.len().saturating_sub(MAX_PENDING_EVENTS_PER_GROUP);
+        for (_, id) in &queued[..excess] {
+            state.pending_events.remove(id);
+            dirty.pending_events.insert(*id);
+        }
+
+        drop(state);
+        self.mark_dirty(|d| d.merge(dirty));
+        queued.len() - excess
+    }
+
+    /// A group's waiting events (oldest first), dropping any that waited too long
+    pub(crate) fn pending_events(&self, group_id: &GroupId) -> Vec<Event> {
+        let mut state = self.state.lock().unwrap();
+        let mut dirty = DirtyRecords::default();
+        Self::expire_pending_events(&mut state, &mut dirty);
+
+        let mut pending: Vec<&PendingEvent> = state.pending_events.values()
+            .filter(|pending| &pending.group_id == group_id)
+            .collect();
+        pending.sort_by_key(|pending| pending.queued_at);
+        let events = pending.into_iter().map(|pending| pending.event.clone()).collect();
+
+        drop(state);
+        self.mark_dirty(|d| d.merge(dirty));
+        events
+    }
+
+    pub(crate) fn is_pending_event(&self, event_id: &EventId) -> bool {
+        self.state.lock().unwrap().pending_events.contains_key(event_id)
+    }
+
+    /// Drop an event from the queue once it was processed
+    pub(crate) fn remove_pending_event(&self, event_id: &EventId) {
+        if self.state.lock().unwrap().pending_events.remove(event_id).is_some() {
+            self.mark_dirty(|d| {
+                d.pending_events.insert(*event_id);
+            });
+        }
+    }
+
+    fn expire_pending_events(state: &mut MdkState, dirty: &mut DirtyRecords) {
+        let cutoff = Timestamp::now().as_u64().saturating_sub(PENDING_EVENT_MAX_AGE_SECS);
+        let expired: Vec<EventId> = state.pending_events.values()
+            .filter(|pending| pending.queued_at < cutoff)
+            .map(|pending| pending.event.id)
+            .collect();
+        for id in expired {
+            log(&format!("Dropping event {} that waited too long for its epoch", id.to_hex()));
+            state.pending_events.remove(&id);
+            dirty.pending_events.insert(id);
+        }
+    }
+}
+
+/// Version of the layout produced by `export_backup`
+pub const BACKUP_VERSION: u32 = 1;
+
+/// MDK state plus the OpenMLS map (hex key -> hex value), as stored in backups
+#[derive(Serialize, Deserialize)]
+struct MdkBackup {
+    state: SerializableState,
+    openmls: HashMap<String, String>,
+}
+
+/// A backup that parsed, ready to be written by `import_backup`
+pub struct MdkRestore {
+    state: MdkState,
+    openmls: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MdkHybridStorage {
+    /// Everything needed to rebuild this storage elsewhere (see `import_backup`)
+    pub fn export_backup(&self) -> Result<serde_json::Value, JsValue> {
+        let state = self.state.lock().unwrap().to_serializable();
+        let openmls = self.openmls_storage.lock().unwrap()
+            .values.read().unwrap()
+            .iter()
+            .map(|(k, v)| (hex::encode(k), hex::encode(v)))
+            .collect();
+
+        serde_json::to_value(MdkBackup { state, openmls })
+            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
+    }
+
+    /// Parse a backup from `export_backup` without writing anything
+    pub fn parse_backup(backup: serde_json::Value) -> Result<MdkRestore, JsValue> {
+        let backup: MdkBackup = serde_json::from_value(backup)
+            .map_err(|e| JsValue::from_str(&format!("Invalid MDK backup: {}", e)))?;
+
+        let state = MdkState::from_serializable(backup.state)
+            .map_err(|e| JsValue::from_str(&format!("State conversion error: {}", e)))?;
+        let openmls = backup.openmls
+            .into_iter()
+            .map(|(k, v)| {
+                let key = hex::decode(&k).map_err(|e| JsValue::from_str(&format!("Failed to decode key: {}", e)))?;
+                let value = hex::decode(&v).map_err(|e| JsValue::from_str(&format!("Failed to decode value: {}", e)))?;
+                Ok((key, value))
+            })
+            .collect::<Result<HashMap<_, _>, JsValue>>()?;
+
+        Ok(MdkRestore { state, openmls })
+    }
+
+    /// Write a parsed backup into IndexedDB.
+    /// Call delete_persisted_state first: records not in the backup are left alone.
+    /// Returns the number of groups and messages restored.
+    pub async fn import_backup(backup: MdkRestore) -> Result<(usize, usize), JsValue> {
+        let MdkRestore { state, openmls: openmls_values } = backup;
+
+        let counts = (state.groups.len(), state.messages.len());
+
+        // Nothing is persisted yet, so the first save writes every record
+        let storage = Self {
+            dirty: Mutex::new(DirtyRecords::all(&state)),
+            state: Mutex::new(state),
+            openmls_storage: Mutex::new(MemoryStorage {
+                values: std::sync::RwLock::new(openmls_values),
+            }),
+            persisted_openmls: Mutex::new(HashMap::new()),
+        };
+        storage.save_snapshot().await?;
+
+        Ok(counts)
+    }
+}
+
+/// Records moved to quarantine because they couldn't be read
+pub async fn quarantined() -> Result<Vec<QuarantineEntry>, JsValue> {
+    let db = idb::connection(&SCHEMA).await?;
+    schema::quarantined_records(&db).await
+}
+
+/// Delete all persisted MDK state (IndexedDB and any legacy localStorage snapshot).
+/// Call clear_storage_cache first so nothing re-saves the old in-memory state.
+pub fn delete_persisted_state() -> Result<(), JsValue> {
+    idb::delete_database(DB_NAME)?;
+
+    let storage = window()
+        .ok_or_else(|| JsValue::from_str("No window"))?
+        .local_storage()?
+        .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+    storage.remove_item(LEGACY_MDK_STATE_KEY)?;
+    storage.remove_item(LEGACY_OPENMLS_KEY)?;
+    Ok(())
 }
 
 fn log(msg: &str) {
@@ -321,6 +1092,7 @@ impl GroupStorage for MdkHybridStorage {
     fn save_group(&self, group: Group) -> Result<(), GroupError> {
         let mut state = self.state.lock().unwrap();
         state.groups_by_nostr_id.insert(group.nostr_group_id, group.clone());
+        self.mark_dirty(|d| { d.groups.insert(group.mls_group_id.clone()); });
         state.groups.insert(group.mls_group_id.clone(), group);
         // Don't auto-save - will be saved explicitly at strategic points
         Ok(())
@@ -363,6 +1135,7 @@ impl GroupStorage for MdkHybridStorage {
             })
             .collect();
         state.group_relays.insert(group_id.clone(), group_relays);
+        self.mark_dirty(|d| { d.group_relays.insert(group_id.clone()); });
         // Don't auto-save - will be saved explicitly at strategic points
         Ok(())
     }
@@ -383,6 +1156,7 @@ impl GroupStorage for MdkHybridStorage {
         group_exporter_secret: GroupExporterSecret,
     ) -> Result<(), GroupError> {
         let key = (group_exporter_secret.mls_group_id.clone(), group_exporter_secret.epoch);
+        self.mark_dirty(|d| { d.group_exporter_secrets.insert(key.clone()); });
         self.state.lock().unwrap()
             .group_exporter_secrets
             .insert(key, group_exporter_secret);
@@ -398,6 +1172,7 @@ impl MessageStorage for MdkHybridStorage {
 
         // Add to messages by event ID
         state.messages.insert(message.id, message.clone());
+        self.mark_dirty(|d| { d.messages.insert(message.id); });
 
         // Add to messages by group (with deduplication)
         let group_messages = state.messages_by_group
@@ -425,6 +1200,7 @@ impl MessageStorage for MdkHybridStorage {
         &self,
         processed_message: ProcessedMessage,
     ) -> Result<(), MessageError> {
+        self.mark_dirty(|d| { d.processed_messages.insert(processed_message.wrapper_event_id); });
         self.state.lock().unwrap()
             .processed_messages
             .insert(processed_message.wrapper_event_id, processed_message);
@@ -446,6 +1222,7 @@ impl MessageStorage for MdkHybridStorage {
 // Implement WelcomeStorage trait
 impl WelcomeStorage for MdkHybridStorage {
     fn save_welcome(&self, welcome: Welcome) -> Result<(), WelcomeError> {
+        self.mark_dirty(|d| { d.welcomes.insert(welcome.id); });
         self.state.lock().unwrap()
             .welcomes
             .insert(welcome.id, welcome);
@@ -471,6 +1248,7 @@ impl WelcomeStorage for MdkHybridStorage {
         &self,
         processed_welcome: ProcessedWelcome,
     ) -> Result<(), WelcomeError> {
+        self.mark_dirty(|d| { d.processed_welcomes.insert(processed_welcome.wrapper_event_id); });
         self.state.lock().unwrap()
             .processed_welcomes
             .insert(processed_welcome.wrapper_event_id, processed_welcome);
@@ -635,3 +1413,127 @@ impl MdkStorageProvider for SharedMdkStorage {
         }
     }
 }
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, Keys};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn local_storage() -> Storage {
+        window().unwrap().local_storage().unwrap().unwrap()
+    }
+
+    /// MDK storage over empty object stores, with no legacy snapshot left by earlier tests
+    async fn empty_storage() -> MdkHybridStorage {
+        let db = idb::connection(&SCHEMA).await.unwrap();
+        let tx = idb::write_transaction(&db, ALL_STORES).unwrap();
+        for store in ALL_STORES {
+            tx.object_store(store).unwrap().clear().unwrap();
+        }
+        idb::await_transaction(&tx).await.unwrap();
+        local_storage().remove_item(LEGACY_MDK_STATE_KEY).unwrap();
+        local_storage().remove_item(LEGACY_OPENMLS_KEY).unwrap();
+
+        MdkHybridStorage::new().await.unwrap()
+    }
+
+    async fn stored(store: &str) -> HashMap<String, JsValue> {
+        let db = idb::connection(&SCHEMA).await.unwrap();
+        idb::read_all(&db, store).await.unwrap()
+            .into_iter()
+            .map(|(k, v)| (k.as_string().unwrap(), v))
+            .collect()
+    }
+
+    fn set_openmls(storage: &MdkHybridStorage, key: &[u8], value: Option<&[u8]>) {
+        let openmls = storage.openmls_storage.lock().unwrap();
+        let mut values = openmls.values.write().unwrap();
+        match value {
+            Some(value) => values.insert(key.to_vec(), value.to_vec()),
+            None => values.remove(key),
+        };
+    }
+
+    fn event(content: &str) -> Event {
+        EventBuilder::text_note(content).sign_with_keys(&Keys::generate()).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    async fn save_writes_only_the_records_that_changed() {
+        let storage = empty_storage().await;
+        let group_id = GroupId::from_slice(&[7; 32]);
+        let waiting = event("waiting");
+
+        set_openmls(&storage, b"a", Some(&[1]));
+        set_openmls(&storage, b"b", Some(&[2]));
+        storage.queue_pending_event(&group_id, &waiting);
+        storage.save_snapshot().await.unwrap();
+        assert_eq!(stored(OPENMLS_STORE).await.len(), 2);
+        assert!(stored(PENDING_EVENTS_STORE).await.contains_key(&waiting.id.to_hex()));
+
+        // Mark the stored records, so a rewrite of any of them shows
+        let db = idb::connection(&SCHEMA).await.unwrap();
+        let tx = idb::write_transaction(&db, ALL_STORES).unwrap();
+        let untouched = JsValue::from_str("untouched");
+        tx.object_store(OPENMLS_STORE).unwrap().put_with_key(&untouched, &JsValue::from_str(&hex::encode(b"a"))).unwrap();
+        tx.object_store(OPENMLS_STORE).unwrap().put_with_key(&untouched, &JsValue::from_str(&hex::encode(b"b"))).unwrap();
+        tx.object_store(PENDING_EVENTS_STORE).unwrap().put_with_key(&untouched, &JsValue::from_str(&waiting.id.to_hex())).unwrap();
+        idb::await_transaction(&tx).await.unwrap();
+
+        // Nothing changed: nothing is written
+        storage.save_snapshot().await.unwrap();
+        assert!(stored(OPENMLS_STORE).await.values().all(|v| v == &untouched));
+
+        // One OpenMLS value changed: only its record is written
+        set_openmls(&storage, b"a", Some(&[3]));
+        storage.save_snapshot().await.unwrap();
+        let openmls = stored(OPENMLS_STORE).await;
+        assert_ne!(openmls[&hex::encode(b"a")], untouched);
+        assert_eq!(openmls[&hex::encode(b"b")], untouched);
+        assert_eq!(stored(PENDING_EVENTS_STORE).await[&waiting.id.to_hex()], untouched);
+
+        // Removals delete their records
+        set_openmls(&storage, b"a", None);
+        storage.remove_pending_event(&waiting.id);
+        storage.save_snapshot().await.unwrap();
+        assert_eq!(stored(OPENMLS_STORE).await.keys().collect::<Vec<_>>(), vec![&hex::encode(b"b")]);
+        assert!(stored(PENDING_EVENTS_STORE).await.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn reload_reads_back_every_record() {
+        let storage = empty_storage().await;
+        let group_id = GroupId::from_slice(&[8; 32]);
+        let waiting = event("still waiting");
+
+        set_openmls(&storage, b"key", Some(&[0, 1, 2, 255]));
+        storage.queue_pending_event(&group_id, &waiting);
+        storage.save_snapshot().await.unwrap();
+
+        let reloaded = MdkHybridStorage::new().await.unwrap();
+        let values = reloaded.openmls_storage.lock().unwrap().values.read().unwrap().clone();
+        assert_eq!(values, HashMap::from([(b"key".to_vec(), vec![0, 1, 2, 255])]));
+        assert_eq!(reloaded.pending_events(&group_id).iter().map(|e| e.id).collect::<Vec<_>>(), vec![waiting.id]);
+
+        // Loaded records count as persisted, so the next save has nothing to write
+        assert!(reloaded.openmls_writes().unwrap().0.is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn migrates_the_legacy_localstorage_snapshot() {
+        use base64::{Engine as _, engine::general_purpose};
+
+        empty_storage().await;
+        let legacy = serde_json::json!({ hex::encode(b"legacy"): hex::encode([9, 9]) });
+        local_storage()
+            .set_item(LEGACY_OPENMLS_KEY, &general_purpose::STANDARD.encode(legacy.to_string()))
+            .unwrap();
+
+        let storage = MdkHybridStorage::new().await.unwrap();
+        let values = storage.openmls_storage.lock().unwrap().values.read().unwrap().clone();
+        assert_eq!(values, HashMap::from([(b"legacy".to_vec(), vec![9, 9])]));
+        assert!(stored(OPENMLS_STORE).await.contains_key(&hex::encode(b"legacy")));
+        assert_eq!(local_storage().get_item(LEGACY_OPENMLS_KEY).unwrap(), None);
+    }
+}
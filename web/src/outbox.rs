@@ -0,0 +1,455 @@
+//! Outbox of signed events that have to reach the relays
+//!
+//! Group messages, commits and Welcomes are recorded here before they are
+//! published, together with the outcome on each relay. Relays that didn't
+//! accept an event are retried with backoff by `run` (started with the relay
+//! pool) once they are connected again, until they accept it or the attempts
+//! run out, so an event that failed to publish is never silently dropped.
+//! The delivery receipt of a group message is stored next to the message, by
+//! its event ID, and updated with the entry; it is kept after the entry expires.
+//! Entries that still have relays to retry are also listed, with when they are
+//! due, in a small unencrypted index, so a retry tick only decrypts those.
+//! Done entries expire after a week.
+//! Entries live in the MDK database and go away with the identity they belong to.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use mdk_storage_traits::GroupId;
+use nostr::{Event, EventId, JsonUtil, RelayUrl};
+use nostr_sdk::{Client, Output};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::mdk_storage::{self, DELIVERY_STORE, OUTBOX_PENDING_STORE, OUTBOX_STORE};
+use crate::{idb, schema, vault};
+
+/// Attempts per relay before it is marked failed
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Wait after the first failed attempt, doubled after each further one
+const RETRY_BASE_MS: f64 = 5_000.0;
+const RETRY_MAX_MS: f64 = 10.0 * 60_000.0;
+
+/// How often `run` looks for entries that are due
+const RETRY_TICK: Duration = Duration::from_secs(10);
+
+/// How often `run` drops expired entries (this reads every entry)
+const PRUNE_INTERVAL_MS: f64 = 3600_000.0;
+
+/// Entries that are done (sent everywhere, or given up on) are dropped after this long
+const KEEP_DONE_MS: f64 = 7.0 * 24.0 * 3600_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeliveryStatus {
+    /// Not accepted yet, will be retried
+    Pending,
+    /// Accepted (by at least one relay, for an entry)
+    Sent,
+    /// Out of attempts
+    Failed,
+}
+
+/// What an outbox event is, so the UI can label it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EventPurpose {
+    Message,
+    Commit,
+    Welcome,
+}
+
+/// Outcome of an event on one relay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RelayDelivery {
+    pub status: DeliveryStatus,
+    /// Last rejection or connection error
+    pub error: Option<String>,
+    pub attempts: u32,
+    /// Unix milliseconds
+    pub last_attempt_at: Option<f64>,
+}
+
+/// Which relays accepted or rejected a message, and why
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DeliveryReceipt {
+    pub status: DeliveryStatus,
+    pub relays: BTreeMap<String, RelayDelivery>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct OutboxEntry {
+    pub event_id: String,
+    pub purpose: EventPurpose,
+    /// MLS group ID (hex)
+    pub group_id: Option<String>,
+    /// The signed event (JSON)
+    pub event: String,
+    /// Unix milliseconds
+    pub created_at: f64,
+    pub relays: BTreeMap<String, RelayDelivery>,
+    /// Unix milliseconds; None once no relay is pending
+    pub next_retry_at: Option<f64>,
+}
+
+impl OutboxEntry {
+    fn new(event: &Event, purpose: EventPurpose, group_id: Option<&GroupId>, relays: &[RelayUrl]) -> Self {
+        Self {
+            event_id: event.id.to_hex(),
+            purpose,
+            group_id: group_id.map(|id| hex::encode(id.as_slice())),
+            event: event.as_json(),
+            created_at: js_sys::Date::now(),
+            relays: relays.iter()
+                .map(|url| (url.to_string(), RelayDelivery {
+                    status: DeliveryStatus::Pending,
+                    error: None,
+                    attempts: 0,
+                    last_attempt_at: None,
+                }))
+                .collect(),
+            next_retry_at: None,
+        }
+    }
+
+    /// Sent once any relay accepted the event, failed once every relay gave up
+    pub(crate) fn status(&self) -> DeliveryStatus {
+        let statuses = || self.relays.values().map(|r| r.status);
+        if statuses().any(|s| s == DeliveryStatus::Sent) {
+            DeliveryStatus::Sent
+        } else if statuses().any(|s| s == DeliveryStatus::Pending) {
+            DeliveryStatus::Pending
+        } else {
+            DeliveryStatus::Failed
+        }
+    }
+
+    pub(crate) fn receipt(&self) -> DeliveryReceipt {
+        DeliveryReceipt {
+            status: self.status(),
+            relays: self.relays.clone(),
+        }
+    }
+
+    fn pending_relays(&self) -> Vec<RelayUrl> {
+        self.relays.iter()
+            .filter(|(_, r)| r.status == DeliveryStatus::Pending)
+            .filter_map(|(url, _)| RelayUrl::parse(url).ok())
+            .collect()
+    }
+
+    fn is_done(&self) -> bool {
+        self.relays.values().all(|r| r.status != DeliveryStatus::Pending)
+    }
+
+    /// Record the result of sending to `targets`
+    fn record(&mut self, targets: &[RelayUrl], result: &Result<Output<EventId>, String>) {
+        let now = js_sys::Date::now();
+        for url in targets {
+            let outcome = match result {
+                Ok(output) if output.success.contains(url) => Ok(()),
+                Ok(output) => Err(output.failed.get(url).cloned().unwrap_or_else(|| "No response".to_string())),
+                Err(e) => Err(e.clone()),
+            };
+
+            let delivery = self.relays.entry(url.to_string()).or_insert(RelayDelivery {
+                status: DeliveryStatus::Pending,
+                error: None,
+                attempts: 0,
+                last_attempt_at: None,
+            });
+            delivery.attempts += 1;
+            delivery.last_attempt_at = Some(now);
+            match outcome {
+                Ok(()) => {
+                    delivery.status = DeliveryStatus::Sent;
+                    delivery.error = None;
+                }
+                Err(e) => {
+                    delivery.error = Some(e);
+                    if delivery.attempts >= MAX_ATTEMPTS {
+                        delivery.status = DeliveryStatus::Failed;
+                    }
+                }
+            }
+        }
+
+        // Back off by the most-tried pending relay
+        self.next_retry_at = self.relays.values()
+            .filter(|r| r.status == DeliveryStatus::Pending)
+            .map(|r| r.attempts)
+            .max()
+            .map(|attempts| {
+                let delay = RETRY_BASE_MS * 2f64.powi(attempts.saturating_sub(1) as i32);
+                now + delay.min(RETRY_MAX_MS)
+            });
+    }
+}
+
+fn sealed<T: Serialize>(store: &str, value: &T) -> Result<JsValue, JsValue> {
+    let json = schema::encode(store, value)
+        .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+    Ok(JsValue::from_str(&vault::seal(&json)?))
+}
+
+/// Write an entry, keeping the pending index and a message's receipt in step with it
+async fn save(entry: &OutboxEntry) -> Result<(), JsValue> {
+    let value = sealed(OUTBOX_STORE, entry)?;
+    let key = JsValue::from_str(&entry.event_id);
+
+    let db = mdk_storage::database().await?;
+    let tx = idb::write_transaction(&db, &[OUTBOX_STORE, OUTBOX_PENDING_STORE, DELIVERY_STORE])?;
+    tx.object_store(OUTBOX_STORE)?.put_with_key(&value, &key)?;
+    if entry.purpose == EventPurpose::Message {
+        tx.object_store(DELIVERY_STORE)?.put_with_key(&sealed(DELIVERY_STORE, &entry.receipt())?, &key)?;
+    }
+    let pending = tx.object_store(OUTBOX_PENDING_STORE)?;
+    if entry.is_done() {
+        pending.delete(&key)?;
+    } else {
+        // Not tried yet is due right away
+        pending.put_with_key(&JsValue::from_f64(entry.next_retry_at.unwrap_or(0.0)), &key)?;
+    }
+    idb::await_transaction(&tx).await
+}
+
+async fn delete(event_ids: &[String]) -> Result<(), JsValue> {
+    if event_ids.is_empty() {
+        return Ok(());
+    }
+    let db = mdk_storage::database().await?;
+    let tx = idb::write_transaction(&db, &[OUTBOX_STORE, OUTBOX_PENDING_STORE])?;
+    let store = tx.object_store(OUTBOX_STORE)?;
+    let pending = tx.object_store(OUTBOX_PENDING_STORE)?;
+    for event_id in event_ids {
+        store.delete(&JsValue::from_str(event_id))?;
+        pending.delete(&JsValue::from_str(event_id))?;
+    }
+    idb::await_transaction(&tx).await
+}
+
+/// IDs of the entries with relays left to retry, and when each is due (unix milliseconds)
+async fn pending_index() -> Result<Vec<(String, f64)>, JsValue> {
+    let db = mdk_storage::database().await?;
+    Ok(idb::read_all(&db, OUTBOX_PENDING_STORE).await?
+        .into_iter()
+        .filter_map(|(key, due)| Some((key.as_string()?, due.as_f64().unwrap_or(0.0))))
+        .collect())
+}
+
+/// Every entry, oldest first
+pub(crate) async fn entries() -> Result<Vec<OutboxEntry>, JsValue> {
+    let db = mdk_storage::database().await?;
+    let mut entries: Vec<OutboxEntry> = mdk_storage::read_json_store(&db, OUTBOX_STORE).await?
+        .into_values()
+        .collect();
+    entries.sort_by(|a, b| a.created_at.total_cmp(&b.created_at));
+    Ok(entries)
+}
+
+async fn load(event_id: &EventId) -> Result<Option<OutboxEntry>, JsValue> {
+    let db = mdk_storage::database().await?;
+    let Some(stored) = idb::get(&db, OUTBOX_STORE, &JsValue::from_str(&event_id.to_hex())).await? else {
+        return Ok(None);
+    };
+    let stored = stored.as_string()
+        .ok_or_else(|| JsValue::from_str("Non-string outbox entry"))?;
+    schema::decode(OUTBOX_STORE, &vault::open(&stored)?)
+        .map(Some)
+        .map_err(|e| JsValue::from_str(&format!("Invalid outbox entry: {}", e)))
+}
+
+/// Delivery receipt of one of our group messages (None if it wasn't sent from here)
+pub(crate) async fn receipt(event_id: &EventId) -> Result<Option<DeliveryReceipt>, JsValue> {
+    let db = mdk_storage::database().await?;
+    let Some(stored) = idb::get(&db, DELIVERY_STORE, &JsValue::from_str(&event_id.to_hex())).await? else {
+        return Ok(None);
+    };
+    let stored = stored.as_string()
+        .ok_or_else(|| JsValue::from_str("Non-string delivery receipt"))?;
+    schema::decode(DELIVERY_STORE, &vault::open(&stored)?)
+        .map(Some)
+        .map_err(|e| JsValue::from_str(&format!("Invalid delivery receipt: {}", e)))
+}
+
+/// Delivery receipts of our group messages, by event ID (hex)
+pub(crate) async fn receipts() -> Result<HashMap<String, DeliveryReceipt>, JsValue> {
+    let db = mdk_storage::database().await?;
+    mdk_storage::read_json_store(&db, DELIVERY_STORE).await
+}
+
+/// Stop retrying an event that no longer applies (a commit that lost to a competing one)
+pub(crate) async fn cancel(event_id: &EventId) -> Result<(), JsValue> {
+    let Some(mut entry) = load(event_id).await? else {
+        return Ok(());
+    };
+    for delivery in entry.relays.values_mut().filter(|d| d.status == DeliveryStatus::Pending) {
+        delivery.status = DeliveryStatus::Failed;
+        delivery.error = Some("Cancelled".to_string());
+    }
+    entry.next_retry_at = None;
+    save(&entry).await
+}
+
+/// Send to `targets` and record the outcome (errors become per-relay failures)
+async fn attempt(client: &Client, entry: &mut OutboxEntry, event: &Event, targets: &[RelayUrl]) -> Output<EventId> {
+    let result = client.send_event_to(targets.to_vec(), event).await
+        .map_err(|e| e.to_string());
+    entry.record(targets, &result);
+
+    result.unwrap_or_else(|e| Output {
+        val: event.id,
+        success: HashSet::new(),
+        failed: targets.iter().map(|url| (url.clone(), e.clone())).collect::<HashMap<_, _>>(),
+    })
+}
+
+/// Record an event in the outbox, then publish it to every relay in the pool.
+/// Relays that don't accept it are retried later, so only storage errors fail this.
+pub(crate) async fn publish(
+    client: &Client,
+    event: &Event,
+    purpose: EventPurpose,
+    group_id: Option<&GroupId>,
+) -> Result<Output<EventId>, JsValue> {
+    let relays: Vec<RelayUrl> = client.relays().await.into_keys().collect();
+    let mut entry = OutboxEntry::new(event, purpose, group_id, &relays);
+    save(&entry).await?;
+
+    let output = attempt(client, &mut entry, event, &relays).await;
+    save(&entry).await?;
+
+    if entry.status() != DeliveryStatus::Sent {
+        crate::log(&format!("⚠️ No relay accepted {}, queued for retry", entry.event_id));
+    }
+    Ok(output)
+}
+
+/// Retry pending relays of entries that are due (or all of them with `force`).
+/// Only connected relays are tried, so a relay that is down doesn't use up attempts.
+/// Only the entries in the pending index are read.
+/// Returns how many entries were retried.
+pub(crate) async fn retry(client: &Client, force: bool) -> Result<usize, JsValue> {
+    let now = js_sys::Date::now();
+    let pool = client.relays().await;
+    let mut retried = 0;
+
+    let mut due: Vec<(String, f64)> = pending_index().await?
+        .into_iter()
+        .filter(|(_, due_at)| force || *due_at <= now)
+        .collect();
+    due.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    for (event_id, _) in due {
+        let Ok(event_id) = EventId::from_hex(&event_id) else {
+            delete(&[event_id]).await?;
+            continue;
+        };
+        let Some(mut entry) = load(&event_id).await? else {
+            // Left behind by an entry that is gone
+            delete(&[event_id.to_hex()]).await?;
+            continue;
+        };
+
+        // Relays removed from the pool won't get it anymore
+        let mut changed = entry.is_done();
+        for (url, delivery) in entry.relays.iter_mut() {
+            let in_pool = RelayUrl::parse(url).is_ok_and(|url| pool.contains_key(&url));
+            if delivery.status == DeliveryStatus::Pending && !in_pool {
+                delivery.status = DeliveryStatus::Failed;
+                delivery.error = Some("Relay removed".to_string());
+                changed = true;
+            }
+        }
+
+        let targets: Vec<RelayUrl> = entry.pending_relays()
+            .into_iter()
+            .filter(|url| pool.get(url).is_some_and(|relay| relay.is_connected()))
+            .collect();
+
+        if !targets.is_empty() {
+            let event = Event::from_json(&entry.event)
+                .map_err(|e| JsValue::from_str(&format!("Invalid outbox event: {}", e)))?;
+            let output = attempt(client, &mut entry, &event, &targets).await;
+            crate::log(&format!(
+                "📮 Retried {} on {} relay(s): {} accepted",
+                entry.event_id, targets.len(), output.success.len()
+            ));
+            retried += 1;
+            changed = true;
+        }
+
+        if changed {
+            save(&entry).await?;
+        }
+    }
+
+    Ok(retried)
+}
+
+/// Drop entries that have been done for longer than KEEP_DONE_MS, and index
+/// pending entries the index doesn't list, or store receipts of messages that
+/// don't have one (both written before those stores existed).
+/// Reads every entry, so `run` only does it now and then.
+async fn prune() -> Result<(), JsValue> {
+    let now = js_sys::Date::now();
+    let indexed: HashSet<String> = pending_index().await?
+        .into_iter()
+        .map(|(event_id, _)| event_id)
+        .collect();
+    let db = mdk_storage::database().await?;
+    let with_receipt: HashSet<String> = idb::read_all(&db, DELIVERY_STORE).await?
+        .into_iter()
+        .filter_map(|(key, _)| key.as_string())
+        .collect();
+
+    let mut expired = Vec::new();
+    for entry in entries().await? {
+        if entry.purpose == EventPurpose::Message && !with_receipt.contains(&entry.event_id) {
+            save(&entry).await?;
+        }
+        if entry.is_done() {
+            if now - entry.created_at > KEEP_DONE_MS {
+                expired.push(entry.event_id.clone());
+            }
+        } else if !indexed.contains(&entry.event_id) {
+            save(&entry).await?;
+        }
+    }
+
+    if !expired.is_empty() {
+        crate::log(&format!("📮 Dropped {} expired outbox entries", expired.len()));
+    }
+    delete(&expired).await
+}
+
+/// Retry loop for the shared relay pool
+pub(crate) async fn run(client: Client) {
+    let mut pruned_at: Option<f64> = None;
+    loop {
+        crate::sleep(RETRY_TICK).await;
+
+        // Entries can't be read (or written) without the passphrase
+        if vault::is_locked() {
+            continue;
+        }
+        if pruned_at.is_none_or(|at| js_sys::Date::now() - at > PRUNE_INTERVAL_MS) {
+            match prune().await {
+                Ok(()) => pruned_at = Some(js_sys::Date::now()),
+                Err(e) => crate::log(&format!("⚠️ Outbox prune failed: {:?}", e)),
+            }
+        }
+        if let Err(e) = retry(&client, false).await {
+            crate::log(&format!("⚠️ Outbox retry failed: {:?}", e));
+        }
+    }
+}
+
+/// Re-encrypt every entry with the current vault key (after the passphrase changed)
+pub(crate) async fn reseal() -> Result<(), JsValue> {
+    for entry in entries().await? {
+        save(&entry).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! P2PK locks (NUT-11) on the tokens we send
+//!
+//! A token sent to an npub is locked to its key. With a locktime the lock also
+//! names refund keys (the sender's own by default) that can spend it once the
+//! locktime passed, so a payment to someone who never comes online isn't stuck.
+//! CDK only signs for the keys a token is locked to, so signing as a refund key
+//! is done here before the proofs are swapped. So is co-signing a multisig lock,
+//! whose signatures of the whole swap are collected from other members (see `treasury`).
+
+use cdk::nuts::{
+    Conditions, P2PKWitness, Proof, Proofs, PublicKey, SecretKey, SigFlag, SpendingConditions, SwapRequest, Witness,
+};
+use nostr::ToBech32;
+use wasm_bindgen::JsValue;
+
+/// The P2PK key of a Nostr public key (x-only, so even parity is assumed, as in Nostr)
+pub(crate) fn pubkey_from_nostr(pubkey: &nostr::PublicKey) -> Result<PublicKey, JsValue> {
+    let x_only = nostr::secp256k1::XOnlyPublicKey::from_slice(&pubkey.to_bytes())
+        .map_err(|e| JsValue::from_str(&format!("Failed to parse x-only pubkey: {}", e)))?;
+    let full_pubkey = nostr::secp256k1::PublicKey::from_x_only_public_key(x_only, nostr::secp256k1::Parity::Even);
+
+    PublicKey::from_slice(&full_pubkey.serialize())
+        .map_err(|e| JsValue::from_str(&format!("Failed to convert pubkey: {}", e)))
+}
+
+/// The npub of a P2PK key
+pub(crate) fn npub(pubkey: &PublicKey) -> Option<String> {
+    let x_only = pubkey.x_only_public_key().serialize();
+    nostr::PublicKey::from_slice(&x_only).ok()?.to_bech32().ok()
+}
+
+pub(crate) fn same_key(a: &PublicKey, b: &PublicKey) -> bool {
+    a.x_only_public_key() == b.x_only_public_key()
+}
+
+/// Conditions locking a token to `recipient`. With a locktime (unix seconds),
+/// `refund_keys` can spend it after that.
+pub(crate) fn conditions(
+    recipient: PublicKey,
+    locktime: Option<u64>,
+    refund_keys: Vec<PublicKey>,
+) -> Result<SpendingConditions, JsValue> {
+    let Some(locktime) = locktime else {
+        if !refund_keys.is_empty() {
+            return Err(JsValue::from_str("Refund keys need a locktime"));
+        }
+        return Ok(SpendingConditions::new_p2pk(recipient, None));
+    };
+    if refund_keys.is_empty() {
+        // Without refund keys anyone could spend it after the locktime
+        return Err(JsValue::from_str("A locktime needs at least one refund key"));
+    }
+
+    let conditions = Conditions::new(Some(locktime), None, Some(refund_keys), None, None, None)
+        .map_err(|e| JsValue::from_str(&format!("Invalid lock: {}", e)))?;
+    Ok(SpendingConditions::new_p2pk(recipient, Some(conditions)))
+}
+
+/// Conditions any `threshold` of `keys` have to sign together (NUT-11 multisig).
+/// They sign the whole swap (SIG_ALL), so their signatures only release the
+/// proofs to the outputs they saw.
+pub(crate) fn multisig(mut keys: Vec<PublicKey>, threshold: u64) -> Result<SpendingConditions, JsValue> {
+    if threshold == 0 || threshold > keys.len() as u64 {
+        return Err(JsValue::from_str(&format!("The threshold must be between 1 and {}", keys.len())));
+    }
+    let data = keys.remove(0);
+    let pubkeys = Some(keys).filter(|keys| !keys.is_empty());
+
+    let conditions = Conditions::new(None, pubkeys, None, Some(threshold), Some(SigFlag::SigAll), None)
+        .map_err(|e| JsValue::from_str(&format!("Invalid lock: {}", e)))?;
+    Ok(SpendingConditions::new_p2pk(data, Some(conditions)))
+}
+
+/// Our signature (hex) of a swap of SIG_ALL proofs (its inputs' secrets and its
+/// outputs), for a witness someone else puts together
+pub(crate) fn swap_signature(swap: &SwapRequest, key: &SecretKey) -> Result<String, JsValue> {
+    key.sign(swap.sig_all_msg_to_sign().as_bytes())
+        .map(|signature| signature.to_string())
+        .map_err(|e| JsValue::from_str(&format!("Failed to sign swap: {}", e)))
+}
+
+/// Whether `signature` (hex) is `key`'s signature of a swap of SIG_ALL proofs
+pub(crate) fn signed_swap(swap: &SwapRequest, key: &PublicKey, signature: &str) -> bool {
+    signature.parse()
+        .is_ok_and(|signature| key.verify(swap.sig_all_msg_to_sign().as_bytes(), &signature).is_ok())
+}
+
+/// Witness a proof with signatures collected from its co-signers
+/// (for SIG_ALL, the first input carries the signatures of the whole swap)
+pub(crate) fn witness(proof: &mut Proof, signatures: Vec<String>) {
+    proof.witness = Some(Witness::P2PKWitness(P2PKWitness { signatures }));
+}
+
+/// The P2PK lock of a proof
+#[derive(Debug, Clone)]
+pub(crate) struct Lock {
+    /// Keys that can spend it any time, `num_sigs` of them together
+    pub pubkeys: Vec<PublicKey>,
+    pub num_sigs: u64,
+    /// Whether signatures cover the whole swap (SIG_ALL) rather than each proof
+    pub sig_all: bool,
+    pub locktime: Option<u64>,
+    /// Keys that can spend it after the locktime
+    pub refund_keys: Vec<PublicKey>,
+}
+
+impl Lock {
+    pub fn of(proof: &Proof) -> Option<Self> {
+        let SpendingConditions::P2PKConditions { data, conditions } = SpendingConditions::try_from(&proof.secret).ok()? else {
+            return None;
+        };
+        let mut lock = Self { pubkeys: vec![data], num_sigs: 1, sig_all: false, locktime: None, refund_keys: Vec::new() };
+        if let Some(conditions) = conditions {
+            lock.pubkeys.extend(conditions.pubkeys.unwrap_or_default());
+            lock.num_sigs = conditions.num_sigs.unwrap_or(1);
+            lock.sig_all = conditions.sig_flag == SigFlag::SigAll;
+            lock.locktime = conditions.locktime;
+            lock.refund_keys = conditions.refund_keys.unwrap_or_default();
+        }
+        Some(lock)
+    }
+
+    pub fn expired(&self) -> bool {
+        self.locktime.is_some_and(|locktime| locktime <= nostr::Timestamp::now().as_u64())
+    }
+
+    /// Whether `key` is one of the keys it is locked to
+    pub fn locked_to(&self, key: &PublicKey) -> bool {
+        self.pubkeys.iter().any(|pubkey| same_key(pubkey, key))
+    }
+
+    /// Whether `key` can only spend it as a refund key
+    fn refunds_to(&self, key: &PublicKey) -> bool {
+        !self.locked_to(key)
+            && self.refund_keys.iter().any(|refund_key| same_key(refund_key, key))
+    }
+}
+
+/// Sign the proofs whose lock expired and refunds to `key`. Returns how many were signed.
+pub(crate) fn sign_refunds(proofs: &mut Proofs, key: &SecretKey) -> Result<usize, JsValue> {
+    let pubkey = key.public_key();
+    let mut signed = 0;
+    for proof in proofs.iter_mut() {
+        let Some(lock) = Lock::of(proof) else {
+            continue;
+        };
+        if lock.expired() && lock.refunds_to(&pubkey) {
+            proof.sign_p2pk(key.clone())
+                .map_err(|e| JsValue::from_str(&format!("Failed to sign refund: {}", e)))?;
+            signed += 1;
+        }
+    }
+    Ok(signed)
+}
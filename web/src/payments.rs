@@ -0,0 +1,456 @@
+//! Ecash tokens sent as group chat messages
+//!
+//! A token message is an MLS application message whose rumor has its own kind,
+//! with the token, its amount, unit and mint in tags and the memo as content,
+//! so members see a payment instead of a token string pasted into the chat.
+//! Claiming one redeems the token into our wallet; the claims we made are kept
+//! in the MDK database, next to the messages they belong to.
+//!
+//! Whoever claims a token message posts a claim notice for it, and the proofs of
+//! the token messages we have seen are tracked by their Y values: `check` asks
+//! each mint for their state (NUT-07), so every member can tell whether a token
+//! is still unclaimed, and who took it. Any member can post a notice, so a
+//! notice only says who took proofs the mint reports spent (and triggers that
+//! check); it never marks a token claimed by itself. A spent token nobody
+//! announced was claimed by someone else, unless our own wallet received it.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use cdk::nuts::{PublicKey as CashuPublicKey, State, Token};
+use cdk_common::database::WalletDatabase;
+use cdk_common::wallet::TransactionDirection;
+use js_sys::Function;
+use mdk_storage_traits::groups::GroupStorage;
+use mdk_storage_traits::messages::types::Message;
+use mdk_storage_traits::GroupId;
+use nostr::{EventId, Kind, PublicKey, Tag, TagKind, Tags, ToBech32};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::mdk_storage::{self, MdkHybridStorage, CLAIMS_STORE, TOKEN_STATUS_STORE};
+use crate::{idb, schema, vault};
+
+/// Rumor kind of a chat message carrying an ecash token (only ever seen inside a group)
+pub(crate) const TOKEN_MESSAGE_KIND: Kind = Kind::Custom(9310);
+
+/// Rumor kind announcing that a token message was claimed (e-tags the token message)
+pub(crate) const CLAIM_NOTICE_KIND: Kind = Kind::Custom(9311);
+
+pub(crate) const TOKEN_TAG: &str = "cashu";
+const AMOUNT_TAG: &str = "amount";
+const UNIT_TAG: &str = "unit";
+const MINT_TAG: &str = "u";
+
+/// Who has the proofs of a token message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ClaimStatus {
+    /// Not checked with the mint yet
+    Unknown,
+    Unclaimed,
+    ClaimedByMe,
+    ClaimedByOther,
+    ReclaimedBySender,
+}
+
+impl ClaimStatus {
+    /// Spent proofs stay spent, so the mint isn't asked about these again
+    fn is_settled(self) -> bool {
+        !matches!(self, Self::Unknown | Self::Unclaimed)
+    }
+}
+
+/// A token message, as passed to JS with the message
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TokenPayment {
+    pub token: String,
+    pub amount: u64,
+    pub unit: String,
+    pub mint: String,
+    pub memo: Option<String>,
+    /// Unix seconds of when we claimed it (None if we didn't)
+    pub claimed_at: Option<u64>,
+    pub status: ClaimStatus,
+    /// Who claimed it (npub), when known
+    pub claimed_by: Option<String>,
+}
+
+/// Tags of the rumor carrying `token`
+pub(crate) fn token_tags(token: &Token) -> Result<Tags, JsValue> {
+    let amount = token.value()
+        .map_err(|e| JsValue::from_str(&format!("Failed to get value: {}", e)))?;
+    let mint = token.mint_url()
+        .map_err(|e| JsValue::from_str(&format!("Failed to get mint URL: {}", e)))?;
+    let unit = token.unit().map(|unit| unit.to_string()).unwrap_or_else(|| "sat".to_string());
+
+    let mut tags = Tags::new();
+    tags.push(Tag::custom(TagKind::custom(TOKEN_TAG), [token.to_string()]));
+    tags.push(Tag::custom(TagKind::custom(AMOUNT_TAG), [u64::from(amount).to_string()]));
+    tags.push(Tag::custom(TagKind::custom(UNIT_TAG), [unit]));
+    tags.push(Tag::custom(TagKind::custom(MINT_TAG), [mint.to_string()]));
+    Ok(tags)
+}
+
+/// Tags of the claim notice for a token message
+pub(crate) fn claim_notice_tags(message_id: &EventId) -> Tags {
+    let mut tags = Tags::new();
+    tags.push(Tag::event(*message_id));
+    tags
+}
+
+pub(crate) fn tag_value(msg: &Message, name: &str) -> Option<String> {
+    msg.tags.iter().find_map(|tag| {
+        let tag = tag.clone().to_vec();
+        (tag.first().map(|s| s.as_str()) == Some(name)).then(|| tag.get(1).cloned()).flatten()
+    })
+}
+
+/// The token message a claim notice is about
+fn noticed_message(msg: &Message) -> Option<String> {
+    if msg.kind != CLAIM_NOTICE_KIND {
+        return None;
+    }
+    tag_value(msg, "e")
+}
+
+/// Messages that are bookkeeping rather than chat (claim notices and the group treasury)
+pub(crate) fn is_hidden(msg: &Message) -> bool {
+    msg.kind == CLAIM_NOTICE_KIND || crate::treasury::is_message(msg)
+}
+
+/// The payment a message carries (None for ordinary chat messages), with our claim of it
+/// and what is known about who claimed it
+pub(crate) fn payment(msg: &Message, claim: Option<&Claim>, status: Option<&TokenStatus>) -> Option<TokenPayment> {
+    if msg.kind != TOKEN_MESSAGE_KIND {
+        return None;
+    }
+
+    let token = tag_value(msg, TOKEN_TAG)?;
+    // The tags only describe the token; what counts is the token itself
+    let parsed = Token::from_str(&token).ok()?;
+    let amount = parsed.value().ok().map(u64::from)?;
+    let mint = parsed.mint_url().ok()?.to_string();
+    if tag_value(msg, AMOUNT_TAG).is_some_and(|tagged| tagged != amount.to_string()) {
+        crate::log(&format!("⚠️ Token message {} has a wrong amount tag", msg.id.to_hex()));
+    }
+
+    Some(TokenPayment {
+        token,
+        amount,
+        unit: tag_value(msg, UNIT_TAG).unwrap_or_else(|| "sat".to_string()),
+        mint,
+        memo: Some(msg.content.clone()).filter(|memo| !memo.is_empty()),
+        claimed_at: claim.map(|claim| claim.claimed_at),
+        status: status.map(|s| s.status).unwrap_or(ClaimStatus::Unknown),
+        claimed_by: status.and_then(|s| s.claimed_by_npub()),
+    })
+}
+
+/// A token message we redeemed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Claim {
+    /// Message (rumor) ID, hex
+    pub message_id: String,
+    /// MLS group ID, hex
+    pub group_id: String,
+    /// Amount received, after fees
+    pub amount: u64,
+    /// Unix seconds
+    pub claimed_at: u64,
+}
+
+/// What we know about the proofs of a token message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TokenStatus {
+    /// Message (rumor) ID, hex
+    pub message_id: String,
+    /// MLS group ID, hex
+    pub group_id: String,
+    pub mint: String,
+    /// Y values of the token's proofs (hash_to_curve of their secrets, hex)
+    pub ys: Vec<String>,
+    pub status: ClaimStatus,
+    /// Who claimed it (hex), when our wallet or, for proofs the mint reports spent, a claim notice told us
+    pub claimed_by: Option<String>,
+    /// Unix seconds of the last check with the mint
+    pub checked_at: Option<u64>,
+}
+
+impl TokenStatus {
+    /// Claimed as far as the mint (or our own claim) is concerned. Statuses that
+    /// older versions settled from a claim notice alone were never checked.
+    fn is_settled(&self) -> bool {
+        self.status.is_settled() && (self.checked_at.is_some() || self.status == ClaimStatus::ClaimedByMe)
+    }
+
+    fn claimed_by_npub(&self) -> Option<String> {
+        let pubkey = PublicKey::from_hex(self.claimed_by.as_deref()?).ok()?;
+        pubkey.to_bech32().ok()
+    }
+
+    /// As passed to the status callback and returned by check_token_claims
+    pub(crate) fn report(&self) -> serde_json::Value {
+        serde_json::json!({
+            "message_id": self.message_id,
+            "group_id": self.group_id,
+            "status": self.status,
+            "claimed_by": self.claimed_by_npub(),
+        })
+    }
+}
+
+async fn get<T: DeserializeOwned>(store: &str, key: &str) -> Result<Option<T>, JsValue> {
+    let db = mdk_storage::database().await?;
+    let Some(stored) = idb::get(&db, store, &JsValue::from_str(key)).await? else {
+        return Ok(None);
+    };
+    let stored = stored.as_string()
+        .ok_or_else(|| JsValue::from_str(&format!("Non-string record in {}", store)))?;
+    schema::decode(store, &vault::open(&stored)?)
+        .map(Some)
+        .map_err(|e| JsValue::from_str(&format!("Invalid record in {}: {}", store, e)))
+}
+
+async fn put<T: Serialize>(store: &str, key: &str, value: &T) -> Result<(), JsValue> {
+    let json = schema::encode(store, value)
+        .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+    let value = JsValue::from_str(&vault::seal(&json)?);
+
+    let db = mdk_storage::database().await?;
+    let tx = idb::write_transaction(&db, &[store])?;
+    tx.object_store(store)?.put_with_key(&value, &JsValue::from_str(key))?;
+    idb::await_transaction(&tx).await
+}
+
+/// Our claims, by message ID (hex)
+pub(crate) async fn claims() -> Result<HashMap<String, Claim>, JsValue> {
+    let db = mdk_storage::database().await?;
+    mdk_storage::read_json_store(&db, CLAIMS_STORE).await
+}
+
+pub(crate) async fn claim(message_id: &EventId) -> Result<Option<Claim>, JsValue> {
+    get(CLAIMS_STORE, &message_id.to_hex()).await
+}
+
+pub(crate) async fn record_claim(message_id: &EventId, group_id: &GroupId, amount: u64) -> Result<Claim, JsValue> {
+    let claim = Claim {
+        message_id: message_id.to_hex(),
+        group_id: hex::encode(group_id.as_slice()),
+        amount,
+        claimed_at: nostr::Timestamp::now().as_u64(),
+    };
+    put(CLAIMS_STORE, &claim.message_id, &claim).await?;
+    Ok(claim)
+}
+
+/// Tracked token messages, by message ID (hex)
+pub(crate) async fn statuses() -> Result<HashMap<String, TokenStatus>, JsValue> {
+    let db = mdk_storage::database().await?;
+    mdk_storage::read_json_store(&db, TOKEN_STATUS_STORE).await
+}
+
+pub(crate) async fn status(message_id: &EventId) -> Result<Option<TokenStatus>, JsValue> {
+    get(TOKEN_STATUS_STORE, &message_id.to_hex()).await
+}
+
+thread_local! {
+    // JS callback for claim status changes
+    static CALLBACK: RefCell<Option<Function>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn set_callback(callback: Option<Function>) {
+    CALLBACK.with(|c| *c.borrow_mut() = callback);
+}
+
+fn report(status: &TokenStatus) {
+    crate::log(&format!("💸 Token message {} is {:?}", status.message_id, status.status));
+
+    let Some(callback) = CALLBACK.with(|c| c.borrow().clone()) else {
+        return;
+    };
+    if let Err(e) = callback.call1(&JsValue::NULL, &JsValue::from_str(&status.report().to_string())) {
+        crate::log(&format!("  ❌ Token status callback failed: {:?}", e));
+    }
+}
+
+/// Status of a token sent by `sender` that `claimer` redeemed
+fn claimed_status(claimer: &PublicKey, sender: &PublicKey, me: &PublicKey) -> ClaimStatus {
+    if claimer == sender {
+        ClaimStatus::ReclaimedBySender
+    } else if claimer == me {
+        ClaimStatus::ClaimedByMe
+    } else {
+        ClaimStatus::ClaimedByOther
+    }
+}
+
+/// A token message that needs the mint to tell
+struct Unsettled {
+    status: TokenStatus,
+    sender: PublicKey,
+    token: Token,
+    /// Who posted the first claim notice for it, if anyone
+    noticed_by: Option<PublicKey>,
+}
+
+/// Update the claim status of the token messages in `groups` (all groups if None),
+/// from our claims and the state of their proofs: asked with `ask_mint`, and for
+/// token messages with a claim notice the mint hasn't confirmed yet.
+/// Changes are reported to the status callback; returns the changed statuses.
+pub(crate) async fn check(
+    storage: &MdkHybridStorage,
+    groups: Option<Vec<GroupId>>,
+    ask_mint: bool,
+) -> Result<Vec<TokenStatus>, JsValue> {
+    let me = crate::signer::get_public_key()?;
+    let groups = match groups {
+        Some(groups) => groups,
+        None => storage.all_groups()
+            .map_err(|e| JsValue::from_str(&format!("Failed to get groups: {}", e)))?
+            .into_iter()
+            .map(|group| group.mls_group_id)
+            .collect(),
+    };
+    let claims = claims().await?;
+    let known = statuses().await?;
+
+    let mut updated = Vec::new();
+    let mut unsettled: HashMap<String, Vec<Unsettled>> = HashMap::new();
+    for group_id in &groups {
+        let mut messages = storage.messages(group_id)
+            .map_err(|e| JsValue::from_str(&format!("Failed to get messages: {}", e)))?;
+        messages.sort_by_key(|msg| msg.created_at);
+
+        // The first notice for a token message counts (who posted it, and when)
+        let mut notices: HashMap<String, (PublicKey, u64)> = HashMap::new();
+        for msg in &messages {
+            if let Some(message_id) = noticed_message(msg) {
+                notices.entry(message_id).or_insert((msg.pubkey, msg.created_at.as_u64()));
+            }
+        }
+
+        for msg in messages.iter().filter(|msg| msg.kind == TOKEN_MESSAGE_KIND) {
+            let message_id = msg.id.to_hex();
+            let Some(token) = tag_value(msg, TOKEN_TAG).and_then(|token| Token::from_str(&token).ok()) else {
+                continue;
+            };
+            let Ok(mint) = token.mint_url() else {
+                continue;
+            };
+            let mut status = known.get(&message_id).cloned().unwrap_or_else(|| TokenStatus {
+                message_id: message_id.clone(),
+                group_id: hex::encode(group_id.as_slice()),
+                mint: mint.to_string(),
+                ys: Vec::new(),
+                status: ClaimStatus::Unknown,
+                claimed_by: None,
+                checked_at: None,
+            });
+
+            let notice = notices.get(&message_id).copied();
+            let noticed_by = notice.map(|(claimer, _)| claimer);
+            // A notice the mint hasn't been asked about since it was posted
+            let unchecked_notice = notice.is_some_and(|(_, at)| status.checked_at.is_none_or(|checked| checked < at));
+            if claims.contains_key(&message_id) {
+                status.status = claimed_status(&me, &msg.pubkey, &me);
+                status.claimed_by = Some(me.to_hex());
+                updated.push(status);
+            } else if status.is_settled() {
+                // The mint said it's spent: a notice only tells who took it
+                if let (None, Some(claimer)) = (&status.claimed_by, noticed_by) {
+                    status.status = claimed_status(&claimer, &msg.pubkey, &me);
+                    status.claimed_by = Some(claimer.to_hex());
+                    updated.push(status);
+                }
+            } else if ask_mint || unchecked_notice {
+                unsettled.entry(status.mint.clone()).or_default().push(Unsettled { status, sender: msg.pubkey, token, noticed_by });
+            }
+        }
+    }
+
+    for (mint, tokens) in unsettled {
+        match check_with_mint(&mint, tokens, &me).await {
+            Ok(checked) => updated.extend(checked),
+            Err(e) => crate::log(&format!("⚠️ Couldn't check tokens with {}: {:?}", mint, e)),
+        }
+    }
+
+    let mut changed = Vec::new();
+    for status in updated {
+        let before = known.get(&status.message_id);
+        let is_change = before.is_none_or(|before| before.status != status.status || before.claimed_by != status.claimed_by);
+        let rechecked = before.is_some_and(|before| before.checked_at != status.checked_at);
+        if is_change || rechecked {
+            put(TOKEN_STATUS_STORE, &status.message_id, &status).await?;
+        }
+        if is_change {
+            report(&status);
+            changed.push(status);
+        }
+    }
+    Ok(changed)
+}
+
+/// Ask a mint for the state of the proofs of its token messages (one NUT-07 request).
+/// Spent proofs our wallet received were claimed by us, or reclaimed if we sent them;
+/// other spent proofs were taken by whoever posted a claim notice, if anyone did.
+async fn check_with_mint(mint: &str, tokens: Vec<Unsettled>, me: &PublicKey) -> Result<Vec<TokenStatus>, JsValue> {
+    let wallet = crate::create_wallet_for_mint(mint.to_string()).await?;
+    let keysets = wallet.get_mint_keysets().await
+        .map_err(|e| JsValue::from_str(&format!("Failed to get keysets: {}", e)))?;
+
+    let mut proofs = Vec::new();
+    let mut tracked = Vec::new();
+    for Unsettled { mut status, sender, token, noticed_by } in tokens {
+        let token_proofs = match token.proofs(&keysets) {
+            Ok(token_proofs) => token_proofs,
+            Err(e) => {
+                crate::log(&format!("⚠️ Token message {} has unreadable proofs: {}", status.message_id, e));
+                continue;
+            }
+        };
+        let ys: Vec<CashuPublicKey> = token_proofs.iter().filter_map(|proof| proof.y().ok()).collect();
+        status.ys = ys.iter().map(|y| y.to_hex()).collect();
+        proofs.extend(token_proofs);
+        tracked.push((status, sender, noticed_by, ys));
+    }
+    if proofs.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let states: HashMap<CashuPublicKey, State> = wallet.check_proofs_spent(proofs).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to check proof states: {}", e)))?
+        .into_iter()
+        .map(|state| (state.y, state.state))
+        .collect();
+
+    // Proofs this wallet took in (the inputs of our receives)
+    let db = crate::get_or_create_wallet_db().await?;
+    let received: HashSet<CashuPublicKey> = db.list_transactions(Some(wallet.mint_url.clone()), Some(TransactionDirection::Incoming), None).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to list transactions: {}", e)))?
+        .into_iter()
+        .flat_map(|transaction| transaction.ys)
+        .collect();
+
+    let now = nostr::Timestamp::now().as_u64();
+    Ok(tracked.into_iter()
+        .map(|(mut status, sender, noticed_by, ys)| {
+            let spent = ys.iter().any(|y| states.get(y) == Some(&State::Spent));
+            let ours = ys.iter().any(|y| received.contains(y));
+            (status.status, status.claimed_by) = match (spent, ours, noticed_by) {
+                (false, _, _) => (ClaimStatus::Unclaimed, None),
+                (true, true, _) => (claimed_status(me, &sender, me), Some(me.to_hex())),
+                // Our wallet didn't take it, whatever a notice in our name says
+                (true, false, Some(claimer)) if claimer != *me => {
+                    (claimed_status(&claimer, &sender, me), Some(claimer.to_hex()))
+                }
+                (true, false, _) => (ClaimStatus::ClaimedByOther, None),
+            };
+            status.checked_at = Some(now);
+            status
+        })
+        .collect())
+}
@@ -0,0 +1,413 @@
+//! Epoch fork detection and recovery (MIP-03)
+//!
+//! When two members commit for the same epoch at about the same time, each
+//! applies its own commit and then can't process the other's: the group has
+//! forked and the two sides can't read each other anymore. MIP-03 makes the
+//! commit with the earliest `created_at` (then the lowest event ID) canonical.
+//!
+//! Before a commit is applied, the group's state at that epoch is saved as a
+//! checkpoint (the last few per group, in the MDK database). A commit that
+//! comes back as `ProcessMessageWrongEpoch` is compared with the commits we
+//! applied: if it sorts before one of them, we roll back to that checkpoint,
+//! apply it, refetch the group's events from the relays and replay them in
+//! MIP-03 order. Events we can't read yet (from an epoch we haven't reached)
+//! are queued in the MDK storage, so they survive a reload, and retried when
+//! the epoch advances. Recoveries are reported to the callback set with
+//! `set_group_recovery_callback`.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use js_sys::Function;
+use mdk_core::MDK;
+use mdk_storage_traits::groups::types::Group;
+use mdk_storage_traits::groups::GroupStorage;
+use mdk_storage_traits::GroupId;
+use nostr::{Alphabet, Event, Filter, Kind, SingleLetterTag, Timestamp};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::mdk_storage::{self, GroupSnapshot, MdkHybridStorage, SharedMdkStorage, CHECKPOINTS_STORE};
+use crate::{idb, relay_pool, schema, vault};
+
+/// Checkpoints kept per group, i.e. how many epochs back a fork can be repaired
+const MAX_CHECKPOINTS: usize = 8;
+
+/// Clock skew allowed between members when matching commits to epochs
+pub(crate) const CLOCK_SKEW_SECS: u64 = 60;
+
+const REFETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sort key for group events: MIP-03 puts the earlier `created_at` first, then the lower event ID
+pub(crate) fn mip03_order(event: &Event) -> (Timestamp, String) {
+    (event.created_at, event.id.to_hex())
+}
+
+/// The commit applied on top of a checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AppliedCommit {
+    event_id: String,
+    created_at: u64,
+    /// Whether we created it
+    own: bool,
+}
+
+impl AppliedCommit {
+    /// MIP-03 order: earlier `created_at` wins, then the lower event ID
+    fn sorts_after(&self, event: &Event) -> bool {
+        (self.created_at, self.event_id.as_str()) > (event.created_at.as_u64(), event.id.to_hex().as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Checkpoint {
+    /// MLS group ID (hex)
+    group_id: String,
+    epoch: u64,
+    /// None until a commit moves the group past this epoch
+    commit: Option<AppliedCommit>,
+    snapshot: GroupSnapshot,
+}
+
+fn checkpoint_key(group_id: &str, epoch: u64) -> String {
+    // Zero-padded so keys sort by epoch
+    format!("{}:{:020}", group_id, epoch)
+}
+
+async fn save(checkpoint: &Checkpoint) -> Result<(), JsValue> {
+    let json = schema::encode(CHECKPOINTS_STORE, checkpoint)
+        .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+    let value = JsValue::from_str(&vault::seal(&json)?);
+
+    let db = mdk_storage::database().await?;
+    let tx = idb::write_transaction(&db, &[CHECKPOINTS_STORE])?;
+    tx.object_store(CHECKPOINTS_STORE)?
+        .put_with_key(&value, &JsValue::from_str(&checkpoint_key(&checkpoint.group_id, checkpoint.epoch)))?;
+    idb::await_transaction(&tx).await
+}
+
+async fn delete(checkpoints: &[Checkpoint]) -> Result<(), JsValue> {
+    if checkpoints.is_empty() {
+        return Ok(());
+    }
+    let db = mdk_storage::database().await?;
+    let tx = idb::write_transaction(&db, &[CHECKPOINTS_STORE])?;
+    let store = tx.object_store(CHECKPOINTS_STORE)?;
+    for checkpoint in checkpoints {
+        let key = checkpoint_key(&checkpoint.group_id, checkpoint.epoch);
+        store.delete(&JsValue::from_str(&key))?;
+        CHECKPOINTED.with(|c| c.borrow_mut().remove(&key));
+    }
+    idb::await_transaction(&tx).await
+}
+
+/// A group's checkpoints, oldest epoch first
+async fn checkpoints(group_id: &GroupId) -> Result<Vec<Checkpoint>, JsValue> {
+    let group_hex = hex::encode(group_id.as_slice());
+    let db = mdk_storage::database().await?;
+    let mut checkpoints: Vec<Checkpoint> = mdk_storage::read_json_store(&db, CHECKPOINTS_STORE).await?
+        .into_values()
+        .filter(|c: &Checkpoint| c.group_id == group_hex)
+        .collect();
+    checkpoints.sort_by_key(|c| c.epoch);
+    Ok(checkpoints)
+}
+
+pub(crate) fn epoch_of(storage: &MdkHybridStorage, group_id: &GroupId) -> Option<u64> {
+    storage.find_group_by_mls_group_id(group_id).ok().flatten().map(|group| group.epoch)
+}
+
+/// Save the group's state at its current epoch, unless there already is a checkpoint for it.
+/// Returns the epoch, for `record_commit`.
+pub(crate) async fn checkpoint(storage: &MdkHybridStorage, group_id: &GroupId) -> Result<u64, JsValue> {
+    let epoch = epoch_of(storage, group_id)
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+    let key = checkpoint_key(&hex::encode(group_id.as_slice()), epoch);
+
+    // Called for every group event, so only look at the database once per epoch
+    if CHECKPOINTED.with(|c| c.borrow().contains(&key)) {
+        return Ok(epoch);
+    }
+    let db = mdk_storage::database().await?;
+    if idb::get(&db, CHECKPOINTS_STORE, &JsValue::from_str(&key)).await?.is_none() {
+        let snapshot = storage.snapshot_group(group_id)
+            .ok_or_else(|| JsValue::from_str("Group not found"))?;
+        save(&Checkpoint {
+            group_id: hex::encode(group_id.as_slice()),
+            epoch,
+            commit: None,
+            snapshot,
+        })
+        .await?;
+
+        let existing = checkpoints(group_id).await?;
+        let excess = existing.len().saturating_sub(MAX_CHECKPOINTS);
+        delete(&existing[..excess]).await?;
+    }
+    CHECKPOINTED.with(|c| c.borrow_mut().insert(key));
+    Ok(epoch)
+}
+
+/// Note the commit that moved a group past `epoch`
+pub(crate) async fn record_commit(group_id: &GroupId, epoch: u64, commit: &Event, own: bool) -> Result<(), JsValue> {
+    let Some(mut checkpoint) = checkpoints(group_id).await?.into_iter().find(|c| c.epoch == epoch) else {
+        return Ok(());
+    };
+    checkpoint.commit = Some(AppliedCommit {
+        event_id: commit.id.to_hex(),
+        created_at: commit.created_at.as_u64(),
+        own,
+    });
+    save(&checkpoint).await
+}
+
+/// The group an event is addressed to (by its `#h` tag), if we are in it
+pub(crate) fn group_of(storage: &MdkHybridStorage, event: &Event) -> Option<Group> {
+    let nostr_group_id: [u8; 32] = event.tags.iter()
+        .find_map(|tag| {
+            let tag = tag.clone().to_vec();
+            (tag.first().map(|s| s.as_str()) == Some("h")).then(|| tag.get(1).cloned()).flatten()
+        })
+        .and_then(|h| hex::decode(h).ok())
+        .and_then(|bytes| bytes.try_into().ok())?;
+    storage.find_group_by_nostr_group_id(&nostr_group_id).ok().flatten()
+}
+
+/// Checkpoint the event's group before processing, so a commit can be rolled back.
+/// Returns the group as it was before the event.
+pub(crate) async fn prepare(storage: &MdkHybridStorage, event: &Event) -> Option<Group> {
+    let group = group_of(storage, event)?;
+    if let Err(e) = checkpoint(storage, &group.mls_group_id).await {
+        crate::log(&format!("  ⚠️  Failed to checkpoint group: {:?}", e));
+    }
+    Some(group)
+}
+
+/// After an event was processed: whether it moved `group` to a new epoch (noting the commit if so)
+pub(crate) async fn advanced(storage: &MdkHybridStorage, group: &Group, event: &Event) -> bool {
+    if epoch_of(storage, &group.mls_group_id).is_none_or(|epoch| epoch <= group.epoch) {
+        return false;
+    }
+    if let Err(e) = record_commit(&group.mls_group_id, group.epoch, event, false).await {
+        crate::log(&format!("  ⚠️  Failed to record commit: {:?}", e));
+    }
+    true
+}
+
+thread_local! {
+    // Checkpoint keys known to be stored
+    static CHECKPOINTED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // Conflicting commits already looked at this session
+    static SEEN_CONFLICTS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    // JS callback for recovery reports
+    static CALLBACK: RefCell<Option<Function>> = const { RefCell::new(None) };
+}
+
+/// Hold an event that failed to process until the group's epoch advances
+pub(crate) fn buffer(storage: &MdkHybridStorage, group_id: &GroupId, event: &Event) {
+    let waiting = storage.queue_pending_event(group_id, event);
+    crate::log(&format!("  📥 Queued {} until the epoch advances ({} waiting)", event.id.to_hex(), waiting));
+}
+
+/// A group's waiting events, in MIP-03 order, ready to be processed again
+fn pending(storage: &MdkHybridStorage, group_id: &GroupId) -> Vec<Event> {
+    let mut events = storage.pending_events(group_id);
+    events.sort_by_key(mip03_order);
+    storage.forget_processed_messages(&events.iter().map(|e| e.id).collect::<Vec<_>>());
+    events
+}
+
+/// Process a group's waiting events again. Those that still fail stay queued.
+pub(crate) async fn retry_pending(storage: &MdkHybridStorage, group_id: &GroupId) -> Result<(), JsValue> {
+    for event in pending(storage, group_id) {
+        // An earlier one may have advanced the epoch and retried it already
+        if storage.is_pending_event(&event.id) {
+            Box::pin(crate::handle_group_event(Box::new(event))).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forget the session's checkpoint and conflict bookkeeping (when the identity changes)
+pub(crate) fn reset() {
+    CHECKPOINTED.with(|c| c.borrow_mut().clear());
+    SEEN_CONFLICTS.with(|s| s.borrow_mut().clear());
+}
+
+pub(crate) fn set_callback(callback: Option<Function>) {
+    CALLBACK.with(|c| *c.borrow_mut() = callback);
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RecoveryOutcome {
+    /// We were on the losing side, rolled back and switched to the canonical commit
+    RolledBack,
+    /// The conflicting commit should win but couldn't be applied on any checkpoint
+    Unresolved,
+}
+
+/// What a recovery did, as passed to the JS callback
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RecoveryReport {
+    pub group_id: String,
+    pub outcome: RecoveryOutcome,
+    /// Epoch both sides committed for
+    pub fork_epoch: Option<u64>,
+    /// Epoch after the recovery
+    pub epoch: u64,
+    pub canonical_commit: String,
+    pub discarded_commit: Option<String>,
+    /// Our own commit was discarded, so its change has to be made again
+    pub own_commit_discarded: bool,
+    /// Events applied again after the rollback
+    pub replayed: usize,
+}
+
+fn report(report: &RecoveryReport) {
+    crate::log(&format!(
+        "🩹 Group {} fork: {:?} (fork epoch {:?}, now epoch {}, {} event(s) replayed)",
+        &report.group_id[..16.min(report.group_id.len())], report.outcome, report.fork_epoch, report.epoch, report.replayed
+    ));
+
+    let Some(callback) = CALLBACK.with(|c| c.borrow().clone()) else {
+        return;
+    };
+    match serde_json::to_string(report) {
+        Ok(json) => {
+            if let Err(e) = callback.call1(&JsValue::NULL, &JsValue::from_str(&json)) {
+                crate::log(&format!("  ❌ Recovery callback failed: {:?}", e));
+            }
+        }
+        Err(e) => crate::log(&format!("  ❌ Failed to serialize recovery report: {}", e)),
+    }
+}
+
+/// Handle an event that came back as `ProcessMessageWrongEpoch` for `group` (its state before the event).
+/// Returns whether we switched to its branch; if not, it may be from an epoch we haven't reached yet.
+pub(crate) async fn resolve_fork(mdk: &MDK<SharedMdkStorage>, storage: &MdkHybridStorage, group: &Group, event: &Event) -> bool {
+    let first_time = SEEN_CONFLICTS.with(|s| s.borrow_mut().insert(event.id.to_hex()));
+    if !first_time {
+        return false;
+    }
+
+    match try_resolve(mdk, storage, group, event).await {
+        Ok(Some(outcome)) => {
+            report(&outcome);
+            matches!(outcome.outcome, RecoveryOutcome::RolledBack)
+        }
+        Ok(None) => {
+            crate::log("  ℹ️  Not a commit that wins under MIP-03 ordering, keeping our branch");
+            false
+        }
+        Err(e) => {
+            crate::log(&format!("  ❌ Fork recovery failed: {:?}", e));
+            false
+        }
+    }
+}
+
+async fn try_resolve(
+    mdk: &MDK<SharedMdkStorage>,
+    storage: &MdkHybridStorage,
+    group: &Group,
+    event: &Event,
+) -> Result<Option<RecoveryReport>, JsValue> {
+    let group_id = &group.mls_group_id;
+    let all = checkpoints(group_id).await?;
+
+    // Epochs the commit could be for: our commit there sorts after it, and it isn't
+    // older than the commit that started the epoch. Newest first.
+    let candidates: Vec<&Checkpoint> = all.iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, c)| c.commit.as_ref().is_some_and(|applied| applied.sorts_after(event)))
+        .filter(|(i, _)| {
+            let started_by = i.checked_sub(1).and_then(|prev| all[prev].commit.as_ref());
+            started_by.is_none_or(|prev| prev.created_at <= event.created_at.as_u64() + CLOCK_SKEW_SECS)
+        })
+        .map(|(_, c)| c)
+        .collect();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let current = storage.snapshot_group(group_id)
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+
+    // Roll back to each candidate until the commit applies there
+    let mut fork = None;
+    for checkpoint in candidates {
+        storage.restore_group(&checkpoint.snapshot)?;
+        storage.forget_processed_messages(&[event.id]);
+        if mdk.process_message(event).is_ok() && epoch_of(storage, group_id).is_some_and(|e| e > checkpoint.epoch) {
+            fork = Some(checkpoint);
+            break;
+        }
+    }
+
+    let Some(fork) = fork else {
+        storage.restore_group(&current)?;
+        storage.save_snapshot().await?;
+        return Ok(Some(RecoveryReport {
+            group_id: hex::encode(group_id.as_slice()),
+            outcome: RecoveryOutcome::Unresolved,
+            fork_epoch: None,
+            epoch: current.epoch(),
+            canonical_commit: event.id.to_hex(),
+            discarded_commit: None,
+            own_commit_discarded: false,
+            replayed: 0,
+        }));
+    };
+    let discarded = fork.commit.clone().expect("candidates have a commit");
+    crate::log(&format!("  🔀 Switched epoch {} to commit {}", fork.epoch, event.id.to_hex()));
+
+    // Later checkpoints belong to the discarded branch
+    let stale: Vec<Checkpoint> = all.iter().filter(|c| c.epoch > fork.epoch).cloned().collect();
+    delete(&stale).await?;
+    record_commit(group_id, fork.epoch, event, false).await?;
+
+    // Everything since the fork, in MIP-03 order, plus whatever was waiting for a newer epoch
+    let since = discarded.created_at.min(event.created_at.as_u64()).saturating_sub(CLOCK_SKEW_SECS);
+    let filter = Filter::new()
+        .kind(Kind::MlsGroupMessage)
+        .custom_tag(SingleLetterTag::lowercase(Alphabet::H), hex::encode(group.nostr_group_id))
+        .since(Timestamp::from(since));
+    let client = relay_pool::client().await?;
+    let mut events: Vec<Event> = client.fetch_events(filter, REFETCH_TIMEOUT).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to refetch group events: {}", e)))?
+        .into_iter()
+        .collect();
+    events.extend(pending(storage, group_id));
+    events.retain(|e| e.id != event.id);
+    events.sort_by_key(mip03_order);
+    events.dedup_by_key(|e| e.id);
+    storage.forget_processed_messages(&events.iter().map(|e| e.id).collect::<Vec<_>>());
+
+    let mut replayed = 0;
+    for replay in &events {
+        let epoch = checkpoint(storage, group_id).await?;
+        if mdk.process_message(replay).is_ok() {
+            storage.remove_pending_event(&replay.id);
+            replayed += 1;
+            if epoch_of(storage, group_id).is_some_and(|e| e > epoch) {
+                record_commit(group_id, epoch, replay, false).await?;
+            }
+        }
+    }
+
+    storage.save_snapshot().await?;
+
+    Ok(Some(RecoveryReport {
+        group_id: hex::encode(group_id.as_slice()),
+        outcome: RecoveryOutcome::RolledBack,
+        fork_epoch: Some(fork.epoch),
+        epoch: epoch_of(storage, group_id).unwrap_or(fork.epoch),
+        canonical_commit: event.id.to_hex(),
+        discarded_commit: Some(discarded.event_id),
+        own_commit_discarded: discarded.own,
+        replayed,
+    }))
+}
@@ -0,0 +1,243 @@
+//! Shared relay connection pool
+//!
+//! One long-lived nostr-sdk `Client` serves every publish, fetch and
+//! subscription, instead of opening (and closing) websockets to every relay
+//! per call. Relays that drop reconnect on their own, waiting longer between
+//! attempts while they keep failing. `sync` applies edits of the relay list to
+//! the live pool and `health` reports the state of each relay. The pool also
+//! drives the outbox retries.
+//!
+//! nostr-sdk only sends REQs with a single filter, so `subscribe` sends
+//! multi-filter REQs itself and re-sends them to relays that (re)connect.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use nostr::{ClientMessage, Filter, RelayUrl, SubscriptionId};
+use nostr_sdk::{Client, RelayOptions};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Mutex as TokioMutex;
+use wasm_bindgen::JsValue;
+
+use crate::outbox;
+
+static POOL: Lazy<TokioMutex<Option<Client>>> = Lazy::new(|| TokioMutex::new(None));
+
+/// REQs sent with `subscribe`, by subscription ID
+static REQUESTS: Lazy<Mutex<HashMap<SubscriptionId, Vec<Filter>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Wait before the first reconnect; nostr-sdk stretches it for relays that keep failing
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often relays are checked for new connections that need the REQs re-sent
+const RESUBSCRIBE_TICK: Duration = Duration::from_secs(2);
+
+fn relay_options() -> RelayOptions {
+    RelayOptions::new()
+        .reconnect(true)
+        .retry_interval(RETRY_INTERVAL)
+        .adjust_retry_interval(true)
+}
+
+fn configured_relays() -> Result<HashSet<RelayUrl>, JsValue> {
+    Ok(crate::get_relays_internal()?
+        .iter()
+        .filter_map(|relay| match RelayUrl::parse(relay) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                crate::log(&format!("⚠️ Skipping invalid relay {}: {}", relay, e));
+                None
+            }
+        })
+        .collect())
+}
+
+async fn add(client: &Client, url: RelayUrl) -> Result<(), JsValue> {
+    client.pool().add_relay(url.clone(), relay_options()).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to add relay {}: {}", url, e)))?;
+    Ok(())
+}
+
+/// The shared client, connected to the configured relays on first use
+pub(crate) async fn client() -> Result<Client, JsValue> {
+    let mut pool = POOL.lock().await;
+    if let Some(client) = pool.as_ref() {
+        return Ok(client.clone());
+    }
+
+    let client = Client::default();
+    let relays = configured_relays()?;
+    for url in &relays {
+        add(&client, url.clone()).await?;
+    }
+    client.connect().await;
+    crate::log(&format!("🔌 Relay pool started with {} relay(s)", relays.len()));
+
+    wasm_bindgen_futures::spawn_local(outbox::run(client.clone()));
+    wasm_bindgen_futures::spawn_local(resubscribe(client.clone()));
+
+    *pool = Some(client.clone());
+    Ok(client)
+}
+
+/// Add and remove relays of the live pool to match the configured list
+pub(crate) async fn sync() -> Result<(), JsValue> {
+    let pool = POOL.lock().await;
+    // Not started yet: the configured list is read on first use
+    let Some(client) = pool.as_ref() else {
+        return Ok(());
+    };
+
+    let configured = configured_relays()?;
+    let current = client.relays().await;
+
+    for url in current.keys().filter(|url| !configured.contains(*url)) {
+        client.remove_relay(url.clone()).await
+            .map_err(|e| JsValue::from_str(&format!("Failed to remove relay {}: {}", url, e)))?;
+        crate::log(&format!("🔌 Disconnected from {}", url));
+    }
+
+    for url in configured.into_iter().filter(|url| !current.contains_key(url)) {
+        add(client, url.clone()).await?;
+        client.connect_relay(url.clone()).await
+            .map_err(|e| JsValue::from_str(&format!("Failed to connect to {}: {}", url, e)))?;
+        crate::log(&format!("🔌 Connecting to {}", url));
+    }
+
+    Ok(())
+}
+
+fn request(subscription_id: &SubscriptionId, filters: &[Filter]) -> ClientMessage<'static> {
+    ClientMessage::ReqMultiFilter {
+        subscription_id: Cow::Owned(subscription_id.clone()),
+        filters: filters.to_vec(),
+    }
+}
+
+/// Send a REQ with several filters to every relay in the pool (replacing an
+/// open REQ with the same ID). Relays that connect later get it then.
+pub(crate) async fn subscribe(client: &Client, subscription_id: SubscriptionId, filters: Vec<Filter>) -> Result<(), JsValue> {
+    let msg = request(&subscription_id, &filters);
+    REQUESTS.lock().unwrap().insert(subscription_id, filters);
+
+    let relays = client.relays().await;
+    let mut sent = 0;
+    for (url, relay) in &relays {
+        match relay.send_msg(msg.clone()) {
+            Ok(()) => sent += 1,
+            Err(e) => crate::log(&format!("⚠️ REQ not sent to {} yet: {}", url, e)),
+        }
+    }
+    if sent == 0 && !relays.is_empty() {
+        crate::log("⚠️ No relay connected, the REQ is sent once one connects");
+    }
+    Ok(())
+}
+
+/// The REQs sent with `subscribe` that are still open
+pub(crate) fn requests() -> HashMap<SubscriptionId, Vec<Filter>> {
+    REQUESTS.lock().unwrap().clone()
+}
+
+/// Close a REQ sent with `subscribe`
+pub(crate) async fn unsubscribe(client: &Client, subscription_id: &SubscriptionId) {
+    REQUESTS.lock().unwrap().remove(subscription_id);
+    for relay in client.relays().await.values() {
+        let _ = relay.send_msg(ClientMessage::close(subscription_id.clone()));
+    }
+}
+
+/// Re-send the open REQs to every relay that connected since the last check
+async fn resubscribe(client: Client) {
+    // Connection time each relay was last seen with
+    let mut seen: HashMap<RelayUrl, u64> = HashMap::new();
+    loop {
+        crate::sleep(RESUBSCRIBE_TICK).await;
+
+        let relays = client.relays().await;
+        seen.retain(|url, _| relays.contains_key(url));
+        for (url, relay) in relays {
+            if !relay.is_connected() {
+                continue;
+            }
+            let connected_at = relay.stats().connected_at().as_u64();
+            if seen.insert(url.clone(), connected_at) == Some(connected_at) {
+                continue;
+            }
+
+            let requests: Vec<ClientMessage<'static>> = REQUESTS.lock().unwrap()
+                .iter()
+                .map(|(id, filters)| request(id, filters))
+                .collect();
+            for msg in requests {
+                if let Err(e) = relay.send_msg(msg) {
+                    crate::log(&format!("⚠️ Failed to re-send REQ to {}: {}", url, e));
+                }
+            }
+        }
+    }
+}
+
+/// Try a relay through the live pool, returning whether it connected within `timeout`.
+/// A relay that isn't configured leaves the pool again afterwards if it failed.
+pub(crate) async fn probe(url: &RelayUrl, timeout: Duration) -> Result<bool, JsValue> {
+    let client = client().await?;
+    let known = client.relays().await.contains_key(url);
+    if !known {
+        add(&client, url.clone()).await?;
+    }
+    let _ = client.connect_relay(url.clone()).await;
+
+    crate::sleep(timeout).await;
+
+    let connected = client.relay(url.clone()).await
+        .map(|relay| relay.is_connected())
+        .unwrap_or(false);
+    if !connected && !known {
+        let _ = client.remove_relay(url.clone()).await;
+    }
+    Ok(connected)
+}
+
+/// Connection state of one relay in the pool
+#[derive(Serialize)]
+pub(crate) struct RelayHealth {
+    pub url: String,
+    /// nostr-sdk relay status, e.g. "connected", "connecting", "disconnected"
+    pub status: String,
+    pub connected: bool,
+    /// Connection attempts and how many of them succeeded
+    pub attempts: usize,
+    pub successes: usize,
+    /// Unix seconds of the last successful connection
+    pub connected_at: Option<u64>,
+}
+
+/// State of every relay in the pool (empty until the pool is first used)
+pub(crate) async fn health() -> Vec<RelayHealth> {
+    let pool = POOL.lock().await;
+    let Some(client) = pool.as_ref() else {
+        return Vec::new();
+    };
+
+    let mut health: Vec<RelayHealth> = client.relays().await
+        .into_iter()
+        .map(|(url, relay)| {
+            let stats = relay.stats();
+            let connected_at = stats.connected_at().as_u64();
+            RelayHealth {
+                url: url.to_string(),
+                status: relay.status().to_string().to_lowercase(),
+                connected: relay.is_connected(),
+                attempts: stats.attempts(),
+                successes: stats.success(),
+                connected_at: (connected_at > 0).then_some(connected_at),
+            }
+        })
+        .collect();
+    health.sort_by(|a, b| a.url.cmp(&b.url));
+    health
+}
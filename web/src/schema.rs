@@ -0,0 +1,308 @@
+//! Versioned envelopes, migrations and quarantine for persisted records
+//!
+//! Every JSON value we persist is wrapped as {"schema_version": N, "data": ...}
+//! before it is (optionally) encrypted. Values written before envelopes existed
+//! are read as version 1. On read, the registered migration steps bring old
+//! values up to the current version before they are deserialized.
+//!
+//! A value that still can't be read is never dropped: callers move the
+//! original into quarantine (a `quarantine` object store, or a localStorage
+//! key with a ".quarantine" suffix), which logs it and lets `get_quarantine`
+//! report it to the user.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use wasm_bindgen::JsValue;
+use web_sys::{IdbDatabase, Storage};
+
+use crate::idb;
+
+const VERSION_FIELD: &str = "schema_version";
+const DATA_FIELD: &str = "data";
+
+/// Version of values written before envelopes existed
+const UNVERSIONED: u32 = 1;
+
+/// Name of the object store holding quarantined records (in each database)
+pub(crate) const QUARANTINE_STORE: &str = "quarantine";
+
+/// Marks the localStorage keys quarantined localStorage values are moved to
+const QUARANTINE_SUFFIX: &str = ".quarantine";
+
+/// One upgrade step for the values of a record kind (an object store or localStorage key)
+struct Migration {
+    kind: &'static str,
+    from: u32,
+    step: fn(Value) -> Result<Value, String>,
+}
+
+/// Current version of each record kind; kinds not listed are at version 1.
+/// Bump a kind here and register a step below when its serde layout changes.
+const CURRENT_VERSIONS: &[(&str, u32)] = &[
+    // 2: OpenMLS values as hex strings instead of raw bytes
+    ("openmls", 2),
+];
+
+/// Steps from `from` to `from + 1`, e.g. for a renamed field:
+/// `Migration { kind: "groups", from: 1, step: |mut v| { rename(&mut v, "old", "new"); Ok(v) } }`
+const MIGRATIONS: &[Migration] = &[
+    Migration { kind: "openmls", from: 1, step: bytes_to_hex },
+];
+
+/// OpenMLS values from before envelopes were raw bytes (read as an array of numbers)
+fn bytes_to_hex(value: Value) -> Result<Value, String> {
+    let bytes: Vec<u8> = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    Ok(Value::String(hex::encode(bytes)))
+}
+
+fn current_version(versions: &[(&str, u32)], kind: &str) -> u32 {
+    versions
+        .iter()
+        .find(|(k, _)| *k == kind)
+        .map(|(_, v)| *v)
+        .unwrap_or(UNVERSIONED)
+}
+
+/// Wrap a value in an envelope at the current version of `kind`
+pub(crate) fn encode<T: Serialize>(kind: &str, value: &T) -> Result<String, serde_json::Error> {
+    let mut envelope = serde_json::Map::new();
+    envelope.insert(VERSION_FIELD.to_string(), Value::from(current_version(CURRENT_VERSIONS, kind)));
+    envelope.insert(DATA_FIELD.to_string(), serde_json::to_value(value)?);
+    serde_json::to_string(&envelope)
+}
+
+fn is_envelope_map(map: &serde_json::Map<String, Value>) -> bool {
+    map.len() == 2 && map.contains_key(DATA_FIELD) && map.get(VERSION_FIELD).is_some_and(Value::is_u64)
+}
+
+/// Whether a value was written by `encode`
+pub(crate) fn is_envelope(value: &Value) -> bool {
+    value.as_object().is_some_and(is_envelope_map)
+}
+
+/// Split an envelope into (version, data); anything else is an unversioned value
+fn unwrap_envelope(value: Value) -> (u32, Value) {
+    match value {
+        Value::Object(mut map) if is_envelope_map(&map) => {
+            let version = map[VERSION_FIELD].as_u64().unwrap_or_default() as u32;
+            (version, map.remove(DATA_FIELD).unwrap_or(Value::Null))
+        }
+        other => (UNVERSIONED, other),
+    }
+}
+
+/// Read a value written by `encode` (or before envelopes existed), migrating it to the current version
+pub(crate) fn decode<T: DeserializeOwned>(kind: &str, json: &str) -> Result<T, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("Invalid JSON: {}", e))?;
+    decode_value(kind, value)
+}
+
+/// `decode` for a value that is already parsed
+pub(crate) fn decode_value<T: DeserializeOwned>(kind: &str, value: Value) -> Result<T, String> {
+    decode_with(CURRENT_VERSIONS, MIGRATIONS, kind, value)
+}
+
+fn decode_with<T: DeserializeOwned>(
+    versions: &[(&str, u32)],
+    migrations: &[Migration],
+    kind: &str,
+    value: Value,
+) -> Result<T, String> {
+    let (mut version, mut data) = unwrap_envelope(value);
+
+    let current = current_version(versions, kind);
+    if version > current {
+        return Err(format!(
+            "Written by a newer version of the app (schema {} > {})",
+            version, current
+        ));
+    }
+
+    while version < current {
+        let migration = migrations
+            .iter()
+            .find(|m| m.kind == kind && m.from == version)
+            .ok_or_else(|| format!("No migration for {} from schema {}", kind, version))?;
+        data = (migration.step)(data)
+            .map_err(|e| format!("Migration of {} from schema {} failed: {}", kind, version, e))?;
+        version += 1;
+    }
+
+    serde_json::from_value(data).map_err(|e| format!("Schema {} mismatch: {}", version, e))
+}
+
+/// A value moved to quarantine because it couldn't be read
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuarantineEntry {
+    /// IndexedDB database name, or "localStorage"
+    pub database: String,
+    /// Object store or localStorage key the value came from
+    pub store: String,
+    pub key: String,
+    pub error: String,
+    /// Unix milliseconds
+    pub quarantined_at: f64,
+}
+
+impl QuarantineEntry {
+    pub(crate) fn new(database: &str, store: &str, key: &str, error: &str) -> Self {
+        Self {
+            database: database.to_string(),
+            store: store.to_string(),
+            key: key.to_string(),
+            error: error.to_string(),
+            quarantined_at: js_sys::Date::now(),
+        }
+    }
+
+    /// Key of the entry in a quarantine store (or suffix of a localStorage key)
+    fn quarantine_key(&self) -> String {
+        format!("{}/{}/{}", self.store, self.key, self.quarantined_at)
+    }
+}
+
+/// Move an unreadable record into the database's quarantine store (in one
+/// transaction, so it is never lost in between) and report it
+pub(crate) async fn quarantine_record(
+    db: &IdbDatabase,
+    entry: QuarantineEntry,
+    key: &JsValue,
+    record: &JsValue,
+) -> Result<(), JsValue> {
+    let entry_json = serde_json::to_string(&entry)
+        .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+    let quarantined = idb::object(&[
+        ("entry", JsValue::from_str(&entry_json)),
+        ("record", record.clone()),
+    ])?;
+
+    let tx = idb::write_transaction(db, &[entry.store.as_str(), QUARANTINE_STORE])?;
+    let queued = tx.object_store(QUARANTINE_STORE)
+        .and_then(|s| s.put_with_key(&quarantined, &JsValue::from_str(&entry.quarantine_key())))
+        .and_then(|_| tx.object_store(&entry.store))
+        .and_then(|s| s.delete(key))
+        .map(|_| ());
+    if let Err(e) = queued {
+        let _ = tx.abort();
+        return Err(e);
+    }
+    idb::await_transaction(&tx).await?;
+
+    report(&entry);
+    Ok(())
+}
+
+/// Entries in a database's quarantine store
+pub(crate) async fn quarantined_records(db: &IdbDatabase) -> Result<Vec<QuarantineEntry>, JsValue> {
+    idb::get_all_values(db, QUARANTINE_STORE).await?
+        .iter()
+        .filter_map(|record| idb::string_field(record, "entry"))
+        .map(|json| {
+            serde_json::from_str(&json)
+                .map_err(|e| JsValue::from_str(&format!("Invalid quarantine entry: {}", e)))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+struct QuarantinedValue {
+    entry: QuarantineEntry,
+    value: String,
+}
+
+/// Move an unreadable localStorage value to a quarantine key and report it
+pub(crate) fn quarantine_local(storage: &Storage, key: &str, error: &str) -> Result<(), JsValue> {
+    let Some(value) = storage.get_item(key)? else {
+        return Ok(());
+    };
+
+    let entry = QuarantineEntry::new("localStorage", key, key, error);
+    let quarantine_key = format!("{}{}/{}", key, QUARANTINE_SUFFIX, entry.quarantined_at);
+    report(&entry);
+    let json = serde_json::to_string(&QuarantinedValue { entry, value })
+        .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+
+    // Copy before removing the original
+    storage.set_item(&quarantine_key, &json)?;
+    storage.remove_item(key)?;
+    Ok(())
+}
+
+/// Entries quarantined from localStorage
+pub(crate) fn quarantined_local(storage: &Storage) -> Result<Vec<QuarantineEntry>, JsValue> {
+    let mut entries = Vec::new();
+    for i in 0..storage.length()? {
+        let Some(key) = storage.key(i)? else { continue };
+        if !key.contains(QUARANTINE_SUFFIX) {
+            continue;
+        }
+        if let Some(json) = storage.get_item(&key)? {
+            let quarantined: QuarantinedValue = serde_json::from_str(&json)
+                .map_err(|e| JsValue::from_str(&format!("Invalid quarantine entry: {}", e)))?;
+            entries.push(quarantined.entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn report(entry: &QuarantineEntry) {
+    web_sys::console::error_1(&JsValue::from_str(&format!(
+        "⚠️ Quarantined unreadable {} record {}/{}: {}",
+        entry.database, entry.store, entry.key, entry.error
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct Note {
+        title: String,
+    }
+
+    fn rename_name_to_title(mut value: Value) -> Result<Value, String> {
+        let map = value.as_object_mut().ok_or("Not an object")?;
+        let name = map.remove("name").ok_or("No name")?;
+        map.insert("title".to_string(), name);
+        Ok(value)
+    }
+
+    const VERSIONS: &[(&str, u32)] = &[("notes", 2), ("stuck", 2)];
+    const STEPS: &[Migration] = &[Migration { kind: "notes", from: 1, step: rename_name_to_title }];
+
+    #[test]
+    fn registered_step_upgrades_old_values() {
+        for old in [json!({ "name": "a" }), json!({ "schema_version": 1, "data": { "name": "a" } })] {
+            let note: Note = decode_with(VERSIONS, STEPS, "notes", old).unwrap();
+            assert_eq!(note.title, "a");
+        }
+
+        let current: Note = decode_with(VERSIONS, STEPS, "notes", json!({ "schema_version": 2, "data": { "title": "b" } })).unwrap();
+        assert_eq!(current.title, "b");
+    }
+
+    #[test]
+    fn refuses_newer_and_unmigratable_values() {
+        let newer = decode_with::<Note>(VERSIONS, STEPS, "notes", json!({ "schema_version": 3, "data": { "title": "c" } }));
+        assert!(newer.unwrap_err().contains("newer version"));
+
+        let stuck = decode_with::<Note>(VERSIONS, STEPS, "stuck", json!({ "title": "d" }));
+        assert!(stuck.unwrap_err().contains("No migration for stuck from schema 1"));
+
+        let failed = decode_with::<Note>(VERSIONS, STEPS, "notes", json!({ "title": "e" }));
+        assert!(failed.unwrap_err().contains("Migration of notes from schema 1 failed"));
+    }
+
+    #[test]
+    fn migrates_raw_openmls_bytes_to_hex() {
+        let legacy: String = decode_value("openmls", Value::from(vec![0u8, 171, 255])).unwrap();
+        assert_eq!(legacy, "00abff");
+
+        let json = encode("openmls", &"00abff").unwrap();
+        assert!(is_envelope(&serde_json::from_str(&json).unwrap()));
+        assert_eq!(decode::<String>("openmls", &json).unwrap(), "00abff");
+    }
+}
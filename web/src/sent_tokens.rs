@@ -0,0 +1,226 @@
+//! Tokens we sent, until someone claims them
+//!
+//! Sending takes proofs out of the wallet for good, so a token nobody claims
+//! would be lost unless its string was saved somewhere. Every token we create
+//! is kept in the wallet database with its proofs, committed together with the
+//! send. `check` asks the mints which of them were claimed (NUT-07); `reclaim`
+//! swaps the proofs that are still unspent back into the wallet, which `check`
+//! also does for tokens older than the auto-reclaim timeout, if one is set.
+//! A P2PK token locked to someone else can only be reclaimed after its
+//! locktime, with our key as a refund key (see `p2pk`).
+
+use std::collections::HashMap;
+
+use cdk::mint_url::MintUrl;
+use cdk::nuts::{CurrencyUnit, Proofs, State, Token};
+use cdk::wallet::ReceiveOptions;
+use cdk_common::wallet::TransactionId;
+use mdk_storage_traits::GroupId;
+use nostr::EventId;
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+
+use crate::p2pk;
+use crate::wallet_db::{HybridWalletDatabase, SentToken, SentTokenStatus};
+
+/// localStorage key of the auto-reclaim timeout (seconds)
+const AUTO_RECLAIM_KEY: &str = "auto_reclaim_after_secs";
+
+/// Record of a token we just created, to be stored with the send
+pub(crate) fn sent_token(token: &Token, proofs: Proofs, locked_to: Option<String>) -> Result<SentToken, JsValue> {
+    let ys = proofs.iter()
+        .map(|proof| proof.y())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| JsValue::from_str(&format!("Invalid proof: {}", e)))?;
+    let amount = token.value()
+        .map_err(|e| JsValue::from_str(&format!("Failed to get value: {}", e)))?;
+
+    Ok(SentToken {
+        id: TransactionId::new(ys).to_string(),
+        token: token.to_string(),
+        mint_url: token.mint_url()
+            .map_err(|e| JsValue::from_str(&format!("Failed to get mint URL: {}", e)))?,
+        unit: token.unit().unwrap_or(CurrencyUnit::Sat),
+        amount: u64::from(amount),
+        proofs,
+        created_at: nostr::Timestamp::now().as_u64(),
+        locked_to,
+        group_id: None,
+        message_id: None,
+        status: SentTokenStatus::Pending,
+    })
+}
+
+pub(crate) fn auto_reclaim_timeout() -> Result<Option<u64>, JsValue> {
+    let storage = crate::get_local_storage()?;
+    Ok(storage.get_item(AUTO_RECLAIM_KEY)?.and_then(|secs| secs.parse().ok()))
+}
+
+pub(crate) fn set_auto_reclaim_timeout(secs: Option<u64>) -> Result<(), JsValue> {
+    let storage = crate::get_local_storage()?;
+    match secs {
+        Some(secs) => storage.set_item(AUTO_RECLAIM_KEY, &secs.to_string()),
+        None => storage.remove_item(AUTO_RECLAIM_KEY),
+    }
+}
+
+/// The pending token we sent as `token_str`, if any
+pub(crate) async fn find_pending(db: &HybridWalletDatabase, token_str: &str) -> Result<Option<SentToken>, JsValue> {
+    Ok(db.sent_tokens().await?
+        .into_iter()
+        .find(|sent| sent.status == SentTokenStatus::Pending && sent.token == token_str.trim()))
+}
+
+/// Remember the group message a token we sent was posted as
+pub(crate) async fn link_message(token_str: &str, group_id: &GroupId, message_id: &EventId) -> Result<(), JsValue> {
+    let db = crate::get_or_create_wallet_db().await?;
+    let Some(mut sent) = find_pending(&db, token_str).await? else {
+        return Ok(());
+    };
+    sent.group_id = Some(hex::encode(group_id.as_slice()));
+    sent.message_id = Some(message_id.to_hex());
+    db.put_sent_token(sent).await
+}
+
+/// Ask the mint which of the proofs are unspent. A token without any is marked claimed.
+async fn unspent_proofs(wallet: &cdk::wallet::Wallet, db: &HybridWalletDatabase, sent: &mut SentToken) -> Result<Proofs, JsValue> {
+    let states: HashMap<_, _> = wallet.check_proofs_spent(sent.proofs.clone()).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to check proof states: {}", e)))?
+        .into_iter()
+        .map(|state| (state.y, state.state))
+        .collect();
+    let unspent: Proofs = sent.proofs.iter()
+        .filter(|proof| proof.y().is_ok_and(|y| states.get(&y) == Some(&State::Unspent)))
+        .cloned()
+        .collect();
+
+    if unspent.is_empty() && !sent.proofs.is_empty() {
+        sent.status = SentTokenStatus::Claimed;
+        db.put_sent_token(sent.clone()).await?;
+    }
+    Ok(unspent)
+}
+
+/// Whether we can spend the proofs of a token we sent: a P2PK lock has to have expired
+fn unlocked(sent: &SentToken) -> bool {
+    sent.locked_to.is_none() || sent.proofs.iter().all(|proof| p2pk::Lock::of(proof).is_some_and(|lock| lock.expired()))
+}
+
+/// Swap the unspent proofs of a pending token back into the wallet. Returns the amount received.
+pub(crate) async fn reclaim(id: &str) -> Result<u64, JsValue> {
+    let db = crate::get_or_create_wallet_db().await?;
+    let mut sent = db.sent_tokens().await?
+        .into_iter()
+        .find(|sent| sent.id == id)
+        .ok_or_else(|| JsValue::from_str("Sent token not found"))?;
+    match sent.status {
+        SentTokenStatus::Pending => {}
+        SentTokenStatus::Claimed => return Err(JsValue::from_str("The token was already claimed")),
+        SentTokenStatus::Reclaimed => return Err(JsValue::from_str("The token was already reclaimed")),
+    }
+
+    let wallet = crate::create_wallet_for_mint(sent.mint_url.to_string()).await?;
+    let mut unspent = unspent_proofs(&wallet, &db, &mut sent).await?;
+    if unspent.is_empty() {
+        return Err(JsValue::from_str("The token was already claimed"));
+    }
+
+    crate::log(&format!("↩️ Reclaiming {} of {} proof(s) of sent token {}", unspent.len(), sent.proofs.len(), id));
+    // A P2PK token can only be taken back by a key it names: ours as a refund
+    // key once the locktime passed (or alone, e.g. for a deposit we can spend by ourselves)
+    let mut p2pk_signing_keys = Vec::new();
+    if sent.locked_to.is_some() {
+        let key = crate::p2pk_signing_key()?;
+        let refunds = p2pk::sign_refunds(&mut unspent, &key)?;
+        let ours = unspent.iter()
+            .filter(|proof| p2pk::Lock::of(proof).is_some_and(|lock| lock.num_sigs == 1 && lock.locked_to(&key.public_key())))
+            .count();
+        if refunds + ours < unspent.len() {
+            let now = nostr::Timestamp::now().as_u64();
+            return Err(JsValue::from_str(&match unspent.iter().find_map(|proof| p2pk::Lock::of(proof)?.locktime) {
+                Some(locktime) if locktime > now => format!(
+                    "The token is locked to its recipient for another {} minute(s)",
+                    (locktime - now).div_ceil(60)
+                ),
+                _ => "The token is locked to its recipient and can't be reclaimed".to_string(),
+            }));
+        }
+        p2pk_signing_keys.push(key);
+    }
+    let receive_options = ReceiveOptions {
+        p2pk_signing_keys,
+        ..Default::default()
+    };
+
+    let mint_url = wallet.mint_url.clone();
+    let amount = db.atomic(|db| async move {
+        let amount = crate::wallet_on(db.clone(), mint_url).await?
+            .receive_proofs(unspent, receive_options, None)
+            .await
+            .map_err(|e| JsValue::from_str(&format!("Failed to reclaim token: {}", e)))?;
+        sent.status = SentTokenStatus::Reclaimed;
+        db.put_sent_token(sent.clone()).await?;
+        Ok::<_, JsValue>((u64::from(amount), sent))
+    }).await;
+    let (amount, sent) = amount?;
+    crate::log(&format!("✅ Reclaimed {} sats", amount));
+
+    // Let the group see the sender took it back
+    if let (Some(group_id), Some(message_id)) = (&sent.group_id, &sent.message_id) {
+        let group_id = hex::decode(group_id).map(|bytes| GroupId::from_slice(&bytes));
+        if let (Ok(group_id), Ok(message_id)) = (group_id, EventId::from_hex(message_id)) {
+            crate::announce_claim(&group_id, &message_id).await;
+        }
+    }
+
+    Ok(amount)
+}
+
+/// Outcome of `check`
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CheckResult {
+    /// Sent tokens found claimed (IDs)
+    pub claimed: Vec<String>,
+    /// Amounts received for sent tokens reclaimed after the timeout, by ID
+    pub reclaimed: HashMap<String, u64>,
+}
+
+/// See which pending tokens were claimed (NUT-07) and reclaim those older
+/// than the auto-reclaim timeout
+pub(crate) async fn check() -> Result<CheckResult, JsValue> {
+    let db = crate::get_or_create_wallet_db().await?;
+    let mut by_mint: HashMap<MintUrl, Vec<SentToken>> = HashMap::new();
+    for sent in db.sent_tokens().await?.into_iter().filter(|sent| sent.status == SentTokenStatus::Pending) {
+        by_mint.entry(sent.mint_url.clone()).or_default().push(sent);
+    }
+
+    let timeout = auto_reclaim_timeout()?;
+    let now = nostr::Timestamp::now().as_u64();
+    let mut result = CheckResult::default();
+    for (mint_url, pending) in by_mint {
+        let wallet = match crate::create_wallet_for_mint(mint_url.to_string()).await {
+            Ok(wallet) => wallet,
+            Err(e) => {
+                crate::log(&format!("⚠️ Couldn't check sent tokens with {}: {:?}", mint_url, e));
+                continue;
+            }
+        };
+
+        for mut sent in pending {
+            match unspent_proofs(&wallet, &db, &mut sent).await {
+                Ok(_) if sent.status == SentTokenStatus::Claimed => result.claimed.push(sent.id),
+                Ok(_) if timeout.is_some_and(|timeout| sent.created_at + timeout <= now) && unlocked(&sent) => {
+                    match reclaim(&sent.id).await {
+                        Ok(amount) => {
+                            result.reclaimed.insert(sent.id, amount);
+                        }
+                        Err(e) => crate::log(&format!("⚠️ Couldn't reclaim sent token {}: {:?}", sent.id, e)),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => crate::log(&format!("⚠️ Couldn't check sent token {}: {:?}", sent.id, e)),
+            }
+        }
+    }
+    Ok(result)
+}
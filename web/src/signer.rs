@@ -0,0 +1,314 @@
+//! Event signing backends
+//!
+//! Everything that signs events or needs NIP-44 goes through `AppSigner`,
+//! which implements nostr's `NostrSigner` for the local keys in localStorage,
+//! a NIP-07 browser extension (`window.nostr`) or a NIP-46 remote signer
+//! (bunker). The chosen backend is remembered in localStorage under `signer_type`.
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Duration;
+
+use js_sys::{Function, Promise, Reflect};
+use nostr::signer::{NostrSigner, SignerBackend, SignerError};
+use nostr::util::BoxedFuture;
+use nostr::{Event, JsonUtil, Keys, PublicKey, UnsignedEvent};
+use nostr_connect::prelude::{NostrConnect, NostrConnectURI};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::window;
+
+/// localStorage key recording which signer is in use (absent = local keys)
+pub(crate) const SIGNER_TYPE_KEY: &str = "signer_type";
+
+/// localStorage key caching the external signer's public key, so pubkey lookups stay synchronous
+pub(crate) const SIGNER_PUBKEY_KEY: &str = "signer_pubkey";
+
+pub(crate) const SIGNER_TYPE_LOCAL: &str = "local";
+pub(crate) const SIGNER_TYPE_NIP07: &str = "nip07";
+pub(crate) const SIGNER_TYPE_NIP46: &str = "nip46";
+
+/// localStorage key holding the NIP-46 session (sealed by the vault, it contains the app key)
+pub(crate) const NIP46_SESSION_KEY: &str = "nip46_session";
+
+/// localStorage key holding the app key of a nostrconnect:// handshake that hasn't completed yet
+pub(crate) const NIP46_PENDING_KEY: &str = "nip46_pending_app_key";
+
+/// How long to wait for the remote signer to answer a request
+const NIP46_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A persisted NIP-46 session: enough to reconnect to the bunker after a reload
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Nip46Session {
+    /// bunker:// URI of the remote signer
+    pub bunker_uri: String,
+    /// Secret key (hex) of our app keypair, which the bunker has authorized
+    pub app_secret_key: String,
+}
+
+thread_local! {
+    // The NIP-46 client keeps relay connections open, so it is built once per page
+    static REMOTE_SIGNER: RefCell<Option<Arc<NostrConnect>>> = RefCell::new(None);
+}
+
+/// NIP-07 signer backed by the `window.nostr` object injected by browser extensions
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BrowserSigner;
+
+impl BrowserSigner {
+    /// Whether an extension has injected `window.nostr`
+    pub(crate) fn is_available() -> bool {
+        Self::nostr().is_ok()
+    }
+
+    fn nostr() -> Result<JsValue, SignerError> {
+        let window = window().ok_or_else(|| SignerError::backend("No window"))?;
+        let nostr = Reflect::get(&window, &JsValue::from_str("nostr"))
+            .map_err(|e| SignerError::backend(format!("{:?}", e)))?;
+        if nostr.is_undefined() || nostr.is_null() {
+            return Err(SignerError::backend("No NIP-07 extension found (window.nostr is missing)"));
+        }
+        Ok(nostr)
+    }
+
+    /// Call `target[method](...args)` and await the returned promise
+    async fn call(target: &JsValue, method: &str, args: &[JsValue]) -> Result<JsValue, SignerError> {
+        let function: Function = Reflect::get(target, &JsValue::from_str(method))
+            .ok()
+            .and_then(|f| f.dyn_into().ok())
+            .ok_or_else(|| SignerError::backend(format!("Extension does not support {}", method)))?;
+
+        let args: js_sys::Array = args.iter().collect();
+        let returned = function
+            .apply(target, &args)
+            .map_err(|e| SignerError::backend(format!("{} failed: {:?}", method, e)))?;
+
+        JsFuture::from(Promise::resolve(&returned))
+            .await
+            .map_err(|e| SignerError::backend(format!("{} rejected: {:?}", method, e)))
+    }
+
+    /// Call `window.nostr[namespace][method](pubkey, content)` (nip04/nip44 encrypt/decrypt)
+    async fn cipher(namespace: &str, method: &str, public_key: &PublicKey, content: &str) -> Result<String, SignerError> {
+        let api = Reflect::get(&Self::nostr()?, &JsValue::from_str(namespace))
+            .ok()
+            .filter(|api| !api.is_undefined())
+            .ok_or_else(|| SignerError::backend(format!("Extension does not support {}", namespace)))?;
+
+        let args = [JsValue::from_str(&public_key.to_hex()), JsValue::from_str(content)];
+        Self::call(&api, method, &args)
+            .await?
+            .as_string()
+            .ok_or_else(|| SignerError::backend(format!("{}.{} returned a non-string", namespace, method)))
+    }
+}
+
+impl NostrSigner for BrowserSigner {
+    fn backend(&self) -> SignerBackend {
+        SignerBackend::BrowserExtension
+    }
+
+    fn get_public_key(&self) -> BoxedFuture<Result<PublicKey, SignerError>> {
+        Box::pin(async move {
+            let hex = Self::call(&Self::nostr()?, "getPublicKey", &[])
+                .await?
+                .as_string()
+                .ok_or_else(|| SignerError::backend("getPublicKey returned a non-string"))?;
+            PublicKey::from_hex(&hex).map_err(SignerError::backend)
+        })
+    }
+
+    fn sign_event(&self, unsigned: UnsignedEvent) -> BoxedFuture<Result<Event, SignerError>> {
+        Box::pin(async move {
+            let template = js_sys::JSON::parse(&unsigned.as_json())
+                .map_err(|e| SignerError::backend(format!("{:?}", e)))?;
+            let signed = Self::call(&Self::nostr()?, "signEvent", &[template]).await?;
+
+            let json = js_sys::JSON::stringify(&signed)
+                .map_err(|e| SignerError::backend(format!("{:?}", e)))?
+                .as_string()
+                .unwrap_or_default();
+            let event = Event::from_json(json).map_err(SignerError::backend)?;
+
+            // Don't trust the extension to have signed exactly what we asked for
+            event.verify().map_err(SignerError::backend)?;
+            if event.pubkey != unsigned.pubkey || unsigned.id.is_some_and(|id| id != event.id) {
+                return Err(SignerError::backend("Extension signed a different event than requested"));
+            }
+
+            Ok(event)
+        })
+    }
+
+    fn nip04_encrypt<'a>(&'a self, public_key: &'a PublicKey, content: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(Self::cipher("nip04", "encrypt", public_key, content))
+    }
+
+    fn nip04_decrypt<'a>(&'a self, public_key: &'a PublicKey, encrypted_content: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(Self::cipher("nip04", "decrypt", public_key, encrypted_content))
+    }
+
+    fn nip44_encrypt<'a>(&'a self, public_key: &'a PublicKey, content: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(Self::cipher("nip44", "encrypt", public_key, content))
+    }
+
+    fn nip44_decrypt<'a>(&'a self, public_key: &'a PublicKey, payload: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(Self::cipher("nip44", "decrypt", public_key, payload))
+    }
+}
+
+/// The signer selected by the user
+#[derive(Debug, Clone)]
+pub(crate) enum AppSigner {
+    Local(Keys),
+    Browser(BrowserSigner),
+    Remote(Arc<NostrConnect>),
+}
+
+impl NostrSigner for AppSigner {
+    fn backend(&self) -> SignerBackend {
+        match self {
+            Self::Local(keys) => keys.backend(),
+            Self::Browser(signer) => signer.backend(),
+            Self::Remote(signer) => signer.backend(),
+        }
+    }
+
+    fn get_public_key(&self) -> BoxedFuture<Result<PublicKey, SignerError>> {
+        match self {
+            Self::Local(keys) => keys.get_public_key(),
+            Self::Browser(signer) => signer.get_public_key(),
+            Self::Remote(signer) => signer.get_public_key(),
+        }
+    }
+
+    fn sign_event(&self, unsigned: UnsignedEvent) -> BoxedFuture<Result<Event, SignerError>> {
+        match self {
+            Self::Local(keys) => keys.sign_event(unsigned),
+            Self::Browser(signer) => signer.sign_event(unsigned),
+            Self::Remote(signer) => signer.sign_event(unsigned),
+        }
+    }
+
+    fn nip04_encrypt<'a>(&'a self, public_key: &'a PublicKey, content: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        match self {
+            Self::Local(keys) => keys.nip04_encrypt(public_key, content),
+            Self::Browser(signer) => signer.nip04_encrypt(public_key, content),
+            Self::Remote(signer) => signer.nip04_encrypt(public_key, content),
+        }
+    }
+
+    fn nip04_decrypt<'a>(&'a self, public_key: &'a PublicKey, encrypted_content: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        match self {
+            Self::Local(keys) => keys.nip04_decrypt(public_key, encrypted_content),
+            Self::Browser(signer) => signer.nip04_decrypt(public_key, encrypted_content),
+            Self::Remote(signer) => signer.nip04_decrypt(public_key, encrypted_content),
+        }
+    }
+
+    fn nip44_encrypt<'a>(&'a self, public_key: &'a PublicKey, content: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        match self {
+            Self::Local(keys) => keys.nip44_encrypt(public_key, content),
+            Self::Browser(signer) => signer.nip44_encrypt(public_key, content),
+            Self::Remote(signer) => signer.nip44_encrypt(public_key, content),
+        }
+    }
+
+    fn nip44_decrypt<'a>(&'a self, public_key: &'a PublicKey, payload: &'a str) -> BoxedFuture<'a, Result<String, SignerError>> {
+        match self {
+            Self::Local(keys) => keys.nip44_decrypt(public_key, payload),
+            Self::Browser(signer) => signer.nip44_decrypt(public_key, payload),
+            Self::Remote(signer) => signer.nip44_decrypt(public_key, payload),
+        }
+    }
+}
+
+/// Which signer is configured ("local", "nip07" or "nip46")
+pub(crate) fn signer_type() -> Result<String, JsValue> {
+    let storage = crate::get_local_storage()?;
+    Ok(storage
+        .get_item(SIGNER_TYPE_KEY)?
+        .unwrap_or_else(|| SIGNER_TYPE_LOCAL.to_string()))
+}
+
+/// Get the configured signer
+pub(crate) fn get_signer() -> Result<AppSigner, JsValue> {
+    match signer_type()?.as_str() {
+        SIGNER_TYPE_NIP07 => Ok(AppSigner::Browser(BrowserSigner)),
+        SIGNER_TYPE_NIP46 => Ok(AppSigner::Remote(remote_signer()?)),
+        _ => Ok(AppSigner::Local(crate::get_keys()?)),
+    }
+}
+
+/// Build a NIP-46 client for `uri` (bunker:// or nostrconnect://) using our app keys
+pub(crate) fn new_remote_signer(uri: &str, app_keys: Keys) -> Result<NostrConnect, JsValue> {
+    let uri = NostrConnectURI::parse(uri.trim())
+        .map_err(|e| JsValue::from_str(&format!("Invalid NIP-46 URI: {}", e)))?;
+    NostrConnect::new(uri, app_keys, NIP46_TIMEOUT, None)
+        .map_err(|e| JsValue::from_str(&format!("Failed to create remote signer: {}", e)))
+}
+
+/// The cached NIP-46 client, rebuilt from the persisted session after a reload
+fn remote_signer() -> Result<Arc<NostrConnect>, JsValue> {
+    if let Some(signer) = REMOTE_SIGNER.with(|s| s.borrow().clone()) {
+        return Ok(signer);
+    }
+
+    let session = load_nip46_session()?
+        .ok_or_else(|| JsValue::from_str("No remote signer session - connect your bunker again"))?;
+    let app_keys = Keys::parse(&session.app_secret_key)
+        .map_err(|e| JsValue::from_str(&format!("Invalid app key in session: {}", e)))?;
+
+    let signer = Arc::new(new_remote_signer(&session.bunker_uri, app_keys)?);
+    set_remote_signer(Some(Arc::clone(&signer)));
+    Ok(signer)
+}
+
+/// Replace (or drop) the cached NIP-46 client
+pub(crate) fn set_remote_signer(signer: Option<Arc<NostrConnect>>) {
+    REMOTE_SIGNER.with(|s| *s.borrow_mut() = signer);
+}
+
+pub(crate) fn load_nip46_session() -> Result<Option<Nip46Session>, JsValue> {
+    match crate::get_local_storage()?.get_item(NIP46_SESSION_KEY)? {
+        Some(stored) => {
+            let json = crate::vault::open(&stored)?;
+            serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|e| JsValue::from_str(&format!("Invalid remote signer session: {}", e)))
+        }
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn save_nip46_session(session: &Nip46Session) -> Result<(), JsValue> {
+    let json = serde_json::to_string(session)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize session: {}", e)))?;
+    crate::get_local_storage()?.set_item(NIP46_SESSION_KEY, &crate::vault::seal(&json)?)
+}
+
+/// Rewrite the stored session and pending app key under the vault's current setting
+pub(crate) fn reseal() -> Result<(), JsValue> {
+    let storage = crate::get_local_storage()?;
+    for key in [NIP46_SESSION_KEY, NIP46_PENDING_KEY] {
+        if let Some(stored) = storage.get_item(key)? {
+            storage.set_item(key, &crate::vault::seal(&crate::vault::open(&stored)?)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Public key of the configured signer (cached for remote signers)
+pub(crate) fn get_public_key() -> Result<PublicKey, JsValue> {
+    match signer_type()?.as_str() {
+        SIGNER_TYPE_LOCAL => Ok(crate::get_keys()?.public_key()),
+        _ => {
+            let hex = crate::get_local_storage()?
+                .get_item(SIGNER_PUBKEY_KEY)?
+                .ok_or_else(|| JsValue::from_str("No public key cached for the external signer - reconnect it"))?;
+            PublicKey::from_hex(&hex)
+                .map_err(|e| JsValue::from_str(&format!("Invalid cached public key: {}", e)))
+        }
+    }
+}
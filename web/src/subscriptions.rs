@@ -0,0 +1,208 @@
+//! Shared subscription for group messages and Welcomes
+//!
+//! Instead of a REQ (and a listener that never ends) per opened group, one REQ
+//! on the relay pool carries two filters: the group messages of all joined
+//! groups, covering their `#h` tags, and the Welcomes addressed to our `#p`.
+//! Each event is processed once and routed by kind to the callbacks
+//! registered for its group (or for Welcomes).
+//!
+//! Registering a callback returns a handle for `unsubscribe`. The REQ is only
+//! re-sent when what it covers changes, and closed when no callbacks are left.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use js_sys::Function;
+use mdk_storage_traits::GroupId;
+use nostr::{Filter, Kind, SubscriptionId};
+use wasm_bindgen::JsValue;
+
+use crate::relay_pool;
+
+/// The shared REQ (relays replace a REQ sent again under the same ID)
+const SUBSCRIPTION_ID: &str = "mdk-inbox";
+
+/// Part of the shared REQ, one filter each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum Feed {
+    GroupMessages,
+    Welcomes,
+}
+
+impl Feed {
+    /// The feed an event of the shared REQ belongs to
+    fn of(event: &nostr::Event) -> Option<Self> {
+        match event.kind {
+            Kind::MlsGroupMessage => Some(Feed::GroupMessages),
+            Kind::Custom(444) => Some(Feed::Welcomes),
+            _ => None,
+        }
+    }
+}
+
+struct Handler {
+    feed: Feed,
+    /// Group whose messages the callback receives (None for Welcomes)
+    group_id: Option<GroupId>,
+    callback: Function,
+}
+
+/// What a feed contributes to the REQ
+struct Part {
+    /// `#h` tags (or our pubkey) the filter covers
+    scope: BTreeSet<String>,
+    filter: Filter,
+}
+
+/// The REQ that is open on the relays
+struct ActiveRequest {
+    /// Scope of each feed the REQ was sent with
+    scope: BTreeMap<Feed, BTreeSet<String>>,
+    /// Listener that handles the REQ's events; older listeners stop
+    generation: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_handle: u32,
+    handlers: HashMap<u32, Handler>,
+    parts: BTreeMap<Feed, Part>,
+    active: Option<ActiveRequest>,
+    next_generation: u64,
+}
+
+thread_local! {
+    // Callbacks are JS functions, so the registry lives on the page's thread
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+fn register(feed: Feed, group_id: Option<GroupId>, callback: Function) -> u32 {
+    REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        registry.next_handle += 1;
+        let handle = registry.next_handle;
+        registry.handlers.insert(handle, Handler { feed, group_id, callback });
+        handle
+    })
+}
+
+/// Register a callback for the application messages of a group
+pub(crate) fn register_group(group_id: GroupId, callback: Function) -> u32 {
+    register(Feed::GroupMessages, Some(group_id), callback)
+}
+
+/// Register a callback for processed Welcomes
+pub(crate) fn register_welcomes(callback: Function) -> u32 {
+    register(Feed::Welcomes, None, callback)
+}
+
+/// Drop a callback, returning the feed it was registered for (None for unknown handles)
+pub(crate) fn unregister(handle: u32) -> Option<Feed> {
+    REGISTRY.with(|r| r.borrow_mut().handlers.remove(&handle).map(|h| h.feed))
+}
+
+pub(crate) fn has_callbacks(feed: Feed) -> bool {
+    REGISTRY.with(|r| r.borrow().handlers.values().any(|h| h.feed == feed))
+}
+
+/// Callbacks registered for a group's messages
+pub(crate) fn group_callbacks(group_id: &GroupId) -> Vec<Function> {
+    REGISTRY.with(|r| {
+        r.borrow().handlers.values()
+            .filter(|h| h.group_id.as_ref() == Some(group_id))
+            .map(|h| h.callback.clone())
+            .collect()
+    })
+}
+
+/// Callbacks registered for Welcomes
+pub(crate) fn welcome_callbacks() -> Vec<Function> {
+    REGISTRY.with(|r| {
+        r.borrow().handlers.values()
+            .filter(|h| h.feed == Feed::Welcomes)
+            .map(|h| h.callback.clone())
+            .collect()
+    })
+}
+
+/// Whether a listener still handles the REQ
+fn is_current(generation: u64) -> bool {
+    REGISTRY.with(|r| r.borrow().active.as_ref().map(|a| a.generation) == Some(generation))
+}
+
+/// Set a feed's filter and re-send the shared REQ if what it covers changed,
+/// starting a new listener. A feed with no callbacks (or nothing to cover) is
+/// left out of the REQ, which is closed once no feed is left.
+pub(crate) async fn update(feed: Feed, scope: BTreeSet<String>, filter: Filter) -> Result<(), JsValue> {
+    let client = relay_pool::client().await?;
+    let subscription_id = SubscriptionId::new(SUBSCRIPTION_ID);
+
+    // None if the open REQ already covers the same, Some(None) if it has to be closed
+    let request = REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        if scope.is_empty() || !registry.handlers.values().any(|h| h.feed == feed) {
+            registry.parts.remove(&feed);
+        } else {
+            registry.parts.insert(feed, Part { scope, filter });
+        }
+
+        let scope: BTreeMap<Feed, BTreeSet<String>> = registry.parts.iter()
+            .map(|(feed, part)| (*feed, part.scope.clone()))
+            .collect();
+        if registry.active.as_ref().map(|a| &a.scope) == Some(&scope) {
+            return None;
+        }
+        if scope.is_empty() {
+            return registry.active.take().map(|_| None);
+        }
+
+        registry.next_generation += 1;
+        let generation = registry.next_generation;
+        registry.active = Some(ActiveRequest { scope, generation });
+        let filters: Vec<Filter> = registry.parts.values().map(|part| part.filter.clone()).collect();
+        Some(Some((generation, filters)))
+    });
+
+    let (generation, filters) = match request {
+        None => return Ok(()),
+        Some(None) => {
+            relay_pool::unsubscribe(&client, &subscription_id).await;
+            crate::log("📴 Closed the group subscription");
+            return Ok(());
+        }
+        Some(Some(request)) => request,
+    };
+
+    crate::log(&format!("📡 Subscribing with {} filter(s)", filters.len()));
+
+    // Same subscription ID, so relays replace the previous REQ
+    wasm_bindgen_futures::spawn_local(async move {
+        let result = crate::subscribe_with_ordered_history(
+            &client,
+            subscription_id,
+            filters,
+            crate::HistoryOptions::load(),
+            move || is_current(generation),
+            |event| async move {
+                match Feed::of(&event) {
+                    Some(feed) => crate::handle_subscription_event(feed, event).await,
+                    None => Ok(()),
+                }
+            },
+        )
+        .await;
+
+        if let Err(e) = result {
+            crate::log(&format!("❌ Group subscription error: {:?}", e));
+            // Let the next subscribe call start over
+            REGISTRY.with(|r| {
+                let mut registry = r.borrow_mut();
+                if registry.active.as_ref().is_some_and(|a| a.generation == generation) {
+                    registry.active = None;
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
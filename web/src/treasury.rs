@@ -0,0 +1,510 @@
+//! Group treasuries: ecash that any k of a group's admins spend together
+//!
+//! A deposit is a token locked to the group's admins at the time (NUT-11
+//! multisig with `n_sigs` = k), posted as a treasury message. Spending one goes
+//! through the group as well: a member posts a spend request for a deposit with
+//! the blinded outputs to swap it for (derived from their wallet seed), admins
+//! post their signatures of that swap, and once k of them signed, the member who
+//! asked swaps the proofs into their own wallet and posts a claim notice for the
+//! deposit. Deposits are locked with SIG_ALL, so a signature only releases the
+//! deposit to the outputs of the request it was made for.
+//!
+//! The treasury is read back from the group's messages, so every member sees the
+//! same one; whether a deposit is spent is asked from its mint, the claim notice
+//! only says by whom.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use cdk::amount::SplitTarget;
+use cdk::dhke::construct_proofs;
+use cdk::nuts::{BlindedMessage, CurrencyUnit, Id, KeySetInfo, PreMintSecrets, Proofs, SpendingConditions, State, SwapRequest, Token};
+use cdk::wallet::{HttpClient, MintConnector, Wallet};
+use cdk::Amount;
+use cdk_common::common::ProofInfo;
+use cdk_common::database::WalletDatabase;
+use js_sys::Function;
+use mdk_storage_traits::groups::GroupStorage;
+use mdk_storage_traits::messages::types::Message;
+use mdk_storage_traits::messages::MessageStorage;
+use mdk_storage_traits::GroupId;
+use nostr::{EventId, Kind, PublicKey, Tag, Tags, ToBech32};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::mdk_storage::MdkHybridStorage;
+use crate::p2pk::{self, Lock};
+use crate::payments;
+
+/// Rumor kind of a treasury deposit (a multisig token, tagged like a token message)
+pub(crate) const DEPOSIT_KIND: Kind = Kind::Custom(9312);
+
+/// Rumor kind asking the signers to release a deposit to the author's outputs (e-tags the deposit)
+pub(crate) const SPEND_REQUEST_KIND: Kind = Kind::Custom(9313);
+
+/// Rumor kind carrying a signer's signature of a spend request's swap (e-tags the request)
+pub(crate) const SIGNATURES_KIND: Kind = Kind::Custom(9314);
+
+/// Messages that make up the treasury rather than the chat
+pub(crate) fn is_message(msg: &Message) -> bool {
+    [DEPOSIT_KIND, SPEND_REQUEST_KIND, SIGNATURES_KIND].contains(&msg.kind)
+}
+
+thread_local! {
+    // JS callback for changes to a group's treasury
+    static CALLBACK: RefCell<Option<Function>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn set_callback(callback: Option<Function>) {
+    CALLBACK.with(|c| *c.borrow_mut() = callback);
+}
+
+/// Tell the callback that a message changed its group's treasury
+pub(crate) fn report(msg: &Message) {
+    crate::log(&format!("🏦 Treasury message {} (kind {})", msg.id.to_hex(), msg.kind.as_u16()));
+
+    let Some(callback) = CALLBACK.with(|c| c.borrow().clone()) else {
+        return;
+    };
+    let json = serde_json::json!({
+        "group_id": hex::encode(msg.mls_group_id.as_slice()),
+        "message_id": msg.id.to_hex(),
+    });
+    if let Err(e) = callback.call1(&JsValue::NULL, &JsValue::from_str(&json.to_string())) {
+        crate::log(&format!("  ❌ Treasury callback failed: {:?}", e));
+    }
+}
+
+/// Conditions of a deposit to a group: any `threshold` of its admins
+pub(crate) fn admin_lock(storage: &MdkHybridStorage, group_id: &GroupId, threshold: u64) -> Result<SpendingConditions, JsValue> {
+    let group = storage.find_group_by_mls_group_id(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get group: {}", e)))?
+        .ok_or_else(|| JsValue::from_str("Group not found"))?;
+    if group.admin_pubkeys.is_empty() {
+        return Err(JsValue::from_str("The group has no admins"));
+    }
+
+    let keys = group.admin_pubkeys.iter()
+        .map(p2pk::pubkey_from_nostr)
+        .collect::<Result<Vec<_>, JsValue>>()?;
+    p2pk::multisig(keys, threshold)
+}
+
+/// Tags of a message about another one
+pub(crate) fn reply_tags(message_id: &EventId) -> Tags {
+    let mut tags = Tags::new();
+    tags.push(Tag::event(*message_id));
+    tags
+}
+
+/// A deposit, as listed by get_treasury
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Deposit {
+    /// Message (rumor) ID, hex
+    pub id: String,
+    pub amount: u64,
+    pub unit: String,
+    pub mint: String,
+    pub memo: Option<String>,
+    /// Who deposited it (npub)
+    pub sender: String,
+    /// Unix seconds
+    pub created_at: u64,
+    /// How many of the signers (npubs) have to sign to spend it
+    pub threshold: u64,
+    pub signers: Vec<String>,
+    /// Whether the mint reports it spent
+    pub spent: bool,
+    /// Who spent it (npub), if their claim notice arrived
+    pub spent_by: Option<String>,
+    pub requests: Vec<SpendRequest>,
+}
+
+/// A request to spend a deposit
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SpendRequest {
+    /// Message (rumor) ID, hex
+    pub id: String,
+    /// Who asked (npub); the sats go to them
+    pub requested_by: String,
+    pub memo: Option<String>,
+    /// Unix seconds
+    pub created_at: u64,
+    /// Signers who signed it (npubs)
+    pub signed_by: Vec<String>,
+    /// Whether enough signers signed (counting the requester, who signs when spending)
+    pub ready: bool,
+}
+
+/// Content of a spend request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Spend {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+    /// The requester's blinded outputs: the deposit, less the mint's input fee
+    outputs: Vec<BlindedMessage>,
+    /// Where in the requester's wallet seed the outputs come from (NUT-13)
+    keyset_id: Id,
+    counter: u32,
+}
+
+impl Spend {
+    /// The swap the signers sign: the deposit's proofs for the requester's outputs
+    fn swap(&self, proofs: &Proofs) -> SwapRequest {
+        SwapRequest::new(proofs.clone(), self.outputs.clone())
+    }
+}
+
+/// Content of a signatures message
+#[derive(Debug, Serialize, Deserialize)]
+struct Signatures {
+    /// Signature (hex) of the request's swap (SIG_ALL)
+    signature: String,
+}
+
+fn npub(pubkey: &PublicKey) -> String {
+    pubkey.to_bech32().unwrap_or_else(|_| pubkey.to_hex())
+}
+
+fn replied_to(msg: &Message) -> Option<String> {
+    payments::tag_value(msg, "e")
+}
+
+/// The token of a deposit message
+fn deposit_token(msg: &Message) -> Option<Token> {
+    if msg.kind != DEPOSIT_KIND {
+        return None;
+    }
+    Token::from_str(&payments::tag_value(msg, payments::TOKEN_TAG)?).ok()
+}
+
+fn is_signer(lock: &Lock, pubkey: &PublicKey) -> bool {
+    p2pk::pubkey_from_nostr(pubkey).is_ok_and(|key| lock.locked_to(&key))
+}
+
+/// A group's treasury messages, sorted out
+struct Ledger {
+    deposits: Vec<(Message, Token)>,
+    /// Spend requests by deposit ID
+    requests: HashMap<String, Vec<(Message, Spend)>>,
+    /// Signatures by request ID, in the order they were posted
+    signatures: HashMap<String, Vec<(PublicKey, Signatures)>>,
+    /// Author of the first claim notice for each deposit. Anyone can post one,
+    /// so it only says who spent a deposit the mint reports spent.
+    claimed_by: HashMap<String, PublicKey>,
+}
+
+fn ledger(storage: &MdkHybridStorage, group_id: &GroupId) -> Result<Ledger, JsValue> {
+    let mut messages = storage.messages(group_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get messages: {}", e)))?;
+    messages.sort_by_key(|msg| msg.created_at);
+
+    let mut ledger = Ledger {
+        deposits: Vec::new(),
+        requests: HashMap::new(),
+        signatures: HashMap::new(),
+        claimed_by: HashMap::new(),
+    };
+    for msg in messages {
+        if msg.kind == DEPOSIT_KIND {
+            if let Some(token) = deposit_token(&msg) {
+                ledger.deposits.push((msg, token));
+            }
+        } else if msg.kind == SPEND_REQUEST_KIND {
+            let Some(deposit_id) = replied_to(&msg) else {
+                continue;
+            };
+            let Ok(spend) = serde_json::from_str::<Spend>(&msg.content) else {
+                crate::log(&format!("⚠️ Unreadable treasury spend request {}", msg.id.to_hex()));
+                continue;
+            };
+            ledger.requests.entry(deposit_id).or_default().push((msg, spend));
+        } else if msg.kind == SIGNATURES_KIND {
+            let Some(request_id) = replied_to(&msg) else {
+                continue;
+            };
+            let Ok(signatures) = serde_json::from_str::<Signatures>(&msg.content) else {
+                crate::log(&format!("⚠️ Unreadable treasury signatures in {}", msg.id.to_hex()));
+                continue;
+            };
+            ledger.signatures.entry(request_id).or_default().push((msg.pubkey, signatures));
+        } else if msg.kind == payments::CLAIM_NOTICE_KIND {
+            if let Some(deposit_id) = replied_to(&msg) {
+                ledger.claimed_by.entry(deposit_id).or_insert(msg.pubkey);
+            }
+        }
+    }
+    Ok(ledger)
+}
+
+impl Ledger {
+    /// Signers of the deposit who validly signed a request's swap, with their first valid signature
+    fn signed(&self, request_id: &str, swap: &SwapRequest, lock: &Lock) -> Vec<(PublicKey, &str)> {
+        let mut signed: Vec<(PublicKey, &str)> = Vec::new();
+        for (signer, signatures) in self.signatures.get(request_id).into_iter().flatten() {
+            let valid = p2pk::pubkey_from_nostr(signer)
+                .is_ok_and(|key| lock.locked_to(&key) && p2pk::signed_swap(swap, &key, &signatures.signature));
+            if valid && !signed.iter().any(|(other, _)| other == signer) {
+                signed.push((*signer, &signatures.signature));
+            }
+        }
+        signed
+    }
+
+    fn deposit(&self, deposit_id: &str) -> Option<&(Message, Token)> {
+        self.deposits.iter().find(|(msg, _)| msg.id.to_hex() == deposit_id)
+    }
+
+    /// The error for spending a deposit the mint reports spent
+    fn already_spent(&self, deposit_id: &str) -> JsValue {
+        match self.claimed_by.get(deposit_id) {
+            Some(spender) => JsValue::from_str(&format!("The deposit was already spent by {}", npub(spender))),
+            None => JsValue::from_str("The deposit was already spent"),
+        }
+    }
+}
+
+/// A deposit as its mint sees it
+struct Checked {
+    wallet: Wallet,
+    keysets: Vec<KeySetInfo>,
+    proofs: Proofs,
+    lock: Lock,
+    /// Whether the mint reports its proofs spent (NUT-07)
+    spent: bool,
+}
+
+/// Read a deposit's proofs with its mint's keysets and ask the mint whether they are spent
+async fn check(token: &Token) -> Result<Checked, JsValue> {
+    let mint = token.mint_url()
+        .map_err(|e| JsValue::from_str(&format!("Failed to get mint URL: {}", e)))?;
+    let wallet = crate::create_wallet_for_mint(mint.to_string()).await?;
+    let keysets = wallet.get_mint_keysets().await
+        .map_err(|e| JsValue::from_str(&format!("Failed to get keysets: {}", e)))?;
+    let proofs = token.proofs(&keysets)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get proofs: {}", e)))?;
+    let lock = proofs.first()
+        .and_then(Lock::of)
+        .ok_or_else(|| JsValue::from_str("The deposit isn't locked"))?;
+    if !lock.sig_all {
+        return Err(JsValue::from_str("The deposit isn't locked to whole swaps (SIG_ALL)"));
+    }
+
+    let spent = wallet.check_proofs_spent(proofs.clone()).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to check proof states: {}", e)))?
+        .iter()
+        .any(|state| state.state == State::Spent);
+    Ok(Checked { wallet, keysets, proofs, lock, spent })
+}
+
+/// A group's deposits, oldest first, with their spend requests.
+/// Deposits whose mint can't be reached are left out.
+pub(crate) async fn deposits(storage: &MdkHybridStorage, group_id: &GroupId) -> Result<Vec<Deposit>, JsValue> {
+    let ledger = ledger(storage, group_id)?;
+
+    let mut deposits = Vec::new();
+    for (msg, token) in &ledger.deposits {
+        let id = msg.id.to_hex();
+        let Checked { proofs, lock, spent, .. } = match check(token).await {
+            Ok(checked) => checked,
+            Err(e) => {
+                crate::log(&format!("⚠️ Couldn't check treasury deposit {}: {:?}", id, e));
+                continue;
+            }
+        };
+        let requests = ledger.requests.get(&id)
+            .into_iter()
+            .flatten()
+            .map(|(request, spend)| {
+                let mut signed_by: Vec<PublicKey> = ledger.signed(&request.id.to_hex(), &spend.swap(&proofs), &lock)
+                    .into_iter()
+                    .map(|(signer, _)| signer)
+                    .collect();
+                let requester_signs = is_signer(&lock, &request.pubkey) && !signed_by.contains(&request.pubkey);
+                let ready = signed_by.len() as u64 + u64::from(requester_signs) >= lock.num_sigs;
+                signed_by.sort();
+                SpendRequest {
+                    id: request.id.to_hex(),
+                    requested_by: npub(&request.pubkey),
+                    memo: spend.memo.clone().filter(|memo| !memo.is_empty()),
+                    created_at: request.created_at.as_u64(),
+                    signed_by: signed_by.iter().map(npub).collect(),
+                    ready,
+                }
+            })
+            .collect();
+
+        let (Ok(amount), Ok(mint)) = (token.value(), token.mint_url()) else {
+            continue;
+        };
+        deposits.push(Deposit {
+            amount: u64::from(amount),
+            unit: token.unit().map(|unit| unit.to_string()).unwrap_or_else(|| "sat".to_string()),
+            mint: mint.to_string(),
+            memo: Some(msg.content.clone()).filter(|memo| !memo.is_empty()),
+            sender: npub(&msg.pubkey),
+            created_at: msg.created_at.as_u64(),
+            threshold: lock.num_sigs,
+            signers: lock.pubkeys.iter().filter_map(p2pk::npub).collect(),
+            spent,
+            spent_by: ledger.claimed_by.get(&id).filter(|_| spent).map(npub),
+            requests,
+            id,
+        });
+    }
+    Ok(deposits)
+}
+
+/// Ask to spend a deposit that can still be spent, for outputs from our wallet seed.
+/// Returns the group and the content of the spend request to post to it.
+pub(crate) async fn request_spend(storage: &MdkHybridStorage, deposit_id: &EventId, memo: Option<String>) -> Result<(GroupId, String), JsValue> {
+    let deposit = storage.find_message_by_event_id(deposit_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get message: {}", e)))?
+        .filter(|msg| msg.kind == DEPOSIT_KIND)
+        .ok_or_else(|| JsValue::from_str("Deposit not found"))?;
+    let token = deposit_token(&deposit)
+        .ok_or_else(|| JsValue::from_str("The deposit has no readable token"))?;
+    let Checked { keysets, proofs, spent, .. } = check(&token).await?;
+    if spent {
+        let ledger = ledger(storage, &deposit.mls_group_id)?;
+        return Err(ledger.already_spent(&deposit_id.to_hex()));
+    }
+
+    // The swap pays the mint's input fee (NUT-02) out of the deposit
+    let fee_ppk: u64 = proofs.iter()
+        .filter_map(|proof| keysets.iter().find(|keyset| keyset.id == proof.keyset_id))
+        .map(|keyset| keyset.input_fee_ppk)
+        .sum();
+    let total: u64 = proofs.iter().map(|proof| u64::from(proof.amount)).sum();
+    let amount = total.checked_sub(fee_ppk.div_ceil(1000))
+        .filter(|amount| *amount > 0)
+        .map(Amount::from)
+        .ok_or_else(|| JsValue::from_str("The deposit doesn't cover the mint's fee"))?;
+    let keyset = keysets.iter()
+        .filter(|keyset| keyset.active && keyset.unit == CurrencyUnit::Sat)
+        .min_by_key(|keyset| keyset.input_fee_ppk)
+        .ok_or_else(|| JsValue::from_str("The mint has no active keyset"))?;
+
+    // Reserve the outputs' place in our seed, so restore_wallet finds them too
+    let db = crate::get_or_create_wallet_db().await?;
+    let seed = crate::wallet_seed::wallet_seed(&db).await?;
+    let count = amount.split().len() as u32;
+    let counter = db.increment_keyset_counter(&keyset.id, count).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to reserve outputs: {}", e)))?
+        - count;
+    let premint = PreMintSecrets::from_seed(keyset.id, counter, &seed, amount, &SplitTarget::default())
+        .map_err(|e| JsValue::from_str(&format!("Failed to create outputs: {}", e)))?;
+
+    let spend = Spend {
+        memo: memo.filter(|memo| !memo.is_empty()),
+        outputs: premint.blinded_messages(),
+        keyset_id: keyset.id,
+        counter,
+    };
+    let content = serde_json::to_string(&spend)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))?;
+    Ok((deposit.mls_group_id, content))
+}
+
+/// A spend request, its group's treasury and the deposit it is for, which must be unspent
+async fn request(storage: &MdkHybridStorage, request_id: &EventId) -> Result<(Message, Spend, Ledger, Message, Checked), JsValue> {
+    let request = storage.find_message_by_event_id(request_id)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get message: {}", e)))?
+        .filter(|msg| msg.kind == SPEND_REQUEST_KIND)
+        .ok_or_else(|| JsValue::from_str("Spend request not found"))?;
+    let deposit_id = replied_to(&request)
+        .ok_or_else(|| JsValue::from_str("The spend request doesn't name a deposit"))?;
+    let spend = serde_json::from_str::<Spend>(&request.content)
+        .map_err(|e| JsValue::from_str(&format!("Unreadable spend request: {}", e)))?;
+
+    let ledger = ledger(storage, &request.mls_group_id)?;
+    let (deposit, token) = ledger.deposit(&deposit_id)
+        .ok_or_else(|| JsValue::from_str("Deposit not found"))?;
+    let checked = check(token).await?;
+    if checked.spent {
+        return Err(ledger.already_spent(&deposit_id));
+    }
+    let deposit = deposit.clone();
+    Ok((request, spend, ledger, deposit, checked))
+}
+
+/// Sign a spend request's swap as one of its deposit's signers.
+/// Returns the group and the content of the signatures message to post to it.
+pub(crate) async fn sign(storage: &MdkHybridStorage, request_id: &EventId) -> Result<(GroupId, String), JsValue> {
+    let (request, spend, _, _, Checked { proofs, lock, .. }) = request(storage, request_id).await?;
+
+    // Whether we are a signer goes by our identity; signing needs its raw key,
+    // which an extension or bunker doesn't hand out (p2pk_signing_key says so)
+    if !is_signer(&lock, &crate::signer::get_public_key()?) {
+        return Err(JsValue::from_str("You're not one of the signers of this deposit"));
+    }
+    let key = crate::p2pk_signing_key()?;
+
+    let signatures = Signatures { signature: p2pk::swap_signature(&spend.swap(&proofs), &key)? };
+    let content = serde_json::to_string(&signatures)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize: {}", e)))?;
+    Ok((request.mls_group_id, content))
+}
+
+/// Spend the deposit of our own spend request into our wallet, with the signatures
+/// collected for it (and ours, if we are a signer).
+/// Returns the group, the deposit's message ID and the amount received.
+pub(crate) async fn spend(storage: &MdkHybridStorage, request_id: &EventId) -> Result<(GroupId, EventId, u64), JsValue> {
+    let (request, spend, ledger, deposit, Checked { wallet, mut proofs, lock, .. }) = request(storage, request_id).await?;
+    if request.pubkey != crate::signer::get_public_key()? {
+        return Err(JsValue::from_str("Only the member who asked can spend the deposit"));
+    }
+
+    let swap = spend.swap(&proofs);
+    let mut signatures: Vec<String> = ledger.signed(&request_id.to_hex(), &swap, &lock)
+        .into_iter()
+        .filter(|(signer, _)| *signer != request.pubkey)
+        .map(|(_, signature)| signature.to_string())
+        .collect();
+    let count = signatures.len() as u64 + u64::from(is_signer(&lock, &request.pubkey));
+    if count < lock.num_sigs {
+        return Err(JsValue::from_str(&format!("{} of {} signatures so far", count, lock.num_sigs)));
+    }
+
+    // Just enough signatures: the first signers', then ours if they fall short
+    // (only then do we need our raw key, see sign)
+    signatures.truncate(lock.num_sigs as usize);
+    if (signatures.len() as u64) < lock.num_sigs {
+        signatures.push(p2pk::swap_signature(&swap, &crate::p2pk_signing_key()?)?);
+    }
+
+    // Our outputs come from our wallet seed, where the request says
+    let db = crate::get_or_create_wallet_db().await?;
+    let seed = crate::wallet_seed::wallet_seed(&db).await?;
+    let amount = Amount::from(spend.outputs.iter().map(|output| u64::from(output.amount)).sum::<u64>());
+    let premint = PreMintSecrets::from_seed(spend.keyset_id, spend.counter, &seed, amount, &SplitTarget::default())
+        .map_err(|e| JsValue::from_str(&format!("Failed to create outputs: {}", e)))?;
+    if premint.blinded_messages() != spend.outputs {
+        return Err(JsValue::from_str("The request's outputs aren't from this wallet's seed"));
+    }
+
+    let first = proofs.first_mut()
+        .ok_or_else(|| JsValue::from_str("The deposit has no proofs"))?;
+    p2pk::witness(first, signatures);
+
+    crate::log(&format!("🏦 Spending treasury deposit {} with {} signature(s)", deposit.id.to_hex(), lock.num_sigs));
+    let client = HttpClient::new(wallet.mint_url.clone());
+    let response = client.post_swap(SwapRequest::new(proofs, spend.outputs)).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to spend deposit: {}", e)))?;
+    let keys = client.get_mint_keyset(spend.keyset_id).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to get keys: {}", e)))?
+        .keys;
+    let received = construct_proofs(response.signatures, premint.rs(), premint.secrets(), &keys)
+        .map_err(|e| JsValue::from_str(&format!("Failed to unblind proofs: {}", e)))?;
+
+    // Were this to fail, restore_wallet would still find them (the outputs are from our seed)
+    let amount: u64 = received.iter().map(|proof| u64::from(proof.amount)).sum();
+    let received = received.into_iter()
+        .map(|proof| ProofInfo::new(proof, wallet.mint_url.clone(), State::Unspent, wallet.unit.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| JsValue::from_str(&format!("Invalid proof: {}", e)))?;
+    db.update_proofs(received, Vec::new()).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to store proofs: {}", e)))?;
+    Ok((request.mls_group_id, deposit.id, amount))
+}
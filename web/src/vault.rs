@@ -0,0 +1,311 @@
+//! Optional passphrase encryption for everything we persist
+//! (the Nostr secret key, MDK/OpenMLS records and wallet records)
+//!
+//! Records are encrypted with a random data key using XChaCha20-Poly1305 and
+//! stored as "enc1:" + base64(nonce || ciphertext). The passphrase only wraps
+//! the data key (scrypt-derived key encryption key), so changing it rewrites
+//! a single localStorage entry instead of every record. Values without the
+//! prefix are plaintext written before encryption was enabled and are read
+//! as-is.
+
+use std::sync::Mutex;
+
+use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+use web_sys::{window, Storage};
+
+/// localStorage key holding the vault metadata (present = encryption enabled)
+const VAULT_METADATA_KEY: &str = "vault";
+
+/// Prefix marking an encrypted value
+const ENVELOPE_PREFIX: &str = "enc1:";
+
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+/// scrypt cost (log2 N) for new passphrases - kept in the metadata so it can change later
+const DEFAULT_LOG_N: u8 = 15;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VaultMetadata {
+    version: u32,
+    /// scrypt salt (hex)
+    salt: String,
+    log_n: u8,
+    /// Data key encrypted with the passphrase-derived key (envelope format)
+    wrapped_key: String,
+}
+
+#[derive(Default)]
+struct VaultState {
+    /// Data key, only present while unlocked
+    data_key: Option<[u8; KEY_LEN]>,
+    /// When false, writes stay plaintext even though we hold a key (used while disabling)
+    plaintext_writes: bool,
+}
+
+static STATE: Lazy<Mutex<VaultState>> = Lazy::new(|| Mutex::new(VaultState::default()));
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum VaultError {
+    #[error("Storage is locked - unlock it with your passphrase first")]
+    Locked,
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+    #[error("Encryption is not enabled")]
+    NotEnabled,
+    #[error("Encrypted value is corrupt: {0}")]
+    Corrupt(String),
+    #[error("Vault storage error: {0}")]
+    Storage(String),
+}
+
+impl From<VaultError> for JsValue {
+    fn from(e: VaultError) -> Self {
+        JsValue::from_str(&e.to_string())
+    }
+}
+
+fn local_storage() -> Result<Storage, VaultError> {
+    window()
+        .and_then(|w| w.local_storage().ok().flatten())
+        .ok_or_else(|| VaultError::Storage("No localStorage".to_string()))
+}
+
+fn load_metadata() -> Result<Option<VaultMetadata>, VaultError> {
+    let json = local_storage()?
+        .get_item(VAULT_METADATA_KEY)
+        .map_err(|e| VaultError::Storage(format!("{:?}", e)))?;
+
+    json.map(|json| serde_json::from_str(&json).map_err(|e| VaultError::Corrupt(e.to_string())))
+        .transpose()
+}
+
+fn store_metadata(metadata: &VaultMetadata) -> Result<(), VaultError> {
+    let json = serde_json::to_string(metadata).map_err(|e| VaultError::Storage(e.to_string()))?;
+    local_storage()?
+        .set_item(VAULT_METADATA_KEY, &json)
+        .map_err(|e| VaultError::Storage(format!("{:?}", e)))
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], VaultError> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| VaultError::Storage(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8) -> Result<[u8; KEY_LEN], VaultError> {
+    let params = scrypt::Params::new(log_n, 8, 1, KEY_LEN)
+        .map_err(|e| VaultError::Storage(format!("Invalid scrypt parameters: {}", e)))?;
+    let mut key = [0u8; KEY_LEN];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| VaultError::Storage(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+fn encrypt_with(key: &[u8; KEY_LEN], plaintext: &[u8]) -> Result<String, VaultError> {
+    let nonce = random_bytes::<NONCE_LEN>()?;
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| VaultError::Storage("Encryption failed".to_string()))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend(ciphertext);
+    Ok(format!("{}{}", ENVELOPE_PREFIX, general_purpose::STANDARD.encode(payload)))
+}
+
+fn decrypt_with(key: &[u8; KEY_LEN], envelope: &str) -> Result<Vec<u8>, VaultError> {
+    let encoded = envelope
+        .strip_prefix(ENVELOPE_PREFIX)
+        .ok_or_else(|| VaultError::Corrupt("Missing envelope prefix".to_string()))?;
+    let payload = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| VaultError::Corrupt(e.to_string()))?;
+    if payload.len() < NONCE_LEN {
+        return Err(VaultError::Corrupt("Envelope too short".to_string()));
+    }
+
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| VaultError::Corrupt("Authentication failed".to_string()))
+}
+
+/// Wrap `data_key` under a fresh salt for `passphrase`
+fn wrap_data_key(passphrase: &str, data_key: &[u8; KEY_LEN]) -> Result<VaultMetadata, VaultError> {
+    let salt = random_bytes::<SALT_LEN>()?;
+    let key = derive_key(passphrase, &salt, DEFAULT_LOG_N)?;
+    Ok(VaultMetadata {
+        version: 1,
+        salt: hex::encode(salt),
+        log_n: DEFAULT_LOG_N,
+        wrapped_key: encrypt_with(&key, data_key)?,
+    })
+}
+
+fn current_key() -> Option<[u8; KEY_LEN]> {
+    STATE.lock().unwrap().data_key
+}
+
+/// Data encrypted under a passphrase alone, independent of the vault (used for backups)
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PassphraseSealed {
+    /// scrypt salt (hex)
+    salt: String,
+    log_n: u8,
+    /// Envelope format
+    ciphertext: String,
+}
+
+/// Highest scrypt cost accepted when opening - bounds the memory a crafted file can demand
+const MAX_LOG_N: u8 = 22;
+
+/// Encrypt `plaintext` under a key derived from `passphrase`
+pub(crate) fn seal_with_passphrase(passphrase: &str, plaintext: &[u8]) -> Result<PassphraseSealed, VaultError> {
+    let salt = random_bytes::<SALT_LEN>()?;
+    let key = derive_key(passphrase, &salt, DEFAULT_LOG_N)?;
+    Ok(PassphraseSealed {
+        salt: hex::encode(salt),
+        log_n: DEFAULT_LOG_N,
+        ciphertext: encrypt_with(&key, plaintext)?,
+    })
+}
+
+/// Decrypt data sealed with `seal_with_passphrase`
+pub(crate) fn open_with_passphrase(passphrase: &str, sealed: &PassphraseSealed) -> Result<Vec<u8>, VaultError> {
+    if sealed.log_n > MAX_LOG_N {
+        return Err(VaultError::Corrupt(format!("scrypt cost {} is too high", sealed.log_n)));
+    }
+    let salt = hex::decode(&sealed.salt).map_err(|e| VaultError::Corrupt(e.to_string()))?;
+    let key = derive_key(passphrase, &salt, sealed.log_n)?;
+    decrypt_with(&key, &sealed.ciphertext).map_err(|_| VaultError::WrongPassphrase)
+}
+
+/// Whether a passphrase has been set
+pub(crate) fn is_enabled() -> bool {
+    matches!(load_metadata(), Ok(Some(_)) | Err(_))
+}
+
+/// Whether encryption is enabled but the data key isn't loaded
+pub(crate) fn is_locked() -> bool {
+    current_key().is_none() && is_enabled()
+}
+
+/// Fail early with `Locked` instead of on the first encrypted record
+pub(crate) fn ensure_unlocked() -> Result<(), VaultError> {
+    if is_locked() {
+        return Err(VaultError::Locked);
+    }
+    Ok(())
+}
+
+/// Whether a value was written encrypted
+pub(crate) fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(ENVELOPE_PREFIX)
+}
+
+/// Whether new writes must be encrypted. Errors while locked so nothing
+/// is ever written in plaintext once a passphrase is set.
+pub(crate) fn sealing() -> Result<bool, VaultError> {
+    let state = STATE.lock().unwrap();
+    match state.data_key {
+        Some(_) => Ok(!state.plaintext_writes),
+        None if is_enabled() => Err(VaultError::Locked),
+        None => Ok(false),
+    }
+}
+
+/// Encrypt raw bytes into an envelope (requires the vault to be unlocked)
+pub(crate) fn encrypt(plaintext: &[u8]) -> Result<String, VaultError> {
+    let key = current_key().ok_or(VaultError::Locked)?;
+    encrypt_with(&key, plaintext)
+}
+
+/// Decrypt an envelope back to raw bytes (requires the vault to be unlocked)
+pub(crate) fn decrypt(envelope: &str) -> Result<Vec<u8>, VaultError> {
+    let key = current_key().ok_or(VaultError::Locked)?;
+    decrypt_with(&key, envelope)
+}
+
+/// Prepare a string value for storage (encrypted if a passphrase is set)
+pub(crate) fn seal(plaintext: &str) -> Result<String, VaultError> {
+    if sealing()? {
+        encrypt(plaintext.as_bytes())
+    } else {
+        Ok(plaintext.to_string())
+    }
+}
+
+/// Read a stored string value, decrypting it if needed
+pub(crate) fn open(stored: &str) -> Result<String, VaultError> {
+    if !is_sealed(stored) {
+        return Ok(stored.to_string());
+    }
+    String::from_utf8(decrypt(stored)?).map_err(|e| VaultError::Corrupt(e.to_string()))
+}
+
+/// Load the data key using the passphrase
+pub(crate) fn unlock(passphrase: &str) -> Result<(), VaultError> {
+    let metadata = load_metadata()?.ok_or(VaultError::NotEnabled)?;
+    let salt = hex::decode(&metadata.salt).map_err(|e| VaultError::Corrupt(e.to_string()))?;
+    let key = derive_key(passphrase, &salt, metadata.log_n)?;
+
+    let data_key: [u8; KEY_LEN] = decrypt_with(&key, &metadata.wrapped_key)
+        .map_err(|_| VaultError::WrongPassphrase)?
+        .try_into()
+        .map_err(|_| VaultError::Corrupt("Wrapped key has the wrong length".to_string()))?;
+
+    *STATE.lock().unwrap() = VaultState {
+        data_key: Some(data_key),
+        plaintext_writes: false,
+    };
+    Ok(())
+}
+
+/// Forget the data key
+pub(crate) fn lock() {
+    let mut state = STATE.lock().unwrap();
+    if let Some(key) = state.data_key.as_mut() {
+        key.fill(0);
+    }
+    *state = VaultState::default();
+}
+
+/// Set a first passphrase. Existing plaintext records stay readable and are
+/// encrypted as they are rewritten.
+pub(crate) fn enable(passphrase: &str) -> Result<(), VaultError> {
+    let data_key = random_bytes::<KEY_LEN>()?;
+    store_metadata(&wrap_data_key(passphrase, &data_key)?)?;
+
+    *STATE.lock().unwrap() = VaultState {
+        data_key: Some(data_key),
+        plaintext_writes: false,
+    };
+    Ok(())
+}
+
+/// Re-wrap the data key under a new passphrase (requires the vault to be unlocked)
+pub(crate) fn rewrap(new_passphrase: &str) -> Result<(), VaultError> {
+    let data_key = current_key().ok_or(VaultError::Locked)?;
+    store_metadata(&wrap_data_key(new_passphrase, &data_key)?)
+}
+
+/// Keep the key for reading but write plaintext from now on.
+/// Used while decrypting everything before `disable`.
+pub(crate) fn set_plaintext_writes(enabled: bool) {
+    STATE.lock().unwrap().plaintext_writes = enabled;
+}
+
+/// Remove the passphrase. Only call once every record has been rewritten in plaintext.
+pub(crate) fn disable() -> Result<(), VaultError> {
+    local_storage()?
+        .remove_item(VAULT_METADATA_KEY)
+        .map_err(|e| VaultError::Storage(format!("{:?}", e)))?;
+    lock();
+    Ok(())
+}
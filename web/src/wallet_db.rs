@@ -1,22 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use wasm_bindgen::JsValue;
-use web_sys::window;
+use tokio::sync::Mutex as TokioMutex;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{window, DomException, IdbDatabase, IdbTransaction};
+
+use crate::idb::{self, DbSchema};
+use crate::schema::{self, QuarantineEntry, QUARANTINE_STORE};
+use crate::vault;
 
 use cdk_common::database::Error as DbError;
 use cdk_common::database::WalletDatabase;
 use cdk_common::common::ProofInfo;
 use cdk_common::mint_url::MintUrl;
 use cdk_common::nuts::{
-    CurrencyUnit, Id, KeySetInfo, Keys, MintInfo, PublicKey, SpendingConditions, State,
+    CurrencyUnit, Id, KeySetInfo, Keys, MintInfo, Proofs, PublicKey, SpendingConditions, State,
 };
 use cdk_common::wallet::{
     MintQuote, MeltQuote, Transaction, TransactionDirection, TransactionId,
 };
 use cashu::KeySet;
 
+/// Layout of the legacy localStorage snapshot, migrated into IndexedDB on first load.
+/// Also the layout of wallet backups.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct WalletState {
     mints: HashMap<MintUrl, Option<MintInfo>>,
@@ -28,70 +39,720 @@ struct WalletState {
     proofs: Vec<ProofInfo>,
     keyset_counters: HashMap<Id, u32>,
     transactions: Vec<Transaction>,
+    #[serde(default)]
+    sent_tokens: Vec<SentToken>,
+}
+
+/// What became of a token we sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SentTokenStatus {
+    /// Not known to be spent, so its proofs can still be reclaimed
+    Pending,
+    /// Spent by someone else
+    Claimed,
+    /// Swapped back into this wallet
+    Reclaimed,
+}
+
+/// A token that left the wallet, kept with its proofs until it is claimed or reclaimed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentToken {
+    /// Transaction ID of its proofs (from their Y values)
+    pub id: String,
+    pub token: String,
+    pub mint_url: MintUrl,
+    pub unit: CurrencyUnit,
+    pub amount: u64,
+    pub proofs: Proofs,
+    /// Unix seconds
+    pub created_at: u64,
+    /// Who can spend a P2PK-locked token: its recipient (npub), or which group admins
+    pub locked_to: Option<String>,
+    /// MLS group (hex) and message (hex) it was posted to, if any
+    pub group_id: Option<String>,
+    pub message_id: Option<String>,
+    pub status: SentTokenStatus,
+}
+
+/// IndexedDB database holding the wallet, one object store per record type
+const DB_NAME: &str = "wallet_db";
+
+const MINTS_STORE: &str = "mints";
+const KEYSETS_STORE: &str = "keysets";
+const KEYS_STORE: &str = "keys";
+const MINT_QUOTES_STORE: &str = "mint_quotes";
+const MELT_QUOTES_STORE: &str = "melt_quotes";
+const PROOFS_STORE: &str = "proofs";
+const KEYSET_COUNTERS_STORE: &str = "keyset_counters";
+const TRANSACTIONS_STORE: &str = "transactions";
+const SENT_TOKENS_STORE: &str = "sent_tokens";
+
+const ALL_STORES: &[&str] = &[
+    MINTS_STORE,
+    KEYSETS_STORE,
+    KEYS_STORE,
+    MINT_QUOTES_STORE,
+    MELT_QUOTES_STORE,
+    PROOFS_STORE,
+    KEYSET_COUNTERS_STORE,
+    TRANSACTIONS_STORE,
+    SENT_TOKENS_STORE,
+];
+
+// Index names (each indexes the record field of the same name)
+const MINT_URL_INDEX: &str = "mint_url";
+const UNIT_INDEX: &str = "unit";
+const STATE_INDEX: &str = "state";
+const MINT_UNIT_INDEX: &str = "mint_unit";
+
+/// Every record is an object whose `data` field holds the JSON-encoded value,
+/// plus whichever plain string fields the store indexes
+const DATA_FIELD: &str = "data";
+
+static SCHEMA: DbSchema = DbSchema {
+    name: DB_NAME,
+    // 2: quarantine store
+    // 3: sent tokens store
+    version: 3,
+    upgrade: upgrade_db,
+};
+
+fn upgrade_db(db: &IdbDatabase, _tx: &IdbTransaction, _old_version: u32) -> Result<(), JsValue> {
+    let existing = db.object_store_names();
+
+    idb::create_missing_stores(db, &[
+        MINTS_STORE,
+        KEYS_STORE,
+        MINT_QUOTES_STORE,
+        MELT_QUOTES_STORE,
+        KEYSET_COUNTERS_STORE,
+        QUARANTINE_STORE,
+        SENT_TOKENS_STORE,
+    ])?;
+
+    if !existing.contains(KEYSETS_STORE) {
+        let store = db.create_object_store(KEYSETS_STORE)?;
+        store.create_index_with_str(MINT_URL_INDEX, "mint_url")?;
+    }
+
+    if !existing.contains(PROOFS_STORE) {
+        // Proofs are keyed by Y and indexed by mint, unit, state and (mint, unit)
+        let store = db.create_object_store(PROOFS_STORE)?;
+        store.create_index_with_str(MINT_URL_INDEX, "mint_url")?;
+        store.create_index_with_str(UNIT_INDEX, "unit")?;
+        store.create_index_with_str(STATE_INDEX, "state")?;
+        let mint_unit: js_sys::Array = ["mint_url", "unit"].iter().map(|s| JsValue::from_str(s)).collect();
+        store.create_index_with_str_sequence(MINT_UNIT_INDEX, &mint_unit)?;
+    }
+
+    if !existing.contains(TRANSACTIONS_STORE) {
+        let store = db.create_object_store(TRANSACTIONS_STORE)?;
+        store.create_index_with_str(MINT_URL_INDEX, "mint_url")?;
+    }
+
+    Ok(())
+}
+
+/// localStorage key used before the wallet moved to IndexedDB
+const LEGACY_WALLET_STATE_KEY: &str = "wallet_state";
+
+/// Version of the layout produced by `export_backup`
+pub const BACKUP_VERSION: u32 = 1;
+
+/// A wallet backup that parsed, ready to be merged by `import_backup`
+pub struct WalletRestore(WalletState);
+
+/// Serializes `atomic` scopes so two operations never stage the same proofs
+static ATOMIC_LOCK: Lazy<TokioMutex<()>> = Lazy::new(|| TokioMutex::new(()));
+
+/// Proof and transaction writes staged during an `atomic` scope.
+/// Keyset counters are deliberately not staged: they are written through
+/// immediately, because rolling a counter back would make the wallet reuse
+/// blinded outputs the mint may already have signed.
+#[derive(Debug, Default)]
+struct PendingBatch {
+    /// Proofs added or modified, by Y (hex)
+    proofs_added: HashMap<String, ProofInfo>,
+    /// Proofs removed, by Y (hex). Applied before `proofs_added` on commit.
+    proofs_removed: HashSet<String>,
+    transactions: Vec<Transaction>,
+    sent_tokens: Vec<SentToken>,
+    /// The wallet stored proofs the mint returned (or spent) during the scope,
+    /// so the batch must not be dropped even if the operation fails afterwards
+    from_mint: bool,
+}
+
+impl PendingBatch {
+    fn is_empty(&self) -> bool {
+        self.proofs_added.is_empty()
+            && self.proofs_removed.is_empty()
+            && self.transactions.is_empty()
+            && self.sent_tokens.is_empty()
+    }
+
+    /// Overlay staged proof changes on proofs read from IndexedDB
+    fn overlay_proofs(&self, stored: Vec<ProofInfo>) -> Vec<ProofInfo> {
+        stored
+            .into_iter()
+            .filter(|p| {
+                let key = p.y.to_hex();
+                !self.proofs_removed.contains(&key) && !self.proofs_added.contains_key(&key)
+            })
+            .chain(self.proofs_added.values().cloned())
+            .collect()
+    }
+}
+
+/// Typed failure of a wallet write. Whatever was being written was rolled back.
+#[derive(Debug, thiserror::Error)]
+pub enum WalletWriteError {
+    #[error("Storage quota exceeded - wallet changes were rolled back")]
+    QuotaExceeded,
+    #[error("Wallet write failed and was rolled back: {0}")]
+    Failed(String),
+    #[error("The mint processed the operation but the wallet could not save its result ({0}) - run restore_wallet to recover the funds")]
+    RestoreNeeded(String),
+}
+
+impl WalletWriteError {
+    fn from_js(e: JsValue) -> Self {
+        match e.dyn_ref::<DomException>().map(|d| d.name()).as_deref() {
+            Some("QuotaExceededError") => Self::QuotaExceeded,
+            _ => Self::Failed(format!("{:?}", e)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct HybridWalletDatabase {
-    state: Arc<Mutex<WalletState>>,
+    // All persistent state lives in IndexedDB; the connection is cached by the idb module.
+    // Only set on the handle an `atomic` call passes to its operation, so writes made
+    // through other handles (e.g. by concurrent wallet calls) are never staged with it.
+    batch: Arc<Mutex<Option<PendingBatch>>>,
 }
 
 impl HybridWalletDatabase {
     pub async fn new() -> Result<Self, JsValue> {
-        // Try to load from localStorage
-        let state = match Self::load_from_localstorage().await {
-            Ok(state) => {
-                log("Loaded wallet state from localStorage");
-                state
-            }
-            Err(_) => {
-                log("No existing wallet state, starting fresh");
-                WalletState::default()
-            }
-        };
+        vault::ensure_unlocked()?;
 
         let db = Self {
-            state: Arc::new(Mutex::new(state)),
+            batch: Arc::new(Mutex::new(None)),
         };
 
-        // Save immediately so wallet_state appears in localStorage
-        db.save_snapshot().await?;
-        log("Saved initial wallet snapshot to localStorage");
+        // Open (and create/upgrade) the database up front so errors surface here
+        idb::connection(&SCHEMA).await?;
+
+        match Self::load_from_localstorage() {
+            Ok(Some(state)) => {
+                log("Migrating wallet state from localStorage to IndexedDB...");
+                db.import_state(&state).await.map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+                let storage = window()
+                    .ok_or_else(|| JsValue::from_str("No window"))?
+                    .local_storage()?
+                    .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+                storage.remove_item(LEGACY_WALLET_STATE_KEY)?;
+                log(&format!("✓ Migrated wallet ({} proofs) to IndexedDB", state.proofs.len()));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let storage = window()
+                    .ok_or_else(|| JsValue::from_str("No window"))?
+                    .local_storage()?
+                    .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+                schema::quarantine_local(&storage, LEGACY_WALLET_STATE_KEY, &format!("{:?}", e))?;
+            }
+        }
+
+        db.quarantine_unreadable().await?;
+
+        log("Initialized wallet database");
 
         Ok(db)
     }
 
-    async fn save_snapshot(&self) -> Result<(), JsValue> {
-        let state = self.state.lock().unwrap().clone();
-        let json = serde_json::to_string(&state)
-            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))?;
+    /// Move records that no longer decode (e.g. after a cdk serde change) into
+    /// quarantine, so one bad record can't make the whole wallet unreadable
+    async fn quarantine_unreadable(&self) -> Result<(), JsValue> {
+        let db = idb::connection(&SCHEMA).await?;
 
-        // Save to localStorage for now (simpler than IndexedDB)
-        let storage = window()
-            .ok_or_else(|| JsValue::from_str("No window"))?
-            .local_storage()?
-            .ok_or_else(|| JsValue::from_str("No localStorage"))?;
+        for store in ALL_STORES {
+            for (key, record) in idb::read_all(&db, store).await? {
+                let Err(e) = check_record(store, &record) else {
+                    continue;
+                };
+                // A locked vault is not a broken record
+                vault::ensure_unlocked()?;
 
-        storage.set_item("wallet_state", &json)?;
+                let key_str = key.as_string().unwrap_or_else(|| format!("{:?}", key));
+                let entry = QuarantineEntry::new(DB_NAME, store, &key_str, &e.to_string());
+                schema::quarantine_record(&db, entry, &key, &record).await?;
+            }
+        }
         Ok(())
     }
 
-    async fn load_from_localstorage() -> Result<WalletState, JsValue> {
+    fn load_from_localstorage() -> Result<Option<WalletState>, JsValue> {
         let storage = window()
             .ok_or_else(|| JsValue::from_str("No window"))?
             .local_storage()?
             .ok_or_else(|| JsValue::from_str("No localStorage"))?;
 
-        let json = storage
-            .get_item("wallet_state")?
-            .ok_or_else(|| JsValue::from_str("No wallet state found"))?;
+        let json = match storage.get_item(LEGACY_WALLET_STATE_KEY)? {
+            Some(json) => json,
+            None => return Ok(None),
+        };
 
         let state: WalletState = serde_json::from_str(&json)
             .map_err(|e| JsValue::from_str(&format!("Deserialization error: {}", e)))?;
 
-        Ok(state)
+        Ok(Some(state))
+    }
+
+    /// Write a whole WalletState into IndexedDB in a single transaction
+    /// (records with the same key are overwritten, others are kept)
+    async fn import_state(&self, state: &WalletState) -> Result<(), DbError> {
+        let db = connection().await?;
+        let tx = idb::write_transaction(&db, ALL_STORES).map_err(to_db_error)?;
+
+        for (mint_url, info) in &state.mints {
+            put(&tx, MINTS_STORE, &mint_url.to_string(), mint_record(mint_url, info)?)?;
+        }
+        for keyset in state.keyset_map.values() {
+            put(&tx, KEYSETS_STORE, &keyset.id.to_string(), keyset_record(&keyset_mint(state, &keyset.id), keyset)?)?;
+        }
+        for (id, keys) in &state.keys {
+            put(&tx, KEYS_STORE, &id.to_string(), data_record(KEYS_STORE, keys)?)?;
+        }
+        for quote in state.mint_quotes.values() {
+            put(&tx, MINT_QUOTES_STORE, &quote.id, data_record(MINT_QUOTES_STORE, quote)?)?;
+        }
+        for quote in state.melt_quotes.values() {
+            put(&tx, MELT_QUOTES_STORE, &quote.id, data_record(MELT_QUOTES_STORE, quote)?)?;
+        }
+        for proof in &state.proofs {
+            put(&tx, PROOFS_STORE, &proof.y.to_hex(), proof_record(proof)?)?;
+        }
+        for (id, counter) in &state.keyset_counters {
+            put(&tx, KEYSET_COUNTERS_STORE, &id.to_string(), data_record(KEYSET_COUNTERS_STORE, counter)?)?;
+        }
+        for transaction in &state.transactions {
+            put(&tx, TRANSACTIONS_STORE, &transaction_key(transaction), transaction_record(transaction)?)?;
+        }
+        for sent in &state.sent_tokens {
+            put(&tx, SENT_TOKENS_STORE, &sent.id, data_record(SENT_TOKENS_STORE, sent)?)?;
+        }
+
+        idb::await_transaction(&tx).await.map_err(to_write_error)
+    }
+
+    /// Run a wallet operation with all-or-nothing persistence.
+    ///
+    /// `op` gets its own handle on the database: proof changes, transaction
+    /// records and sent tokens written through it (build the operation's wallet
+    /// on it) are staged in memory (reads see them) and committed in a single
+    /// IndexedDB transaction once `op` succeeds. If `op` fails before the mint
+    /// returned anything, the staged changes are dropped and the stored wallet is
+    /// untouched. Once the mint swapped, melted or minted, its result is always
+    /// saved: the commit is retried, and if it still fails the error says a
+    /// restore is needed (keyset counters are written through, so restore_wallet
+    /// finds the outputs again).
+    pub async fn atomic<T, F, Fut>(&self, op: F) -> Result<T, JsValue>
+    where
+        F: FnOnce(HybridWalletDatabase) -> Fut,
+        Fut: Future<Output = Result<T, JsValue>>,
+    {
+        let _guard = ATOMIC_LOCK.lock().await;
+
+        let scoped = Self {
+            batch: Arc::new(Mutex::new(Some(PendingBatch::default()))),
+        };
+        let result = op(scoped.clone()).await;
+        let batch = scoped.batch.lock().unwrap().take().unwrap_or_default();
+
+        match result {
+            Ok(value) => {
+                Self::commit_mint_result(&batch).await.map_err(|e| JsValue::from_str(&e.to_string()))?;
+                Ok(value)
+            }
+            Err(e) if batch.from_mint => {
+                log("Wallet operation failed after the mint processed it - saving what the mint returned");
+                Self::commit_mint_result(&batch).await.map_err(|e| JsValue::from_str(&e.to_string()))?;
+                Err(e)
+            }
+            Err(e) => {
+                if !batch.is_empty() {
+                    log("Wallet operation failed - discarded its staged changes");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Commit a batch, retrying when it holds the mint's result (which can't be rolled back)
+    async fn commit_mint_result(batch: &PendingBatch) -> Result<(), WalletWriteError> {
+        const ATTEMPTS: u32 = 3;
+
+        let mut attempt = 1;
+        loop {
+            match Self::commit(batch).await {
+                Ok(()) => return Ok(()),
+                Err(e) if !batch.from_mint => return Err(e),
+                Err(e) if attempt >= ATTEMPTS => {
+                    log(&format!("❌ Could not save the mint's result after {} attempts: {}", ATTEMPTS, e));
+                    return Err(WalletWriteError::RestoreNeeded(e.to_string()));
+                }
+                Err(e) => {
+                    log(&format!("⚠️ Saving the mint's result failed (attempt {}/{}): {}", attempt, ATTEMPTS, e));
+                    attempt += 1;
+                    crate::sleep(std::time::Duration::from_millis(200 * u64::from(attempt))).await;
+                }
+            }
+        }
+    }
+
+    /// Write a staged batch in one transaction
+    async fn commit(batch: &PendingBatch) -> Result<(), WalletWriteError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let db = idb::connection(&SCHEMA).await.map_err(WalletWriteError::from_js)?;
+        let tx = idb::write_transaction(&db, &[PROOFS_STORE, TRANSACTIONS_STORE, SENT_TOKENS_STORE])
+            .map_err(WalletWriteError::from_js)?;
+
+        let queued = (|| {
+            for y in &batch.proofs_removed {
+                delete(&tx, PROOFS_STORE, y)?;
+            }
+            for (y, proof) in &batch.proofs_added {
+                put(&tx, PROOFS_STORE, y, proof_record(proof)?)?;
+            }
+            for transaction in &batch.transactions {
+                put(&tx, TRANSACTIONS_STORE, &transaction_key(transaction), transaction_record(transaction)?)?;
+            }
+            for sent in &batch.sent_tokens {
+                put(&tx, SENT_TOKENS_STORE, &sent.id, data_record(SENT_TOKENS_STORE, sent)?)?;
+            }
+            Ok::<(), DbError>(())
+        })();
+
+        abort_on_error(&tx, queued).map_err(|e| WalletWriteError::Failed(e.to_string()))?;
+
+        idb::await_transaction(&tx).await.map_err(WalletWriteError::from_js)
+    }
+
+    fn in_atomic(&self) -> bool {
+        self.batch.lock().unwrap().is_some()
+    }
+
+    /// Rewrite every record so its data field is stored under the vault's
+    /// current setting (encrypted after a passphrase is set, plaintext after it is removed)
+    pub async fn reseal(&self) -> Result<(), JsValue> {
+        let _guard = ATOMIC_LOCK.lock().await;
+        let db = idb::connection(&SCHEMA).await?;
+
+        let mut records = Vec::new();
+        for store in ALL_STORES {
+            for (key, record) in idb::read_all(&db, store).await? {
+                records.push((*store, key, record));
+            }
+        }
+
+        // Re-encode everything before opening the write transaction so it can't auto-commit halfway
+        for (_, _, record) in &records {
+            let stored = idb::string_field(record, DATA_FIELD)
+                .ok_or_else(|| JsValue::from_str("Record has no data field"))?;
+            let resealed = vault::seal(&vault::open(&stored)?)?;
+            js_sys::Reflect::set(record, &JsValue::from_str(DATA_FIELD), &JsValue::from_str(&resealed))?;
+        }
+
+        let tx = idb::write_transaction(&db, ALL_STORES)?;
+        let queued = records.iter().try_for_each(|(store, key, record)| {
+            tx.object_store(store)?.put_with_key(record, key).map(|_| ())
+        });
+        if let Err(e) = queued {
+            let _ = tx.abort();
+            return Err(e);
+        }
+        idb::await_transaction(&tx).await?;
+
+        log(&format!("Re-sealed {} wallet record(s)", records.len()));
+        Ok(())
+    }
+
+    /// The whole wallet in the legacy WalletState layout, for backups
+    pub async fn export_backup(&self) -> Result<serde_json::Value, JsValue> {
+        let _guard = ATOMIC_LOCK.lock().await;
+        let db = idb::connection(&SCHEMA).await?;
+        let js_error = |e: DbError| JsValue::from_str(&e.to_string());
+        let parse_id = |key: JsValue| {
+            key.as_string()
+                .and_then(|k| Id::from_str(&k).ok())
+                .ok_or_else(|| JsValue::from_str("Invalid keyset id key"))
+        };
+
+        let mut state = WalletState::default();
+
+        for (_, record) in idb::read_all(&db, MINTS_STORE).await? {
+            let (mint_url, info): (MintUrl, Option<MintInfo>) = decode(MINTS_STORE, &record).map_err(js_error)?;
+            state.mints.insert(mint_url, info);
+        }
+        for (_, record) in idb::read_all(&db, KEYSETS_STORE).await? {
+            let keyset: KeySetInfo = decode(KEYSETS_STORE, &record).map_err(js_error)?;
+            if let Some(mint_url) = idb::string_field(&record, "mint_url").and_then(|m| MintUrl::from_str(&m).ok()) {
+                state.keysets.entry(mint_url).or_default().push(keyset.clone());
+            }
+            state.keyset_map.insert(keyset.id, keyset);
+        }
+        for (key, record) in idb::read_all(&db, KEYS_STORE).await? {
+            state.keys.insert(parse_id(key)?, decode(KEYS_STORE, &record).map_err(js_error)?);
+        }
+        for (key, record) in idb::read_all(&db, KEYSET_COUNTERS_STORE).await? {
+            state.keyset_counters.insert(parse_id(key)?, decode(KEYSET_COUNTERS_STORE, &record).map_err(js_error)?);
+        }
+        for (_, record) in idb::read_all(&db, MINT_QUOTES_STORE).await? {
+            let quote: MintQuote = decode(MINT_QUOTES_STORE, &record).map_err(js_error)?;
+            state.mint_quotes.insert(quote.id.clone(), quote);
+        }
+        for (_, record) in idb::read_all(&db, MELT_QUOTES_STORE).await? {
+            let quote: MeltQuote = decode(MELT_QUOTES_STORE, &record).map_err(js_error)?;
+            state.melt_quotes.insert(quote.id.clone(), quote);
+        }
+        for (_, record) in idb::read_all(&db, PROOFS_STORE).await? {
+            state.proofs.push(decode(PROOFS_STORE, &record).map_err(js_error)?);
+        }
+        for (_, record) in idb::read_all(&db, TRANSACTIONS_STORE).await? {
+            state.transactions.push(decode(TRANSACTIONS_STORE, &record).map_err(js_error)?);
+        }
+        for (_, record) in idb::read_all(&db, SENT_TOKENS_STORE).await? {
+            state.sent_tokens.push(decode(SENT_TOKENS_STORE, &record).map_err(js_error)?);
+        }
+
+        serde_json::to_value(&state)
+            .map_err(|e| JsValue::from_str(&format!("Serialization error: {}", e)))
+    }
+
+    /// Parse a backup from `export_backup` without writing anything
+    pub fn parse_backup(backup: serde_json::Value) -> Result<WalletRestore, JsValue> {
+        serde_json::from_value(backup)
+            .map(WalletRestore)
+            .map_err(|e| JsValue::from_str(&format!("Invalid wallet backup: {}", e)))
+    }
+
+    /// Merge a parsed backup into this wallet.
+    /// Keyset counters only move forward, so outputs are never derived twice.
+    /// Returns the number of proofs restored.
+    pub async fn import_backup(&self, backup: WalletRestore) -> Result<usize, JsValue> {
+        let WalletRestore(mut state) = backup;
+
+        let _guard = ATOMIC_LOCK.lock().await;
+        let db = idb::connection(&SCHEMA).await?;
+        for (key, record) in idb::read_all(&db, KEYSET_COUNTERS_STORE).await? {
+            let Some(id) = key.as_string().and_then(|k| Id::from_str(&k).ok()) else {
+                continue;
+            };
+            let current: u32 = decode(KEYSET_COUNTERS_STORE, &record).map_err(|e| JsValue::from_str(&e.to_string()))?;
+            let counter = state.keyset_counters.entry(id).or_insert(0);
+            *counter = (*counter).max(current);
+        }
+
+        self.import_state(&state).await.map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(state.proofs.len())
+    }
+
+    /// Start every keyset counter from zero again. Only safe right after
+    /// switching to a fresh seed, which has never had outputs signed, or to an
+    /// imported one that restore_wallet then moves past its used outputs.
+    pub async fn reset_keyset_counters(&self) -> Result<(), JsValue> {
+        let _guard = ATOMIC_LOCK.lock().await;
+        let db = idb::connection(&SCHEMA).await?;
+        let tx = idb::write_transaction(&db, &[KEYSET_COUNTERS_STORE])?;
+        tx.object_store(KEYSET_COUNTERS_STORE)?.clear()?;
+        idb::await_transaction(&tx).await
+    }
+
+    /// Add or update a token we sent (staged with the rest of an `atomic` scope)
+    pub async fn put_sent_token(&self, sent: SentToken) -> Result<(), JsValue> {
+        if self.staged(|batch| batch.sent_tokens.push(sent.clone())).is_some() {
+            return Ok(());
+        }
+        let record = data_record(SENT_TOKENS_STORE, &sent).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        put_one(SENT_TOKENS_STORE, &sent.id, record).await
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Tokens we sent, oldest first (with changes staged in an `atomic` scope)
+    pub async fn sent_tokens(&self) -> Result<Vec<SentToken>, JsValue> {
+        let mut stored: HashMap<String, SentToken> = get_all::<SentToken>(SENT_TOKENS_STORE).await
+            .map_err(|e| JsValue::from_str(&e.to_string()))?
+            .into_iter()
+            .map(|sent| (sent.id.clone(), sent))
+            .collect();
+        if let Some(staged) = self.staged(|batch| batch.sent_tokens.clone()) {
+            stored.extend(staged.into_iter().map(|sent| (sent.id.clone(), sent)));
+        }
+
+        let mut sent_tokens: Vec<SentToken> = stored.into_values().collect();
+        sent_tokens.sort_by_key(|sent| sent.created_at);
+        Ok(sent_tokens)
+    }
+
+    /// Apply `f` to the staged batch, if an `atomic` scope is active
+    fn staged<R>(&self, f: impl FnOnce(&mut PendingBatch) -> R) -> Option<R> {
+        self.batch.lock().unwrap().as_mut().map(f)
     }
 }
 
+/// Records moved to quarantine because they couldn't be read
+pub async fn quarantined() -> Result<Vec<QuarantineEntry>, JsValue> {
+    let db = idb::connection(&SCHEMA).await?;
+    schema::quarantined_records(&db).await
+}
+
+/// Check that a record decodes to the type its store holds
+fn check_record(store: &str, record: &JsValue) -> Result<(), DbError> {
+    match store {
+        MINTS_STORE => decode::<(MintUrl, Option<MintInfo>)>(store, record).map(|_| ()),
+        KEYSETS_STORE => decode::<KeySetInfo>(store, record).map(|_| ()),
+        KEYS_STORE => decode::<Keys>(store, record).map(|_| ()),
+        MINT_QUOTES_STORE => decode::<MintQuote>(store, record).map(|_| ()),
+        MELT_QUOTES_STORE => decode::<MeltQuote>(store, record).map(|_| ()),
+        PROOFS_STORE => decode::<ProofInfo>(store, record).map(|_| ()),
+        KEYSET_COUNTERS_STORE => decode::<u32>(store, record).map(|_| ()),
+        TRANSACTIONS_STORE => decode::<Transaction>(store, record).map(|_| ()),
+        SENT_TOKENS_STORE => decode::<SentToken>(store, record).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Mint a keyset belongs to, from the legacy per-mint keyset lists
+fn keyset_mint(state: &WalletState, id: &Id) -> Option<MintUrl> {
+    state.keysets.iter()
+        .find(|(_, keysets)| keysets.iter().any(|k| &k.id == id))
+        .map(|(mint_url, _)| mint_url.clone())
+}
+
+async fn connection() -> Result<IdbDatabase, DbError> {
+    idb::connection(&SCHEMA).await.map_err(to_db_error)
+}
+
+fn serde_error(e: serde_json::Error) -> DbError {
+    DbError::Database(Box::new(e))
+}
+
+fn record_with_fields<T: Serialize>(store: &str, value: &T, fields: &[(&str, JsValue)]) -> Result<JsValue, DbError> {
+    // Only the data field is encrypted; the indexed fields hold no secrets
+    let json = schema::encode(store, value).map_err(serde_error)?;
+    let sealed = vault::seal(&json).map_err(vault_error)?;
+    let mut all_fields = vec![(DATA_FIELD, JsValue::from_str(&sealed))];
+    all_fields.extend(fields.iter().cloned());
+    idb::object(&all_fields).map_err(to_db_error)
+}
+
+fn data_record<T: Serialize>(store: &str, value: &T) -> Result<JsValue, DbError> {
+    record_with_fields(store, value, &[])
+}
+
+fn mint_record(mint_url: &MintUrl, info: &Option<MintInfo>) -> Result<JsValue, DbError> {
+    data_record(MINTS_STORE, &(mint_url, info))
+}
+
+fn keyset_record(mint_url: &Option<MintUrl>, keyset: &KeySetInfo) -> Result<JsValue, DbError> {
+    let mint_field = mint_url.as_ref().map(|m| JsValue::from_str(&m.to_string())).unwrap_or(JsValue::NULL);
+    record_with_fields(KEYSETS_STORE, keyset, &[("mint_url", mint_field)])
+}
+
+fn proof_record(proof: &ProofInfo) -> Result<JsValue, DbError> {
+    record_with_fields(PROOFS_STORE, proof, &[
+        ("mint_url", JsValue::from_str(&proof.mint_url.to_string())),
+        ("unit", JsValue::from_str(&proof.unit.to_string())),
+        ("state", JsValue::from_str(&proof.state.to_string())),
+    ])
+}
+
+fn transaction_key(transaction: &Transaction) -> String {
+    TransactionId::new(transaction.ys.clone()).to_string()
+}
+
+fn transaction_record(transaction: &Transaction) -> Result<JsValue, DbError> {
+    record_with_fields(TRANSACTIONS_STORE, transaction, &[
+        ("mint_url", JsValue::from_str(&transaction.mint_url.to_string())),
+    ])
+}
+
+/// Decode the JSON `data` field of a record from `store` (migrating old schema versions)
+fn decode<T: DeserializeOwned>(store: &str, record: &JsValue) -> Result<T, DbError> {
+    let stored = idb::string_field(record, DATA_FIELD)
+        .ok_or_else(|| DbError::Database(Box::new(StorageError("Record has no data field".to_string()))))?;
+    let json = vault::open(&stored).map_err(vault_error)?;
+    schema::decode(store, &json).map_err(|e| DbError::Database(Box::new(StorageError(e))))
+}
+
+fn decode_all<T: DeserializeOwned>(store: &str, records: Vec<JsValue>) -> Result<Vec<T>, DbError> {
+    records.iter().map(|record| decode(store, record)).collect()
+}
+
+/// Queue a put in a readwrite transaction
+fn put(tx: &IdbTransaction, store: &str, key: &str, record: JsValue) -> Result<(), DbError> {
+    tx.object_store(store)
+        .and_then(|s| s.put_with_key(&record, &JsValue::from_str(key)))
+        .map(|_| ())
+        .map_err(to_db_error)
+}
+
+/// Queue a delete in a readwrite transaction
+fn delete(tx: &IdbTransaction, store: &str, key: &str) -> Result<(), DbError> {
+    tx.object_store(store)
+        .and_then(|s| s.delete(&JsValue::from_str(key)))
+        .map(|_| ())
+        .map_err(to_db_error)
+}
+
+/// Read a record inside an open transaction
+async fn get_in(tx: &IdbTransaction, store: &str, key: &str) -> Result<Option<JsValue>, DbError> {
+    let request = tx.object_store(store)
+        .and_then(|s| s.get(&JsValue::from_str(key)))
+        .map_err(to_db_error)?;
+    let value = idb::await_request(&request).await.map_err(to_db_error)?;
+    Ok(if value.is_undefined() { None } else { Some(value) })
+}
+
+async fn get_one<T: DeserializeOwned>(store: &str, key: &str) -> Result<Option<T>, DbError> {
+    let db = connection().await?;
+    idb::get(&db, store, &JsValue::from_str(key)).await
+        .map_err(to_db_error)?
+        .map(|record| decode(store, &record))
+        .transpose()
+}
+
+async fn get_all<T: DeserializeOwned>(store: &str) -> Result<Vec<T>, DbError> {
+    let db = connection().await?;
+    decode_all(store, idb::get_all_values(&db, store).await.map_err(to_db_error)?)
+}
+
+async fn get_by_index<T: DeserializeOwned>(store: &str, index: &str, key: &JsValue) -> Result<Vec<T>, DbError> {
+    let db = connection().await?;
+    decode_all(store, idb::get_all_by_index(&db, store, index, key).await.map_err(to_db_error)?)
+}
+
+/// Put a single record in its own transaction
+async fn put_one(store: &str, key: &str, record: JsValue) -> Result<(), DbError> {
+    let db = connection().await?;
+    let tx = idb::write_transaction(&db, &[store]).map_err(to_db_error)?;
+    put(&tx, store, key, record)?;
+    idb::await_transaction(&tx).await.map_err(to_write_error)
+}
+
+/// Delete a single record in its own transaction
+async fn delete_one(store: &str, key: &str) -> Result<(), DbError> {
+    let db = connection().await?;
+    let tx = idb::write_transaction(&db, &[store]).map_err(to_db_error)?;
+    delete(&tx, store, key)?;
+    idb::await_transaction(&tx).await.map_err(to_write_error)
+}
+
 fn log(msg: &str) {
     web_sys::console::log_1(&JsValue::from_str(msg));
 }
@@ -104,6 +765,38 @@ fn to_db_error(e: JsValue) -> DbError {
     DbError::Database(Box::new(StorageError(format!("{:?}", e))))
 }
 
+/// Abort `tx` if queueing its requests failed, so nothing already queued gets committed
+fn abort_on_error<T>(tx: &IdbTransaction, result: Result<T, DbError>) -> Result<T, DbError> {
+    if result.is_err() {
+        let _ = tx.abort();
+    }
+    result
+}
+
+fn vault_error(e: vault::VaultError) -> DbError {
+    DbError::Database(Box::new(e))
+}
+
+fn to_write_error(e: JsValue) -> DbError {
+    DbError::Database(Box::new(WalletWriteError::from_js(e)))
+}
+
+fn proof_matches(
+    p: &ProofInfo,
+    mint_url: &Option<MintUrl>,
+    unit: &Option<CurrencyUnit>,
+    state: &Option<Vec<State>>,
+    spending_conditions: &Option<Vec<SpendingConditions>>,
+) -> bool {
+    mint_url.as_ref().map_or(true, |url| &p.mint_url == url)
+        && unit.as_ref().map_or(true, |u| &p.unit == u)
+        && state.as_ref().map_or(true, |states| states.contains(&p.state))
+        && spending_conditions.as_ref().map_or(true, |conds| {
+            p.spending_condition.as_ref().map_or(false, |pc| conds.contains(pc))
+                || p.spending_condition.is_none()
+        })
+}
+
 #[async_trait(?Send)]
 impl WalletDatabase for HybridWalletDatabase {
     type Err = DbError;
@@ -113,23 +806,21 @@ impl WalletDatabase for HybridWalletDatabase {
         mint_url: MintUrl,
         mint_info: Option<MintInfo>,
     ) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().mints.insert(mint_url, mint_info);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        put_one(MINTS_STORE, &mint_url.to_string(), mint_record(&mint_url, &mint_info)?).await
     }
 
     async fn remove_mint(&self, mint_url: MintUrl) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().mints.remove(&mint_url);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        delete_one(MINTS_STORE, &mint_url.to_string()).await
     }
 
     async fn get_mint(&self, mint_url: MintUrl) -> Result<Option<MintInfo>, Self::Err> {
-        Ok(self.state.lock().unwrap().mints.get(&mint_url).cloned().flatten())
+        let entry: Option<(MintUrl, Option<MintInfo>)> = get_one(MINTS_STORE, &mint_url.to_string()).await?;
+        Ok(entry.and_then(|(_, info)| info))
     }
 
     async fn get_mints(&self) -> Result<HashMap<MintUrl, Option<MintInfo>>, Self::Err> {
-        Ok(self.state.lock().unwrap().mints.clone())
+        let entries: Vec<(MintUrl, Option<MintInfo>)> = get_all(MINTS_STORE).await?;
+        Ok(entries.into_iter().collect())
     }
 
     async fn update_mint_url(
@@ -137,14 +828,16 @@ impl WalletDatabase for HybridWalletDatabase {
         old_mint_url: MintUrl,
         new_mint_url: MintUrl,
     ) -> Result<(), Self::Err> {
-        {
-            let mut state = self.state.lock().unwrap();
-            if let Some(info) = state.mints.remove(&old_mint_url) {
-                state.mints.insert(new_mint_url, info);
-            }
+        let db = connection().await?;
+        let tx = idb::write_transaction(&db, &[MINTS_STORE]).map_err(to_db_error)?;
+
+        if let Some(record) = get_in(&tx, MINTS_STORE, &old_mint_url.to_string()).await? {
+            let (_, info): (MintUrl, Option<MintInfo>) = decode(MINTS_STORE, &record)?;
+            delete(&tx, MINTS_STORE, &old_mint_url.to_string())?;
+            put(&tx, MINTS_STORE, &new_mint_url.to_string(), mint_record(&new_mint_url, &info)?)?;
         }
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+
+        idb::await_transaction(&tx).await.map_err(to_write_error)
     }
 
     async fn add_mint_keysets(
@@ -152,84 +845,72 @@ impl WalletDatabase for HybridWalletDatabase {
         mint_url: MintUrl,
         keysets: Vec<KeySetInfo>,
     ) -> Result<(), Self::Err> {
-        {
-            let mut state = self.state.lock().unwrap();
-            state.keysets.insert(mint_url, keysets.clone());
-            for keyset in keysets {
-                state.keyset_map.insert(keyset.id, keyset);
-            }
+        let db = connection().await?;
+        let tx = idb::write_transaction(&db, &[KEYSETS_STORE]).map_err(to_db_error)?;
+
+        let mint = Some(mint_url);
+        for keyset in &keysets {
+            put(&tx, KEYSETS_STORE, &keyset.id.to_string(), keyset_record(&mint, keyset)?)?;
         }
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+
+        idb::await_transaction(&tx).await.map_err(to_write_error)
     }
 
     async fn get_mint_keysets(
         &self,
         mint_url: MintUrl,
     ) -> Result<Option<Vec<KeySetInfo>>, Self::Err> {
-        Ok(self.state.lock().unwrap().keysets.get(&mint_url).cloned())
+        let key = JsValue::from_str(&mint_url.to_string());
+        let keysets: Vec<KeySetInfo> = get_by_index(KEYSETS_STORE, MINT_URL_INDEX, &key).await?;
+        Ok(if keysets.is_empty() { None } else { Some(keysets) })
     }
 
     async fn get_keyset_by_id(&self, keyset_id: &Id) -> Result<Option<KeySetInfo>, Self::Err> {
-        Ok(self.state.lock().unwrap().keyset_map.get(keyset_id).cloned())
+        get_one(KEYSETS_STORE, &keyset_id.to_string()).await
     }
 
     async fn add_mint_quote(&self, quote: MintQuote) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().mint_quotes.insert(quote.id.clone(), quote);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        put_one(MINT_QUOTES_STORE, &quote.id, data_record(MINT_QUOTES_STORE, &quote)?).await
     }
 
     async fn get_mint_quote(&self, quote_id: &str) -> Result<Option<MintQuote>, Self::Err> {
-        Ok(self.state.lock().unwrap().mint_quotes.get(quote_id).cloned())
+        get_one(MINT_QUOTES_STORE, quote_id).await
     }
 
     async fn get_mint_quotes(&self) -> Result<Vec<MintQuote>, Self::Err> {
-        Ok(self.state.lock().unwrap().mint_quotes.values().cloned().collect())
+        get_all(MINT_QUOTES_STORE).await
     }
 
     async fn remove_mint_quote(&self, quote_id: &str) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().mint_quotes.remove(quote_id);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        delete_one(MINT_QUOTES_STORE, quote_id).await
     }
 
     async fn add_melt_quote(&self, quote: MeltQuote) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().melt_quotes.insert(quote.id.clone(), quote);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        put_one(MELT_QUOTES_STORE, &quote.id, data_record(MELT_QUOTES_STORE, &quote)?).await
     }
 
     async fn get_melt_quote(&self, quote_id: &str) -> Result<Option<MeltQuote>, Self::Err> {
-        Ok(self.state.lock().unwrap().melt_quotes.get(quote_id).cloned())
+        get_one(MELT_QUOTES_STORE, quote_id).await
     }
 
     async fn get_melt_quotes(&self) -> Result<Vec<MeltQuote>, Self::Err> {
-        Ok(self.state.lock().unwrap().melt_quotes.values().cloned().collect())
+        get_all(MELT_QUOTES_STORE).await
     }
 
     async fn remove_melt_quote(&self, quote_id: &str) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().melt_quotes.remove(quote_id);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        delete_one(MELT_QUOTES_STORE, quote_id).await
     }
 
     async fn add_keys(&self, keyset: KeySet) -> Result<(), Self::Err> {
-        let id = keyset.id;
-        let keys = keyset.keys;
-        self.state.lock().unwrap().keys.insert(id, keys);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        put_one(KEYS_STORE, &keyset.id.to_string(), data_record(KEYS_STORE, &keyset.keys)?).await
     }
 
     async fn get_keys(&self, id: &Id) -> Result<Option<Keys>, Self::Err> {
-        Ok(self.state.lock().unwrap().keys.get(id).cloned())
+        get_one(KEYS_STORE, &id.to_string()).await
     }
 
     async fn remove_keys(&self, id: &Id) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().keys.remove(id);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        delete_one(KEYS_STORE, &id.to_string()).await
     }
 
     async fn update_proofs(
@@ -237,17 +918,37 @@ impl WalletDatabase for HybridWalletDatabase {
         added: Vec<ProofInfo>,
         removed_ys: Vec<PublicKey>,
     ) -> Result<(), Self::Err> {
-        {
-            let mut state = self.state.lock().unwrap();
+        let staged = self.staged(|batch| {
+            batch.from_mint |= !added.is_empty() || !removed_ys.is_empty();
+            for y in &removed_ys {
+                let key = y.to_hex();
+                batch.proofs_added.remove(&key);
+                batch.proofs_removed.insert(key);
+            }
+            for proof in &added {
+                batch.proofs_added.insert(proof.y.to_hex(), proof.clone());
+            }
+        });
+        if staged.is_some() {
+            return Ok(());
+        }
 
-            // Remove proofs by Y value
-            state.proofs.retain(|p| !removed_ys.contains(&p.y));
+        let db = connection().await?;
+        let tx = idb::write_transaction(&db, &[PROOFS_STORE]).map_err(to_db_error)?;
 
-            // Add new proofs
-            state.proofs.extend(added);
-        }
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        // Removals first so a proof that is both removed and re-added ends up present
+        let queued = (|| {
+            for y in &removed_ys {
+                delete(&tx, PROOFS_STORE, &y.to_hex())?;
+            }
+            for proof in &added {
+                put(&tx, PROOFS_STORE, &proof.y.to_hex(), proof_record(proof)?)?;
+            }
+            Ok(())
+        })();
+        abort_on_error(&tx, queued)?;
+
+        idb::await_transaction(&tx).await.map_err(to_write_error)
     }
 
     async fn get_proofs(
@@ -257,63 +958,125 @@ impl WalletDatabase for HybridWalletDatabase {
         state: Option<Vec<State>>,
         spending_conditions: Option<Vec<SpendingConditions>>,
     ) -> Result<Vec<ProofInfo>, Self::Err> {
-        let proofs = self.state.lock().unwrap().proofs.clone();
+        // Narrow the read with the most specific index available,
+        // then apply the full filter to what comes back
+        let proofs: Vec<ProofInfo> = match (&mint_url, &unit, &state) {
+            (Some(url), Some(unit), _) => {
+                let key: js_sys::Array = [url.to_string(), unit.to_string()]
+                    .iter()
+                    .map(|s| JsValue::from_str(s))
+                    .collect();
+                get_by_index(PROOFS_STORE, MINT_UNIT_INDEX, &key).await?
+            }
+            (Some(url), None, _) => {
+                get_by_index(PROOFS_STORE, MINT_URL_INDEX, &JsValue::from_str(&url.to_string())).await?
+            }
+            (None, _, Some(states)) => {
+                let mut proofs = Vec::new();
+                for s in states {
+                    let key = JsValue::from_str(&s.to_string());
+                    proofs.extend(get_by_index::<ProofInfo>(PROOFS_STORE, STATE_INDEX, &key).await?);
+                }
+                proofs
+            }
+            (None, Some(unit), None) => {
+                get_by_index(PROOFS_STORE, UNIT_INDEX, &JsValue::from_str(&unit.to_string())).await?
+            }
+            (None, None, None) => get_all(PROOFS_STORE).await?,
+        };
 
-        let filtered: Vec<ProofInfo> = proofs
-            .into_iter()
-            .filter(|p| {
-                mint_url.as_ref().map_or(true, |url| &p.mint_url == url)
-                    && unit.as_ref().map_or(true, |u| &p.unit == u)
-                    && state.as_ref().map_or(true, |states| states.contains(&p.state))
-                    && spending_conditions.as_ref().map_or(true, |conds| {
-                        p.spending_condition.as_ref().map_or(false, |pc| conds.contains(pc))
-                            || p.spending_condition.is_none()
-                    })
-            })
-            .collect();
+        let proofs = match self.batch.lock().unwrap().as_ref() {
+            Some(batch) => batch.overlay_proofs(proofs),
+            None => proofs,
+        };
 
-        Ok(filtered)
+        Ok(proofs
+            .into_iter()
+            .filter(|p| proof_matches(p, &mint_url, &unit, &state, &spending_conditions))
+            .collect())
     }
 
     async fn update_proofs_state(&self, ys: Vec<PublicKey>, new_state: State) -> Result<(), Self::Err> {
-        {
-            let mut state = self.state.lock().unwrap();
-            for proof in &mut state.proofs {
-                if ys.contains(&proof.y) {
-                    proof.state = new_state;
+        if self.in_atomic() {
+            // Pull proofs that aren't staged yet into the batch, then update them there
+            let mut loaded = Vec::new();
+            for y in &ys {
+                let key = y.to_hex();
+                let is_staged = self.staged(|b| b.proofs_added.contains_key(&key) || b.proofs_removed.contains(&key))
+                    .unwrap_or(false);
+                if !is_staged {
+                    if let Some(proof) = get_one::<ProofInfo>(PROOFS_STORE, &key).await? {
+                        loaded.push(proof);
+                    }
+                }
+            }
+
+            self.staged(|batch| {
+                for proof in loaded {
+                    batch.proofs_added.entry(proof.y.to_hex()).or_insert(proof);
+                }
+                for y in &ys {
+                    if let Some(proof) = batch.proofs_added.get_mut(&y.to_hex()) {
+                        proof.state = new_state;
+                    }
                 }
+            });
+            return Ok(());
+        }
+
+        let db = connection().await?;
+        let tx = idb::write_transaction(&db, &[PROOFS_STORE]).map_err(to_db_error)?;
+
+        for y in &ys {
+            let key = y.to_hex();
+            if let Some(record) = abort_on_error(&tx, get_in(&tx, PROOFS_STORE, &key).await)? {
+                let updated = decode::<ProofInfo>(PROOFS_STORE, &record).and_then(|mut proof| {
+                    proof.state = new_state;
+                    put(&tx, PROOFS_STORE, &key, proof_record(&proof)?)
+                });
+                abort_on_error(&tx, updated)?;
             }
         }
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+
+        idb::await_transaction(&tx).await.map_err(to_write_error)
     }
 
     async fn increment_keyset_counter(&self, keyset_id: &Id, count: u32) -> Result<u32, Self::Err> {
-        let new_value = {
-            let mut state = self.state.lock().unwrap();
-            let counter = state.keyset_counters.entry(*keyset_id).or_insert(0);
-            *counter += count;
-            *counter
+        let db = connection().await?;
+        let tx = idb::write_transaction(&db, &[KEYSET_COUNTERS_STORE]).map_err(to_db_error)?;
+
+        // Read-modify-write inside one transaction so concurrent increments can't interleave
+        let key = keyset_id.to_string();
+        let current: u32 = match get_in(&tx, KEYSET_COUNTERS_STORE, &key).await? {
+            Some(record) => decode(KEYSET_COUNTERS_STORE, &record)?,
+            None => 0,
         };
-        self.save_snapshot().await.map_err(to_db_error)?;
+        let new_value = current + count;
+        put(&tx, KEYSET_COUNTERS_STORE, &key, data_record(KEYSET_COUNTERS_STORE, &new_value)?)?;
+
+        idb::await_transaction(&tx).await.map_err(to_write_error)?;
         Ok(new_value)
     }
 
     async fn add_transaction(&self, transaction: Transaction) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().transactions.push(transaction);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        if self.staged(|batch| batch.transactions.push(transaction.clone())).is_some() {
+            return Ok(());
+        }
+        put_one(TRANSACTIONS_STORE, &transaction_key(&transaction), transaction_record(&transaction)?).await
     }
 
     async fn get_transaction(
         &self,
         transaction_id: TransactionId,
     ) -> Result<Option<Transaction>, Self::Err> {
-        Ok(self.state.lock().unwrap()
-            .transactions
-            .iter()
-            .find(|t| TransactionId::new(t.ys.clone()) == transaction_id)
-            .cloned())
+        let key = transaction_id.to_string();
+        let staged = self.staged(|batch| {
+            batch.transactions.iter().find(|t| transaction_key(t) == key).cloned()
+        }).flatten();
+        if staged.is_some() {
+            return Ok(staged);
+        }
+        get_one(TRANSACTIONS_STORE, &key).await
     }
 
     async fn list_transactions(
@@ -322,7 +1085,15 @@ impl WalletDatabase for HybridWalletDatabase {
         direction: Option<TransactionDirection>,
         unit: Option<CurrencyUnit>,
     ) -> Result<Vec<Transaction>, Self::Err> {
-        let transactions = self.state.lock().unwrap().transactions.clone();
+        let mut transactions: Vec<Transaction> = match &mint_url {
+            Some(url) => {
+                get_by_index(TRANSACTIONS_STORE, MINT_URL_INDEX, &JsValue::from_str(&url.to_string())).await?
+            }
+            None => get_all(TRANSACTIONS_STORE).await?,
+        };
+        if let Some(staged) = self.staged(|batch| batch.transactions.clone()) {
+            transactions.extend(staged);
+        }
 
         let filtered: Vec<Transaction> = transactions
             .into_iter()
@@ -337,9 +1108,7 @@ impl WalletDatabase for HybridWalletDatabase {
     }
 
     async fn remove_transaction(&self, transaction_id: TransactionId) -> Result<(), Self::Err> {
-        self.state.lock().unwrap().transactions.retain(|t| TransactionId::new(t.ys.clone()) != transaction_id);
-        self.save_snapshot().await.map_err(to_db_error)?;
-        Ok(())
+        delete_one(TRANSACTIONS_STORE, &transaction_id.to_string()).await
     }
 
     async fn get_balance(
@@ -357,3 +1126,159 @@ impl WalletDatabase for HybridWalletDatabase {
         Ok(balance)
     }
 }
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use cashu::secret::Secret;
+    use cashu::{Amount, Proof, SecretKey};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    const MINT_A: &str = "https://mint-a.example.com";
+    const MINT_B: &str = "https://mint-b.example.com";
+
+    fn mint(url: &str) -> MintUrl {
+        MintUrl::from_str(url).unwrap()
+    }
+
+    fn proof(mint_url: &str, unit: CurrencyUnit, state: State, amount: u64) -> ProofInfo {
+        let proof = Proof::new(
+            Amount::from(amount),
+            Id::from_str("009a1f293253e41e").unwrap(),
+            Secret::generate(),
+            SecretKey::generate().public_key(),
+        );
+        ProofInfo::new(proof, mint(mint_url), state, unit).unwrap()
+    }
+
+    fn sent_token(id: &str) -> SentToken {
+        SentToken {
+            id: id.to_string(),
+            token: format!("cashuB{}", id),
+            mint_url: mint(MINT_A),
+            unit: CurrencyUnit::Sat,
+            amount: 1,
+            proofs: Vec::new(),
+            created_at: 0,
+            locked_to: None,
+            group_id: None,
+            message_id: None,
+            status: SentTokenStatus::Pending,
+        }
+    }
+
+    fn amounts(proofs: &[ProofInfo]) -> Vec<u64> {
+        let mut amounts: Vec<u64> = proofs.iter().map(|p| u64::from(p.proof.amount)).collect();
+        amounts.sort();
+        amounts
+    }
+
+    /// The wallet database with everything left by earlier tests cleared
+    async fn empty_db() -> HybridWalletDatabase {
+        let db = HybridWalletDatabase::new().await.unwrap();
+        let connection = idb::connection(&SCHEMA).await.unwrap();
+        let tx = idb::write_transaction(&connection, ALL_STORES).unwrap();
+        for store in ALL_STORES {
+            tx.object_store(store).unwrap().clear().unwrap();
+        }
+        idb::await_transaction(&tx).await.unwrap();
+        db
+    }
+
+    #[wasm_bindgen_test]
+    async fn get_proofs_uses_the_mint_unit_and_state_indexes() {
+        let db = empty_db().await;
+        let proofs = vec![
+            proof(MINT_A, CurrencyUnit::Sat, State::Unspent, 1),
+            proof(MINT_A, CurrencyUnit::Sat, State::Spent, 2),
+            proof(MINT_A, CurrencyUnit::Usd, State::Unspent, 4),
+            proof(MINT_B, CurrencyUnit::Sat, State::Unspent, 8),
+            proof(MINT_B, CurrencyUnit::Sat, State::Pending, 16),
+        ];
+        db.update_proofs(proofs.clone(), Vec::new()).await.unwrap();
+
+        let query = |mint_url: Option<&str>, unit: Option<CurrencyUnit>, state: Option<Vec<State>>| {
+            let db = db.clone();
+            async move { amounts(&db.get_proofs(mint_url.map(mint), unit, state, None).await.unwrap()) }
+        };
+
+        assert_eq!(query(None, None, None).await, vec![1, 2, 4, 8, 16]);
+        assert_eq!(query(Some(MINT_A), None, None).await, vec![1, 2, 4]);
+        assert_eq!(query(Some(MINT_A), Some(CurrencyUnit::Sat), None).await, vec![1, 2]);
+        assert_eq!(query(Some(MINT_A), None, Some(vec![State::Unspent])).await, vec![1, 4]);
+        assert_eq!(query(None, Some(CurrencyUnit::Usd), None).await, vec![4]);
+        assert_eq!(query(None, None, Some(vec![State::Unspent, State::Pending])).await, vec![1, 4, 8, 16]);
+        assert_eq!(query(None, Some(CurrencyUnit::Sat), Some(vec![State::Unspent])).await, vec![1, 8]);
+
+        // State changes and removals move proofs between index keys
+        db.update_proofs_state(vec![proofs[0].y], State::Spent).await.unwrap();
+        db.update_proofs(Vec::new(), vec![proofs[3].y]).await.unwrap();
+        assert_eq!(query(None, None, Some(vec![State::Spent])).await, vec![1, 2]);
+        assert_eq!(query(Some(MINT_B), None, None).await, vec![16]);
+        assert_eq!(query(Some(MINT_A), Some(CurrencyUnit::Sat), Some(vec![State::Unspent])).await, Vec::<u64>::new());
+    }
+
+    #[wasm_bindgen_test]
+    async fn atomic_commits_staged_changes_together() {
+        let db = empty_db().await;
+        let minted = proof(MINT_A, CurrencyUnit::Sat, State::Unspent, 32);
+
+        let staged = db.atomic(|scoped| {
+            let (db, minted) = (db.clone(), minted.clone());
+            async move {
+                scoped.update_proofs(vec![minted], Vec::new()).await.map_err(|e| JsValue::from_str(&e.to_string()))?;
+                scoped.put_sent_token(sent_token("together")).await?;
+
+                // Staged changes are only visible through the scoped handle until the commit
+                let inside = scoped.get_proofs(None, None, None, None).await.unwrap().len();
+                let outside = db.get_proofs(None, None, None, None).await.unwrap().len();
+                Ok((inside, outside))
+            }
+        }).await.unwrap();
+        assert_eq!(staged, (1, 0));
+
+        assert_eq!(amounts(&db.get_proofs(None, None, None, None).await.unwrap()), vec![32]);
+        assert_eq!(db.sent_tokens().await.unwrap().len(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    async fn atomic_drops_staged_changes_when_the_operation_fails() {
+        let db = empty_db().await;
+
+        let result = db.atomic(|scoped| async move {
+            scoped.put_sent_token(sent_token("dropped")).await?;
+            Err::<(), _>(JsValue::from_str("mint unreachable"))
+        }).await;
+
+        assert!(result.is_err());
+        assert!(db.sent_tokens().await.unwrap().is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn atomic_keeps_the_mints_result_when_the_operation_fails_afterwards() {
+        let db = empty_db().await;
+        let minted = proof(MINT_A, CurrencyUnit::Sat, State::Unspent, 64);
+
+        let result = db.atomic(|scoped| {
+            let minted = minted.clone();
+            async move {
+                scoped.update_proofs(vec![minted], Vec::new()).await.map_err(|e| JsValue::from_str(&e.to_string()))?;
+                scoped.put_sent_token(sent_token("after mint")).await?;
+                Err::<(), _>(JsValue::from_str("failed after the swap"))
+            }
+        }).await;
+
+        assert_eq!(result.unwrap_err().as_string().as_deref(), Some("failed after the swap"));
+        assert_eq!(amounts(&db.get_proofs(None, None, None, None).await.unwrap()), vec![64]);
+        assert_eq!(db.sent_tokens().await.unwrap().len(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn quota_errors_are_typed() {
+        let quota = DomException::new_with_message_and_name("full", "QuotaExceededError").unwrap();
+        assert!(matches!(WalletWriteError::from_js(quota.into()), WalletWriteError::QuotaExceeded));
+
+        let other = DomException::new_with_message_and_name("gone", "InvalidStateError").unwrap();
+        assert!(matches!(WalletWriteError::from_js(other.into()), WalletWriteError::Failed(_)));
+    }
+}
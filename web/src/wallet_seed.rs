@@ -0,0 +1,228 @@
+//! Cashu wallet seed, independent of the Nostr identity
+//!
+//! New wallets get a BIP-39 mnemonic, stored (sealed by the vault) under
+//! `wallet_mnemonic`. Wallets created before that used the Nostr secret key
+//! as seed (32 key bytes, zero padded); that seed is pinned under
+//! `wallet_legacy_seed` the first time it is needed, so importing or rotating
+//! the nsec no longer changes the wallet's deterministic secrets.
+
+use bip39::Mnemonic;
+use cdk_common::database::WalletDatabase;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::vault;
+use crate::wallet_db::HybridWalletDatabase;
+
+const MNEMONIC_KEY: &str = "wallet_mnemonic";
+const LEGACY_SEED_KEY: &str = "wallet_legacy_seed";
+
+/// 128 bits of entropy = 12 words
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+
+/// Where the wallet seed comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SeedSource {
+    Mnemonic,
+    /// Derived from the nsec by older versions
+    LegacyNsec,
+    /// Not created yet (happens on first wallet use)
+    None,
+}
+
+impl SeedSource {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mnemonic => "mnemonic",
+            Self::LegacyNsec => "legacy_nsec",
+            Self::None => "none",
+        }
+    }
+}
+
+fn read(key: &str) -> Result<Option<String>, JsValue> {
+    match crate::get_local_storage()?.get_item(key)? {
+        Some(stored) => Ok(Some(vault::open(&stored)?)),
+        None => Ok(None),
+    }
+}
+
+fn write(key: &str, value: &str) -> Result<(), JsValue> {
+    crate::get_local_storage()?.set_item(key, &vault::seal(value)?)
+}
+
+pub(crate) fn seed_source() -> Result<SeedSource, JsValue> {
+    let storage = crate::get_local_storage()?;
+    if storage.get_item(MNEMONIC_KEY)?.is_some() {
+        Ok(SeedSource::Mnemonic)
+    } else if storage.get_item(LEGACY_SEED_KEY)?.is_some() {
+        Ok(SeedSource::LegacyNsec)
+    } else {
+        Ok(SeedSource::None)
+    }
+}
+
+pub(crate) fn load_mnemonic() -> Result<Option<Mnemonic>, JsValue> {
+    read(MNEMONIC_KEY)?
+        .map(|words| parse_mnemonic(&words))
+        .transpose()
+}
+
+pub(crate) fn parse_mnemonic(words: &str) -> Result<Mnemonic, JsValue> {
+    Mnemonic::parse(words.trim())
+        .map_err(|e| JsValue::from_str(&format!("Invalid mnemonic: {}", e)))
+}
+
+pub(crate) fn save_mnemonic(mnemonic: &Mnemonic) -> Result<(), JsValue> {
+    write(MNEMONIC_KEY, &mnemonic.to_string())
+}
+
+pub(crate) fn generate_mnemonic() -> Result<Mnemonic, JsValue> {
+    let mut entropy = [0u8; MNEMONIC_ENTROPY_LEN];
+    getrandom::getrandom(&mut entropy)
+        .map_err(|e| JsValue::from_str(&format!("Failed to get randomness: {}", e)))?;
+    Mnemonic::from_entropy(&entropy)
+        .map_err(|e| JsValue::from_str(&format!("Failed to create mnemonic: {}", e)))
+}
+
+/// The seed older versions derived from the Nostr secret key
+fn seed_from_secret_hex(secret_hex: &str) -> Result<[u8; 64], JsValue> {
+    let keys = nostr::Keys::parse(secret_hex)
+        .map_err(|e| JsValue::from_str(&format!("Failed to parse keys: {}", e)))?;
+    let mut seed = [0u8; 64];
+    seed[..32].copy_from_slice(keys.secret_key().as_secret_bytes());
+    Ok(seed)
+}
+
+pub(crate) fn legacy_seed() -> Result<Option<[u8; 64]>, JsValue> {
+    let Some(seed_hex) = read(LEGACY_SEED_KEY)? else {
+        return Ok(None);
+    };
+    let seed: [u8; 64] = hex::decode(&seed_hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| JsValue::from_str("Invalid legacy wallet seed"))?;
+    Ok(Some(seed))
+}
+
+/// The stored seed material, as carried in backups
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct StoredSeeds {
+    pub mnemonic: Option<String>,
+    /// 64-byte hex
+    pub legacy_seed: Option<String>,
+}
+
+pub(crate) fn stored_seeds() -> Result<StoredSeeds, JsValue> {
+    Ok(StoredSeeds {
+        mnemonic: read(MNEMONIC_KEY)?,
+        legacy_seed: read(LEGACY_SEED_KEY)?,
+    })
+}
+
+impl StoredSeeds {
+    pub(crate) fn validate(&self) -> Result<(), JsValue> {
+        if let Some(words) = &self.mnemonic {
+            parse_mnemonic(words)?;
+        }
+        if let Some(seed_hex) = &self.legacy_seed {
+            if hex::decode(seed_hex).map(|b| b.len()) != Ok(64) {
+                return Err(JsValue::from_str("Invalid legacy wallet seed"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse to discard a different stored seed unless `replace` is true:
+    /// funds only known to it can't be restored once it is gone (export it first)
+    pub(crate) fn check_replaces(&self, replace: bool) -> Result<(), JsValue> {
+        if replace {
+            return Ok(());
+        }
+        if let Some(current) = load_mnemonic()? {
+            let incoming = self.mnemonic.as_deref().map(parse_mnemonic).transpose()?;
+            if incoming.as_ref() != Some(&current) {
+                return Err(JsValue::from_str(
+                    "Wallet already has a different mnemonic. Export it first, then import with replace to discard it",
+                ));
+            }
+        }
+        if let Some(current) = read(LEGACY_SEED_KEY)? {
+            if self.legacy_seed.as_ref() != Some(&current) {
+                return Err(JsValue::from_str(
+                    "Wallet already has a different nsec-derived seed. Import with replace to discard it",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replace the stored seeds (checked first, so nothing changes on error)
+pub(crate) fn restore_seeds(seeds: &StoredSeeds, replace: bool) -> Result<(), JsValue> {
+    seeds.validate()?;
+    seeds.check_replaces(replace)?;
+
+    let storage = crate::get_local_storage()?;
+    for (key, value) in [(MNEMONIC_KEY, &seeds.mnemonic), (LEGACY_SEED_KEY, &seeds.legacy_seed)] {
+        match value {
+            Some(value) => write(key, value)?,
+            None => storage.remove_item(key)?,
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite the stored seed under the vault's current setting
+pub(crate) fn reseal() -> Result<(), JsValue> {
+    for key in [MNEMONIC_KEY, LEGACY_SEED_KEY] {
+        if let Some(value) = read(key)? {
+            write(key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Pin the nsec-derived seed before the nsec is replaced, unless the wallet
+/// already has its own seed. Call this before importing or generating keys.
+pub(crate) fn pin_legacy_seed_before_identity_change() -> Result<(), JsValue> {
+    if seed_source()? != SeedSource::None {
+        return Ok(());
+    }
+    let storage = crate::get_local_storage()?;
+    if let Some(secret_hex) = crate::read_secret_hex(&storage)? {
+        write(LEGACY_SEED_KEY, &hex::encode(seed_from_secret_hex(&secret_hex)?))?;
+        crate::log("Pinned the nsec-derived wallet seed before changing identity");
+    }
+    Ok(())
+}
+
+/// The 64-byte seed for CDK wallets, creating it on first use:
+/// wallets with history keep their nsec-derived seed, new wallets get a mnemonic
+pub(crate) async fn wallet_seed(db: &HybridWalletDatabase) -> Result<[u8; 64], JsValue> {
+    if let Some(mnemonic) = load_mnemonic()? {
+        return Ok(mnemonic.to_seed(""));
+    }
+    if let Some(seed) = legacy_seed()? {
+        return Ok(seed);
+    }
+
+    let has_history = !db.get_proofs(None, None, None, None).await
+        .map_err(|e| JsValue::from_str(&format!("Failed to read proofs: {}", e)))?
+        .is_empty();
+    let storage = crate::get_local_storage()?;
+
+    if has_history {
+        if let Some(secret_hex) = crate::read_secret_hex(&storage)? {
+            let seed = seed_from_secret_hex(&secret_hex)?;
+            write(LEGACY_SEED_KEY, &hex::encode(seed))?;
+            crate::log("Existing wallet keeps its nsec-derived seed (migrate to a mnemonic from the wallet settings)");
+            return Ok(seed);
+        }
+    }
+
+    let mnemonic = generate_mnemonic()?;
+    save_mnemonic(&mnemonic)?;
+    crate::log("Created a new wallet mnemonic");
+    Ok(mnemonic.to_seed(""))
+}